@@ -252,7 +252,17 @@ impl MetricsRegistry {
         help: &str,
         variable_labels: &[&str],
     ) -> Result<HistogramVec, PrometheusError> {
-        let opts = HistogramOpts::new(name, help);
+        self.global_histogram_vec_with_labels(name, help, HashMap::new(), variable_labels)
+    }
+
+    pub fn global_histogram_vec_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+        variable_labels: &[&str],
+    ) -> Result<HistogramVec, PrometheusError> {
+        let opts = HistogramOpts::new(name, help).const_labels(const_labels);
         let histograms = HistogramVec::new(opts, variable_labels)?;
         let id = histograms.desc().first().unwrap().id;
         let maybe_histogram = self.global_histogram_vecs.read().unwrap().get(&id).cloned();
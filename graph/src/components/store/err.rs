@@ -1,9 +1,10 @@
 use super::{BlockNumber, DeploymentSchemaVersion};
-use crate::prelude::QueryExecutionError;
+use crate::prelude::{QueryExecutionError, ENV_VARS};
 use crate::{data::store::EntityValidationError, prelude::DeploymentHash};
 
 use anyhow::{anyhow, Error};
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use std::collections::HashSet;
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -154,19 +155,60 @@ impl StoreError {
         None
     }
 
+    fn write_unavailable(e: &DieselError, retryable_sqlstates: &HashSet<String>) -> Option<Self> {
+        // Writes that fail with an error code that the operator declared as
+        // safe to retry are also treated as 'database unavailable'. We only
+        // do that for writes since only they get retried by the indexing
+        // machinery
+        if let Some(e) = Self::database_unavailable(e) {
+            return Some(e);
+        }
+        if let DieselError::DatabaseError(kind, _) = e {
+            if sqlstate(kind).is_some_and(|code| retryable_sqlstates.contains(code)) {
+                return Some(Self::DatabaseUnavailable);
+            }
+        }
+        None
+    }
+
+    /// Convert an error that happened while writing data. Errors with one
+    /// of the codes from `GRAPH_STORE_RETRYABLE_SQLSTATES` are turned into
+    /// `DatabaseUnavailable` so that the write is retried
+    pub fn from_write_error(error: DieselError) -> Self {
+        match Self::write_unavailable(&error, &ENV_VARS.store.retryable_sqlstates.0) {
+            Some(e) => e,
+            None => StoreError::Unknown(error.into()),
+        }
+    }
+
     pub fn write_failure(
         error: DieselError,
         entity: &str,
         block: BlockNumber,
         query: String,
     ) -> Self {
-        match Self::database_unavailable(&error) {
+        match Self::write_unavailable(&error, &ENV_VARS.store.retryable_sqlstates.0) {
             Some(e) => return e,
             None => StoreError::WriteFailure(entity.to_string(), block, error.to_string(), query),
         }
     }
 }
 
+/// The Postgres error code (SQLSTATE) for an error. Diesel does not expose
+/// the error code directly, but derives the kind of error from it, and we
+/// can therefore only report the code for the errors that diesel knows
+fn sqlstate(kind: &DatabaseErrorKind) -> Option<&'static str> {
+    match kind {
+        DatabaseErrorKind::UniqueViolation => Some("23505"),
+        DatabaseErrorKind::ForeignKeyViolation => Some("23503"),
+        DatabaseErrorKind::SerializationFailure => Some("40001"),
+        DatabaseErrorKind::ReadOnlyTransaction => Some("25006"),
+        DatabaseErrorKind::NotNullViolation => Some("23502"),
+        DatabaseErrorKind::CheckViolation => Some("23514"),
+        _ => None,
+    }
+}
+
 impl From<DieselError> for StoreError {
     fn from(e: DieselError) -> Self {
         match Self::database_unavailable(&e) {
@@ -205,3 +247,48 @@ impl From<std::fmt::Error> for StoreError {
         StoreError::Unknown(anyhow!("{}", e.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_error(kind: DatabaseErrorKind, msg: &str) -> DieselError {
+        DieselError::DatabaseError(kind, Box::new(msg.to_string()))
+    }
+
+    #[test]
+    fn retryable_sqlstates() {
+        let retryable: HashSet<String> = ["40001".to_string()].into_iter().collect();
+        let is_retried = |e: &DieselError| {
+            matches!(
+                StoreError::write_unavailable(e, &retryable),
+                Some(StoreError::DatabaseUnavailable)
+            )
+        };
+
+        let serialization = db_error(
+            DatabaseErrorKind::SerializationFailure,
+            "could not serialize access due to concurrent update",
+        );
+        assert!(is_retried(&serialization));
+        // Only writes are retried
+        assert!(StoreError::database_unavailable(&serialization).is_none());
+
+        // The error code is never guessed from the message
+        let deadlock = db_error(DatabaseErrorKind::Unknown, "deadlock detected");
+        assert!(!is_retried(&deadlock));
+
+        let unique = db_error(DatabaseErrorKind::UniqueViolation, "duplicate key");
+        assert!(!is_retried(&unique));
+
+        let other = db_error(DatabaseErrorKind::Unknown, "relation does not exist");
+        assert!(!is_retried(&other));
+
+        // Closed connections are always retried
+        let closed = db_error(
+            DatabaseErrorKind::Unknown,
+            "server closed the connection unexpectedly",
+        );
+        assert!(is_retried(&closed));
+    }
+}
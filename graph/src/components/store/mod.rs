@@ -32,7 +32,7 @@ use crate::data::store::{Id, IdList, Value};
 use crate::data::value::Word;
 use crate::data_source::CausalityRegion;
 use crate::derive::CheapClone;
-use crate::env::ENV_VARS;
+use crate::env::{PruneTableOrder, ENV_VARS};
 use crate::prelude::{s, Attribute, DeploymentHash, ValueType};
 use crate::schema::{ast as sast, EntityKey, EntityType, InputSchema};
 use crate::util::stats::MovingStats;
@@ -897,6 +897,31 @@ pub struct VersionStats {
     pub ratio: f64,
     /// The last block to which this table was pruned
     pub last_pruned_block: Option<BlockNumber>,
+    /// Overrides for `PruneRequest.rebuild_threshold` and
+    /// `PruneRequest.delete_threshold` for this table from the
+    /// `subgraphs.table_stats` table. They are only used when
+    /// `PruneRequest.thresholds_from_db` is set
+    pub rebuild_threshold: Option<f64>,
+    pub delete_threshold: Option<f64>,
+}
+
+/// A maintenance operation like pruning or copying that finished for one
+/// table of a deployment, as recorded when `GRAPH_STORE_MAINTENANCE_HISTORY`
+/// is turned on
+#[derive(Clone, Debug)]
+pub struct MaintenanceRecord {
+    /// The kind of operation, `prune` or `copy`
+    pub operation: String,
+    pub table: String,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+    /// The number of rows that were copied or deleted
+    pub rows: i64,
+    /// The size of the table after the operation
+    pub bytes: i64,
+    /// How the operation was performed, e.g., `rebuild` or `delete` for
+    /// pruning
+    pub strategy: String,
 }
 
 /// What phase of pruning we are working on
@@ -907,13 +932,15 @@ pub enum PrunePhase {
     CopyNonfinal,
     /// Delete unneeded entity versions
     Delete,
+    /// Truncate a table none of whose versions are needed any more
+    Truncate,
 }
 
 impl PrunePhase {
     pub fn strategy(&self) -> PruningStrategy {
         match self {
             PrunePhase::CopyFinal | PrunePhase::CopyNonfinal => PruningStrategy::Rebuild,
-            PrunePhase::Delete => PruningStrategy::Delete,
+            PrunePhase::Delete | PrunePhase::Truncate => PruningStrategy::Delete,
         }
     }
 }
@@ -958,6 +985,29 @@ pub enum PruningStrategy {
     Delete,
 }
 
+/// What to do with a table when pruning, as decided by
+/// `PruneRequest::decide`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneAction {
+    /// Prune the table with `PruningStrategy::Rebuild`
+    Rebuild,
+    /// Prune the table with `PruningStrategy::Delete`
+    Delete,
+    /// Do not prune the table
+    Skip,
+}
+
+impl PruneAction {
+    /// The strategy for pruning, or `None` if the table is not pruned
+    pub fn strategy(self) -> Option<PruningStrategy> {
+        match self {
+            PruneAction::Rebuild => Some(PruningStrategy::Rebuild),
+            PruneAction::Delete => Some(PruningStrategy::Delete),
+            PruneAction::Skip => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 /// A request to prune a deployment. This struct encapsulates decision
 /// making around the best strategy for pruning (deleting historical
@@ -983,6 +1033,38 @@ pub struct PruneRequest {
     /// `ENV_VARS.store.delete_threshold`, but can be modified after
     /// construction
     pub delete_threshold: f64,
+    /// Tables with fewer versions than this are pruned by deleting even if
+    /// `rebuild_threshold` calls for rebuilding them. Initialized from
+    /// `ENV_VARS.store.prune_rebuild_min_rows`, but can be modified after
+    /// construction
+    pub rebuild_min_rows: i64,
+    /// Never use the delete strategy when removing less than this fraction
+    /// of history, regardless of `delete_threshold`. Initialized from
+    /// `ENV_VARS.store.prune_delete_min_fraction`
+    pub delete_min_fraction: f64,
+    /// Only analyze tables whose statistics are older than this before
+    /// deciding how to prune them; when this is `None`, all tables are
+    /// analyzed. Initialized from `ENV_VARS.store.prune_stats_max_age`, but
+    /// can be modified after construction
+    pub stats_max_age: Option<Duration>,
+    /// Truncate tables from which pruning would remove all versions
+    /// instead of using `strategy` for them. Initialized from
+    /// `ENV_VARS.store.prune_use_truncate_when_full`, but can be modified
+    /// after construction
+    pub truncate_when_full: bool,
+    /// The order in which tables are pruned. Initialized from
+    /// `ENV_VARS.store.prune_table_order`, but can be modified after
+    /// construction
+    pub table_order: PruneTableOrder,
+    /// Whether to go through tables in batches that only depend on the
+    /// data and to delete rows ordered by `id`, so that repeated runs on
+    /// the same data run the same statements. Initialized from
+    /// `ENV_VARS.store.prune_deterministic`
+    pub deterministic: bool,
+    /// Whether the per-table thresholds in `VersionStats` take precedence
+    /// over `rebuild_threshold` and `delete_threshold`. Initialized from
+    /// `ENV_VARS.store.prune_thresholds_from_db`
+    pub thresholds_from_db: bool,
 }
 
 impl PruneRequest {
@@ -1037,6 +1119,13 @@ impl PruneRequest {
             latest_block,
             rebuild_threshold,
             delete_threshold,
+            rebuild_min_rows: ENV_VARS.store.prune_rebuild_min_rows,
+            delete_min_fraction: ENV_VARS.store.prune_delete_min_fraction,
+            stats_max_age: ENV_VARS.store.prune_stats_max_age,
+            truncate_when_full: ENV_VARS.store.prune_use_truncate_when_full,
+            table_order: ENV_VARS.store.prune_table_order,
+            deterministic: ENV_VARS.store.prune_deterministic,
+            thresholds_from_db: ENV_VARS.store.prune_thresholds_from_db,
         })
     }
 
@@ -1048,7 +1137,9 @@ impl PruneRequest {
     /// rebuilding, and if we are removing more than `delete_threshold`
     /// percent of the versions, we prune by deleting. If we would remove
     /// less than `delete_threshold` percent of the versions, we don't
-    /// prune.
+    /// prune. Tables with fewer than `rebuild_min_rows` versions are never
+    /// rebuilt since deleting from them is fast anyway. We never delete
+    /// when removing less than `delete_min_fraction` of the versions.
     pub fn strategy(&self, stats: &VersionStats) -> Option<PruningStrategy> {
         // If the deployment doesn't have enough history to cover the reorg
         // threshold, do not prune
@@ -1061,12 +1152,52 @@ impl PruneRequest {
         // that `history_pct` will tell us how much of that data pruning
         // will remove.
         let removal_ratio = self.history_pct(stats) * (1.0 - stats.ratio);
-        if removal_ratio >= self.rebuild_threshold {
-            Some(PruningStrategy::Rebuild)
-        } else if removal_ratio >= self.delete_threshold {
-            Some(PruningStrategy::Delete)
+        let removable_versions = (removal_ratio * stats.versions as f64).round() as i64;
+        self.decide(stats, removable_versions).strategy()
+    }
+
+    /// Return this request with the thresholds for the table with `stats`
+    /// replaced by the ones stored in the database if there are any and
+    /// `thresholds_from_db` is set. Like the thresholds from the
+    /// environment, overrides must be between 0 and 1; others are ignored
+    fn for_table(&self, stats: &VersionStats) -> PruneRequest {
+        if !self.thresholds_from_db {
+            return *self;
+        }
+        let valid = |threshold: &f64| (0.0..=1.0).contains(threshold);
+        PruneRequest {
+            rebuild_threshold: stats
+                .rebuild_threshold
+                .filter(valid)
+                .unwrap_or(self.rebuild_threshold),
+            delete_threshold: stats
+                .delete_threshold
+                .filter(valid)
+                .unwrap_or(self.delete_threshold),
+            ..*self
+        }
+    }
+
+    /// Decide how to prune the table with `stats` if pruning would remove
+    /// `removable_versions` of its `stats.versions` entity versions. The
+    /// thresholds for the table are the ones from `for_table`
+    pub fn decide(&self, stats: &VersionStats, removable_versions: i64) -> PruneAction {
+        let req = self.for_table(stats);
+        let removal_ratio = if stats.versions <= 0 {
+            0.0
         } else {
-            None
+            removable_versions as f64 / stats.versions as f64
+        };
+        if removal_ratio >= req.rebuild_threshold {
+            if stats.versions < req.rebuild_min_rows {
+                PruneAction::Delete
+            } else {
+                PruneAction::Rebuild
+            }
+        } else if removal_ratio >= req.delete_threshold.max(req.delete_min_fraction) {
+            PruneAction::Delete
+        } else {
+            PruneAction::Skip
         }
     }
 
@@ -1103,3 +1234,181 @@ pub struct CachedEthereumCall {
     /// The encoded return value of this call.
     pub return_value: Vec<u8>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version_stats(
+        versions: i64,
+        rebuild_threshold: Option<f64>,
+        delete_threshold: Option<f64>,
+    ) -> VersionStats {
+        VersionStats {
+            entities: versions / 10,
+            versions,
+            tablename: "thing".to_string(),
+            ratio: 0.1,
+            last_pruned_block: None,
+            rebuild_threshold,
+            delete_threshold,
+        }
+    }
+
+    #[test]
+    fn prune_rebuild_min_rows() {
+        let deployment =
+            DeploymentLocator::new(DeploymentId(1), DeploymentHash::new("QmPrune").unwrap());
+        let mut req = PruneRequest::new(&deployment, 100, 10, 0, 1000).unwrap();
+        req.rebuild_threshold = 0.5;
+        req.delete_threshold = 0.05;
+
+        // Pruning removes about 90% of the versions of this table
+        let stats = |versions| VersionStats {
+            entities: versions / 10,
+            versions,
+            tablename: "thing".to_string(),
+            ratio: 0.01,
+            last_pruned_block: None,
+            rebuild_threshold: None,
+            delete_threshold: None,
+        };
+
+        assert_eq!(Some(PruningStrategy::Rebuild), req.strategy(&stats(1_000)));
+
+        // A small table is pruned by deleting even though the rebuild
+        // threshold is met
+        req.rebuild_min_rows = 10_000;
+        assert_eq!(Some(PruningStrategy::Delete), req.strategy(&stats(1_000)));
+        assert_eq!(Some(PruningStrategy::Rebuild), req.strategy(&stats(10_000)));
+    }
+
+    #[test]
+    fn prune_thresholds_from_db() {
+        use PruningStrategy::*;
+
+        let deployment =
+            DeploymentLocator::new(DeploymentId(1), DeploymentHash::new("QmThresh").unwrap());
+        let mut req = PruneRequest::new(&deployment, 100, 10, 0, 1000).unwrap();
+        req.rebuild_threshold = 0.5;
+        req.delete_threshold = 0.05;
+        req.rebuild_min_rows = 0;
+        req.delete_min_fraction = 0.0;
+
+        // Pruning removes about 89% of the versions of this table
+        let stats = |rebuild_threshold, delete_threshold| VersionStats {
+            entities: 10,
+            versions: 1_000,
+            tablename: "thing".to_string(),
+            ratio: 0.01,
+            last_pruned_block: None,
+            rebuild_threshold,
+            delete_threshold,
+        };
+
+        // Without overrides in the database, the thresholds of the request
+        // are used
+        req.thresholds_from_db = true;
+        assert_eq!(Some(Rebuild), req.strategy(&stats(None, None)));
+
+        // Overrides in the database take precedence
+        assert_eq!(Some(Delete), req.strategy(&stats(Some(0.95), None)));
+        assert_eq!(None, req.strategy(&stats(Some(0.95), Some(0.95))));
+
+        // Overrides outside of 0..1 are ignored
+        assert_eq!(Some(Rebuild), req.strategy(&stats(Some(1.5), None)));
+        assert_eq!(Some(Rebuild), req.strategy(&stats(Some(-0.5), Some(2.0))));
+        assert_eq!(Some(Delete), req.strategy(&stats(Some(0.95), Some(-1.0))));
+
+        // ... but only if reading them is turned on
+        req.thresholds_from_db = false;
+        assert_eq!(Some(Rebuild), req.strategy(&stats(Some(0.95), Some(0.95))));
+    }
+
+    #[test]
+    fn prune_delete_min_fraction() {
+        use PruneAction::*;
+
+        let deployment =
+            DeploymentLocator::new(DeploymentId(1), DeploymentHash::new("QmFloor").unwrap());
+        let mut req = PruneRequest::new(&deployment, 100, 10, 0, 1000).unwrap();
+        req.rebuild_threshold = 0.5;
+        req.delete_threshold = 0.05;
+        req.rebuild_min_rows = 0;
+        req.delete_min_fraction = 0.2;
+
+        let stats = |versions| version_stats(versions, None, None);
+
+        // Fractions just above and below the floor
+        assert_eq!(Delete, req.decide(&stats(1000), 201));
+        assert_eq!(Delete, req.decide(&stats(1000), 200));
+        assert_eq!(Skip, req.decide(&stats(1000), 199));
+        // Even though the delete threshold is met
+        assert_eq!(Skip, req.decide(&stats(1000), 50));
+
+        // Lowering the delete threshold does not get below the floor
+        req.delete_threshold = 0.0;
+        assert_eq!(Skip, req.decide(&stats(1000), 199));
+
+        // A delete threshold above the floor still applies
+        req.delete_threshold = 0.3;
+        assert_eq!(Skip, req.decide(&stats(1000), 250));
+        assert_eq!(Delete, req.decide(&stats(1000), 300));
+
+        // Rebuilding is not affected by the floor
+        req.delete_min_fraction = 0.9;
+        assert_eq!(Rebuild, req.decide(&stats(1000), 500));
+    }
+
+    #[test]
+    fn prune_decide() {
+        use PruneAction::*;
+
+        let deployment =
+            DeploymentLocator::new(DeploymentId(1), DeploymentHash::new("QmDecide").unwrap());
+        let mut req = PruneRequest::new(&deployment, 100, 10, 0, 1000).unwrap();
+        req.rebuild_threshold = 0.5;
+        req.delete_threshold = 0.25;
+        req.rebuild_min_rows = 0;
+
+        let stats = |versions| version_stats(versions, None, None);
+
+        // Removing at least `rebuild_threshold` of the versions rebuilds
+        assert_eq!(Rebuild, req.decide(&stats(100), 100));
+        assert_eq!(Rebuild, req.decide(&stats(100), 50));
+        // Removing less than that, but at least `delete_threshold` deletes
+        assert_eq!(Delete, req.decide(&stats(100), 49));
+        assert_eq!(Delete, req.decide(&stats(100), 25));
+        // Removing less than `delete_threshold` does not prune at all
+        assert_eq!(Skip, req.decide(&stats(100), 24));
+        assert_eq!(Skip, req.decide(&stats(100), 0));
+
+        // Tables with fewer than `rebuild_min_rows` rows are never rebuilt
+        req.rebuild_min_rows = 100;
+        assert_eq!(Delete, req.decide(&stats(99), 50));
+        assert_eq!(Rebuild, req.decide(&stats(100), 50));
+        assert_eq!(Delete, req.decide(&stats(99), 25));
+        assert_eq!(Skip, req.decide(&stats(99), 24));
+
+        // An empty table has nothing to remove
+        req.rebuild_min_rows = 0;
+        assert_eq!(Skip, req.decide(&stats(0), 0));
+
+        // With thresholds of 0, tables are always pruned
+        req.rebuild_threshold = 0.0;
+        req.delete_threshold = 0.0;
+        assert_eq!(Rebuild, req.decide(&stats(0), 0));
+        req.rebuild_threshold = 1.0;
+        assert_eq!(Delete, req.decide(&stats(100), 99));
+        assert_eq!(Rebuild, req.decide(&stats(100), 100));
+
+        // Thresholds stored for the table are used if reading them is
+        // turned on
+        let stats = version_stats(100, Some(0.5), Some(0.25));
+        req.thresholds_from_db = true;
+        assert_eq!(Rebuild, req.decide(&stats, 50));
+        assert_eq!(Skip, req.decide(&stats, 24));
+        req.thresholds_from_db = false;
+        assert_eq!(Delete, req.decide(&stats, 50));
+    }
+}
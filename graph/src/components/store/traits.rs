@@ -706,6 +706,17 @@ pub trait StatusStore: Send + Sync + 'static {
         deployment_hash: &str,
     ) -> Result<Vec<(String, String)>, StoreError>;
 
+    /// Return the number of deployments stored in each database pool as
+    /// `(pool, count)` pairs, sorted by pool name
+    fn deployments_per_pool(&self) -> Result<Vec<(String, i64)>, StoreError>;
+
+    /// Return the maintenance operations that were recorded for the
+    /// deployment `id`, oldest first
+    fn maintenance_history(
+        &self,
+        id: &DeploymentHash,
+    ) -> Result<Vec<MaintenanceRecord>, StoreError>;
+
     /// A value of None indicates that the table is not available. Re-deploying
     /// the subgraph fixes this. It is undesirable to force everything to
     /// re-sync from scratch, so existing deployments will continue without a
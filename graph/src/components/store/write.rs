@@ -9,6 +9,7 @@ use crate::{
     data::{store::Id, subgraph::schema::SubgraphError},
     data_source::CausalityRegion,
     derive::CacheWeight,
+    env::ENV_VARS,
     util::cache_weight::CacheWeight,
 };
 
@@ -310,6 +311,9 @@ pub struct RowGroup {
     rows: Vec<EntityModification>,
 
     immutable: bool,
+    /// Whether changes to an entity at the same block as the previous
+    /// change to it replace that change rather than being rejected
+    coalesce: bool,
 }
 
 impl RowGroup {
@@ -318,6 +322,7 @@ impl RowGroup {
             entity_type,
             rows: Vec::new(),
             immutable,
+            coalesce: ENV_VARS.store.coalesce_batch_writes,
         }
     }
 
@@ -422,6 +427,15 @@ impl RowGroup {
             return Ok(());
         }
 
+        let row = if self.coalesce {
+            match self.coalesce_row(row)? {
+                Some(row) => row,
+                None => return Ok(()),
+            }
+        } else {
+            row
+        };
+
         if let Some(prev_row) = self.prev_row_mut(row.id()) {
             use EntityModification::*;
 
@@ -474,6 +488,75 @@ impl RowGroup {
         Ok(())
     }
 
+    /// Combine `row` with the most recent row for the same entity if that
+    /// row affects the same block so that only the effect of `row` remains.
+    /// Return `None` if `row` was combined, and `row` itself if it needs to
+    /// be appended normally
+    fn coalesce_row(
+        &mut self,
+        row: EntityModification,
+    ) -> Result<Option<EntityModification>, StoreError> {
+        use EntityModification::*;
+
+        let Some(pos) = self.rows.iter().rposition(|emod| emod.id() == row.id()) else {
+            return Ok(Some(row));
+        };
+
+        let block = row.block();
+        let prev_end = match &self.rows[pos] {
+            Insert { end, .. } | Overwrite { end, .. } => *end,
+            Remove { .. } => None,
+        };
+
+        if prev_end == Some(block) {
+            // The entity was already removed at `block`; removing it again
+            // does nothing, and writing it creates a new version
+            if !matches!(row, Remove { .. }) {
+                self.rows.push(row.as_insert(&self.entity_type)?);
+            }
+            return Ok(None);
+        }
+        if prev_end.is_some() || self.rows[pos].block() != block {
+            return Ok(Some(row));
+        }
+
+        // Keep an `Insert` if the entity did not exist before `block`,
+        // since nothing needs to be clamped in that case
+        let prev_is_insert = matches!(self.rows[pos], Insert { .. });
+        match row {
+            Insert {
+                key, data, block, ..
+            }
+            | Overwrite {
+                key, data, block, ..
+            } => {
+                self.rows[pos] = if prev_is_insert {
+                    Insert {
+                        key,
+                        data,
+                        block,
+                        end: None,
+                    }
+                } else {
+                    Overwrite {
+                        key,
+                        data,
+                        block,
+                        end: None,
+                    }
+                };
+            }
+            Remove { key, block } => {
+                if prev_is_insert {
+                    self.rows.remove(pos);
+                } else {
+                    self.rows[pos] = Remove { key, block };
+                }
+            }
+        }
+        Ok(None)
+    }
+
     fn append(&mut self, group: RowGroup) -> Result<(), StoreError> {
         if self.entity_type != group.entity_type {
             return Err(constraint_violation!(
@@ -909,7 +992,10 @@ mod test {
         components::store::{
             write::EntityModification, write::EntityOp, BlockNumber, EntityType, StoreError,
         },
-        data::{store::Id, value::Word},
+        data::{
+            store::{Id, Value},
+            value::Word,
+        },
         entity,
         prelude::DeploymentHash,
         schema::InputSchema,
@@ -938,6 +1024,7 @@ mod test {
             entity_type: ENTRY_TYPE.clone(),
             rows,
             immutable: false,
+            coalesce: false,
         };
         let act = group
             .clamps_by_block()
@@ -1060,6 +1147,13 @@ mod test {
             group.append(mods)?;
             Ok(group)
         }
+
+        fn coalesced(mods: &[Mod]) -> Result<Group, StoreError> {
+            let mut group = Self::new();
+            group.group.coalesce = true;
+            group.append(mods)?;
+            Ok(group)
+        }
     }
 
     impl PartialEq<&[Mod]> for Group {
@@ -1104,6 +1198,61 @@ mod test {
         assert_eq!(group, &[OvwC(1, 2), Ins(3)]);
     }
 
+    #[test]
+    fn coalesce() {
+        use Mod::*;
+
+        let res = Group::with(&[Ins(1), Ovw(1)]);
+        assert!(res.is_err());
+
+        let group = Group::coalesced(&[Ins(1), Ovw(1), Ovw(1)]).unwrap();
+        assert_eq!(group, &[Ins(1)]);
+
+        let group = Group::coalesced(&[Ovw(1), Ovw(1)]).unwrap();
+        assert_eq!(group, &[Ovw(1)]);
+
+        let group = Group::coalesced(&[Ins(1), Ovw(2), Ovw(2)]).unwrap();
+        assert_eq!(group, &[InsC(1, 2), Ins(2)]);
+
+        let group = Group::coalesced(&[Ins(1), Ovw(1), Rem(1)]).unwrap();
+        assert_eq!(0, group.group.row_count());
+
+        let group = Group::coalesced(&[Ovw(1), Rem(1)]).unwrap();
+        assert_eq!(group, &[Rem(1)]);
+
+        let group = Group::coalesced(&[Rem(1), Ovw(1)]).unwrap();
+        assert_eq!(group, &[Ovw(1)]);
+
+        let group = Group::coalesced(&[Ins(1), Rem(2), Rem(2), Ovw(2)]).unwrap();
+        assert_eq!(group, &[InsC(1, 2), Ins(2)]);
+
+        // Going backwards is still an error
+        let res = Group::coalesced(&[Ins(2), Ovw(1)]);
+        assert!(res.is_err());
+
+        // The latest data is kept
+        let key = THING_TYPE.parse_key("one").unwrap();
+        let mut group = RowGroup::new(THING_TYPE.clone(), false);
+        group.coalesce = true;
+        for count in 1..=3 {
+            group
+                .append_row(EntityModification::Overwrite {
+                    key: key.clone(),
+                    data: Arc::new(entity! { SCHEMA => id: "one", count: count }),
+                    block: 5,
+                    end: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(1, group.row_count());
+        match group.last_op(&key, 5).unwrap() {
+            EntityOp::Write { entity, .. } => {
+                assert_eq!(Some(&Value::Int(3)), entity.get("count"))
+            }
+            EntityOp::Remove { .. } => panic!("expected a write"),
+        }
+    }
+
     #[test]
     fn last_op() {
         #[track_caller]
@@ -71,6 +71,8 @@ pub enum QueryExecutionError {
     SubgraphManifestResolveError(Arc<SubgraphManifestResolveError>),
     InvalidSubgraphManifest,
     ResultTooBig(usize, usize),
+    TooManyRows(usize),
+    QueryMemoryExceeded(usize),
     DeploymentNotFound(String),
     IdMissing,
     IdNotString,
@@ -129,6 +131,8 @@ impl QueryExecutionError {
             | InvalidSubgraphManifest
             | ValidationError(_, _)
             | ResultTooBig(_, _)
+            | TooManyRows(_)
+            | QueryMemoryExceeded(_)
             | DeploymentNotFound(_)
             | IdMissing
             | IdNotString
@@ -271,6 +275,8 @@ impl fmt::Display for QueryExecutionError {
             SubgraphManifestResolveError(e) => write!(f, "failed to resolve subgraph manifest: {}", e),
             InvalidSubgraphManifest => write!(f, "invalid subgraph manifest file"),
             ResultTooBig(actual, limit) => write!(f, "the result size of {} is larger than the allowed limit of {}", actual, limit),
+            TooManyRows(limit) => write!(f, "the query returns more than the allowed limit of {} rows from the store. Use `first` and filters to query fewer entities", limit),
+            QueryMemoryExceeded(limit) => write!(f, "decoding the query result from the store needs more than the allowed limit of {} bytes. Use `first` and filters to query fewer entities", limit),
             DeploymentNotFound(id_or_name) => write!(f, "deployment `{}` does not exist", id_or_name),
             IdMissing => write!(f, "entity is missing an `id` attribute"),
             IdNotString => write!(f, "entity `id` attribute is not a string"),
@@ -10,6 +10,11 @@ use std::{collections::HashSet, env::VarError, fmt, str::FromStr, time::Duration
 use self::graphql::*;
 use self::mappings::*;
 use self::store::*;
+pub use self::store::{
+    register_store_config_metrics, BatchReorgPolicy, ConnectionAcquirePolicy, CopyIndexTiming,
+    CopyOverlapPolicy, CopyRowOrder, CopyTableOrder, GinIndexMode, LargeFieldStorage,
+    NotificationCodec, PoiIndexMethod, PruneTableOrder, SchemaDriftPolicy, SqlStates,
+};
 use crate::{
     components::{store::BlockNumber, subgraph::SubgraphVersionSwitchingMode},
     runtime::gas::CONST_MAX_GAS_PER_HANDLER,
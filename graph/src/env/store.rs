@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::fmt;
 
 use crate::bail;
+use crate::components::metrics::MetricsRegistry;
+use crate::prometheus::Error as PrometheusError;
 
 use super::*;
 
@@ -9,6 +12,12 @@ pub struct EnvVarsStore {
     /// Set by the environment variable `GRAPH_CHAIN_HEAD_WATCHER_TIMEOUT`
     /// (expressed in seconds). The default value is 30 seconds.
     pub chain_head_watcher_timeout: Duration,
+    /// How many blocks the watched chain head may be ahead of the last
+    /// chain head that subscribers processed for the same network before
+    /// a warning is logged.
+    /// Set by `GRAPH_CHAIN_HEAD_LAG_ALERT_BLOCKS`; the default of 0
+    /// disables the check
+    pub chain_head_lag_alert_blocks: u64,
     /// This is how long statistics that influence query execution are cached in
     /// memory before they are reloaded from the database.
     ///
@@ -17,8 +26,22 @@ pub struct EnvVarsStore {
     pub query_stats_refresh_interval: Duration,
     /// How long entries in the schema cache are kept before they are
     /// evicted in seconds. Defaults to
-    /// `2*GRAPH_QUERY_STATS_REFRESH_INTERVAL`
+    /// `2*GRAPH_QUERY_STATS_REFRESH_INTERVAL`, but at most
+    /// `GRAPH_STORE_SCHEMA_CACHE_TTL_MAX` seconds if that is set. The cap
+    /// does not apply when `GRAPH_SCHEMA_CACHE_TTL` is set explicitly
     pub schema_cache_ttl: Duration,
+    /// Deployments whose schema is never evicted from the schema cache so
+    /// that queries for them always find it there. Set by
+    /// `GRAPH_STORE_SCHEMA_CACHE_PINNED` as a comma separated list of
+    /// deployment hashes. Defaults to no deployments
+    pub schema_cache_pinned: HashSet<String>,
+    /// How often to retry loading a schema into the schema cache when the
+    /// database is temporarily unavailable before failing the query that
+    /// needed it. Also how often requests that wait
+    /// for another request to load a schema take over when that load
+    /// fails. Set by
+    /// `GRAPH_STORE_SCHEMA_LOAD_RETRIES`; defaults to 2
+    pub schema_load_retries: usize,
     /// This can be used to effectively disable the query semaphore by setting
     /// it to a high number, but there's typically no need to configure this.
     ///
@@ -52,9 +75,21 @@ pub struct EnvVarsStore {
     /// Set by the environment variable `GRAPH_REMOVE_UNUSED_INTERVAL`
     /// (expressed in minutes). The default value is 360 minutes.
     pub remove_unused_interval: chrono::Duration,
+    /// How long a deployment must have been unused before it can be
+    /// removed. This protects deployments that are only unassigned briefly,
+    /// e.g., during a rolling reassignment. Set by the environment variable
+    /// `GRAPH_REMOVE_UNUSED_MIN_AGE` (expressed in minutes). The default
+    /// value is 0 minutes.
+    pub remove_unused_min_age: chrono::Duration,
     /// Set by the environment variable
     /// `GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY`. The default value is 10 blocks.
     pub recent_blocks_cache_capacity: usize,
+    /// Set by the environment variable
+    /// `GRAPH_STORE_RECENT_BLOCKS_CACHE_BYTES`. When set to a value bigger
+    /// than 0, the recent blocks cache is bounded by the estimated number of
+    /// bytes it holds rather than by `recent_blocks_cache_capacity`. The
+    /// default value is 0
+    pub recent_blocks_cache_bytes: usize,
 
     // These should really be set through the configuration file, especially for
     // `GRAPH_STORE_CONNECTION_MIN_IDLE` and
@@ -69,6 +104,17 @@ pub struct EnvVarsStore {
     /// Set by the environment variable `GRAPH_STORE_CONNECTION_IDLE_TIMEOUT`
     /// (expressed in seconds). The default value is 600s.
     pub connection_idle_timeout: Duration,
+    /// How long a pooled connection may be used before it is closed and
+    /// replaced with a new one, regardless of how busy it is. Set by the
+    /// environment variable `GRAPH_STORE_CONNECTION_MAX_LIFETIME` (expressed
+    /// in seconds). The default value is 0, which means connections are
+    /// never recycled because of their age.
+    pub connection_max_lifetime: Option<Duration>,
+    /// Limits how many new connections per second a pool opens once it
+    /// has at least `connection_min_idle` connections. Set by the
+    /// environment variable `GRAPH_STORE_CONNECTION_RAMP_RATE`. No default
+    /// value is provided, i.e., connections are opened as fast as needed.
+    pub connection_ramp_rate: Option<f64>,
 
     /// The size of the write queue; this many blocks can be buffered for
     /// writing before calls to transact block operations will block.
@@ -81,6 +127,13 @@ pub struct EnvVarsStore {
     /// The default is 180s.
     pub batch_target_duration: Duration,
 
+    /// The shortest duration that batch operations during copying or
+    /// grafting aim for, even if `batch_target_duration` is shorter, so
+    /// that batches do not become so small that the overhead of each batch
+    /// dominates. Set by `GRAPH_STORE_BATCH_MIN_DURATION` (expressed in
+    /// seconds). The default is 10s.
+    pub batch_min_duration: Duration,
+
     /// Prune tables where we will remove at least this fraction of entity
     /// versions by rebuilding the table. Set by
     /// `GRAPH_STORE_HISTORY_REBUILD_THRESHOLD`. The default is 0.5
@@ -95,6 +148,12 @@ pub struct EnvVarsStore {
     /// blocks) than its history limit. The default value is 1.2 and the
     /// value must be at least 1.01
     pub history_slack_factor: f64,
+    /// How many tables may be pruned at the same time across all
+    /// deployments. Deployments take turns pruning their tables so that a
+    /// deployment with many tables can not hold up others. Set by
+    /// `GRAPH_STORE_MAX_CONCURRENT_PRUNES`. The default is 0, which means
+    /// there is no limit
+    pub max_concurrent_prunes: usize,
     /// How long to accumulate changes into a batch before a write has to
     /// happen. Set by the environment variable
     /// `GRAPH_STORE_WRITE_BATCH_DURATION` in seconds. The default is 300s.
@@ -102,13 +161,28 @@ pub struct EnvVarsStore {
     pub write_batch_duration: Duration,
     /// How many changes to accumulate in bytes before a write has to
     /// happen. Set by the environment variable
-    /// `GRAPH_STORE_WRITE_BATCH_SIZE`, which is in kilobytes. The default
-    /// is 10_000 which corresponds to 10MB. Setting this to 0 disables
-    /// write batching.
+    /// `GRAPH_STORE_WRITE_BATCH_SIZE`, which is in the unit given by
+    /// `GRAPH_STORE_WRITE_BATCH_SIZE_UNIT`, kilobytes by default. The
+    /// default is 10_000 which corresponds to 10MB. Setting this to 0
+    /// disables write batching.
     pub write_batch_size: usize,
+    /// Deployments that always write synchronously, i.e., that write each
+    /// block as it is processed without queueing or batching writes,
+    /// regardless of `write_queue_size` and the write batch settings. Set
+    /// by `GRAPH_STORE_WRITE_BATCH_DISABLED_DEPLOYMENTS` as a comma
+    /// separated list of deployment hashes. Defaults to no deployments
+    pub write_batch_disabled_deployments: HashSet<String>,
     /// Whether to create GIN indexes for array attributes. Set by
-    /// `GRAPH_STORE_CREATE_GIN_INDEXES`. The default is `false`
-    pub create_gin_indexes: bool,
+    /// `GRAPH_STORE_CREATE_GIN_INDEXES` to `true`, `false`, or `auto`. The
+    /// default is `false`. With `auto`, no GIN indexes are created when a
+    /// deployment is set up; instead, an index is created for an array
+    /// attribute once `gin_auto_threshold` array containment queries have
+    /// used it
+    pub create_gin_indexes: GinIndexMode,
+    /// How many array containment queries an array attribute needs to see
+    /// before we create a GIN index for it when `create_gin_indexes` is
+    /// `auto`. Set by `GRAPH_STORE_GIN_AUTO_THRESHOLD`. The default is 1000
+    pub gin_auto_threshold: u64,
     /// Temporary env var in case we need to quickly rollback PR #5010
     pub use_brin_for_all_query_types: bool,
     /// Temporary env var to disable certain lookups in the chain store
@@ -127,8 +201,944 @@ pub struct EnvVarsStore {
     /// parameters must be between 0 and 65535' when inserting entities
     pub insert_extra_cols: usize,
     /// The number of rows to fetch from the foreign data wrapper in one go,
-    /// this will be set as the option 'fetch_size' on all foreign servers
+    /// this will be set as the option 'fetch_size' on all foreign servers.
+    /// Set by `GRAPH_STORE_FDW_FETCH_SIZE`; a value of 0 leaves the option
+    /// unset so that `postgres_fdw` uses its own default
     pub fdw_fetch_size: usize,
+    /// The fetch size to use for a foreign table when a cross-shard copy
+    /// fails because the foreign scan ran out of memory or hit a protocol
+    /// error with `fdw_fetch_size`. Set by
+    /// `GRAPH_STORE_FDW_FETCH_SIZE_FALLBACK`; defaults to 1000
+    pub fdw_fetch_size_fallback: usize,
+    /// Postgres error codes (SQLSTATE) for which failed writes are retried
+    /// in addition to the errors that are always treated as transient,
+    /// e.g., `40001` for serialization failures. Set by
+    /// `GRAPH_STORE_RETRYABLE_SQLSTATES` as a comma separated list.
+    /// Defaults to no additional error codes
+    pub retryable_sqlstates: SqlStates,
+    /// What to do when copying a version of an entity would overlap with a
+    /// version of the same entity that is already in the destination. Set
+    /// by `GRAPH_STORE_COPY_OVERLAP_POLICY` to `fail`, `skip`, or
+    /// `prefer_source`. The default is `fail`
+    pub copy_overlap_policy: CopyOverlapPolicy,
+    /// The order in which the tables of a subgraph are copied. Set by
+    /// `GRAPH_STORE_COPY_TABLE_ORDER` to `declared`, `size_asc`,
+    /// `size_desc`, or `random`. The default is `declared`
+    pub copy_table_order: CopyTableOrder,
+    /// The maximum number of rows that one query against the store may
+    /// return. Queries that would return more fail with an error instead
+    /// of truncating the result. Set by `GRAPH_STORE_MAX_QUERY_ROWS`; the
+    /// default of 0 means no limit
+    pub max_query_rows: usize,
+    /// Whether to log a JSON event with the deployment, table, rows
+    /// affected, table size, duration, and strategy for each table that
+    /// was pruned or copied. Set by `GRAPH_STORE_MAINTENANCE_EVENT_LOG`;
+    /// defaults to `false`
+    pub maintenance_event_log: bool,
+    /// Whether repeated writes to the same entity at the same block within
+    /// a write batch are combined into one row operation that keeps the
+    /// latest data. Without it, such writes are rejected. Set by
+    /// `GRAPH_STORE_COALESCE_BATCH_WRITES`; defaults to `false`
+    pub coalesce_batch_writes: bool,
+    /// The value for `work_mem` and `maintenance_work_mem` on connections
+    /// while they are used for pruning or copying, e.g. `256MB`. Query
+    /// connections are not affected. Set by
+    /// `GRAPH_STORE_MAINTENANCE_WORK_MEM`; the default is to use what is
+    /// configured in Postgres
+    pub maintenance_work_mem: Option<String>,
+    /// The fillfactor for the GiST indexes and exclusion constraints on
+    /// `block_range`, between 10 and 100. Set by
+    /// `GRAPH_STORE_BLOCK_RANGE_INDEX_FILLFACTOR`; the default is to use
+    /// the Postgres default
+    pub block_range_index_fillfactor: Option<u8>,
+    /// The maximum number of bind variables per query when inserting
+    /// entities. It also limits `TYPEA_BATCH_SIZE` for tables with many
+    /// columns. Set by `GRAPH_STORE_MAX_INSERT_PARAMS`; defaults to the
+    /// Postgres limit of 65535
+    pub max_insert_params: usize,
+    /// Whether notification payloads are compressed with
+    /// `notification_compression_codec` before checking whether they are
+    /// small enough to be sent with `NOTIFY` directly. All nodes that
+    /// listen for notifications must be able to decompress them. Set by
+    /// `GRAPH_STORE_NOTIFICATION_COMPRESS`; defaults to `false`
+    pub notification_compress: bool,
+    /// Queries whose cost, as estimated by the Postgres planner, is above
+    /// this value may use parallel workers. Set by
+    /// `GRAPH_STORE_PARALLEL_QUERY_MIN_COST`; the default of 0 turns this
+    /// off and leaves `max_parallel_workers_per_gather` alone
+    pub parallel_query_min_cost: f64,
+    /// How long rows that pruning removes stay in the database after they
+    /// have been hidden from queries so that they can still be recovered.
+    /// Set by `GRAPH_STORE_PRUNE_SOFT_DELETE_WINDOW` in minutes; the
+    /// default of 0 removes them right away
+    pub prune_soft_delete_window: Duration,
+    /// The maximum size of connection pools that should differ from what
+    /// the configuration file says, keyed by the name of the pool, e.g.
+    /// `main` or `replica1`, or by `<shard>.<pool>` to only change the pool
+    /// in one shard. Set by `GRAPH_STORE_POOL_MAX_SIZE_OVERRIDES` as a
+    /// comma separated list of `pool=size`; defaults to no overrides
+    pub pool_max_size_overrides: HashMap<String, u32>,
+    /// Whether the timeouts for checking out connections and for waiting
+    /// for chain head updates are driven by a clock that tests advance
+    /// instead of by real time. Only has an effect in debug builds. Set by
+    /// `GRAPH_STORE_DETERMINISTIC_TIMEOUTS`; defaults to `false`
+    pub deterministic_timeouts: bool,
+    /// The minimum time between two automatic prunes of the same
+    /// deployment. Set by `GRAPH_STORE_PRUNE_MIN_INTERVAL` in minutes;
+    /// the default of 0 does not limit how often a deployment is pruned
+    pub prune_min_interval: Duration,
+    /// How full, as a fraction of `write_batch_size`, a write batch has to
+    /// be so that it is written as soon as a block has been added to it
+    /// instead of waiting for `write_batch_duration` to pass. Set by
+    /// `GRAPH_STORE_WRITE_BATCH_MIN_FILL_RATIO` to a number between 0 and
+    /// 1; the default of 0 turns this off
+    pub write_batch_min_fill_ratio: f64,
+    /// What to do when the tables and columns of a deployment in the
+    /// database do not match what its schema requires, which is checked
+    /// when the deployment is started. Set by
+    /// `GRAPH_STORE_SCHEMA_DRIFT_POLICY` to `warn`, `refuse`, or `ignore`.
+    /// The default is `warn`
+    pub schema_drift_policy: SchemaDriftPolicy,
+    /// How often to log that writes are blocked because the write queue
+    /// is full, for as long as they stay blocked. Set by
+    /// `GRAPH_STORE_WRITE_BACKPRESSURE_LOG_INTERVAL` in seconds; the
+    /// default of 0 turns this logging off
+    pub write_backpressure_log_interval: Duration,
+    /// Whether a copy or graft exports one snapshot at its start that all
+    /// transactions copying data from the source import, so that every
+    /// batch sees the same state of the source. Set by
+    /// `GRAPH_STORE_COPY_SNAPSHOT`; defaults to `false`
+    pub copy_snapshot: bool,
+    /// Tables with fewer entity versions than this are always pruned by
+    /// deleting, even if so much of their history would be removed that
+    /// `rebuild_threshold` calls for rebuilding them. Set by
+    /// `GRAPH_STORE_PRUNE_REBUILD_MIN_ROWS`; the default of 0 means that
+    /// the size of a table does not matter
+    pub prune_rebuild_min_rows: i64,
+    /// The `statement_timeout` for the statements of a prune so that a
+    /// prune that hangs is aborted and can be retried later. Set by
+    /// `GRAPH_STORE_PRUNE_TIMEOUT` in seconds; the default of 0 means that
+    /// prune statements do not time out
+    pub prune_timeout: Option<Duration>,
+    /// The index method for the `id` column of the proof of indexing
+    /// table, which is only ever looked up by equality. Set by
+    /// `GRAPH_STORE_POI_INDEX_METHOD` to `btree` or `hash`; the default is
+    /// `btree`. Only affects deployments created after it is changed
+    pub poi_index_method: PoiIndexMethod,
+    /// How many layouts may be loaded from the database at the same time.
+    /// Concurrent requests for the layout of the same deployment always
+    /// share one load. Set by `GRAPH_STORE_MAX_CONCURRENT_SCHEMA_LOADS`;
+    /// the default of 0 means there is no limit
+    pub max_concurrent_schema_loads: usize,
+    /// Below how many blocks per second blocks are written one at a time
+    /// instead of being batched, since batching only delays writes when
+    /// blocks come in slowly, e.g., close to the chain head. Set by
+    /// `GRAPH_STORE_WRITE_BATCH_MIN_BLOCK_RATE`; the default of 0 means
+    /// that the block rate does not affect batching
+    pub write_batch_min_block_rate: f64,
+    /// Warn when the variance of the durations of the last 10 batches for
+    /// copying, grafting, or pruning a table exceeds this many seconds
+    /// squared, since batch durations that vary that much indicate an
+    /// unstable database. Only checked with adaptive batching. Set by
+    /// `GRAPH_STORE_BATCH_DURATION_VARIANCE_ALERT`; the default of 0 turns
+    /// this check off
+    pub batch_duration_variance_alert: f64,
+    /// The maximum number of foreign servers, i.e., shards, that the
+    /// configuration may set up. Checked when the configuration is loaded
+    /// to guard against an accidental explosion of shards. Set by
+    /// `GRAPH_STORE_MAX_FOREIGN_SERVERS`; the default of 0 means there is
+    /// no limit
+    pub max_foreign_servers: usize,
+    /// Whether to load the layouts of all deployments, and with them the
+    /// statistics that queries use, at startup rather than when a
+    /// deployment is first used. Set by `GRAPH_QUERY_STATS_EAGER_LOAD`;
+    /// defaults to `false`
+    pub query_stats_eager_load: bool,
+    /// Whether to log the entire store configuration, with passwords
+    /// hidden, at startup instead of just a summary of it. Set by
+    /// `GRAPH_STORE_LOG_CONFIG`; defaults to `false`
+    pub log_config: bool,
+    /// The capacity of the recent blocks cache that should differ from
+    /// `recent_blocks_cache_capacity`, keyed by the name of the chain
+    /// whose blocks the cache holds. Every chain store has its own cache,
+    /// and all deployments on that chain share it. Set by
+    /// `GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY_OVERRIDES` as a comma
+    /// separated list of `chain=capacity`; defaults to no overrides
+    pub recent_blocks_cache_capacity_overrides: HashMap<String, usize>,
+    /// How old the statistics in a cached schema may get before a query
+    /// waits for them to be refreshed. Expired statistics are normally
+    /// only refreshed when no other refresh is running, which lets them
+    /// get much older than `query_stats_refresh_interval` when refreshes
+    /// are frequent. Set by `GRAPH_QUERY_STATS_MAX_AGE` in seconds; the
+    /// default of 0 means there is no maximum age
+    pub query_stats_max_age: Option<Duration>,
+    /// Tables for which queries never use the BRIN index on the block
+    /// range, regardless of `use_brin_for_all_query_types`, as
+    /// `deployment:table` where `deployment` is either the deployment hash
+    /// or the namespace like `sgd42`. Set by
+    /// `GRAPH_STORE_BRIN_DISABLED_TABLES` as a comma separated list;
+    /// defaults to no tables
+    pub brin_disabled_tables: HashSet<String>,
+    /// Chain head updates that are sent within this interval of each other
+    /// are coalesced into one notification with the latest head. Set by
+    /// `GRAPH_STORE_NOTIFICATION_BATCH_INTERVAL` in milliseconds; the
+    /// default of 0 sends every update right away
+    pub notification_batch_interval: Duration,
+    /// The maximum number of bytes that the write batches of all
+    /// deployments that are still waiting to be written may hold together.
+    /// When they hold more, the largest of them is written right away. Set
+    /// by `GRAPH_STORE_WRITE_BATCH_GLOBAL_MAX_BYTES`; the default of 0
+    /// means there is no limit
+    pub write_batch_global_max_bytes: usize,
+    /// Whether to log how long each relational query spends on being
+    /// built, executed in the database, and decoded, and to report that
+    /// in the `store_query_phase_duration_secs` histogram. Set by
+    /// `GRAPH_STORE_QUERY_PHASE_TIMING`; defaults to `false`
+    pub query_phase_timing: bool,
+    /// How many ancestors of a block to load into the recent blocks cache
+    /// when looking the block up misses the cache. Set by
+    /// `GRAPH_STORE_RECENT_BLOCKS_PREFETCH_DEPTH`; the default of 0 turns
+    /// prefetching off
+    pub recent_blocks_prefetch_depth: usize,
+    /// How many deployments may be copied or grafted at the same time
+    /// across all shards. Set by `GRAPH_STORE_MAX_CONCURRENT_COPIES`. The
+    /// default is 0, which means there is no limit beyond the size of each
+    /// shard's fdw pool
+    pub max_concurrent_copies: usize,
+    /// Whether to add the deployment's network as a `network` label to the
+    /// `store_bytes_written_total` metric. Off by default so that enabling
+    /// it does not unexpectedly change the labels of existing metrics. Set
+    /// by `GRAPH_STORE_METRICS_NETWORK_LABEL`
+    pub metrics_network_label: bool,
+    /// Tables from which pruning would remove less than this fraction of
+    /// entity versions are never pruned by deleting. Unlike
+    /// `delete_threshold`, which `graphman prune` can override for a single
+    /// run, this floor always applies. Set by
+    /// `GRAPH_STORE_PRUNE_DELETE_MIN_FRACTION`; the default of 0 means
+    /// that only `delete_threshold` matters
+    pub prune_delete_min_fraction: f64,
+    /// The `autovacuum_vacuum_scale_factor` storage parameter for newly
+    /// created subgraph tables. Set by
+    /// `GRAPH_STORE_TABLE_AUTOVACUUM_SCALE_FACTOR`; the default is to use
+    /// what is configured in Postgres
+    pub table_autovacuum_scale_factor: Option<f64>,
+    /// The `autovacuum_vacuum_threshold` storage parameter for newly
+    /// created subgraph tables. Set by
+    /// `GRAPH_STORE_TABLE_AUTOVACUUM_THRESHOLD`; the default is to use what
+    /// is configured in Postgres
+    pub table_autovacuum_threshold: Option<u32>,
+    /// What to do with write batches that have not been written yet when
+    /// a revert comes in. Set by `GRAPH_STORE_BATCH_REORG_POLICY`; defaults
+    /// to `flush_then_revert`
+    pub batch_reorg_policy: BatchReorgPolicy,
+    /// Log a warning for queries that return more than this many rows.
+    /// Unlike `max_query_rows`, this does not make the query fail. Set by
+    /// `GRAPH_STORE_LARGE_RESULT_WARN_ROWS`; the default of 0 turns the
+    /// warning off
+    pub large_result_warn_rows: usize,
+    /// The order in which the rows of a table are copied. Set by
+    /// `GRAPH_STORE_COPY_ROW_ORDER` to `physical` or `by_id`. The default
+    /// is `physical`
+    pub copy_row_order: CopyRowOrder,
+    /// The number of connections in each pool that are set aside for
+    /// status queries, like the ones for health checks, so that they do not
+    /// have to wait behind user queries. With reserved connections,
+    /// `extra_query_permits` is ignored. Set by
+    /// `GRAPH_STORE_HEALTH_RESERVED_CONNECTIONS`; defaults to 0
+    pub health_reserved_connections: usize,
+    /// How many deployments to load at the same time when
+    /// `query_stats_eager_load` loads the layouts of all deployments at
+    /// startup. Set by `GRAPH_STORE_SCHEMA_WARMUP_BATCH`; defaults to 1,
+    /// i.e., deployments are loaded one after the other
+    pub schema_warmup_batch: usize,
+    /// Keep at most this many rows in the `large_notifications` table by
+    /// deleting the oldest ones, even if
+    /// `large_notification_cleanup_interval` has not passed for them yet.
+    /// Set by `GRAPH_STORE_LARGE_NOTIFICATION_MAX_ROWS`; the default of 0
+    /// means there is no limit
+    pub large_notification_max_rows: usize,
+    /// Whether to put off pruning a deployment while data is being copied
+    /// into it. Set by `GRAPH_STORE_PRUNE_DEFER_DURING_COPY`; defaults to
+    /// `true`
+    pub prune_defer_during_copy: bool,
+    /// The maximum number of columns in the `order by` clause of a query
+    /// that `order_by_block_range` may add the block range to; when adding
+    /// it would exceed this, queries are not ordered by the block range.
+    /// Set by `GRAPH_STORE_MAX_ORDER_BY_COLUMNS`; the default of 0 means
+    /// there is no limit
+    pub max_order_by_columns: usize,
+    /// The maximum number of bytes that decoding the result of a single
+    /// query may use, as estimated from the rows the database returned. A
+    /// query whose result needs more than that fails with an error. The
+    /// rows themselves are fetched in full before they are decoded, and
+    /// only the decoded copy is bounded by this. Set by `GRAPH_STORE_QUERY_MEMORY_LIMIT_BYTES`; the default of 0
+    /// means there is no limit
+    pub query_memory_limit_bytes: usize,
+    /// How old the statistics for a table may be before pruning analyzes
+    /// it. When this is not set, pruning analyzes all tables of a
+    /// deployment before it decides how to prune them; when it is set,
+    /// only tables whose statistics are older than this get analyzed.
+    /// Set by `GRAPH_STORE_PRUNE_STATS_MAX_AGE` in seconds; the default
+    /// of 0 means that all tables are always analyzed
+    pub prune_stats_max_age: Option<Duration>,
+    /// Whether pruning truncates tables from which it would remove all
+    /// versions instead of deleting them or rebuilding the table. Set by
+    /// `GRAPH_STORE_PRUNE_USE_TRUNCATE_WHEN_FULL`; off by default
+    pub prune_use_truncate_when_full: bool,
+    /// How often copying retries a batch that failed with a transient
+    /// error, like a closed connection or an error code from
+    /// `GRAPH_STORE_RETRYABLE_SQLSTATES`, before giving up. A retry only
+    /// redoes the batch that failed, not the entire copy. Set by
+    /// `GRAPH_STORE_COPY_RETRIES`; the default of 0 means that batches are
+    /// not retried
+    pub copy_retries: usize,
+    /// The `fastupdate` storage parameter for the GIN indexes that are
+    /// created together with a table. Set by `GRAPH_STORE_GIN_FASTUPDATE`;
+    /// the default is to use what Postgres does by default
+    pub gin_fastupdate: Option<bool>,
+    /// The statement timeout for queries against specific deployments,
+    /// keyed by the deployment hash or its namespace, e.g. `sgd42`. These
+    /// take precedence over `GRAPH_SQL_STATEMENT_TIMEOUT`. Set by
+    /// `GRAPH_STORE_QUERY_TIMEOUT_OVERRIDES` as a comma separated list of
+    /// `deployment=ms`; defaults to no overrides
+    pub query_timeout_overrides: HashMap<String, Duration>,
+    /// Whether pruning and copying record each table they finish in the
+    /// `subgraphs.maintenance_history` table so that the history can be
+    /// queried through the status API. Set by
+    /// `GRAPH_STORE_MAINTENANCE_HISTORY`; off by default
+    pub maintenance_history: bool,
+    /// How long entries in the maintenance history are kept. Set by
+    /// `GRAPH_STORE_MAINTENANCE_HISTORY_RETENTION` in days; defaults to 30
+    /// days. A value of 0 keeps entries forever
+    pub maintenance_history_retention: Option<Duration>,
+    /// Queries that the planner expects to read more than this many table
+    /// pages through indexes on `block_range` are run with index scans
+    /// turned off so that they use a sequential scan. Set by
+    /// `GRAPH_STORE_BLOCK_RANGE_SEQSCAN_THRESHOLD`; the default of 0 turns
+    /// this off
+    pub block_range_seqscan_threshold: usize,
+    /// Whether the connection idle timeout only closes idle connections
+    /// beyond `connection_min_idle`. Otherwise, it closes any connection
+    /// that was idle for too long, and pools then open new connections to
+    /// get back to `connection_min_idle`. Set by
+    /// `GRAPH_STORE_IDLE_TIMEOUT_RESPECTS_MIN_IDLE`; defaults to `true`
+    pub idle_timeout_respects_min_idle: bool,
+    /// How long the results of queries for aggregations are kept in memory
+    /// when the queries only cover buckets that are closed and therefore
+    /// do not change anymore. Set by `GRAPH_STORE_ROLLUP_RESULT_CACHE_TTL`
+    /// in seconds; the default of 0 turns caching off
+    pub rollup_result_cache_ttl: Option<Duration>,
+    /// The order in which the tables of a deployment are pruned. Set by
+    /// `GRAPH_STORE_PRUNE_TABLE_ORDER` to `size_desc`, `size_asc`, or
+    /// `declared`. The default is `size_desc`
+    pub prune_table_order: PruneTableOrder,
+    /// Warn when the write queue of a deployment stays at least this full,
+    /// as a fraction of `write_queue_size`, for a sustained period, since
+    /// that indicates that the database can not keep up with writes. Set
+    /// by `GRAPH_STORE_WRITE_QUEUE_SATURATION_WARN_RATIO` to a number
+    /// between 0 and 1; the default of 0 turns this warning off
+    pub write_queue_saturation_warn_ratio: f64,
+    /// The Postgres `STORAGE` setting for string and bytes columns of new
+    /// tables. Set by `GRAPH_STORE_LARGE_FIELD_STORAGE` to `default`,
+    /// `external`, or `extended`. The default of `default` leaves the
+    /// setting that Postgres picks alone
+    pub large_field_storage: LargeFieldStorage,
+    /// Whether pruning should run the same statements when it is run
+    /// repeatedly on the same data, which helps with debugging. Batches
+    /// then have a fixed size instead of adapting to how long they take,
+    /// and deletes process rows ordered by `id`. Set by
+    /// `GRAPH_STORE_PRUNE_DETERMINISTIC`; off by default
+    pub prune_deterministic: bool,
+    /// The maximum number of notifications received from Postgres that
+    /// are kept in memory while they wait to be processed. When there are
+    /// more, the oldest ones are dropped. Set by
+    /// `GRAPH_STORE_MAX_PENDING_NOTIFICATIONS`; the default of 0 means
+    /// there is no limit
+    pub max_pending_notifications: usize,
+    /// Whether copies and grafts create the indexes whose creation is
+    /// postponed before or after loading the data. Set by
+    /// `GRAPH_STORE_COPY_INDEX_TIMING` to `after` or `before`. The default
+    /// is `after`
+    pub copy_index_timing: CopyIndexTiming,
+    /// The order in which threads that wait for a connection from a pool
+    /// get one when all connections are in use. Set by
+    /// `GRAPH_STORE_CONNECTION_ACQUIRE_POLICY` to `fifo` or `lifo`. The
+    /// default is `fifo`
+    pub connection_acquire_policy: ConnectionAcquirePolicy,
+    /// Whether pruning uses the `rebuild_threshold` and `delete_threshold`
+    /// that are stored for individual tables in `subgraphs.table_stats`
+    /// instead of `rebuild_threshold` and `delete_threshold` when they are
+    /// set. Set by `GRAPH_STORE_PRUNE_THRESHOLDS_FROM_DB`; off by default
+    pub prune_thresholds_from_db: bool,
+    /// The approximate number of bytes that the cached layouts of all
+    /// deployments on this node may use together. When the cached layouts
+    /// use more, the least recently used ones are evicted, regardless of
+    /// which shard they belong to. Pinned layouts are not counted. Set by
+    /// `GRAPH_STORE_SCHEMA_CACHE_GLOBAL_MAX_BYTES`; the default of 0 means
+    /// there is no limit
+    pub schema_cache_global_max_bytes: usize,
+    /// The codec used to compress notification payloads when
+    /// `notification_compress` is set. Set by
+    /// `GRAPH_STORE_NOTIFICATION_COMPRESSION_CODEC` to `zstd`, `lz4`, or
+    /// `none`. The default is `zstd`
+    pub notification_compression_codec: NotificationCodec,
+    /// How many bytes the cached results of queries for closed
+    /// aggregation buckets of one deployment may use. When there are more,
+    /// the oldest results are removed. Set by
+    /// `GRAPH_STORE_ROLLUP_RESULT_CACHE_MAX_BYTES`; defaults to 10MB
+    pub rollup_result_cache_max_bytes: usize,
+    /// Whether the size of batches for copying, grafting, and pruning
+    /// adapts to how long previous batches took so that batches take about
+    /// `batch_target_duration`. Only turned off by `GRAPH_STORE_SAFE_MODE`
+    pub adaptive_batching: bool,
+    /// The settings that `GRAPH_STORE_SAFE_MODE` changed from what they
+    /// would otherwise have been, as `setting=value` strings so they can be
+    /// logged at startup. Empty unless safe mode is on
+    pub safe_mode_overrides: Vec<String>,
+}
+
+impl EnvVarsStore {
+    /// Revert optimizations that carry some risk to conservative values
+    /// and record each setting that was changed in `safe_mode_overrides`
+    fn apply_safe_mode(&mut self) {
+        let mut overrides = Vec::new();
+        if self.create_gin_indexes != GinIndexMode::Never {
+            self.create_gin_indexes = GinIndexMode::Never;
+            overrides.push("create_gin_indexes=false".to_string());
+        }
+        if self.order_by_block_range {
+            self.order_by_block_range = false;
+            overrides.push("order_by_block_range=false".to_string());
+        }
+        if !self.write_batch_duration.is_zero() {
+            self.write_batch_duration = Duration::ZERO;
+            overrides.push("write_batch_duration=0".to_string());
+        }
+        if self.adaptive_batching {
+            self.adaptive_batching = false;
+            overrides.push("adaptive_batching=false".to_string());
+        }
+        self.safe_mode_overrides = overrides;
+    }
+
+    /// How long a deployment must have been recorded as unused before it
+    /// is removed; the larger of `remove_unused_interval` and
+    /// `remove_unused_min_age`
+    pub fn remove_unused_after(&self) -> chrono::Duration {
+        self.remove_unused_interval.max(self.remove_unused_min_age)
+    }
+
+    /// The maximum size for the pool `pool` in `shard`; an override for
+    /// `<shard>.<pool>` takes precedence over one for `pool`, and without
+    /// an override, the `configured` size is used
+    pub fn pool_max_size(&self, shard: &str, pool: &str, configured: u32) -> u32 {
+        self.pool_max_size_overrides
+            .get(&format!("{shard}.{pool}"))
+            .or_else(|| self.pool_max_size_overrides.get(pool))
+            .copied()
+            .unwrap_or(configured)
+    }
+
+    /// The capacity of the recent blocks cache for `chain`, taken from
+    /// `recent_blocks_cache_capacity_overrides` if it has an entry for
+    /// `chain` and from `recent_blocks_cache_capacity` otherwise
+    pub fn recent_blocks_cache_capacity_for(&self, chain: &str) -> usize {
+        self.recent_blocks_cache_capacity_overrides
+            .get(chain)
+            .copied()
+            .unwrap_or(self.recent_blocks_cache_capacity)
+    }
+
+    /// The statement timeout for queries against the deployment with hash
+    /// `deployment` and namespace `namespace`. An override for the
+    /// deployment hash takes precedence over one for the namespace, and
+    /// without an override, the `global` timeout is used
+    pub fn query_timeout_for(
+        &self,
+        deployment: &str,
+        namespace: &str,
+        global: Option<Duration>,
+    ) -> Option<Duration> {
+        self.query_timeout_overrides
+            .get(deployment)
+            .or_else(|| self.query_timeout_overrides.get(namespace))
+            .copied()
+            .or(global)
+    }
+
+    /// Whether queries against `table` in the deployment with hash
+    /// `deployment` and namespace `namespace` must not use the BRIN index
+    /// on the block range
+    pub fn brin_disabled(&self, deployment: &str, namespace: &str, table: &str) -> bool {
+        self.brin_disabled_tables
+            .contains(&format!("{deployment}:{table}"))
+            || self
+                .brin_disabled_tables
+                .contains(&format!("{namespace}:{table}"))
+    }
+
+    /// The `connection_min_idle` for a pool with at most `max_size`
+    /// connections, or `None` if it is not set or exceeds `max_size`
+    pub fn connection_min_idle_for(&self, max_size: u32) -> Option<u32> {
+        self.connection_min_idle
+            .filter(|min_idle| *min_idle <= max_size)
+    }
+
+    /// The name and value in seconds of each setting that is a duration,
+    /// so that they can all be logged the same way. Values are fractional
+    /// so that settings below one second are not reported as 0. Settings
+    /// that are not set are left out
+    pub fn durations_summary(&self) -> Vec<(&'static str, f64)> {
+        let chrono_secs = |d: chrono::Duration| d.to_std().map_or(0.0, |d| d.as_secs_f64());
+
+        let mut summary = vec![
+            (
+                "chain_head_watcher_timeout",
+                self.chain_head_watcher_timeout.as_secs_f64(),
+            ),
+            (
+                "query_stats_refresh_interval",
+                self.query_stats_refresh_interval.as_secs_f64(),
+            ),
+            ("schema_cache_ttl", self.schema_cache_ttl.as_secs_f64()),
+            (
+                "large_notification_cleanup_interval",
+                self.large_notification_cleanup_interval.as_secs_f64(),
+            ),
+            (
+                "notification_broadcast_timeout",
+                self.notification_broadcast_timeout.as_secs_f64(),
+            ),
+            (
+                "remove_unused_interval",
+                chrono_secs(self.remove_unused_interval),
+            ),
+            (
+                "remove_unused_min_age",
+                chrono_secs(self.remove_unused_min_age),
+            ),
+            ("connection_timeout", self.connection_timeout.as_secs_f64()),
+            (
+                "connection_idle_timeout",
+                self.connection_idle_timeout.as_secs_f64(),
+            ),
+            (
+                "batch_target_duration",
+                self.batch_target_duration.as_secs_f64(),
+            ),
+            ("batch_min_duration", self.batch_min_duration.as_secs_f64()),
+            (
+                "write_batch_duration",
+                self.write_batch_duration.as_secs_f64(),
+            ),
+            (
+                "prune_soft_delete_window",
+                self.prune_soft_delete_window.as_secs_f64(),
+            ),
+            ("prune_min_interval", self.prune_min_interval.as_secs_f64()),
+            (
+                "write_backpressure_log_interval",
+                self.write_backpressure_log_interval.as_secs_f64(),
+            ),
+            (
+                "notification_batch_interval",
+                self.notification_batch_interval.as_secs_f64(),
+            ),
+        ];
+        if let Some(lifetime) = self.connection_max_lifetime {
+            summary.push(("connection_max_lifetime", lifetime.as_secs_f64()));
+        }
+        if let Some(timeout) = self.prune_timeout {
+            summary.push(("prune_timeout", timeout.as_secs_f64()));
+        }
+        if let Some(max_age) = self.query_stats_max_age {
+            summary.push(("query_stats_max_age", max_age.as_secs_f64()));
+        }
+        if let Some(max_age) = self.prune_stats_max_age {
+            summary.push(("prune_stats_max_age", max_age.as_secs_f64()));
+        }
+        if let Some(ttl) = self.rollup_result_cache_ttl {
+            summary.push(("rollup_result_cache_ttl", ttl.as_secs_f64()));
+        }
+        if let Some(retention) = self.maintenance_history_retention {
+            summary.push(("maintenance_history_retention", retention.as_secs_f64()));
+        }
+        summary
+    }
+}
+
+/// Publish a gauge `store_config_<setting>` with the value of each numeric
+/// setting in `env` so that dashboards can show how the store is tuned.
+/// Durations are reported in seconds, and their gauge names end in
+/// `_secs`. Settings that are not set are not reported
+pub fn register_store_config_metrics(
+    env: &EnvVarsStore,
+    registry: &MetricsRegistry,
+) -> Result<(), PrometheusError> {
+    let secs = |d: Duration| Some(d.as_secs_f64());
+    let minutes = |d: chrono::Duration| Some((d.num_seconds() as f64) / 60.0);
+    let num = |n: usize| Some(n as f64);
+
+    let settings: [(&str, &str, Option<f64>); 69] = [
+        (
+            "chain_head_watcher_timeout_secs",
+            "GRAPH_CHAIN_HEAD_WATCHER_TIMEOUT",
+            secs(env.chain_head_watcher_timeout),
+        ),
+        (
+            "chain_head_lag_alert_blocks",
+            "GRAPH_CHAIN_HEAD_LAG_ALERT_BLOCKS",
+            Some(env.chain_head_lag_alert_blocks as f64),
+        ),
+        (
+            "query_stats_refresh_interval_secs",
+            "GRAPH_QUERY_STATS_REFRESH_INTERVAL",
+            secs(env.query_stats_refresh_interval),
+        ),
+        (
+            "schema_cache_ttl_secs",
+            "GRAPH_SCHEMA_CACHE_TTL",
+            secs(env.schema_cache_ttl),
+        ),
+        (
+            "extra_query_permits",
+            "GRAPH_EXTRA_QUERY_PERMITS",
+            num(env.extra_query_permits),
+        ),
+        (
+            "large_notification_cleanup_interval_secs",
+            "LARGE_NOTIFICATION_CLEANUP_INTERVAL",
+            secs(env.large_notification_cleanup_interval),
+        ),
+        (
+            "notification_broadcast_timeout_secs",
+            "GRAPH_NOTIFICATION_BROADCAST_TIMEOUT",
+            secs(env.notification_broadcast_timeout),
+        ),
+        (
+            "typea_batch_size",
+            "TYPEA_BATCH_SIZE",
+            num(env.typea_batch_size),
+        ),
+        (
+            "typed_children_set_size",
+            "TYPED_CHILDREN_SET_SIZE",
+            num(env.typed_children_set_size),
+        ),
+        (
+            "remove_unused_interval_minutes",
+            "GRAPH_REMOVE_UNUSED_INTERVAL",
+            minutes(env.remove_unused_interval),
+        ),
+        (
+            "remove_unused_min_age_minutes",
+            "GRAPH_REMOVE_UNUSED_MIN_AGE",
+            minutes(env.remove_unused_min_age),
+        ),
+        (
+            "recent_blocks_cache_capacity",
+            "GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY",
+            num(env.recent_blocks_cache_capacity),
+        ),
+        (
+            "recent_blocks_cache_bytes",
+            "GRAPH_STORE_RECENT_BLOCKS_CACHE_BYTES",
+            num(env.recent_blocks_cache_bytes),
+        ),
+        (
+            "connection_timeout_secs",
+            "GRAPH_STORE_CONNECTION_TIMEOUT",
+            secs(env.connection_timeout),
+        ),
+        (
+            "connection_min_idle",
+            "GRAPH_STORE_CONNECTION_MIN_IDLE",
+            env.connection_min_idle.map(|n| n as f64),
+        ),
+        (
+            "connection_idle_timeout_secs",
+            "GRAPH_STORE_CONNECTION_IDLE_TIMEOUT",
+            secs(env.connection_idle_timeout),
+        ),
+        (
+            "connection_max_lifetime_secs",
+            "GRAPH_STORE_CONNECTION_MAX_LIFETIME",
+            env.connection_max_lifetime.and_then(secs),
+        ),
+        (
+            "connection_ramp_rate",
+            "GRAPH_STORE_CONNECTION_RAMP_RATE",
+            env.connection_ramp_rate,
+        ),
+        (
+            "write_queue_size",
+            "GRAPH_STORE_WRITE_QUEUE",
+            num(env.write_queue_size),
+        ),
+        (
+            "batch_target_duration_secs",
+            "GRAPH_STORE_BATCH_TARGET_DURATION",
+            secs(env.batch_target_duration),
+        ),
+        (
+            "batch_min_duration_secs",
+            "GRAPH_STORE_BATCH_MIN_DURATION",
+            secs(env.batch_min_duration),
+        ),
+        (
+            "rebuild_threshold",
+            "GRAPH_STORE_HISTORY_REBUILD_THRESHOLD",
+            Some(env.rebuild_threshold),
+        ),
+        (
+            "delete_threshold",
+            "GRAPH_STORE_HISTORY_DELETE_THRESHOLD",
+            Some(env.delete_threshold),
+        ),
+        (
+            "history_slack_factor",
+            "GRAPH_STORE_HISTORY_SLACK_FACTOR",
+            Some(env.history_slack_factor),
+        ),
+        (
+            "max_concurrent_prunes",
+            "GRAPH_STORE_MAX_CONCURRENT_PRUNES",
+            num(env.max_concurrent_prunes),
+        ),
+        (
+            "write_batch_duration_secs",
+            "GRAPH_STORE_WRITE_BATCH_DURATION",
+            secs(env.write_batch_duration),
+        ),
+        (
+            "write_batch_size",
+            "GRAPH_STORE_WRITE_BATCH_SIZE",
+            num(env.write_batch_size),
+        ),
+        (
+            "insert_extra_cols",
+            "GRAPH_STORE_INSERT_EXTRA_COLS",
+            num(env.insert_extra_cols),
+        ),
+        (
+            "fdw_fetch_size",
+            "GRAPH_STORE_FDW_FETCH_SIZE",
+            num(env.fdw_fetch_size),
+        ),
+        (
+            "fdw_fetch_size_fallback",
+            "GRAPH_STORE_FDW_FETCH_SIZE_FALLBACK",
+            num(env.fdw_fetch_size_fallback),
+        ),
+        (
+            "gin_auto_threshold",
+            "GRAPH_STORE_GIN_AUTO_THRESHOLD",
+            Some(env.gin_auto_threshold as f64),
+        ),
+        (
+            "max_query_rows",
+            "GRAPH_STORE_MAX_QUERY_ROWS",
+            num(env.max_query_rows),
+        ),
+        (
+            "schema_load_retries",
+            "GRAPH_STORE_SCHEMA_LOAD_RETRIES",
+            num(env.schema_load_retries),
+        ),
+        (
+            "block_range_index_fillfactor",
+            "GRAPH_STORE_BLOCK_RANGE_INDEX_FILLFACTOR",
+            env.block_range_index_fillfactor.map(|f| f as f64),
+        ),
+        (
+            "max_insert_params",
+            "GRAPH_STORE_MAX_INSERT_PARAMS",
+            num(env.max_insert_params),
+        ),
+        (
+            "parallel_query_min_cost",
+            "GRAPH_STORE_PARALLEL_QUERY_MIN_COST",
+            Some(env.parallel_query_min_cost),
+        ),
+        (
+            "prune_soft_delete_window_secs",
+            "GRAPH_STORE_PRUNE_SOFT_DELETE_WINDOW",
+            secs(env.prune_soft_delete_window),
+        ),
+        (
+            "prune_min_interval_secs",
+            "GRAPH_STORE_PRUNE_MIN_INTERVAL",
+            secs(env.prune_min_interval),
+        ),
+        (
+            "write_batch_min_fill_ratio",
+            "GRAPH_STORE_WRITE_BATCH_MIN_FILL_RATIO",
+            Some(env.write_batch_min_fill_ratio),
+        ),
+        (
+            "write_backpressure_log_interval_secs",
+            "GRAPH_STORE_WRITE_BACKPRESSURE_LOG_INTERVAL",
+            secs(env.write_backpressure_log_interval),
+        ),
+        (
+            "prune_rebuild_min_rows",
+            "GRAPH_STORE_PRUNE_REBUILD_MIN_ROWS",
+            Some(env.prune_rebuild_min_rows as f64),
+        ),
+        (
+            "prune_timeout_secs",
+            "GRAPH_STORE_PRUNE_TIMEOUT",
+            env.prune_timeout.and_then(secs),
+        ),
+        (
+            "max_concurrent_schema_loads",
+            "GRAPH_STORE_MAX_CONCURRENT_SCHEMA_LOADS",
+            num(env.max_concurrent_schema_loads),
+        ),
+        (
+            "write_batch_min_block_rate",
+            "GRAPH_STORE_WRITE_BATCH_MIN_BLOCK_RATE",
+            Some(env.write_batch_min_block_rate),
+        ),
+        (
+            "batch_duration_variance_alert",
+            "GRAPH_STORE_BATCH_DURATION_VARIANCE_ALERT",
+            Some(env.batch_duration_variance_alert),
+        ),
+        (
+            "max_foreign_servers",
+            "GRAPH_STORE_MAX_FOREIGN_SERVERS",
+            num(env.max_foreign_servers),
+        ),
+        (
+            "query_stats_max_age_secs",
+            "GRAPH_QUERY_STATS_MAX_AGE",
+            env.query_stats_max_age.and_then(secs),
+        ),
+        (
+            "notification_batch_interval_secs",
+            "GRAPH_STORE_NOTIFICATION_BATCH_INTERVAL",
+            secs(env.notification_batch_interval),
+        ),
+        (
+            "write_batch_global_max_bytes",
+            "GRAPH_STORE_WRITE_BATCH_GLOBAL_MAX_BYTES",
+            num(env.write_batch_global_max_bytes),
+        ),
+        (
+            "recent_blocks_prefetch_depth",
+            "GRAPH_STORE_RECENT_BLOCKS_PREFETCH_DEPTH",
+            num(env.recent_blocks_prefetch_depth),
+        ),
+        (
+            "max_concurrent_copies",
+            "GRAPH_STORE_MAX_CONCURRENT_COPIES",
+            num(env.max_concurrent_copies),
+        ),
+        (
+            "prune_delete_min_fraction",
+            "GRAPH_STORE_PRUNE_DELETE_MIN_FRACTION",
+            Some(env.prune_delete_min_fraction),
+        ),
+        (
+            "table_autovacuum_scale_factor",
+            "GRAPH_STORE_TABLE_AUTOVACUUM_SCALE_FACTOR",
+            env.table_autovacuum_scale_factor,
+        ),
+        (
+            "table_autovacuum_threshold",
+            "GRAPH_STORE_TABLE_AUTOVACUUM_THRESHOLD",
+            env.table_autovacuum_threshold.map(|t| t as f64),
+        ),
+        (
+            "large_result_warn_rows",
+            "GRAPH_STORE_LARGE_RESULT_WARN_ROWS",
+            num(env.large_result_warn_rows),
+        ),
+        (
+            "health_reserved_connections",
+            "GRAPH_STORE_HEALTH_RESERVED_CONNECTIONS",
+            num(env.health_reserved_connections),
+        ),
+        (
+            "schema_warmup_batch",
+            "GRAPH_STORE_SCHEMA_WARMUP_BATCH",
+            num(env.schema_warmup_batch),
+        ),
+        (
+            "large_notification_max_rows",
+            "GRAPH_STORE_LARGE_NOTIFICATION_MAX_ROWS",
+            num(env.large_notification_max_rows),
+        ),
+        (
+            "max_order_by_columns",
+            "GRAPH_STORE_MAX_ORDER_BY_COLUMNS",
+            num(env.max_order_by_columns),
+        ),
+        (
+            "query_memory_limit_bytes",
+            "GRAPH_STORE_QUERY_MEMORY_LIMIT_BYTES",
+            num(env.query_memory_limit_bytes),
+        ),
+        (
+            "prune_stats_max_age_secs",
+            "GRAPH_STORE_PRUNE_STATS_MAX_AGE",
+            env.prune_stats_max_age.and_then(secs),
+        ),
+        (
+            "copy_retries",
+            "GRAPH_STORE_COPY_RETRIES",
+            num(env.copy_retries),
+        ),
+        (
+            "block_range_seqscan_threshold",
+            "GRAPH_STORE_BLOCK_RANGE_SEQSCAN_THRESHOLD",
+            num(env.block_range_seqscan_threshold),
+        ),
+        (
+            "rollup_result_cache_ttl_secs",
+            "GRAPH_STORE_ROLLUP_RESULT_CACHE_TTL",
+            env.rollup_result_cache_ttl.and_then(secs),
+        ),
+        (
+            "write_queue_saturation_warn_ratio",
+            "GRAPH_STORE_WRITE_QUEUE_SATURATION_WARN_RATIO",
+            Some(env.write_queue_saturation_warn_ratio),
+        ),
+        (
+            "max_pending_notifications",
+            "GRAPH_STORE_MAX_PENDING_NOTIFICATIONS",
+            num(env.max_pending_notifications),
+        ),
+        (
+            "schema_cache_global_max_bytes",
+            "GRAPH_STORE_SCHEMA_CACHE_GLOBAL_MAX_BYTES",
+            num(env.schema_cache_global_max_bytes),
+        ),
+        (
+            "rollup_result_cache_max_bytes",
+            "GRAPH_STORE_ROLLUP_RESULT_CACHE_MAX_BYTES",
+            num(env.rollup_result_cache_max_bytes),
+        ),
+        (
+            "maintenance_history_retention_secs",
+            "GRAPH_STORE_MAINTENANCE_HISTORY_RETENTION",
+            env.maintenance_history_retention.and_then(secs),
+        ),
+    ];
+
+    for (setting, env_var, value) in settings {
+        let Some(value) = value else {
+            continue;
+        };
+        let gauge = registry.global_gauge(
+            &format!("store_config_{setting}"),
+            &format!("The value of the store setting `{env_var}`"),
+            HashMap::new(),
+        )?;
+        gauge.set(value);
+    }
+    Ok(())
 }
 
 // This does not print any values avoid accidentally leaking any sensitive env vars
@@ -140,15 +1150,27 @@ impl fmt::Debug for EnvVarsStore {
 
 impl From<InnerStore> for EnvVarsStore {
     fn from(x: InnerStore) -> Self {
-        Self {
+        let mut vars = Self {
             chain_head_watcher_timeout: Duration::from_secs(x.chain_head_watcher_timeout_in_secs),
+            chain_head_lag_alert_blocks: x.chain_head_lag_alert_blocks,
             query_stats_refresh_interval: Duration::from_secs(
                 x.query_stats_refresh_interval_in_secs,
             ),
             schema_cache_ttl: x
                 .schema_cache_ttl
                 .map(Duration::from_secs)
-                .unwrap_or_else(|| Duration::from_secs(2 * x.query_stats_refresh_interval_in_secs)),
+                .unwrap_or_else(|| {
+                    let derived = 2 * x.query_stats_refresh_interval_in_secs;
+                    let max = x.schema_cache_ttl_max_in_secs.unwrap_or(u64::MAX);
+                    Duration::from_secs(derived.min(max))
+                }),
+            schema_cache_pinned: x
+                .schema_cache_pinned
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            schema_load_retries: x.schema_load_retries,
             extra_query_permits: x.extra_query_permits,
             large_notification_cleanup_interval: Duration::from_secs(
                 x.large_notification_cleanup_interval_in_secs,
@@ -162,24 +1184,145 @@ impl From<InnerStore> for EnvVarsStore {
             remove_unused_interval: chrono::Duration::minutes(
                 x.remove_unused_interval_in_minutes as i64,
             ),
+            remove_unused_min_age: chrono::Duration::minutes(
+                x.remove_unused_min_age_in_minutes as i64,
+            ),
             recent_blocks_cache_capacity: x.recent_blocks_cache_capacity,
+            recent_blocks_cache_bytes: x.recent_blocks_cache_bytes,
             connection_timeout: Duration::from_millis(x.connection_timeout_in_millis),
             connection_min_idle: x.connection_min_idle,
             connection_idle_timeout: Duration::from_secs(x.connection_idle_timeout_in_secs),
+            connection_max_lifetime: match x.connection_max_lifetime_in_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            connection_ramp_rate: x.connection_ramp_rate.map(|rate| rate.0),
             write_queue_size: x.write_queue_size,
             batch_target_duration: Duration::from_secs(x.batch_target_duration_in_secs),
+            batch_min_duration: Duration::from_secs(x.batch_min_duration_in_secs),
             rebuild_threshold: x.rebuild_threshold.0,
             delete_threshold: x.delete_threshold.0,
             history_slack_factor: x.history_slack_factor.0,
+            max_concurrent_prunes: x.max_concurrent_prunes,
             write_batch_duration: Duration::from_secs(x.write_batch_duration_in_secs),
-            write_batch_size: x.write_batch_size * 1_000,
+            write_batch_size: x.write_batch_size_unit.to_bytes(x.write_batch_size),
+            write_batch_disabled_deployments: x
+                .write_batch_disabled_deployments
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
             create_gin_indexes: x.create_gin_indexes,
+            gin_auto_threshold: x.gin_auto_threshold,
             use_brin_for_all_query_types: x.use_brin_for_all_query_types,
             disable_block_cache_for_lookup: x.disable_block_cache_for_lookup,
             last_rollup_from_poi: x.last_rollup_from_poi,
             insert_extra_cols: x.insert_extra_cols,
             fdw_fetch_size: x.fdw_fetch_size,
+            fdw_fetch_size_fallback: x.fdw_fetch_size_fallback,
+            retryable_sqlstates: x.retryable_sqlstates,
+            copy_overlap_policy: x.copy_overlap_policy,
+            copy_table_order: x.copy_table_order,
+            max_query_rows: x.max_query_rows,
+            maintenance_event_log: x.maintenance_event_log.0,
+            coalesce_batch_writes: x.coalesce_batch_writes.0,
+            maintenance_work_mem: x.maintenance_work_mem.0,
+            block_range_index_fillfactor: x.block_range_index_fillfactor.0,
+            max_insert_params: x.max_insert_params,
+            notification_compress: x.notification_compress.0,
+            parallel_query_min_cost: x.parallel_query_min_cost,
+            prune_soft_delete_window: Duration::from_secs(
+                x.prune_soft_delete_window_in_minutes * 60,
+            ),
+            pool_max_size_overrides: x.pool_max_size_overrides.0,
+            deterministic_timeouts: x.deterministic_timeouts.0,
+            prune_min_interval: Duration::from_secs(x.prune_min_interval_in_minutes * 60),
+            write_batch_min_fill_ratio: x.write_batch_min_fill_ratio.0,
+            schema_drift_policy: x.schema_drift_policy,
+            write_backpressure_log_interval: Duration::from_secs(
+                x.write_backpressure_log_interval_in_secs,
+            ),
+            copy_snapshot: x.copy_snapshot.0,
+            prune_rebuild_min_rows: x.prune_rebuild_min_rows,
+            prune_timeout: match x.prune_timeout_in_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            poi_index_method: x.poi_index_method,
+            max_concurrent_schema_loads: x.max_concurrent_schema_loads,
+            write_batch_min_block_rate: x.write_batch_min_block_rate,
+            batch_duration_variance_alert: x.batch_duration_variance_alert,
+            max_foreign_servers: x.max_foreign_servers,
+            query_stats_eager_load: x.query_stats_eager_load.0,
+            log_config: x.log_config.0,
+            recent_blocks_cache_capacity_overrides: x.recent_blocks_cache_capacity_overrides.0,
+            query_stats_max_age: match x.query_stats_max_age_in_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            brin_disabled_tables: x
+                .brin_disabled_tables
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            notification_batch_interval: Duration::from_millis(
+                x.notification_batch_interval_in_millis,
+            ),
+            write_batch_global_max_bytes: x.write_batch_global_max_bytes,
+            query_phase_timing: x.query_phase_timing.0,
+            recent_blocks_prefetch_depth: x.recent_blocks_prefetch_depth,
+            max_concurrent_copies: x.max_concurrent_copies,
+            metrics_network_label: x.metrics_network_label.0,
+            prune_delete_min_fraction: x.prune_delete_min_fraction.0,
+            table_autovacuum_scale_factor: x.table_autovacuum_scale_factor,
+            table_autovacuum_threshold: x.table_autovacuum_threshold,
+            batch_reorg_policy: x.batch_reorg_policy,
+            large_result_warn_rows: x.large_result_warn_rows,
+            copy_row_order: x.copy_row_order,
+            health_reserved_connections: x.health_reserved_connections,
+            schema_warmup_batch: x.schema_warmup_batch,
+            large_notification_max_rows: x.large_notification_max_rows,
+            prune_defer_during_copy: x.prune_defer_during_copy.0,
+            max_order_by_columns: x.max_order_by_columns,
+            query_memory_limit_bytes: x.query_memory_limit_bytes,
+            prune_stats_max_age: match x.prune_stats_max_age_in_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            prune_use_truncate_when_full: x.prune_use_truncate_when_full.0,
+            copy_retries: x.copy_retries,
+            gin_fastupdate: x.gin_fastupdate,
+            query_timeout_overrides: x.query_timeout_overrides.0,
+            maintenance_history: x.maintenance_history.0,
+            maintenance_history_retention: match x.maintenance_history_retention_in_days {
+                0 => None,
+                days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            },
+            block_range_seqscan_threshold: x.block_range_seqscan_threshold,
+            idle_timeout_respects_min_idle: x.idle_timeout_respects_min_idle.0,
+            rollup_result_cache_ttl: match x.rollup_result_cache_ttl_in_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            prune_table_order: x.prune_table_order,
+            write_queue_saturation_warn_ratio: x.write_queue_saturation_warn_ratio.0,
+            large_field_storage: x.large_field_storage,
+            prune_deterministic: x.prune_deterministic.0,
+            max_pending_notifications: x.max_pending_notifications,
+            copy_index_timing: x.copy_index_timing,
+            connection_acquire_policy: x.connection_acquire_policy,
+            prune_thresholds_from_db: x.prune_thresholds_from_db.0,
+            schema_cache_global_max_bytes: x.schema_cache_global_max_bytes,
+            notification_compression_codec: x.notification_compression_codec,
+            rollup_result_cache_max_bytes: x.rollup_result_cache_max_bytes,
+            adaptive_batching: true,
+            safe_mode_overrides: Vec::new(),
+        };
+        if x.safe_mode.0 {
+            vars.apply_safe_mode();
         }
+        vars
     }
 }
 
@@ -187,10 +1330,16 @@ impl From<InnerStore> for EnvVarsStore {
 pub struct InnerStore {
     #[envconfig(from = "GRAPH_CHAIN_HEAD_WATCHER_TIMEOUT", default = "30")]
     chain_head_watcher_timeout_in_secs: u64,
+    #[envconfig(from = "GRAPH_CHAIN_HEAD_LAG_ALERT_BLOCKS", default = "0")]
+    chain_head_lag_alert_blocks: u64,
     #[envconfig(from = "GRAPH_QUERY_STATS_REFRESH_INTERVAL", default = "300")]
     query_stats_refresh_interval_in_secs: u64,
     #[envconfig(from = "GRAPH_SCHEMA_CACHE_TTL")]
     schema_cache_ttl: Option<u64>,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_CACHE_PINNED", default = "")]
+    schema_cache_pinned: String,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_LOAD_RETRIES", default = "2")]
+    schema_load_retries: usize,
     #[envconfig(from = "GRAPH_EXTRA_QUERY_PERMITS", default = "0")]
     extra_query_permits: usize,
     #[envconfig(from = "LARGE_NOTIFICATION_CLEANUP_INTERVAL", default = "300")]
@@ -205,8 +1354,12 @@ pub struct InnerStore {
     order_by_block_range: EnvVarBoolean,
     #[envconfig(from = "GRAPH_REMOVE_UNUSED_INTERVAL", default = "360")]
     remove_unused_interval_in_minutes: u64,
+    #[envconfig(from = "GRAPH_REMOVE_UNUSED_MIN_AGE", default = "0")]
+    remove_unused_min_age_in_minutes: u64,
     #[envconfig(from = "GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY", default = "10")]
     recent_blocks_cache_capacity: usize,
+    #[envconfig(from = "GRAPH_STORE_RECENT_BLOCKS_CACHE_BYTES", default = "0")]
+    recent_blocks_cache_bytes: usize,
 
     // These should really be set through the configuration file, especially for
     // `GRAPH_STORE_CONNECTION_MIN_IDLE` and
@@ -218,22 +1371,36 @@ pub struct InnerStore {
     connection_min_idle: Option<u32>,
     #[envconfig(from = "GRAPH_STORE_CONNECTION_IDLE_TIMEOUT", default = "600")]
     connection_idle_timeout_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_CONNECTION_MAX_LIFETIME", default = "0")]
+    connection_max_lifetime_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_CONNECTION_RAMP_RATE")]
+    connection_ramp_rate: Option<PositiveF64>,
     #[envconfig(from = "GRAPH_STORE_WRITE_QUEUE", default = "5")]
     write_queue_size: usize,
     #[envconfig(from = "GRAPH_STORE_BATCH_TARGET_DURATION", default = "180")]
     batch_target_duration_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_BATCH_MIN_DURATION", default = "10")]
+    batch_min_duration_in_secs: u64,
     #[envconfig(from = "GRAPH_STORE_HISTORY_REBUILD_THRESHOLD", default = "0.5")]
     rebuild_threshold: ZeroToOneF64,
     #[envconfig(from = "GRAPH_STORE_HISTORY_DELETE_THRESHOLD", default = "0.05")]
     delete_threshold: ZeroToOneF64,
     #[envconfig(from = "GRAPH_STORE_HISTORY_SLACK_FACTOR", default = "1.2")]
     history_slack_factor: HistorySlackF64,
+    #[envconfig(from = "GRAPH_STORE_MAX_CONCURRENT_PRUNES", default = "0")]
+    max_concurrent_prunes: usize,
     #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_DURATION", default = "300")]
     write_batch_duration_in_secs: u64,
     #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_SIZE", default = "10000")]
     write_batch_size: usize,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_SIZE_UNIT", default = "kb")]
+    write_batch_size_unit: SizeUnit,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_DISABLED_DEPLOYMENTS", default = "")]
+    write_batch_disabled_deployments: String,
     #[envconfig(from = "GRAPH_STORE_CREATE_GIN_INDEXES", default = "false")]
-    create_gin_indexes: bool,
+    create_gin_indexes: GinIndexMode,
+    #[envconfig(from = "GRAPH_STORE_GIN_AUTO_THRESHOLD", default = "1000")]
+    gin_auto_threshold: u64,
     #[envconfig(from = "GRAPH_STORE_USE_BRIN_FOR_ALL_QUERY_TYPES", default = "false")]
     use_brin_for_all_query_types: bool,
     #[envconfig(from = "GRAPH_STORE_DISABLE_BLOCK_CACHE_FOR_LOOKUP", default = "false")]
@@ -244,6 +1411,158 @@ pub struct InnerStore {
     insert_extra_cols: usize,
     #[envconfig(from = "GRAPH_STORE_FDW_FETCH_SIZE", default = "10000")]
     fdw_fetch_size: usize,
+    #[envconfig(from = "GRAPH_STORE_FDW_FETCH_SIZE_FALLBACK", default = "1000")]
+    fdw_fetch_size_fallback: usize,
+    #[envconfig(from = "GRAPH_STORE_RETRYABLE_SQLSTATES", default = "")]
+    retryable_sqlstates: SqlStates,
+    #[envconfig(from = "GRAPH_STORE_COPY_OVERLAP_POLICY", default = "fail")]
+    copy_overlap_policy: CopyOverlapPolicy,
+    #[envconfig(from = "GRAPH_STORE_COPY_TABLE_ORDER", default = "declared")]
+    copy_table_order: CopyTableOrder,
+    #[envconfig(from = "GRAPH_STORE_MAX_QUERY_ROWS", default = "0")]
+    max_query_rows: usize,
+    #[envconfig(from = "GRAPH_STORE_MAINTENANCE_EVENT_LOG", default = "false")]
+    maintenance_event_log: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_COALESCE_BATCH_WRITES", default = "false")]
+    coalesce_batch_writes: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_MAINTENANCE_WORK_MEM", default = "")]
+    maintenance_work_mem: PgMemory,
+    #[envconfig(from = "GRAPH_STORE_BLOCK_RANGE_INDEX_FILLFACTOR", default = "")]
+    block_range_index_fillfactor: Fillfactor,
+    #[envconfig(from = "GRAPH_STORE_MAX_INSERT_PARAMS", default = "65535")]
+    max_insert_params: usize,
+    #[envconfig(from = "GRAPH_STORE_NOTIFICATION_COMPRESS", default = "false")]
+    notification_compress: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_PARALLEL_QUERY_MIN_COST", default = "0")]
+    parallel_query_min_cost: f64,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_SOFT_DELETE_WINDOW", default = "0")]
+    prune_soft_delete_window_in_minutes: u64,
+    #[envconfig(from = "GRAPH_STORE_POOL_MAX_SIZE_OVERRIDES", default = "")]
+    pool_max_size_overrides: PoolSizes,
+    #[envconfig(from = "GRAPH_STORE_DETERMINISTIC_TIMEOUTS", default = "false")]
+    deterministic_timeouts: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_MIN_INTERVAL", default = "0")]
+    prune_min_interval_in_minutes: u64,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_MIN_FILL_RATIO", default = "0")]
+    write_batch_min_fill_ratio: ZeroToOneF64,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_DRIFT_POLICY", default = "warn")]
+    schema_drift_policy: SchemaDriftPolicy,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BACKPRESSURE_LOG_INTERVAL", default = "0")]
+    write_backpressure_log_interval_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_COPY_SNAPSHOT", default = "false")]
+    copy_snapshot: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_CACHE_TTL_MAX")]
+    schema_cache_ttl_max_in_secs: Option<u64>,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_REBUILD_MIN_ROWS", default = "0")]
+    prune_rebuild_min_rows: i64,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_TIMEOUT", default = "0")]
+    prune_timeout_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_POI_INDEX_METHOD", default = "btree")]
+    poi_index_method: PoiIndexMethod,
+    #[envconfig(from = "GRAPH_STORE_MAX_CONCURRENT_SCHEMA_LOADS", default = "0")]
+    max_concurrent_schema_loads: usize,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_MIN_BLOCK_RATE", default = "0")]
+    write_batch_min_block_rate: f64,
+    #[envconfig(from = "GRAPH_STORE_BATCH_DURATION_VARIANCE_ALERT", default = "0")]
+    batch_duration_variance_alert: f64,
+    #[envconfig(from = "GRAPH_STORE_MAX_FOREIGN_SERVERS", default = "0")]
+    max_foreign_servers: usize,
+    #[envconfig(from = "GRAPH_QUERY_STATS_EAGER_LOAD", default = "false")]
+    query_stats_eager_load: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_LOG_CONFIG", default = "false")]
+    log_config: EnvVarBoolean,
+    #[envconfig(
+        from = "GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY_OVERRIDES",
+        default = ""
+    )]
+    recent_blocks_cache_capacity_overrides: CacheCapacities,
+    #[envconfig(from = "GRAPH_QUERY_STATS_MAX_AGE", default = "0")]
+    query_stats_max_age_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_BRIN_DISABLED_TABLES", default = "")]
+    brin_disabled_tables: String,
+    #[envconfig(from = "GRAPH_STORE_NOTIFICATION_BATCH_INTERVAL", default = "0")]
+    notification_batch_interval_in_millis: u64,
+    #[envconfig(from = "GRAPH_STORE_WRITE_BATCH_GLOBAL_MAX_BYTES", default = "0")]
+    write_batch_global_max_bytes: usize,
+    #[envconfig(from = "GRAPH_STORE_QUERY_PHASE_TIMING", default = "false")]
+    query_phase_timing: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_RECENT_BLOCKS_PREFETCH_DEPTH", default = "0")]
+    recent_blocks_prefetch_depth: usize,
+    #[envconfig(from = "GRAPH_STORE_MAX_CONCURRENT_COPIES", default = "0")]
+    max_concurrent_copies: usize,
+    #[envconfig(from = "GRAPH_STORE_METRICS_NETWORK_LABEL", default = "false")]
+    metrics_network_label: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_DELETE_MIN_FRACTION", default = "0")]
+    prune_delete_min_fraction: ZeroToOneF64,
+    #[envconfig(from = "GRAPH_STORE_TABLE_AUTOVACUUM_SCALE_FACTOR")]
+    table_autovacuum_scale_factor: Option<f64>,
+    #[envconfig(from = "GRAPH_STORE_TABLE_AUTOVACUUM_THRESHOLD")]
+    table_autovacuum_threshold: Option<u32>,
+    #[envconfig(from = "GRAPH_STORE_BATCH_REORG_POLICY", default = "flush_then_revert")]
+    batch_reorg_policy: BatchReorgPolicy,
+    #[envconfig(from = "GRAPH_STORE_LARGE_RESULT_WARN_ROWS", default = "0")]
+    large_result_warn_rows: usize,
+    #[envconfig(from = "GRAPH_STORE_COPY_ROW_ORDER", default = "physical")]
+    copy_row_order: CopyRowOrder,
+    #[envconfig(from = "GRAPH_STORE_HEALTH_RESERVED_CONNECTIONS", default = "0")]
+    health_reserved_connections: usize,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_WARMUP_BATCH", default = "1")]
+    schema_warmup_batch: usize,
+    #[envconfig(from = "GRAPH_STORE_LARGE_NOTIFICATION_MAX_ROWS", default = "0")]
+    large_notification_max_rows: usize,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_DEFER_DURING_COPY", default = "true")]
+    prune_defer_during_copy: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_MAX_ORDER_BY_COLUMNS", default = "0")]
+    max_order_by_columns: usize,
+    #[envconfig(from = "GRAPH_STORE_QUERY_MEMORY_LIMIT_BYTES", default = "0")]
+    query_memory_limit_bytes: usize,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_STATS_MAX_AGE", default = "0")]
+    prune_stats_max_age_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_USE_TRUNCATE_WHEN_FULL", default = "false")]
+    prune_use_truncate_when_full: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_COPY_RETRIES", default = "0")]
+    copy_retries: usize,
+    #[envconfig(from = "GRAPH_STORE_GIN_FASTUPDATE")]
+    gin_fastupdate: Option<bool>,
+    #[envconfig(from = "GRAPH_STORE_QUERY_TIMEOUT_OVERRIDES", default = "")]
+    query_timeout_overrides: QueryTimeouts,
+    #[envconfig(from = "GRAPH_STORE_MAINTENANCE_HISTORY", default = "false")]
+    maintenance_history: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_MAINTENANCE_HISTORY_RETENTION", default = "30")]
+    maintenance_history_retention_in_days: u64,
+    #[envconfig(from = "GRAPH_STORE_BLOCK_RANGE_SEQSCAN_THRESHOLD", default = "0")]
+    block_range_seqscan_threshold: usize,
+    #[envconfig(from = "GRAPH_STORE_IDLE_TIMEOUT_RESPECTS_MIN_IDLE", default = "true")]
+    idle_timeout_respects_min_idle: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_ROLLUP_RESULT_CACHE_TTL", default = "0")]
+    rollup_result_cache_ttl_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_TABLE_ORDER", default = "size_desc")]
+    prune_table_order: PruneTableOrder,
+    #[envconfig(from = "GRAPH_STORE_WRITE_QUEUE_SATURATION_WARN_RATIO", default = "0")]
+    write_queue_saturation_warn_ratio: ZeroToOneF64,
+    #[envconfig(from = "GRAPH_STORE_LARGE_FIELD_STORAGE", default = "default")]
+    large_field_storage: LargeFieldStorage,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_DETERMINISTIC", default = "false")]
+    prune_deterministic: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_MAX_PENDING_NOTIFICATIONS", default = "0")]
+    max_pending_notifications: usize,
+    #[envconfig(from = "GRAPH_STORE_COPY_INDEX_TIMING", default = "after")]
+    copy_index_timing: CopyIndexTiming,
+    #[envconfig(from = "GRAPH_STORE_CONNECTION_ACQUIRE_POLICY", default = "fifo")]
+    connection_acquire_policy: ConnectionAcquirePolicy,
+    #[envconfig(from = "GRAPH_STORE_PRUNE_THRESHOLDS_FROM_DB", default = "false")]
+    prune_thresholds_from_db: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_STORE_SCHEMA_CACHE_GLOBAL_MAX_BYTES", default = "0")]
+    schema_cache_global_max_bytes: usize,
+    #[envconfig(from = "GRAPH_STORE_NOTIFICATION_COMPRESSION_CODEC", default = "zstd")]
+    notification_compression_codec: NotificationCodec,
+    #[envconfig(
+        from = "GRAPH_STORE_ROLLUP_RESULT_CACHE_MAX_BYTES",
+        default = "10000000"
+    )]
+    rollup_result_cache_max_bytes: usize,
+    #[envconfig(from = "GRAPH_STORE_SAFE_MODE", default = "false")]
+    safe_mode: EnvVarBoolean,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -262,6 +1581,531 @@ impl FromStr for ZeroToOneF64 {
     }
 }
 
+/// The unit in which a size given in an environment variable is expressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SizeUnit {
+    Bytes,
+    Kb,
+    Mb,
+}
+
+impl SizeUnit {
+    fn to_bytes(self, size: usize) -> usize {
+        match self {
+            SizeUnit::Bytes => size,
+            SizeUnit::Kb => size * 1_000,
+            SizeUnit::Mb => size * 1_000_000,
+        }
+    }
+}
+
+impl FromStr for SizeUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bytes" => Ok(SizeUnit::Bytes),
+            "kb" => Ok(SizeUnit::Kb),
+            "mb" => Ok(SizeUnit::Mb),
+            _ => bail!("invalid value: {s} must be one of `bytes`, `kb`, or `mb`"),
+        }
+    }
+}
+
+/// When to create GIN indexes for array attributes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GinIndexMode {
+    /// Never create GIN indexes
+    Never,
+    /// Create GIN indexes for all array attributes when a deployment is
+    /// set up
+    Always,
+    /// Create GIN indexes only for array attributes that are frequently
+    /// used in array containment queries
+    Auto,
+}
+
+impl FromStr for GinIndexMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "false" => Ok(GinIndexMode::Never),
+            "true" => Ok(GinIndexMode::Always),
+            "auto" => Ok(GinIndexMode::Auto),
+            _ => bail!("invalid value: {s} must be one of `true`, `false`, or `auto`"),
+        }
+    }
+}
+
+/// How to resolve a conflict when copying an entity version whose block
+/// range overlaps with a version of the same entity that is already in the
+/// destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyOverlapPolicy {
+    /// Fail the copy
+    Fail,
+    /// Keep the version in the destination and do not copy the source
+    /// version
+    Skip,
+    /// Remove the version in the destination and copy the source version
+    PreferSource,
+}
+
+impl FromStr for CopyOverlapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(CopyOverlapPolicy::Fail),
+            "skip" => Ok(CopyOverlapPolicy::Skip),
+            "prefer_source" => Ok(CopyOverlapPolicy::PreferSource),
+            _ => bail!("invalid value: {s} must be one of `fail`, `skip`, or `prefer_source`"),
+        }
+    }
+}
+
+/// The order in which threads that wait for a database connection get one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionAcquirePolicy {
+    /// The thread that has waited longest gets the next connection
+    Fifo,
+    /// The thread that started waiting last gets the next connection
+    Lifo,
+}
+
+impl FromStr for ConnectionAcquirePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" => Ok(ConnectionAcquirePolicy::Fifo),
+            "lifo" => Ok(ConnectionAcquirePolicy::Lifo),
+            _ => bail!("invalid value: {s} must be one of `fifo` or `lifo`"),
+        }
+    }
+}
+
+/// When the indexes of a copy are created relative to loading its data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyIndexTiming {
+    /// Create indexes once all data is loaded, which is faster
+    After,
+    /// Create indexes before loading data, so that the copy can be queried
+    /// efficiently while it is being loaded
+    Before,
+}
+
+impl FromStr for CopyIndexTiming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "after" => Ok(CopyIndexTiming::After),
+            "before" => Ok(CopyIndexTiming::Before),
+            _ => bail!("invalid value: {s} must be one of `after` or `before`"),
+        }
+    }
+}
+
+/// How notification payloads are compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationCodec {
+    /// Compress with zstd, which compresses better
+    Zstd,
+    /// Compress with lz4, which is faster
+    Lz4,
+    /// Send payloads uncompressed
+    None,
+}
+
+impl FromStr for NotificationCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" => Ok(NotificationCodec::Zstd),
+            "lz4" => Ok(NotificationCodec::Lz4),
+            "none" => Ok(NotificationCodec::None),
+            _ => bail!("invalid value: {s} must be one of `zstd`, `lz4`, or `none`"),
+        }
+    }
+}
+
+/// The order in which the tables of a subgraph are copied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyTableOrder {
+    /// By the name of the entity type, which is how tables have always
+    /// been copied
+    Declared,
+    /// Smallest tables first, so that more tables become available sooner
+    SizeAsc,
+    /// Largest tables first
+    SizeDesc,
+    /// A random order, to spread the load on the source across copies
+    Random,
+}
+
+impl FromStr for CopyTableOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "declared" => Ok(CopyTableOrder::Declared),
+            "size_asc" => Ok(CopyTableOrder::SizeAsc),
+            "size_desc" => Ok(CopyTableOrder::SizeDesc),
+            "random" => Ok(CopyTableOrder::Random),
+            _ => bail!(
+                "invalid value: {s} must be one of `declared`, `size_asc`, `size_desc`, or `random`"
+            ),
+        }
+    }
+}
+
+/// The Postgres `STORAGE` setting for columns that hold strings or bytes,
+/// which can get large
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LargeFieldStorage {
+    /// Leave the storage setting of the columns alone
+    Default,
+    /// Store large values out of line, but uncompressed. That speeds up
+    /// substring operations on them at the expense of more space
+    External,
+    /// Store large values out of line and compressed
+    Extended,
+}
+
+impl LargeFieldStorage {
+    /// The keyword for `alter column .. set storage`, or `None` if the
+    /// storage setting should not be changed
+    pub fn as_sql(&self) -> Option<&'static str> {
+        match self {
+            LargeFieldStorage::Default => None,
+            LargeFieldStorage::External => Some("external"),
+            LargeFieldStorage::Extended => Some("extended"),
+        }
+    }
+}
+
+impl FromStr for LargeFieldStorage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(LargeFieldStorage::Default),
+            "external" => Ok(LargeFieldStorage::External),
+            "extended" => Ok(LargeFieldStorage::Extended),
+            _ => bail!("invalid value: {s} must be one of `default`, `external`, or `extended`"),
+        }
+    }
+}
+
+/// The order in which the tables of a deployment are pruned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneTableOrder {
+    /// Largest tables first, which frees the most space soonest
+    SizeDesc,
+    /// Smallest tables first, so that more tables are done sooner
+    SizeAsc,
+    /// The order in which entity types are declared in the schema
+    Declared,
+}
+
+impl FromStr for PruneTableOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "size_desc" => Ok(PruneTableOrder::SizeDesc),
+            "size_asc" => Ok(PruneTableOrder::SizeAsc),
+            "declared" => Ok(PruneTableOrder::Declared),
+            _ => bail!("invalid value: {s} must be one of `size_desc`, `size_asc`, or `declared`"),
+        }
+    }
+}
+
+/// The order in which the rows of a table are copied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyRowOrder {
+    /// Whatever order Postgres reads the rows of a batch in, which is
+    /// fastest but makes the order of rows in the destination unpredictable
+    Physical,
+    /// Order rows by entity id and then by `vid` so that the destination
+    /// has a predictable layout
+    ById,
+}
+
+impl FromStr for CopyRowOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "physical" => Ok(CopyRowOrder::Physical),
+            "by_id" => Ok(CopyRowOrder::ById),
+            _ => bail!("invalid value: {s} must be one of `physical` or `by_id`"),
+        }
+    }
+}
+
+/// What to do when the tables in the database do not match the layout
+/// that a deployment's schema requires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaDriftPolicy {
+    /// Log a warning and continue
+    Warn,
+    /// Refuse to use the deployment
+    Refuse,
+    /// Do not check for drift
+    Ignore,
+}
+
+impl FromStr for SchemaDriftPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(SchemaDriftPolicy::Warn),
+            "refuse" => Ok(SchemaDriftPolicy::Refuse),
+            "ignore" => Ok(SchemaDriftPolicy::Ignore),
+            _ => bail!("invalid value: {s} must be one of `warn`, `refuse`, or `ignore`"),
+        }
+    }
+}
+
+/// The index method for the lookup column of the proof of indexing table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoiIndexMethod {
+    Btree,
+    Hash,
+}
+
+impl PoiIndexMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PoiIndexMethod::Btree => "btree",
+            PoiIndexMethod::Hash => "hash",
+        }
+    }
+}
+
+impl FromStr for PoiIndexMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "btree" => Ok(PoiIndexMethod::Btree),
+            "hash" => Ok(PoiIndexMethod::Hash),
+            _ => bail!("invalid value: {s} must be one of `btree` or `hash`"),
+        }
+    }
+}
+
+/// What happens to pending write batches when a revert comes in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchReorgPolicy {
+    /// Write all pending batches and then revert them in the database
+    FlushThenRevert,
+    /// Drop pending batches that only contain blocks that the revert
+    /// removes without writing them
+    DiscardPending,
+}
+
+impl FromStr for BatchReorgPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "flush_then_revert" => Ok(BatchReorgPolicy::FlushThenRevert),
+            "discard_pending" => Ok(BatchReorgPolicy::DiscardPending),
+            _ => {
+                bail!("invalid value: {s} must be one of `flush_then_revert` or `discard_pending`")
+            }
+        }
+    }
+}
+
+/// A Postgres memory setting like `256MB`. Since the value is used in
+/// `set` statements, only a number with an optional unit is accepted
+#[derive(Clone, Debug)]
+struct PgMemory(Option<String>);
+
+impl FromStr for PgMemory {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(PgMemory(None));
+        }
+        let unit = s.trim_start_matches(|c: char| c.is_ascii_digit());
+        if unit.len() == s.len() || !["", "kB", "MB", "GB", "TB"].contains(&unit) {
+            bail!(
+                "invalid value: {s} must be a number followed by one of `kB`, `MB`, `GB`, or `TB`"
+            );
+        }
+        Ok(PgMemory(Some(s.to_string())))
+    }
+}
+
+/// A list of `pool=size` entries, separated by commas
+#[derive(Clone, Debug, Default)]
+struct PoolSizes(HashMap<String, u32>);
+
+impl FromStr for PoolSizes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sizes = HashMap::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((pool, size)) = entry.split_once('=') else {
+                bail!("invalid value: {entry} must have the form `pool=size`");
+            };
+            let size = size
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| anyhow::anyhow!("invalid pool size in {entry}: {e}"))?;
+            sizes.insert(pool.trim().to_string(), size);
+        }
+        Ok(PoolSizes(sizes))
+    }
+}
+
+/// The error codes that can be retried. Diesel does not expose the
+/// SQLSTATE of an error, and `sqlstate` in `components::store::err` can
+/// only recover it for a few kinds of errors. Of those, only these are not
+/// constraint violations
+const RETRYABLE_SQLSTATES: [&str; 2] = ["40001", "25006"];
+
+/// A comma separated list of Postgres error codes (SQLSTATE). Codes of
+/// class 23 (integrity constraint violations) are rejected since retrying
+/// a write that violates a constraint can never succeed, and so are codes
+/// that we can not detect in errors
+#[derive(Clone, Debug, Default)]
+pub struct SqlStates(pub HashSet<String>);
+
+impl FromStr for SqlStates {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut codes = HashSet::new();
+        for code in s
+            .split(',')
+            .map(|code| code.trim().to_uppercase())
+            .filter(|code| !code.is_empty())
+        {
+            if code.len() != 5 || !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+                bail!("invalid value: {code} is not a Postgres error code");
+            }
+            if code.starts_with("23") {
+                bail!("invalid value: {code} is a constraint violation and can not be retried");
+            }
+            if !RETRYABLE_SQLSTATES.contains(&code.as_str()) {
+                bail!(
+                    "invalid value: {code} can not be detected in errors; only {} are supported",
+                    RETRYABLE_SQLSTATES.join(", ")
+                );
+            }
+            codes.insert(code);
+        }
+        Ok(SqlStates(codes))
+    }
+}
+
+/// A list of `chain=capacity` entries, separated by commas
+#[derive(Clone, Debug, Default)]
+struct CacheCapacities(HashMap<String, usize>);
+
+impl FromStr for CacheCapacities {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut capacities = HashMap::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((chain, capacity)) = entry.split_once('=') else {
+                bail!("invalid value: {entry} must have the form `chain=capacity`");
+            };
+            let capacity = capacity
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow::anyhow!("invalid cache capacity in {entry}: {e}"))?;
+            capacities.insert(chain.trim().to_string(), capacity);
+        }
+        Ok(CacheCapacities(capacities))
+    }
+}
+
+/// Statement timeouts for individual deployments, given as a comma
+/// separated list of `deployment=ms`
+#[derive(Clone, Debug)]
+struct QueryTimeouts(HashMap<String, Duration>);
+
+impl FromStr for QueryTimeouts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut timeouts = HashMap::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((deployment, ms)) = entry.split_once('=') else {
+                bail!("invalid value: {entry} must have the form `deployment=ms`");
+            };
+            let ms = ms
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("invalid query timeout in {entry}: {e}"))?;
+            timeouts.insert(deployment.trim().to_string(), Duration::from_millis(ms));
+        }
+        Ok(QueryTimeouts(timeouts))
+    }
+}
+
+/// The fillfactor for an index; an empty string means that the Postgres
+/// default should be used
+#[derive(Clone, Copy, Debug)]
+struct Fillfactor(Option<u8>);
+
+impl FromStr for Fillfactor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Fillfactor(None));
+        }
+        match s.parse::<u8>() {
+            Ok(f) if (10..=100).contains(&f) => Ok(Fillfactor(Some(f))),
+            _ => bail!("invalid value: {s} must be a number between 10 and 100"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PositiveF64(f64);
+
+impl FromStr for PositiveF64 {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let f = s.parse::<f64>()?;
+        if f <= 0.0 {
+            bail!("invalid value: {s} must be bigger than 0");
+        } else {
+            Ok(PositiveF64(f))
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct HistorySlackF64(f64);
 
@@ -277,3 +2121,357 @@ impl FromStr for HistorySlackF64 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use slog::{o, Discard, Logger};
+
+    use super::*;
+
+    fn env_vars(vars: &[(&str, &str)]) -> EnvVarsStore {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        InnerStore::init_from_hashmap(&vars)
+            .map(EnvVarsStore::from)
+            .unwrap()
+    }
+
+    #[test]
+    fn write_batch_size_unit() {
+        let batch_size = |unit: Option<&str>| {
+            let mut vars = vec![("GRAPH_STORE_WRITE_BATCH_SIZE", "3")];
+            vars.extend(unit.map(|unit| ("GRAPH_STORE_WRITE_BATCH_SIZE_UNIT", unit)));
+            env_vars(&vars).write_batch_size
+        };
+
+        assert_eq!(3_000, batch_size(None));
+        assert_eq!(3, batch_size(Some("bytes")));
+        assert_eq!(3_000, batch_size(Some("kb")));
+        assert_eq!(3_000_000, batch_size(Some("mb")));
+        assert_eq!(3_000_000, batch_size(Some("MB")));
+        assert!("gb".parse::<SizeUnit>().is_err());
+    }
+
+    #[test]
+    fn copy_overlap_policy() {
+        let policy = |value: &str| {
+            env_vars(&[("GRAPH_STORE_COPY_OVERLAP_POLICY", value)]).copy_overlap_policy
+        };
+
+        assert_eq!(CopyOverlapPolicy::Fail, env_vars(&[]).copy_overlap_policy);
+        assert_eq!(CopyOverlapPolicy::Skip, policy("skip"));
+        assert_eq!(CopyOverlapPolicy::PreferSource, policy("prefer_source"));
+        assert!("merge".parse::<CopyOverlapPolicy>().is_err());
+    }
+
+    #[test]
+    fn retryable_sqlstates() {
+        let codes = |value: &str| value.parse::<SqlStates>().map(|codes| codes.0);
+
+        assert!(env_vars(&[]).retryable_sqlstates.0.is_empty());
+        assert_eq!(
+            HashSet::from(["40001".to_string(), "25006".to_string()]),
+            codes(" 40001, 25006 ").unwrap()
+        );
+        assert!(codes("23505").is_err());
+        assert!(codes("40001,23").is_err());
+        assert!(codes("deadlock").is_err());
+        // Deadlocks are not among the codes that we can detect
+        assert!(codes("40P01").is_err());
+    }
+
+    #[test]
+    fn poi_index_method() {
+        let method =
+            |value: &str| env_vars(&[("GRAPH_STORE_POI_INDEX_METHOD", value)]).poi_index_method;
+
+        assert_eq!(PoiIndexMethod::Btree, env_vars(&[]).poi_index_method);
+        assert_eq!(PoiIndexMethod::Hash, method("hash"));
+        assert_eq!(PoiIndexMethod::Hash, method("HASH"));
+        assert!("gist".parse::<PoiIndexMethod>().is_err());
+    }
+
+    #[test]
+    fn batch_reorg_policy() {
+        let policy =
+            |value: &str| env_vars(&[("GRAPH_STORE_BATCH_REORG_POLICY", value)]).batch_reorg_policy;
+
+        assert_eq!(
+            BatchReorgPolicy::FlushThenRevert,
+            env_vars(&[]).batch_reorg_policy
+        );
+        assert_eq!(BatchReorgPolicy::DiscardPending, policy("discard_pending"));
+        assert_eq!(
+            BatchReorgPolicy::FlushThenRevert,
+            policy("flush_then_revert")
+        );
+        assert!("discard".parse::<BatchReorgPolicy>().is_err());
+    }
+
+    #[test]
+    fn safe_mode() {
+        let vars = env_vars(&[
+            ("GRAPH_STORE_CREATE_GIN_INDEXES", "true"),
+            ("GRAPH_STORE_WRITE_BATCH_DURATION", "60"),
+        ]);
+        assert_eq!(GinIndexMode::Always, vars.create_gin_indexes);
+        assert!(vars.order_by_block_range);
+        assert_eq!(Duration::from_secs(60), vars.write_batch_duration);
+        assert!(vars.adaptive_batching);
+        assert!(vars.safe_mode_overrides.is_empty());
+
+        let vars = env_vars(&[
+            ("GRAPH_STORE_SAFE_MODE", "true"),
+            ("GRAPH_STORE_CREATE_GIN_INDEXES", "true"),
+            ("GRAPH_STORE_WRITE_BATCH_DURATION", "60"),
+        ]);
+        assert_eq!(GinIndexMode::Never, vars.create_gin_indexes);
+        assert!(!vars.order_by_block_range);
+        assert_eq!(Duration::ZERO, vars.write_batch_duration);
+        assert!(!vars.adaptive_batching);
+        assert_eq!(
+            vec![
+                "create_gin_indexes=false",
+                "order_by_block_range=false",
+                "write_batch_duration=0",
+                "adaptive_batching=false"
+            ],
+            vars.safe_mode_overrides
+        );
+
+        // Settings that are already conservative are not reported
+        let vars = env_vars(&[
+            ("GRAPH_STORE_SAFE_MODE", "true"),
+            ("GRAPH_STORE_WRITE_BATCH_DURATION", "0"),
+            ("ORDER_BY_BLOCK_RANGE", "false"),
+        ]);
+        assert_eq!(vec!["adaptive_batching=false"], vars.safe_mode_overrides);
+    }
+
+    #[test]
+    fn maintenance_work_mem() {
+        let work_mem = |value: &str| {
+            env_vars(&[("GRAPH_STORE_MAINTENANCE_WORK_MEM", value)]).maintenance_work_mem
+        };
+
+        assert_eq!(None, env_vars(&[]).maintenance_work_mem);
+        assert_eq!(Some("256MB".to_string()), work_mem("256MB"));
+        assert_eq!(Some("65536".to_string()), work_mem("65536"));
+        assert!(PgMemory::from_str("256mb").is_err());
+        assert!(PgMemory::from_str("MB").is_err());
+        assert!(PgMemory::from_str("1MB; drop table x").is_err());
+    }
+
+    #[test]
+    fn connection_max_lifetime() {
+        let lifetime = |secs: &str| {
+            env_vars(&[("GRAPH_STORE_CONNECTION_MAX_LIFETIME", secs)]).connection_max_lifetime
+        };
+
+        assert_eq!(None, env_vars(&[]).connection_max_lifetime);
+        assert_eq!(None, lifetime("0"));
+        assert_eq!(Some(Duration::from_secs(3600)), lifetime("3600"));
+    }
+
+    #[test]
+    fn create_gin_indexes() {
+        let mode = |value: &str| env_vars(&[("GRAPH_STORE_CREATE_GIN_INDEXES", value)]);
+
+        assert_eq!(GinIndexMode::Never, env_vars(&[]).create_gin_indexes);
+        assert_eq!(GinIndexMode::Never, mode("false").create_gin_indexes);
+        assert_eq!(GinIndexMode::Always, mode("true").create_gin_indexes);
+        assert_eq!(GinIndexMode::Auto, mode("auto").create_gin_indexes);
+        assert_eq!(1000, mode("auto").gin_auto_threshold);
+        assert!("sometimes".parse::<GinIndexMode>().is_err());
+    }
+
+    #[test]
+    fn store_config_metrics() {
+        let env = env_vars(&[
+            ("GRAPH_STORE_WRITE_QUEUE", "7"),
+            ("GRAPH_STORE_WRITE_BATCH_SIZE", "20"),
+        ]);
+        let prometheus = Arc::new(Registry::new());
+        let registry = MetricsRegistry::new(Logger::root(Discard, o!()), prometheus.clone());
+
+        register_store_config_metrics(&env, &registry).unwrap();
+
+        let values: HashMap<_, _> = prometheus
+            .gather()
+            .into_iter()
+            .map(|family| {
+                let value = family.get_metric()[0].get_gauge().get_value();
+                (family.get_name().to_string(), value)
+            })
+            .collect();
+        let value = |name: &str| values.get(&format!("store_config_{name}")).copied();
+
+        assert_eq!(Some(7.0), value("write_queue_size"));
+        assert_eq!(Some(20_000.0), value("write_batch_size"));
+        assert_eq!(Some(30.0), value("chain_head_watcher_timeout_secs"));
+        assert_eq!(Some(5.0), value("connection_timeout_secs"));
+        assert_eq!(Some(360.0), value("remove_unused_interval_minutes"));
+        assert_eq!(Some(0.5), value("rebuild_threshold"));
+        assert_eq!(Some(10_000.0), value("fdw_fetch_size"));
+        // Settings without a value are not reported
+        assert_eq!(None, value("connection_min_idle"));
+    }
+
+    #[test]
+    fn durations_summary() {
+        let summary: HashMap<_, _> = env_vars(&[]).durations_summary().into_iter().collect();
+
+        assert_eq!(Some(&30.0), summary.get("chain_head_watcher_timeout"));
+        assert_eq!(Some(&300.0), summary.get("query_stats_refresh_interval"));
+        assert_eq!(Some(&600.0), summary.get("schema_cache_ttl"));
+        assert_eq!(Some(&5.0), summary.get("connection_timeout"));
+        assert_eq!(Some(&21_600.0), summary.get("remove_unused_interval"));
+        assert_eq!(Some(&0.0), summary.get("remove_unused_min_age"));
+        assert_eq!(Some(&180.0), summary.get("batch_target_duration"));
+        assert_eq!(None, summary.get("connection_max_lifetime"));
+        assert_eq!(
+            Some(&2_592_000.0),
+            summary.get("maintenance_history_retention")
+        );
+
+        let summary = env_vars(&[
+            ("GRAPH_STORE_WRITE_BATCH_DURATION", "45"),
+            ("GRAPH_STORE_CONNECTION_MAX_LIFETIME", "3600"),
+            ("GRAPH_STORE_MAINTENANCE_HISTORY_RETENTION", "0"),
+            ("GRAPH_STORE_NOTIFICATION_BATCH_INTERVAL", "250"),
+        ])
+        .durations_summary();
+        assert!(summary.contains(&("write_batch_duration", 45.0)));
+        assert!(summary.contains(&("connection_max_lifetime", 3600.0)));
+        // Durations below one second are not rounded down to 0
+        assert!(summary.contains(&("notification_batch_interval", 0.25)));
+        assert!(!summary
+            .iter()
+            .any(|(name, _)| *name == "maintenance_history_retention"));
+    }
+
+    #[test]
+    fn schema_cache_ttl_max() {
+        let ttl = |vars: &[(&str, &str)]| env_vars(vars).schema_cache_ttl.as_secs();
+
+        assert_eq!(600, ttl(&[]));
+        assert_eq!(7200, ttl(&[("GRAPH_QUERY_STATS_REFRESH_INTERVAL", "3600")]));
+
+        // A large derived TTL is capped
+        assert_eq!(
+            900,
+            ttl(&[
+                ("GRAPH_QUERY_STATS_REFRESH_INTERVAL", "3600"),
+                ("GRAPH_STORE_SCHEMA_CACHE_TTL_MAX", "900"),
+            ])
+        );
+        assert_eq!(600, ttl(&[("GRAPH_STORE_SCHEMA_CACHE_TTL_MAX", "900")]));
+
+        // An explicit TTL is not capped
+        assert_eq!(
+            7200,
+            ttl(&[
+                ("GRAPH_SCHEMA_CACHE_TTL", "7200"),
+                ("GRAPH_STORE_SCHEMA_CACHE_TTL_MAX", "900"),
+            ])
+        );
+    }
+
+    #[test]
+    fn pool_max_size_overrides() {
+        let vars = env_vars(&[
+            (
+                "GRAPH_STORE_POOL_MAX_SIZE_OVERRIDES",
+                "main=20, shard1.main=5,replica1=8",
+            ),
+            ("GRAPH_STORE_CONNECTION_MIN_IDLE", "10"),
+        ]);
+
+        assert_eq!(20, vars.pool_max_size("primary", "main", 30));
+        assert_eq!(5, vars.pool_max_size("shard1", "main", 30));
+        assert_eq!(8, vars.pool_max_size("primary", "replica1", 30));
+        assert_eq!(30, vars.pool_max_size("primary", "replica2", 30));
+
+        // The min idle setting is only used for pools that are big enough
+        assert_eq!(Some(10), vars.connection_min_idle_for(20));
+        assert_eq!(Some(10), vars.connection_min_idle_for(10));
+        assert_eq!(None, vars.connection_min_idle_for(5));
+        assert_eq!(None, env_vars(&[]).connection_min_idle_for(5));
+
+        assert!(env_vars(&[]).pool_max_size_overrides.is_empty());
+        assert!(PoolSizes::from_str("main").is_err());
+        assert!(PoolSizes::from_str("main=many").is_err());
+    }
+
+    #[test]
+    fn brin_disabled_tables() {
+        let vars = env_vars(&[(
+            "GRAPH_STORE_BRIN_DISABLED_TABLES",
+            "QmDeployment:transfer, sgd42:account",
+        )]);
+
+        assert!(vars.brin_disabled("QmDeployment", "sgd1", "transfer"));
+        assert!(vars.brin_disabled("QmOther", "sgd42", "account"));
+        assert!(!vars.brin_disabled("QmDeployment", "sgd1", "account"));
+        assert!(!vars.brin_disabled("QmOther", "sgd2", "transfer"));
+        assert!(!env_vars(&[]).brin_disabled("QmDeployment", "sgd1", "transfer"));
+    }
+
+    #[test]
+    fn recent_blocks_cache_capacity_overrides() {
+        let vars = env_vars(&[
+            (
+                "GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY_OVERRIDES",
+                "mainnet=50, arbitrum-one = 200",
+            ),
+            ("GRAPH_STORE_RECENT_BLOCKS_CACHE_CAPACITY", "20"),
+        ]);
+
+        assert_eq!(50, vars.recent_blocks_cache_capacity_for("mainnet"));
+        assert_eq!(200, vars.recent_blocks_cache_capacity_for("arbitrum-one"));
+        // Chains without an override use the general capacity
+        assert_eq!(20, vars.recent_blocks_cache_capacity_for("gnosis"));
+        assert_eq!(
+            10,
+            env_vars(&[]).recent_blocks_cache_capacity_for("mainnet")
+        );
+
+        assert!(env_vars(&[])
+            .recent_blocks_cache_capacity_overrides
+            .is_empty());
+        assert!(CacheCapacities::from_str("mainnet").is_err());
+        assert!(CacheCapacities::from_str("mainnet=-1").is_err());
+    }
+
+    #[test]
+    fn query_timeout_overrides() {
+        let global = Some(Duration::from_secs(30));
+        let vars = env_vars(&[(
+            "GRAPH_STORE_QUERY_TIMEOUT_OVERRIDES",
+            "QmSlow=120000, sgd7 = 500",
+        )]);
+
+        // Listed deployments get their override, whether they are listed
+        // by hash or by namespace
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            vars.query_timeout_for("QmSlow", "sgd1", global)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(500)),
+            vars.query_timeout_for("QmFast", "sgd7", None)
+        );
+        // Other deployments get the global timeout
+        assert_eq!(global, vars.query_timeout_for("QmOther", "sgd2", global));
+        assert_eq!(None, vars.query_timeout_for("QmOther", "sgd2", None));
+
+        assert!(env_vars(&[]).query_timeout_overrides.is_empty());
+        assert!(QueryTimeouts::from_str("QmSlow").is_err());
+        assert!(QueryTimeouts::from_str("QmSlow=1s").is_err());
+    }
+}
@@ -153,6 +153,25 @@ impl<T: Clone> BoundedQueue<T> {
         f(queue.back())
     }
 
+    /// Remove the newest entry from the queue if `f` returns `true` for
+    /// it. This never blocks; if a consumer is about to look at the only
+    /// entry in the queue, it is not removed and `None` is returned
+    pub fn pop_newest_if<F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        let permit = self.pop_semaphore.try_acquire().ok()?;
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.back().is_some_and(f) {
+            return None;
+        }
+        let item = queue.pop_back();
+        drop(queue);
+        permit.forget();
+        self.push_semaphore.add_permits(1);
+        item
+    }
+
     /// Iterate over the entries in the queue from newest to oldest entry
     /// atomically, applying `f` to each entry and returning the result of
     /// the last invocation of `f`.
@@ -176,6 +176,22 @@ impl CacheWeight for q::Value {
     }
 }
 
+impl CacheWeight for serde_json::Value {
+    fn indirect_weight(&self) -> usize {
+        match self {
+            serde_json::Value::Null | serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                0
+            }
+            serde_json::Value::String(s) => s.indirect_weight(),
+            serde_json::Value::Array(values) => values.indirect_weight(),
+            serde_json::Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| key.weight() + value.weight())
+                .sum(),
+        }
+    }
+}
+
 impl CacheWeight for usize {
     fn indirect_weight(&self) -> usize {
         0
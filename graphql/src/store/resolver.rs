@@ -244,6 +244,20 @@ impl StoreResolver {
             "hasIndexingErrors".into(),
             r::Value::Boolean(self.has_non_fatal_errors),
         );
+        // Expose the query rewrites that are in effect; these are the same
+        // for all deployments and not sensitive
+        map.insert(
+            "orderByBlockRange".into(),
+            r::Value::Boolean(ENV_VARS.store.order_by_block_range),
+        );
+        map.insert(
+            "useBrinForAllQueryTypes".into(),
+            r::Value::Boolean(ENV_VARS.store.use_brin_for_all_query_types),
+        );
+        map.insert(
+            "typedChildrenSetSize".into(),
+            r::Value::Int(ENV_VARS.store.typed_children_set_size as i64),
+        );
         map.insert(
             "__typename".into(),
             r::Value::String(META_FIELD_TYPE.to_string()),
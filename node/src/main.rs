@@ -193,6 +193,15 @@ async fn main() {
         logger.clone(),
         prometheus_registry.clone(),
     ));
+    graph::env::register_store_config_metrics(&ENV_VARS.store, &metrics_registry)
+        .expect("failed to register store config metrics");
+    match config.store_summary(ENV_VARS.store.log_config) {
+        Ok(summary) => info!(logger, "Store configuration"; "stores" => summary),
+        Err(e) => warn!(logger, "Failed to describe store configuration"; "error" => e.to_string()),
+    }
+    for setting in &ENV_VARS.store.safe_mode_overrides {
+        warn!(logger, "GRAPH_STORE_SAFE_MODE is set, overriding store setting"; "setting" => setting);
+    }
 
     // Create a component and subgraph logger factory
     let logger_factory =
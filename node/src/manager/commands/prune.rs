@@ -63,6 +63,7 @@ fn print_batch(
         (false, PrunePhase::CopyFinal) => "(final)",
         (false, PrunePhase::CopyNonfinal) => "(nonfinal)",
         (false, PrunePhase::Delete) => "(delete)",
+        (false, PrunePhase::Truncate) => "(truncate)",
     };
     print!(
         "\r{:<30} | {:>10} | {:>9}s {phase}",
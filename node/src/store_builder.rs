@@ -2,7 +2,7 @@ use std::iter::FromIterator;
 use std::{collections::HashMap, sync::Arc};
 
 use graph::futures03::future::join_all;
-use graph::prelude::{o, MetricsRegistry, NodeId};
+use graph::prelude::{o, warn, MetricsRegistry, NodeId, ENV_VARS};
 use graph::url::Url;
 use graph::{
     prelude::{info, CheapClone, Logger},
@@ -64,6 +64,25 @@ impl StoreBuilder {
         // using the pool
         join_all(pools.values().map(|pool| pool.setup())).await;
 
+        // Load query statistics before the node starts serving so that the
+        // first queries do not run with cold statistics
+        if ENV_VARS.store.query_stats_eager_load {
+            let store = store.cheap_clone();
+            let load_logger = logger.cheap_clone();
+            let res =
+                graph::spawn_blocking_allow_panic(move || store.load_query_stats(&load_logger))
+                    .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!(logger, "Failed to load query statistics"; "error" => e.to_string())
+                }
+                Err(e) => {
+                    warn!(logger, "Loading query statistics panicked"; "error" => e.to_string())
+                }
+            }
+        }
+
         let chains = HashMap::from_iter(config.chains.chains.iter().map(|(name, chain)| {
             let shard = ShardName::new(chain.shard.to_string())
                 .expect("config validation catches invalid names");
@@ -631,6 +631,54 @@ impl<S: Store> IndexNodeResolver<S> {
         ))
     }
 
+    fn resolve_deployments_per_pool(&self) -> Result<r::Value, QueryExecutionError> {
+        let counts = self.store.deployments_per_pool()?;
+        Ok(r::Value::List(
+            counts
+                .into_iter()
+                .map(|(pool, deployments)| {
+                    r::Value::Object(Object::from_iter(vec![
+                        ("pool".into(), r::Value::String(pool)),
+                        ("deployments".into(), r::Value::Int(deployments)),
+                    ]))
+                })
+                .collect(),
+        ))
+    }
+
+    fn resolve_maintenance_history(
+        &self,
+        field: &a::Field,
+    ) -> Result<r::Value, QueryExecutionError> {
+        let subgraph_id = field
+            .get_required::<DeploymentHash>("subgraphId")
+            .expect("Valid subgraphId required");
+
+        let records = self.store.maintenance_history(&subgraph_id)?;
+        Ok(r::Value::List(
+            records
+                .into_iter()
+                .map(|record| {
+                    r::Value::Object(Object::from_iter(vec![
+                        ("operation".into(), r::Value::String(record.operation)),
+                        ("table".into(), r::Value::String(record.table)),
+                        (
+                            "startedAt".into(),
+                            r::Value::String(record.started_at.to_rfc3339()),
+                        ),
+                        (
+                            "finishedAt".into(),
+                            r::Value::String(record.finished_at.to_rfc3339()),
+                        ),
+                        ("rows".into(), r::Value::Int(record.rows)),
+                        ("bytes".into(), r::Value::Int(record.bytes)),
+                        ("strategy".into(), r::Value::String(record.strategy)),
+                    ]))
+                })
+                .collect(),
+        ))
+    }
+
     fn version(&self) -> Result<r::Value, QueryExecutionError> {
         Ok(VERSION.clone().into_value())
     }
@@ -840,6 +888,11 @@ impl<S: Store> Resolver for IndexNodeResolver<S> {
                 self.resolve_public_proofs_of_indexing(field).await
             }
 
+            (None, "PoolDeployments", "deploymentsPerPool") => self.resolve_deployments_per_pool(),
+            (None, "MaintenanceRecord", "maintenanceHistory") => {
+                self.resolve_maintenance_history(field)
+            }
+
             // Resolve fields of `Object` values (e.g. the `chains` field of `ChainIndexingStatus`)
             (value, _, _) => Ok(value.unwrap_or(r::Value::Null)),
         }
@@ -0,0 +1 @@
+drop table subgraphs.maintenance_history;
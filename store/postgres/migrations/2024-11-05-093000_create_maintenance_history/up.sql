@@ -0,0 +1,18 @@
+create table subgraphs.maintenance_history
+(
+    id          bigserial primary key,
+    deployment  text                     not null,
+    operation   text                     not null check (operation in ('prune', 'copy')),
+    table_name  text                     not null,
+    started_at  timestamp with time zone not null,
+    finished_at timestamp with time zone not null,
+    rows        bigint                   not null,
+    bytes       bigint                   not null,
+    strategy    text                     not null
+);
+
+create index maintenance_history_deployment
+    on subgraphs.maintenance_history(deployment, id);
+
+create index maintenance_history_finished_at
+    on subgraphs.maintenance_history(finished_at);
@@ -0,0 +1,3 @@
+alter table subgraphs.table_stats
+  drop column rebuild_threshold,
+  drop column delete_threshold;
@@ -0,0 +1,5 @@
+alter table subgraphs.table_stats
+  add column rebuild_threshold float8
+    check (rebuild_threshold between 0 and 1),
+  add column delete_threshold float8
+    check (delete_threshold between 0 and 1);
@@ -266,15 +266,11 @@ impl<'a> BlockRangeColumn<'a> {
                 out.push_sql(" @> ");
                 out.push_bind_param::<Integer, _>(block)?;
 
-                let should_use_brin = !filters_by_id || ENV_VARS.store.use_brin_for_all_query_types;
-                if table.is_account_like && *block < BLOCK_NUMBER_MAX && should_use_brin {
-                    // When block is BLOCK_NUMBER_MAX, these checks would be wrong; we
-                    // don't worry about adding the equivalent in that case since
-                    // we generally only see BLOCK_NUMBER_MAX here for metadata
-                    // queries where block ranges don't matter anyway.
-                    //
-                    // We also don't need to add these if the query already filters by ID,
-                    // because the ideal index is the GiST index on id and block_range.
+                if table.use_brin(
+                    *block,
+                    filters_by_id,
+                    ENV_VARS.store.use_brin_for_all_query_types,
+                ) {
                     out.push_sql(" and coalesce(upper(");
                     out.push_identifier(BLOCK_RANGE_COLUMN)?;
                     out.push_sql("), 2147483647) > ");
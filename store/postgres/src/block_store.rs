@@ -321,7 +321,7 @@ impl BlockStore {
     pub(crate) async fn query_permit_primary(&self) -> QueryPermit {
         self.mirror
             .primary()
-            .query_permit()
+            .status_permit()
             .await
             .expect("the primary is never disabled")
     }
@@ -372,13 +372,14 @@ impl BlockStore {
             .get(&chain.shard)
             .ok_or_else(|| constraint_violation!("there is no pool for shard {}", chain.shard))?
             .clone();
+        let logger = self.logger.new(o!("network" => chain.name.clone()));
         let sender = ChainHeadUpdateSender::new(
+            logger.clone(),
             self.mirror.primary().clone(),
             chain.name.clone(),
             self.sender.clone(),
         );
         let ident = chain.network_identifier()?;
-        let logger = self.logger.new(o!("network" => chain.name.clone()));
         let store = ChainStore::new(
             logger,
             chain.name.clone(),
@@ -386,7 +387,8 @@ impl BlockStore {
             status,
             sender,
             pool,
-            ENV_VARS.store.recent_blocks_cache_capacity,
+            ENV_VARS.store.recent_blocks_cache_capacity_for(&chain.name),
+            ENV_VARS.store.recent_blocks_cache_bytes,
             self.chain_store_metrics.clone(),
         );
         if create {
@@ -102,6 +102,8 @@ table! {
         table_name -> Text,
         is_account_like -> Nullable<Bool>,
         last_pruned_block -> Nullable<Integer>,
+        rebuild_threshold -> Nullable<Double>,
+        delete_threshold -> Nullable<Double>,
     }
 }
 
@@ -301,6 +303,38 @@ fn get_text_columns(
     Ok(map)
 }
 
+/// Return the names of the columns of all tables in `namespace`, keyed by
+/// table name
+pub(crate) fn table_columns(
+    conn: &mut PgConnection,
+    namespace: &Namespace,
+) -> Result<HashMap<String, HashSet<String>>, StoreError> {
+    const QUERY: &str = "
+        select table_name, column_name
+          from information_schema.columns
+         where table_schema = $1";
+
+    #[derive(Debug, QueryableByName)]
+    struct Column {
+        #[diesel(sql_type = Text)]
+        pub table_name: String,
+        #[diesel(sql_type = Text)]
+        pub column_name: String,
+    }
+
+    let map: HashMap<String, HashSet<String>> = diesel::sql_query(QUERY)
+        .bind::<Text, _>(namespace.as_str())
+        .load::<Column>(conn)?
+        .into_iter()
+        .fold(HashMap::new(), |mut map, col| {
+            map.entry(col.table_name)
+                .or_default()
+                .insert(col.column_name);
+            map
+        });
+    Ok(map)
+}
+
 pub fn table_exists(
     conn: &mut PgConnection,
     namespace: &str,
@@ -479,6 +513,59 @@ pub fn set_account_like(
     Ok(())
 }
 
+/// Set `work_mem` and `maintenance_work_mem` for the session on `conn` to
+/// `work_mem` so that large sorts during pruning and copying don't spill
+/// to disk. Since connections are pooled, every call must be followed by a
+/// call to `reset_maintenance_work_mem` once the maintenance operation is
+/// done. Does nothing if `work_mem` is `None`
+pub fn set_maintenance_work_mem(
+    conn: &mut PgConnection,
+    work_mem: Option<&str>,
+) -> Result<(), StoreError> {
+    if let Some(work_mem) = work_mem {
+        conn.batch_execute(&format!(
+            "set work_mem = '{work_mem}'; set maintenance_work_mem = '{work_mem}'"
+        ))?;
+    }
+    Ok(())
+}
+
+/// Undo the effect of `set_maintenance_work_mem`
+pub fn reset_maintenance_work_mem(
+    conn: &mut PgConnection,
+    work_mem: Option<&str>,
+) -> Result<(), StoreError> {
+    if work_mem.is_some() {
+        conn.batch_execute("reset work_mem; reset maintenance_work_mem")?;
+    }
+    Ok(())
+}
+
+/// Set `statement_timeout` for the session on `conn` to `timeout` so that
+/// a maintenance operation that hangs is aborted. Like
+/// `set_maintenance_work_mem`, every call must be followed by a call to
+/// `reset_statement_timeout`. Does nothing if `timeout` is `None`
+pub fn set_statement_timeout(
+    conn: &mut PgConnection,
+    timeout: Option<Duration>,
+) -> Result<(), StoreError> {
+    if let Some(timeout) = timeout {
+        conn.batch_execute(&format!("set statement_timeout = {}", timeout.as_millis()))?;
+    }
+    Ok(())
+}
+
+/// Undo the effect of `set_statement_timeout`
+pub fn reset_statement_timeout(
+    conn: &mut PgConnection,
+    timeout: Option<Duration>,
+) -> Result<(), StoreError> {
+    if timeout.is_some() {
+        conn.batch_execute("reset statement_timeout")?;
+    }
+    Ok(())
+}
+
 pub fn copy_account_like(
     conn: &mut PgConnection,
     src: &Site,
@@ -486,8 +573,10 @@ pub fn copy_account_like(
 ) -> Result<usize, StoreError> {
     let src_nsp = ForeignServer::metadata_schema_in(&src.shard, &dst.shard);
     let query = format!(
-        "insert into subgraphs.table_stats(deployment, table_name, is_account_like, last_pruned_block)
-         select $2 as deployment, ts.table_name, ts.is_account_like, ts.last_pruned_block
+        "insert into subgraphs.table_stats(deployment, table_name, is_account_like, last_pruned_block,
+                                           rebuild_threshold, delete_threshold)
+         select $2 as deployment, ts.table_name, ts.is_account_like, ts.last_pruned_block,
+                ts.rebuild_threshold, ts.delete_threshold
            from {src_nsp}.table_stats ts
           where ts.deployment = $1",
         src_nsp = src_nsp
@@ -778,6 +867,10 @@ pub fn stats(conn: &mut PgConnection, site: &Site) -> Result<Vec<VersionStats>,
         pub ratio: f64,
         #[diesel(sql_type = Nullable<Integer>)]
         pub last_pruned_block: Option<i32>,
+        #[diesel(sql_type = Nullable<Double>)]
+        pub rebuild_threshold: Option<f64>,
+        #[diesel(sql_type = Nullable<Double>)]
+        pub delete_threshold: Option<f64>,
     }
 
     impl From<DbStats> for VersionStats {
@@ -788,6 +881,8 @@ pub fn stats(conn: &mut PgConnection, site: &Site) -> Result<Vec<VersionStats>,
                 tablename: s.tablename,
                 ratio: s.ratio,
                 last_pruned_block: s.last_pruned_block,
+                rebuild_threshold: s.rebuild_threshold,
+                delete_threshold: s.delete_threshold,
             }
         }
     }
@@ -806,7 +901,9 @@ pub fn stats(conn: &mut PgConnection, site: &Site) -> Result<Vec<VersionStats>,
                      when s.n_distinct < 0 then (-s.n_distinct)::float8
                      else greatest(s.n_distinct, 1)::float8 / c.reltuples::float8
                  end as ratio,
-                 ts.last_pruned_block
+                 ts.last_pruned_block,
+                 ts.rebuild_threshold,
+                 ts.delete_threshold
            from pg_namespace n, pg_class c, pg_stats s
                 left outer join subgraphs.table_stats ts
                      on (ts.table_name = s.tablename
@@ -828,6 +925,18 @@ pub fn stats(conn: &mut PgConnection, site: &Site) -> Result<Vec<VersionStats>,
     Ok(stats.into_iter().map(|s| s.into()).collect())
 }
 
+/// Return the size in bytes of the table `qualified_name` including its
+/// indexes and TOAST data
+pub(crate) fn table_size(
+    conn: &mut PgConnection,
+    qualified_name: &SqlName,
+) -> Result<i64, StoreError> {
+    use diesel::dsl::sql;
+
+    let query = format!("pg_total_relation_size('{qualified_name}')");
+    Ok(select(sql::<BigInt>(&query)).get_result::<i64>(conn)?)
+}
+
 /// Return by how much the slowest replica connected to the database `conn`
 /// is lagging. The returned value has millisecond precision. If the
 /// database has no replicas, return `0`
@@ -959,6 +1068,36 @@ pub(crate) fn needs_autoanalyze(
     Ok(tables)
 }
 
+/// Return the names of all tables in the `namespace` whose statistics are
+/// older than `max_age`, including tables that have never been analyzed
+pub(crate) fn stale_stats(
+    conn: &mut PgConnection,
+    namespace: &Namespace,
+    max_age: Duration,
+) -> Result<Vec<SqlName>, StoreError> {
+    const QUERY: &str = "select relname \
+                           from pg_stat_user_tables \
+                          where coalesce(greatest(last_analyze, last_autoanalyze), '-infinity') \
+                                < now() - make_interval(secs => $2) \
+                            and schemaname = $1";
+
+    #[derive(Queryable, QueryableByName)]
+    struct TableName {
+        #[diesel(sql_type = Text)]
+        name: SqlName,
+    }
+
+    let tables = sql_query(QUERY)
+        .bind::<Text, _>(namespace.as_str())
+        .bind::<Double, _>(max_age.as_secs_f64())
+        .get_results::<TableName>(conn)?
+        .into_iter()
+        .map(|t| t.name)
+        .collect();
+
+    Ok(tables)
+}
+
 /// Check whether the database for `conn` supports the `minmax_multi_ops`
 /// introduced in Postgres 14
 fn has_minmax_multi_ops(conn: &mut PgConnection) -> Result<bool, StoreError> {
@@ -1,16 +1,18 @@
 use graph::futures03::{self, FutureExt};
 use graph::{
     blockchain::ChainHeadUpdateStream,
-    prelude::{tokio, MetricsRegistry, StoreError},
+    prelude::{MetricsRegistry, StoreError},
     prometheus::{CounterVec, GaugeVec},
     util::timed_rw_lock::TimedRwLock,
 };
 use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 
 use crate::{
+    clock,
     connection_pool::ConnectionPool,
     notification_listener::{JsonNotification, NotificationListener, SafeChannelName},
     NotificationSender,
@@ -18,22 +20,27 @@ use crate::{
 use graph::blockchain::ChainHeadUpdateListener as ChainHeadUpdateListenerTrait;
 use graph::prelude::serde::{Deserialize, Serialize};
 use graph::prelude::serde_json::{self, json};
-use graph::prelude::tokio::sync::{mpsc::Receiver, watch};
-use graph::prelude::{crit, debug, o, CheapClone, Logger, ENV_VARS};
+use graph::prelude::tokio::sync::{
+    mpsc::{self, Receiver, UnboundedSender},
+    watch,
+};
+use graph::prelude::tokio::time::sleep;
+use graph::prelude::{crit, debug, o, warn, CheapClone, Logger, ENV_VARS};
 
 lazy_static! {
     pub static ref CHANNEL_NAME: SafeChannelName =
         SafeChannelName::i_promise_this_is_safe("chain_head_updates");
 }
 
+/// Passes the number of the latest chain head block to subscribers
 struct Watcher {
-    sender: Arc<watch::Sender<()>>,
-    receiver: watch::Receiver<()>,
+    sender: Arc<watch::Sender<u64>>,
+    receiver: watch::Receiver<u64>,
 }
 
 impl Watcher {
     fn new() -> Self {
-        let (sender, receiver) = watch::channel(());
+        let (sender, receiver) = watch::channel(0);
         Watcher {
             sender: Arc::new(sender),
             receiver,
@@ -41,14 +48,15 @@ impl Watcher {
     }
 
     #[allow(dead_code)]
-    fn send(&self) {
+    fn send(&self, head: u64) {
         // Unwrap: `self` holds a receiver.
-        self.sender.send(()).unwrap()
+        self.sender.send(head).unwrap()
     }
 }
 
 pub struct BlockIngestorMetrics {
     chain_head_number: Box<GaugeVec>,
+    chain_head_lag: Box<GaugeVec>,
 }
 
 impl BlockIngestorMetrics {
@@ -61,6 +69,13 @@ impl BlockIngestorMetrics {
                     vec![String::from("network")],
                 )
                 .unwrap(),
+            chain_head_lag: registry
+                .new_gauge_vec(
+                    "chain_head_lag_blocks",
+                    "Number of blocks the watched chain head is ahead of the last processed one",
+                    vec![String::from("network")],
+                )
+                .unwrap(),
         }
     }
 
@@ -69,6 +84,54 @@ impl BlockIngestorMetrics {
             .with_label_values(vec![network_name].as_slice())
             .set(chain_head_number as f64);
     }
+
+    pub fn set_chain_head_lag(&self, network_name: &str, lag: u64) {
+        self.chain_head_lag
+            .with_label_values(vec![network_name].as_slice())
+            .set(lag as f64);
+    }
+}
+
+/// Keeps track of the last chain head that subscribers processed for each
+/// network to detect when they fall behind the chain head that the
+/// watcher sees, i.e., when the watched chain head is many blocks ahead of
+/// the last processed one
+struct HeadLagTracker {
+    /// Lag beyond which we alert; 0 disables tracking
+    threshold: u64,
+    processed: BTreeMap<String, u64>,
+}
+
+impl HeadLagTracker {
+    fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            processed: BTreeMap::new(),
+        }
+    }
+
+    /// Record that a subscriber processed the chain head `head` for
+    /// `network`
+    fn processed(&mut self, network: &str, head: u64) {
+        let processed = self.processed.entry(network.to_string()).or_default();
+        *processed = (*processed).max(head);
+    }
+
+    /// Return by how many blocks the watched chain head `head` for
+    /// `network` is ahead of the last processed chain head. Return `None`
+    /// if tracking is disabled or no chain head for `network` has been
+    /// processed yet
+    fn observe(&self, network: &str, head: u64) -> Option<u64> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let processed = self.processed.get(network)?;
+        Some(head.saturating_sub(*processed))
+    }
+
+    fn exceeds_threshold(&self, lag: u64) -> bool {
+        self.threshold > 0 && lag > self.threshold
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -81,15 +144,93 @@ struct ChainHeadUpdate {
 pub struct ChainHeadUpdateListener {
     /// Update watchers keyed by network.
     watchers: Arc<TimedRwLock<BTreeMap<String, Watcher>>>,
+    lag_tracker: Arc<Mutex<HeadLagTracker>>,
     _listener: NotificationListener,
 }
 
 /// Sender for messages that the `ChainHeadUpdateListener` on other nodes
 /// will receive. The sender is specific to a particular chain.
+#[derive(Clone)]
 pub(crate) struct ChainHeadUpdateSender {
+    logger: Logger,
     pool: ConnectionPool,
     chain_name: String,
     sender: Arc<NotificationSender>,
+    coalescer: Arc<Coalescer<(String, i64)>>,
+    /// The channel to the task that sends the updates that the coalescer
+    /// held back. The task is started when that happens for the first time
+    flusher: Arc<OnceLock<UnboundedSender<Duration>>>,
+}
+
+/// What to do with a value that was offered to a `Coalescer`
+#[derive(Debug, PartialEq)]
+enum Coalesce<T> {
+    /// Send the value right away
+    Send(T),
+    /// The value is held back; call `Coalescer::flush` after the given
+    /// time to get the latest value that was offered until then
+    FlushAfter(Duration),
+    /// The value replaced one that is already held back for a flush
+    Pending,
+}
+
+/// Coalesces values of which only the latest one matters so that at most
+/// one of them is sent per `interval`
+struct Coalescer<T> {
+    interval: Duration,
+    state: Mutex<CoalescerState<T>>,
+}
+
+struct CoalescerState<T> {
+    last_sent: Option<Instant>,
+    pending: Option<T>,
+    flush_scheduled: bool,
+}
+
+impl<T> Coalescer<T> {
+    fn new(interval: Duration) -> Self {
+        Coalescer {
+            interval,
+            state: Mutex::new(CoalescerState {
+                last_sent: None,
+                pending: None,
+                flush_scheduled: false,
+            }),
+        }
+    }
+
+    fn offer(&self, now: Instant, value: T) -> Coalesce<T> {
+        let mut state = self.state.lock().unwrap();
+        if state.flush_scheduled {
+            state.pending = Some(value);
+            return Coalesce::Pending;
+        }
+        let since_sent = state
+            .last_sent
+            .map(|last_sent| now.saturating_duration_since(last_sent));
+        match since_sent {
+            Some(since_sent) if since_sent < self.interval => {
+                state.pending = Some(value);
+                state.flush_scheduled = true;
+                Coalesce::FlushAfter(self.interval - since_sent)
+            }
+            _ => {
+                state.last_sent = Some(now);
+                Coalesce::Send(value)
+            }
+        }
+    }
+
+    /// Take the value that was held back for sending
+    fn flush(&self, now: Instant) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        state.flush_scheduled = false;
+        let value = state.pending.take();
+        if value.is_some() {
+            state.last_sent = Some(now);
+        }
+        value
+    }
 }
 
 impl ChainHeadUpdateListener {
@@ -110,6 +251,9 @@ impl ChainHeadUpdateListener {
             BTreeMap::new(),
             "chain_head_listener_watchers",
         ));
+        let lag_tracker = Arc::new(Mutex::new(HeadLagTracker::new(
+            ENV_VARS.store.chain_head_lag_alert_blocks,
+        )));
 
         Self::listen(
             logger,
@@ -117,11 +261,13 @@ impl ChainHeadUpdateListener {
             &mut listener,
             receiver,
             watchers.cheap_clone(),
+            lag_tracker.cheap_clone(),
             counter,
         );
 
         ChainHeadUpdateListener {
             watchers,
+            lag_tracker,
 
             // We keep the listener around to tie its stream's lifetime to
             // that of the chain head update listener and prevent it from
@@ -136,6 +282,7 @@ impl ChainHeadUpdateListener {
         listener: &mut NotificationListener,
         mut receiver: Receiver<JsonNotification>,
         watchers: Arc<TimedRwLock<BTreeMap<String, Watcher>>>,
+        lag_tracker: Arc<Mutex<HeadLagTracker>>,
         counter: CounterVec,
     ) {
         // Process chain head updates in a dedicated task
@@ -164,6 +311,27 @@ impl ChainHeadUpdateListener {
                 metrics
                     .set_chain_head_number(&update.network_name, update.head_block_number as i64);
 
+                let (lag, threshold) = {
+                    let lag_tracker = lag_tracker.lock().unwrap();
+                    let lag = lag_tracker
+                        .observe(&update.network_name, update.head_block_number)
+                        .map(|lag| (lag, lag_tracker.exceeds_threshold(lag)));
+                    (lag, lag_tracker.threshold)
+                };
+                if let Some((lag, exceeds_threshold)) = lag {
+                    metrics.set_chain_head_lag(&update.network_name, lag);
+                    if exceeds_threshold {
+                        warn!(
+                            logger,
+                            "chain head processing is lagging behind the chain";
+                            "network" => &update.network_name,
+                            "head_block_number" => update.head_block_number,
+                            "lag_blocks" => lag,
+                            "threshold" => threshold
+                        );
+                    }
+                }
+
                 // If there are subscriptions for this network, notify them.
                 // To be extra paranoid to not block this task, use `try_read`.
                 if let Some(watcher) = watchers
@@ -172,7 +340,7 @@ impl ChainHeadUpdateListener {
                     .map(|w| w.get(&update.network_name))
                     .flatten()
                 {
-                    watcher.send();
+                    watcher.send(update.head_block_number);
                 }
             }
         });
@@ -202,33 +370,39 @@ impl ChainHeadUpdateListenerTrait for ChainHeadUpdateListener {
                 // inserted a writer, so we should check the entry again after acquiring the lock.
                 self.watchers
                     .write(&logger)
-                    .entry(network_name)
+                    .entry(network_name.clone())
                     .or_insert_with(Watcher::new)
                     .receiver
                     .clone()
             }
         };
 
+        let lag_tracker = self.lag_tracker.cheap_clone();
         Box::new(futures03::stream::unfold(
             update_receiver,
             move |mut update_receiver| {
                 let logger = logger.clone();
+                let lag_tracker = lag_tracker.cheap_clone();
+                let network_name = network_name.clone();
                 async move {
                     // To be robust against any problems with the listener for the DB channel, a
                     // timeout is set so that subscribers are guaranteed to get periodic updates.
-                    match tokio::time::timeout(
+                    match clock::timeout(
                         ENV_VARS.store.chain_head_watcher_timeout,
                         update_receiver.changed(),
                     )
                     .await
                     {
                         // Received an update.
-                        Ok(Ok(())) => (),
+                        Some(Ok(())) => {
+                            let head = *update_receiver.borrow_and_update();
+                            lag_tracker.lock().unwrap().processed(&network_name, head);
+                        }
 
                         // The sender was dropped, this should never happen.
-                        Ok(Err(_)) => crit!(logger, "chain head watcher terminated"),
+                        Some(Err(_)) => crit!(logger, "chain head watcher terminated"),
 
-                        Err(_) => debug!(
+                        None => debug!(
                             logger,
                             "no chain head update for {} seconds, polling for update",
                             ENV_VARS.store.chain_head_watcher_timeout.as_secs()
@@ -244,18 +418,75 @@ impl ChainHeadUpdateListenerTrait for ChainHeadUpdateListener {
 
 impl ChainHeadUpdateSender {
     pub fn new(
+        logger: Logger,
         pool: ConnectionPool,
         network_name: String,
         sender: Arc<NotificationSender>,
     ) -> Self {
         Self {
+            logger,
             pool,
             chain_name: network_name,
             sender,
+            coalescer: Arc::new(Coalescer::new(ENV_VARS.store.notification_batch_interval)),
+            flusher: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Send an update for the chain head. Updates that come in quick
+    /// succession are coalesced according to
+    /// `GRAPH_STORE_NOTIFICATION_BATCH_INTERVAL`, and only the latest of
+    /// them is sent
     pub fn send(&self, hash: &str, number: i64) -> Result<(), StoreError> {
+        match self
+            .coalescer
+            .offer(Instant::now(), (hash.to_string(), number))
+        {
+            Coalesce::Send((hash, number)) => self.notify(&hash, number),
+            Coalesce::Pending => Ok(()),
+            Coalesce::FlushAfter(delay) => {
+                let flusher = self.flusher.get_or_init(|| self.spawn_flusher());
+                // This only fails if the task is gone, which can not happen
+                // while we are around
+                flusher.send(delay).ok();
+                Ok(())
+            }
+        }
+    }
+
+    /// Start the task that sends the update that the coalescer held back
+    /// after each delay that it receives
+    fn spawn_flusher(&self) -> UnboundedSender<Duration> {
+        let (flusher, mut delays) = mpsc::unbounded_channel::<Duration>();
+        // The task gets its own `flusher` so that it does not keep the
+        // channel open; it ends once all senders have been dropped
+        let this = Self {
+            flusher: Arc::new(OnceLock::new()),
+            ..self.clone()
+        };
+        graph::spawn(async move {
+            while let Some(delay) = delays.recv().await {
+                sleep(delay).await;
+                let Some((hash, number)) = this.coalescer.flush(Instant::now()) else {
+                    continue;
+                };
+                let sender = this.clone();
+                let res =
+                    graph::spawn_blocking_allow_panic(move || sender.notify(&hash, number)).await;
+                let err = match res {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(e)) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                warn!(this.logger, "Failed to send chain head update";
+                      "block_number" => number,
+                      "error" => err);
+            }
+        });
+        flusher
+    }
+
+    fn notify(&self, hash: &str, number: i64) -> Result<(), StoreError> {
         let msg = json! ({
             "network_name": &self.chain_name,
             "head_block_hash": hash,
@@ -271,3 +502,80 @@ impl ChainHeadUpdateSender {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lag_beyond_threshold() {
+        let mut tracker = HeadLagTracker::new(10);
+
+        // Before any chain head was processed, there is nothing to compare to
+        assert_eq!(None, tracker.observe("mainnet", 100));
+
+        tracker.processed("mainnet", 100);
+        let lag = tracker.observe("mainnet", 105).unwrap();
+        assert_eq!(5, lag);
+        assert!(!tracker.exceeds_threshold(lag));
+
+        // Networks are tracked separately
+        assert_eq!(None, tracker.observe("sepolia", 500));
+
+        // The lag grows while subscribers do not process new chain heads
+        let lag = tracker.observe("mainnet", 120).unwrap();
+        assert_eq!(20, lag);
+        assert!(tracker.exceeds_threshold(lag));
+
+        // Once they catch up, the lag goes away
+        tracker.processed("mainnet", 120);
+        assert_eq!(Some(1), tracker.observe("mainnet", 121));
+
+        // Processing an older chain head does not move the processed head
+        // backwards
+        tracker.processed("mainnet", 110);
+        assert_eq!(Some(1), tracker.observe("mainnet", 121));
+    }
+
+    #[test]
+    fn coalesce_updates() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let coalescer = Coalescer::new(Duration::from_millis(100));
+
+        assert_eq!(Coalesce::Send(1), coalescer.offer(at(0), 1));
+        // Updates within the interval are held back and only the latest
+        // one is sent when the flush happens
+        assert_eq!(
+            Coalesce::FlushAfter(Duration::from_millis(90)),
+            coalescer.offer(at(10), 2)
+        );
+        assert_eq!(Coalesce::Pending, coalescer.offer(at(20), 3));
+        assert_eq!(Coalesce::Pending, coalescer.offer(at(50), 4));
+        assert_eq!(Some(4), coalescer.flush(at(100)));
+        assert_eq!(None, coalescer.flush(at(100)));
+
+        // The flush counts as sending
+        assert_eq!(
+            Coalesce::FlushAfter(Duration::from_millis(50)),
+            coalescer.offer(at(150), 5)
+        );
+        assert_eq!(Some(5), coalescer.flush(at(200)));
+        assert_eq!(Coalesce::Send(6), coalescer.offer(at(300), 6));
+
+        // Without an interval, every update is sent
+        let coalescer = Coalescer::new(Duration::ZERO);
+        assert_eq!(Coalesce::Send(1), coalescer.offer(at(0), 1));
+        assert_eq!(Coalesce::Send(2), coalescer.offer(at(0), 2));
+    }
+
+    #[test]
+    fn lag_tracking_disabled() {
+        let mut tracker = HeadLagTracker::new(0);
+
+        tracker.processed("mainnet", 10);
+        assert_eq!(None, tracker.observe("mainnet", 100));
+        assert_eq!(None, tracker.observe("mainnet", 1000));
+        assert!(!tracker.exceeds_threshold(900));
+    }
+}
@@ -12,6 +12,7 @@ use graph::prelude::MetricsRegistry;
 use graph::prometheus::{CounterVec, GaugeVec};
 use graph::slog::Logger;
 use graph::stable_hash::crypto_stable_hash;
+use graph::util::cache_weight::CacheWeight;
 use graph::util::herd_cache::HerdCache;
 
 use std::collections::BTreeMap;
@@ -64,6 +65,12 @@ impl JsonBlock {
     }
 }
 
+impl CacheWeight for JsonBlock {
+    fn indirect_weight(&self) -> usize {
+        self.ptr.hash.0.len() + self.parent_hash.0.len() + self.data.indirect_weight()
+    }
+}
+
 /// Tables in the 'public' database schema that store chain-specific data
 mod public {
     table! {
@@ -686,6 +693,28 @@ mod data {
                 .collect())
         }
 
+        /// Load up to `depth` ancestors of `blocks` by following their
+        /// parent hashes
+        pub(super) fn ancestors(
+            &self,
+            conn: &mut PgConnection,
+            chain: &str,
+            blocks: &[JsonBlock],
+            depth: usize,
+        ) -> Result<Vec<JsonBlock>, StoreError> {
+            let mut ancestors = Vec::new();
+            let mut parents: Vec<_> = blocks.iter().map(|b| b.parent_hash.clone()).collect();
+            for _ in 0..depth {
+                if parents.is_empty() {
+                    break;
+                }
+                let found = self.blocks(conn, chain, &parents)?;
+                parents = found.iter().map(|b| b.parent_hash.clone()).collect();
+                ancestors.extend(found);
+            }
+            Ok(ancestors)
+        }
+
         pub(super) fn block_hashes_by_block_number(
             &self,
             conn: &mut PgConnection,
@@ -1736,10 +1765,15 @@ impl ChainStore {
         chain_head_update_sender: ChainHeadUpdateSender,
         pool: ConnectionPool,
         recent_blocks_cache_capacity: usize,
+        recent_blocks_cache_bytes: usize,
         metrics: Arc<ChainStoreMetrics>,
     ) -> Self {
-        let recent_blocks_cache =
-            RecentBlocksCache::new(recent_blocks_cache_capacity, chain.clone(), metrics);
+        let recent_blocks_cache = RecentBlocksCache::new(
+            recent_blocks_cache_capacity,
+            recent_blocks_cache_bytes,
+            chain.clone(),
+            metrics,
+        );
         let lookup_herd = HerdCache::new(format!("chain_{}_herd_cache", chain));
         ChainStore {
             logger,
@@ -1886,6 +1920,17 @@ impl ChainStore {
         self.recent_blocks_cache.blocks()
     }
 
+    /// The blocks that are currently in the recent blocks cache
+    #[cfg(debug_assertions)]
+    pub fn recent_blocks(&self) -> Vec<(BlockPtr, BlockHash)> {
+        self.recent_blocks_cache.blocks()
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn clear_recent_blocks(&self) {
+        self.recent_blocks_cache.clear();
+    }
+
     pub fn delete_blocks(&self, block_hashes: &[&H256]) -> Result<usize, Error> {
         let mut conn = self.get_conn()?;
         self.storage
@@ -1911,23 +1956,112 @@ impl ChainStore {
         Ok(())
     }
 
+    /// Load the blocks with the given `hashes` from the database. Up to
+    /// `prefetch_depth` ancestors of them are put into the recent blocks
+    /// cache, but not returned
     async fn blocks_from_store(
         self: &Arc<Self>,
         hashes: Vec<BlockHash>,
+        prefetch_depth: usize,
     ) -> Result<Vec<JsonBlock>, StoreError> {
         let store = self.cheap_clone();
         let pool = self.pool.clone();
-        let values = pool
+        let (values, ancestors) = pool
             .with_conn(move |conn, _| {
-                store
-                    .storage
-                    .blocks(conn, &store.chain, &hashes)
-                    .map_err(CancelableError::from)
+                let values = store.storage.blocks(conn, &store.chain, &hashes)?;
+                let ancestors =
+                    store
+                        .storage
+                        .ancestors(conn, &store.chain, &values, prefetch_depth)?;
+                Ok((values, ancestors))
             })
             .await?;
+        for block in ancestors {
+            self.recent_blocks_cache.insert_block(block);
+        }
         Ok(values)
     }
 
+    /// Look up the blocks with the given `hashes`. When some of them are
+    /// not in the recent blocks cache, also load up to `prefetch_depth`
+    /// ancestors of the blocks we get from the database into the cache
+    pub async fn blocks_with_prefetch(
+        self: Arc<Self>,
+        hashes: Vec<BlockHash>,
+        prefetch_depth: usize,
+    ) -> Result<Vec<json::Value>, Error> {
+        if ENV_VARS.store.disable_block_cache_for_lookup {
+            let values = self
+                .blocks_from_store(hashes, 0)
+                .await?
+                .into_iter()
+                .filter_map(|block| block.data)
+                .collect();
+            Ok(values)
+        } else {
+            let cached = self.recent_blocks_cache.get_blocks_by_hash(&hashes);
+            let stored = if cached.len() < hashes.len() {
+                let hashes = hashes
+                    .iter()
+                    .filter(|hash| cached.iter().find(|(ptr, _)| &ptr.hash == *hash).is_none())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                // We key this off the entire list of hashes, which means
+                // that concurrent attempts that look up `[h1, h2]` and
+                // `[h1, h3]` will still run two queries and duplicate the
+                // lookup of `h1`. Noticing that the two requests should be
+                // serialized would require a lot more work, and going to
+                // the database for one block hash, `h3`, is not much faster
+                // than looking up `[h1, h3]` though it would require less
+                // IO bandwidth
+                let hash = crypto_stable_hash(&hashes);
+                let this = self.clone();
+                let lookup_fut = async move {
+                    let res = this.blocks_from_store(hashes, prefetch_depth).await;
+                    BlocksLookupResult::ByHash(Arc::new(res))
+                };
+                let lookup_herd = self.lookup_herd.cheap_clone();
+                let logger = self.logger.cheap_clone();
+                // This match can only return ByHash because lookup_fut explicitly constructs
+                // BlocksLookupResult::ByHash. The cache preserves the exact future result,
+                // so ByNumber variant is structurally impossible here.
+                let res = match lookup_herd.cached_query(hash, lookup_fut, &logger).await {
+                    (BlocksLookupResult::ByHash(res), _) => res,
+                    (BlocksLookupResult::ByNumber(_), _) => {
+                        Arc::new(Err(StoreError::Unknown(anyhow::anyhow!(
+                            "Unexpected BlocksLookupResult::ByNumber returned from cached block lookup by hash"
+                        ))))
+                    }
+                };
+
+                // Try to avoid cloning a non-concurrent lookup; it's not
+                // entirely clear whether that will actually avoid a clone
+                // since it depends on a lot of the details of how the
+                // `HerdCache` is implemented
+                let res = Arc::try_unwrap(res).unwrap_or_else(|arc| (*arc).clone());
+                let stored = match res {
+                    Ok(blocks) => {
+                        for block in &blocks {
+                            self.recent_blocks_cache.insert_block(block.clone());
+                        }
+                        blocks
+                    }
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                };
+                stored
+            } else {
+                Vec::new()
+            };
+
+            let mut result = cached.into_iter().map(|(_, data)| data).collect::<Vec<_>>();
+            let stored = stored.into_iter().filter_map(|block| block.data);
+            result.extend(stored);
+            Ok(result)
+        }
+    }
+
     async fn blocks_from_store_by_numbers(
         self: &Arc<Self>,
         numbers: Vec<BlockNumber>,
@@ -2246,76 +2380,8 @@ impl ChainStoreTrait for ChainStore {
     }
 
     async fn blocks(self: Arc<Self>, hashes: Vec<BlockHash>) -> Result<Vec<json::Value>, Error> {
-        if ENV_VARS.store.disable_block_cache_for_lookup {
-            let values = self
-                .blocks_from_store(hashes)
-                .await?
-                .into_iter()
-                .filter_map(|block| block.data)
-                .collect();
-            Ok(values)
-        } else {
-            let cached = self.recent_blocks_cache.get_blocks_by_hash(&hashes);
-            let stored = if cached.len() < hashes.len() {
-                let hashes = hashes
-                    .iter()
-                    .filter(|hash| cached.iter().find(|(ptr, _)| &ptr.hash == *hash).is_none())
-                    .cloned()
-                    .collect::<Vec<_>>();
-                // We key this off the entire list of hashes, which means
-                // that concurrent attempts that look up `[h1, h2]` and
-                // `[h1, h3]` will still run two queries and duplicate the
-                // lookup of `h1`. Noticing that the two requests should be
-                // serialized would require a lot more work, and going to
-                // the database for one block hash, `h3`, is not much faster
-                // than looking up `[h1, h3]` though it would require less
-                // IO bandwidth
-                let hash = crypto_stable_hash(&hashes);
-                let this = self.clone();
-                let lookup_fut = async move {
-                    let res = this.blocks_from_store(hashes).await;
-                    BlocksLookupResult::ByHash(Arc::new(res))
-                };
-                let lookup_herd = self.lookup_herd.cheap_clone();
-                let logger = self.logger.cheap_clone();
-                // This match can only return ByHash because lookup_fut explicitly constructs
-                // BlocksLookupResult::ByHash. The cache preserves the exact future result,
-                // so ByNumber variant is structurally impossible here.
-                let res = match lookup_herd.cached_query(hash, lookup_fut, &logger).await {
-                    (BlocksLookupResult::ByHash(res), _) => res,
-                    (BlocksLookupResult::ByNumber(_), _) => {
-                        Arc::new(Err(StoreError::Unknown(anyhow::anyhow!(
-                            "Unexpected BlocksLookupResult::ByNumber returned from cached block lookup by hash"
-                        ))))
-                    }
-                };
-
-                // Try to avoid cloning a non-concurrent lookup; it's not
-                // entirely clear whether that will actually avoid a clone
-                // since it depends on a lot of the details of how the
-                // `HerdCache` is implemented
-                let res = Arc::try_unwrap(res).unwrap_or_else(|arc| (*arc).clone());
-                let stored = match res {
-                    Ok(blocks) => {
-                        for block in &blocks {
-                            self.recent_blocks_cache.insert_block(block.clone());
-                        }
-                        blocks
-                    }
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                };
-                stored
-            } else {
-                Vec::new()
-            };
-
-            let mut result = cached.into_iter().map(|(_, data)| data).collect::<Vec<_>>();
-            let stored = stored.into_iter().filter_map(|block| block.data);
-            result.extend(stored);
-            Ok(result)
-        }
+        self.blocks_with_prefetch(hashes, ENV_VARS.store.recent_blocks_prefetch_depth)
+            .await
     }
 
     async fn ancestor_block(
@@ -2541,6 +2607,11 @@ mod recent_blocks_cache {
         blocks: BTreeMap<BlockNumber, JsonBlock>,
         // We only store these many blocks.
         capacity: usize,
+        // If bigger than 0, we bound the cache by the estimated size of
+        // the blocks in it instead of by `capacity`
+        max_bytes: usize,
+        // The estimated size of all blocks in `blocks`
+        bytes: usize,
     }
 
     impl Inner {
@@ -2585,8 +2656,15 @@ mod recent_blocks_cache {
         }
 
         fn evict_if_necessary(&mut self) {
-            while self.blocks.len() > self.capacity {
-                self.blocks.pop_first();
+            // Evict the oldest blocks first. When bounding by size, we
+            // always keep the most recent block, even if it is bigger than
+            // `max_bytes` by itself
+            while (self.max_bytes == 0 && self.blocks.len() > self.capacity)
+                || (self.max_bytes > 0 && self.bytes > self.max_bytes && self.blocks.len() > 1)
+            {
+                if let Some((_, block)) = self.blocks.pop_first() {
+                    self.bytes -= block.weight();
+                }
             }
         }
 
@@ -2611,7 +2689,10 @@ mod recent_blocks_cache {
         }
 
         fn insert_block(&mut self, block: JsonBlock) {
-            self.blocks.insert(block.ptr.number, block);
+            self.bytes += block.weight();
+            if let Some(old) = self.blocks.insert(block.ptr.number, block) {
+                self.bytes -= old.weight();
+            }
             self.evict_if_necessary();
         }
     }
@@ -2625,19 +2706,29 @@ mod recent_blocks_cache {
     }
 
     impl RecentBlocksCache {
-        pub fn new(capacity: usize, network: String, metrics: Arc<ChainStoreMetrics>) -> Self {
+        pub fn new(
+            capacity: usize,
+            max_bytes: usize,
+            network: String,
+            metrics: Arc<ChainStoreMetrics>,
+        ) -> Self {
             RecentBlocksCache {
                 inner: RwLock::new(Inner {
                     network,
                     metrics,
                     blocks: BTreeMap::new(),
                     capacity,
+                    max_bytes,
+                    bytes: 0,
                 }),
             }
         }
 
         pub fn clear(&self) {
-            self.inner.write().blocks.clear();
+            let mut inner = self.inner.write();
+            inner.blocks.clear();
+            inner.bytes = 0;
+            drop(inner);
             self.inner.read().update_write_metrics();
         }
 
@@ -2718,6 +2809,64 @@ mod recent_blocks_cache {
                 .collect()
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn block(number: BlockNumber, data_len: usize) -> JsonBlock {
+            let ptr = BlockPtr::from((vec![number as u8; 32], number));
+            let parent_hash = BlockHash::from(vec![(number - 1) as u8; 32]);
+            let data = json::Value::String("x".repeat(data_len));
+            JsonBlock::new(ptr, parent_hash, Some(data))
+        }
+
+        fn cache(capacity: usize, max_bytes: usize) -> RecentBlocksCache {
+            let metrics = Arc::new(ChainStoreMetrics::new(Arc::new(MetricsRegistry::mock())));
+            RecentBlocksCache::new(capacity, max_bytes, "test".to_string(), metrics)
+        }
+
+        fn numbers(cache: &RecentBlocksCache) -> Vec<BlockNumber> {
+            cache
+                .blocks()
+                .into_iter()
+                .map(|(ptr, _)| ptr.number)
+                .collect()
+        }
+
+        #[test]
+        fn bounded_by_count() {
+            let cache = cache(3, 0);
+            for number in 1..6 {
+                cache.insert_block(block(number, 10_000));
+            }
+            // The oldest blocks get evicted first, regardless of their size
+            assert_eq!(vec![3, 4, 5], numbers(&cache));
+        }
+
+        #[test]
+        fn bounded_by_bytes() {
+            let size = block(1, 1_000).weight();
+            // Room for two and a half blocks; the capacity is ignored
+            let cache = cache(1, 2 * size + size / 2);
+            for number in 1..6 {
+                cache.insert_block(block(number, 1_000));
+            }
+            assert_eq!(vec![4, 5], numbers(&cache));
+
+            // Replacing a block does not count it twice
+            cache.insert_block(block(5, 1_000));
+            assert_eq!(vec![4, 5], numbers(&cache));
+
+            // A big block evicts as many of the oldest blocks as needed
+            cache.insert_block(block(6, 2_000));
+            assert_eq!(vec![6], numbers(&cache));
+
+            // The most recent block is kept even if it is too big by itself
+            cache.insert_block(block(7, 10 * size));
+            assert_eq!(vec![7], numbers(&cache));
+        }
+    }
 }
 
 fn try_parse_timestamp(ts: Option<String>) -> Result<Option<u64>, StoreError> {
@@ -0,0 +1,155 @@
+//! A clock for timeouts that tests can control. When
+//! `GRAPH_STORE_DETERMINISTIC_TIMEOUTS` is set in a debug build, the
+//! timeouts for waiting for chain head updates and for checking out
+//! connections only expire when a test advances `MOCK_CLOCK`, and not
+//! because real time passed
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use graph::prelude::{lazy_static, tokio, ENV_VARS};
+use tokio::sync::watch;
+
+lazy_static! {
+    pub static ref MOCK_CLOCK: Option<Arc<MockClock>> = (cfg!(debug_assertions)
+        && ENV_VARS.store.deterministic_timeouts)
+        .then(|| Arc::new(MockClock::new()));
+}
+
+/// A clock that only moves when `advance` is called
+pub struct MockClock {
+    now: Mutex<Duration>,
+    changed: Condvar,
+    advanced: watch::Sender<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        let (advanced, _) = watch::channel(Duration::ZERO);
+        MockClock {
+            now: Mutex::new(Duration::ZERO),
+            changed: Condvar::new(),
+            advanced,
+        }
+    }
+
+    /// How far the clock has been advanced since it was created
+    pub fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+        self.advanced.send_replace(*now);
+        self.changed.notify_all();
+    }
+
+    /// Block the current thread until the clock has been advanced by
+    /// `duration`
+    pub fn wait(&self, duration: Duration) {
+        self.wait_until(self.now() + duration)
+    }
+
+    /// Block the current thread until the clock reaches `deadline`
+    pub fn wait_until(&self, deadline: Duration) {
+        let mut now = self.now.lock().unwrap();
+        while *now < deadline {
+            now = self.changed.wait(now).unwrap();
+        }
+    }
+
+    /// Wait until the clock has been advanced by `duration`
+    pub async fn sleep(&self, duration: Duration) {
+        let mut advanced = self.advanced.subscribe();
+        let deadline = *advanced.borrow_and_update() + duration;
+        while *advanced.borrow_and_update() < deadline {
+            if advanced.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Like `tokio::time::timeout`, but uses `MOCK_CLOCK` if it is set.
+/// Returns `None` if `future` did not finish within `duration`
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    timeout_with(MOCK_CLOCK.as_deref(), duration, future).await
+}
+
+async fn timeout_with<F: Future>(
+    clock: Option<&MockClock>,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    match clock {
+        Some(clock) => {
+            tokio::select! {
+                output = future => Some(output),
+                _ = clock.sleep(duration) => None,
+            }
+        }
+        None => tokio::time::timeout(duration, future).await.ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn mock_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let clock = Arc::new(MockClock::new());
+            let waiting = tokio::spawn({
+                let clock = clock.clone();
+                async move { timeout_with(Some(&clock), TIMEOUT, std::future::pending::<()>()).await }
+            });
+
+            // The timeout does not expire until the clock has advanced far
+            // enough, no matter how much real time passes
+            tokio::task::yield_now().await;
+            clock.advance(TIMEOUT / 2);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert!(!waiting.is_finished());
+
+            clock.advance(TIMEOUT / 2);
+            assert_eq!(None, waiting.await.unwrap());
+
+            // A future that finishes is not affected by the clock
+            let output = timeout_with(Some(&clock), TIMEOUT, async { 7 }).await;
+            assert_eq!(Some(7), output);
+        });
+    }
+
+    #[test]
+    fn mock_wait() {
+        let clock = Arc::new(MockClock::new());
+        let waiting = thread::spawn({
+            let clock = clock.clone();
+            move || clock.wait_until(TIMEOUT)
+        });
+
+        clock.advance(TIMEOUT / 2);
+        thread::sleep(Duration::from_millis(10));
+        assert!(!waiting.is_finished());
+
+        clock.advance(TIMEOUT / 2);
+        waiting.join().unwrap();
+        assert_eq!(TIMEOUT, clock.now());
+    }
+}
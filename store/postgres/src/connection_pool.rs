@@ -10,6 +10,7 @@ use diesel_migrations::{EmbeddedMigrations, HarnessWithOutput};
 use graph::cheap_clone::CheapClone;
 use graph::components::store::QueryPermit;
 use graph::constraint_violation;
+use graph::env::ConnectionAcquirePolicy;
 use graph::prelude::tokio::time::Instant;
 use graph::prelude::{tokio, MetricsRegistry};
 use graph::slog::warn;
@@ -27,12 +28,16 @@ use graph::{
 
 use std::fmt::{self, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::RwLock,
+};
 
 use postgres::config::{Config, Host};
 
+use crate::clock::MOCK_CLOCK;
 use crate::primary::{self, NAMESPACE_PUBLIC};
 use crate::{advisory_lock, catalog};
 use crate::{Shard, PRIMARY_SHARD};
@@ -58,6 +63,21 @@ const SHARDED_TABLES: [(&str, &[&str]); 2] = [
     ),
 ];
 
+/// The `fetch_size` entry for the `options (..)` list of a foreign server,
+/// including a leading comma. `has_option` is `None` when the server is
+/// being created, and otherwise says whether the server already has a
+/// `fetch_size` option. A `fetch_size` of 0 means that the option is not
+/// set at all, leaving the fetch size up to `postgres_fdw`
+fn fetch_size_option(fetch_size: usize, has_option: Option<bool>) -> String {
+    match (fetch_size, has_option) {
+        (0, Some(true)) => ", drop fetch_size".to_string(),
+        (0, _) => String::new(),
+        (_, None) => format!(", fetch_size '{fetch_size}'"),
+        (_, Some(true)) => format!(", set fetch_size '{fetch_size}'"),
+        (_, Some(false)) => format!(", add fetch_size '{fetch_size}'"),
+    }
+}
+
 pub struct ForeignServer {
     pub name: String,
     pub shard: Shard,
@@ -156,8 +176,7 @@ impl ForeignServer {
                foreign data wrapper postgres_fdw
                options (host '{remote_host}', \
                         port '{remote_port}', \
-                        dbname '{remote_db}', \
-                        fetch_size '{fetch_size}', \
+                        dbname '{remote_db}'{fetch_size}, \
                         updatable 'false');
         create user mapping
                for current_user server \"{name}\"
@@ -168,7 +187,7 @@ impl ForeignServer {
             remote_db = self.dbname,
             remote_user = self.user,
             remote_password = self.password,
-            fetch_size = ENV_VARS.store.fdw_fetch_size,
+            fetch_size = fetch_size_option(ENV_VARS.store.fdw_fetch_size, None),
         );
         Ok(conn.batch_execute(&query)?)
     }
@@ -189,20 +208,21 @@ impl ForeignServer {
         alter server \"{name}\"
               options (set host '{remote_host}', \
                        {set_port} port '{remote_port}', \
-                       set dbname '{remote_db}', \
-                       {set_fetch_size} fetch_size '{fetch_size}');
+                       set dbname '{remote_db}'{fetch_size});
         alter user mapping
               for current_user server \"{name}\"
               options (set user '{remote_user}', set password '{remote_password}');",
             name = self.name,
             remote_host = self.host,
             set_port = set_or_add("port"),
-            set_fetch_size = set_or_add("fetch_size"),
             remote_port = self.port,
             remote_db = self.dbname,
             remote_user = self.user,
             remote_password = self.password,
-            fetch_size = ENV_VARS.store.fdw_fetch_size,
+            fetch_size = fetch_size_option(
+                ENV_VARS.store.fdw_fetch_size,
+                Some(options.contains_key("fetch_size")),
+            ),
         );
         Ok(conn.batch_execute(&query)?)
     }
@@ -566,6 +586,21 @@ impl ConnectionPool {
         })
     }
 
+    pub(crate) async fn status_permit(&self) -> Result<QueryPermit, StoreError> {
+        let pool = match &*self.inner.lock(&self.logger) {
+            PoolState::Created(pool, _) | PoolState::Ready(pool) => pool.clone(),
+            PoolState::Disabled => {
+                return Err(StoreError::DatabaseDisabled);
+            }
+        };
+        let start = Instant::now();
+        let permit = pool.status_permit().await;
+        Ok(QueryPermit {
+            permit,
+            wait: start.elapsed(),
+        })
+    }
+
     pub(crate) fn wait_stats(&self) -> Result<PoolWaitStats, StoreError> {
         match &*self.inner.lock(&self.logger) {
             PoolState::Created(pool, _) | PoolState::Ready(pool) => Ok(pool.wait_stats.clone()),
@@ -573,6 +608,16 @@ impl ConnectionPool {
         }
     }
 
+    /// Keep the idle timeout from closing the pool's `min_idle`
+    /// connections; see `PoolInner::keep_min_idle`
+    pub(crate) fn keep_min_idle(&self) {
+        let pool = match &*self.inner.lock(&self.logger) {
+            PoolState::Created(pool, _) | PoolState::Ready(pool) => pool.clone(),
+            PoolState::Disabled => return,
+        };
+        pool.keep_min_idle();
+    }
+
     /// Mirror key tables from the primary into our own schema. We do this
     /// by manually inserting or deleting rows through comparing it with the
     /// table on the primary. Once we drop support for PG 9.6, we can
@@ -658,6 +703,7 @@ struct EventHandler {
     size_gauge: Gauge,
     wait_stats: PoolWaitStats,
     state_tracker: PoolStateTracker,
+    acquire_queue: Option<Arc<AcquireQueue>>,
 }
 
 impl EventHandler {
@@ -667,6 +713,7 @@ impl EventHandler {
         wait_stats: PoolWaitStats,
         const_labels: HashMap<String, String>,
         state_tracker: PoolStateTracker,
+        acquire_queue: Option<Arc<AcquireQueue>>,
     ) -> Self {
         let count_gauge = registry
             .global_gauge(
@@ -696,6 +743,15 @@ impl EventHandler {
             wait_stats,
             size_gauge,
             state_tracker,
+            acquire_queue,
+        }
+    }
+
+    /// Tell waiters in the `acquire_queue` that there might be an idle
+    /// connection now
+    fn notify_available(&self) {
+        if let Some(queue) = &self.acquire_queue {
+            queue.available();
         }
     }
 
@@ -717,6 +773,7 @@ impl HandleEvent for EventHandler {
     fn handle_acquire(&self, _: e::AcquireEvent) {
         self.size_gauge.inc();
         self.state_tracker.mark_available();
+        self.notify_available();
     }
 
     fn handle_release(&self, _: e::ReleaseEvent) {
@@ -741,6 +798,184 @@ impl HandleEvent for EventHandler {
 
     fn handle_checkin(&self, _: e::CheckinEvent) {
         self.count_gauge.dec();
+        self.notify_available();
+    }
+}
+
+/// Limits how fast a pool opens new connections by handing out slots for
+/// opening a connection that are at least `1/rate` seconds apart
+struct ConnectionRamp {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl ConnectionRamp {
+    fn new(rate: f64) -> Self {
+        ConnectionRamp {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Reserve the next slot for opening a connection and return how long
+    /// after `now` the caller has to wait until that slot comes up
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut next = self.next.lock().unwrap();
+        let slot = next.map(|next| next.max(now)).unwrap_or(now);
+        *next = Some(slot + self.interval);
+        slot - now
+    }
+}
+
+/// How often `keep_min_idle` has to run so that the connections it keeps
+/// are never idle for `idle_timeout`
+pub(crate) fn keep_min_idle_interval(idle_timeout: Duration) -> Duration {
+    (idle_timeout / 2).max(Duration::from_secs(1))
+}
+
+/// The number of the `idle` connections of a pool that `keep_min_idle`
+/// checks out so that the idle timeout does not close them
+fn connections_to_keep(idle: u32, min_idle: u32) -> u32 {
+    idle.min(min_idle)
+}
+
+/// Create the semaphores for user queries and for status queries for a pool
+/// with `pool_size` connections. Up to `reserved` connections are set
+/// aside for status queries, but user queries can always use at least one
+/// connection. Without reserved connections, status queries share the
+/// semaphore for user queries. With reserved connections, `extra_permits`
+/// is ignored since permits beyond `pool_size - reserved` would let user
+/// queries wait for, and take, the connections set aside for status
+/// queries
+fn query_semaphores(
+    pool_size: usize,
+    extra_permits: usize,
+    reserved: usize,
+) -> (Arc<Semaphore>, Option<Arc<Semaphore>>) {
+    let reserved = reserved.min(pool_size.saturating_sub(1));
+    let permits = if reserved > 0 {
+        pool_size - reserved
+    } else {
+        pool_size + extra_permits
+    };
+    let query = Arc::new(Semaphore::new(permits));
+    let status = (reserved > 0).then(|| Arc::new(Semaphore::new(reserved)));
+    (query, status)
+}
+
+/// Where an `AcquireQueue` gets connections from. This is the pool itself,
+/// except in tests
+trait ConnectionSource {
+    type Conn;
+
+    /// Take an idle connection without blocking; this never opens a new
+    /// connection
+    fn try_get(&self) -> Option<Self::Conn>;
+
+    /// Whether the pool has fewer connections than it may open
+    fn can_grow(&self) -> bool;
+
+    /// Get a connection, opening a new one if needed, and wait at most
+    /// `timeout` for it
+    fn get_timeout(&self, timeout: Duration) -> Option<Self::Conn>;
+}
+
+impl ConnectionSource for Pool<ConnectionManager<PgConnection>> {
+    type Conn = PooledConnection<ConnectionManager<PgConnection>>;
+
+    fn try_get(&self) -> Option<Self::Conn> {
+        Pool::try_get(self)
+    }
+
+    fn can_grow(&self) -> bool {
+        self.state().connections < self.max_size()
+    }
+
+    fn get_timeout(&self, timeout: Duration) -> Option<Self::Conn> {
+        Pool::get_timeout(self, timeout).ok()
+    }
+}
+
+/// Orders the threads that wait for a connection from a pool according to
+/// `GRAPH_STORE_CONNECTION_ACQUIRE_POLICY`. The waiter whose turn it is
+/// takes an idle connection whenever a connection is returned to the pool
+/// or opened. If there is no idle connection but the pool has not reached
+/// its maximum size yet, it has the pool open a new connection
+struct AcquireQueue {
+    policy: ConnectionAcquirePolicy,
+    state: Mutex<AcquireState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct AcquireState {
+    next_ticket: u64,
+    /// The tickets of the waiting threads in the order in which they
+    /// arrived
+    waiting: VecDeque<u64>,
+}
+
+/// How long a waiter sleeps at most before checking for an idle
+/// connection again, in case it missed a notification
+const ACQUIRE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl AcquireQueue {
+    fn new(policy: ConnectionAcquirePolicy) -> Self {
+        AcquireQueue {
+            policy,
+            state: Mutex::new(AcquireState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// The ticket of the waiter that should be served next
+    fn next(&self, waiting: &VecDeque<u64>) -> Option<u64> {
+        match self.policy {
+            ConnectionAcquirePolicy::Fifo => waiting.front().copied(),
+            ConnectionAcquirePolicy::Lifo => waiting.back().copied(),
+        }
+    }
+
+    /// Wake up waiters because a connection might have become available
+    fn available(&self) {
+        self.changed.notify_all();
+    }
+
+    /// Wait until it is our turn and get a connection from `pool`. Give up
+    /// and return `None` once `timeout` has passed
+    fn acquire<S: ConnectionSource>(&self, timeout: Duration, pool: &S) -> Option<S::Conn> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        let res = loop {
+            if self.next(&state.waiting) == Some(ticket) {
+                if let Some(conn) = pool.try_get() {
+                    break Some(conn);
+                }
+                // `try_get` never opens connections; let the pool open one
+                // without holding the lock so others can line up meanwhile
+                if pool.can_grow() {
+                    drop(state);
+                    let conn = pool.get_timeout(deadline.saturating_duration_since(Instant::now()));
+                    state = self.state.lock().unwrap();
+                    if conn.is_some() {
+                        break conn;
+                    }
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break None;
+            }
+            let wait = (deadline - now).min(ACQUIRE_POLL_INTERVAL);
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        };
+        state.waiting.retain(|waiting| *waiting != ticket);
+        // Let the next waiter have a go
+        self.changed.notify_all();
+        res
     }
 }
 
@@ -760,6 +995,10 @@ pub struct PoolInner {
     // explicitly close connections to foreign servers when a connection is
     // returned to the pool.
     fdw_pool: Option<Pool<ConnectionManager<PgConnection>>>,
+    // Connections above this number are only opened as fast as `ramp`
+    // allows
+    min_idle: u32,
+    ramp: Option<Arc<ConnectionRamp>>,
     limiter: Arc<Semaphore>,
     postgres_url: String,
     pub(crate) wait_stats: PoolWaitStats,
@@ -769,8 +1008,16 @@ pub struct PoolInner {
     // that waiting queries consume few resources. Still this is placed here because the semaphore
     // is sized acording to the DB connection pool size.
     query_semaphore: Arc<tokio::sync::Semaphore>,
+    // Limits the number of status queries, e.g., for health checks, when
+    // `GRAPH_STORE_HEALTH_RESERVED_CONNECTIONS` sets connections aside for
+    // them. Otherwise, they use `query_semaphore`
+    status_semaphore: Option<Arc<tokio::sync::Semaphore>>,
     semaphore_wait_stats: Arc<RwLock<MovingStats>>,
     semaphore_wait_gauge: Box<Gauge>,
+    // Decide which waiter gets the next connection from `pool` and
+    // `fdw_pool`; `None` if the pool decides that
+    acquire_queue: Option<Arc<AcquireQueue>>,
+    fdw_acquire_queue: Option<Arc<AcquireQueue>>,
 }
 
 impl PoolInner {
@@ -805,51 +1052,85 @@ impl PoolInner {
             state_tracker.clone(),
         ));
         let wait_stats = Arc::new(RwLock::new(MovingStats::default()));
+        // With `fifo`, we rely on the order in which the pool serves
+        // waiters and do not need to queue them ourselves
+        let new_acquire_queue = || match ENV_VARS.store.connection_acquire_policy {
+            ConnectionAcquirePolicy::Fifo => None,
+            policy => Some(Arc::new(AcquireQueue::new(policy))),
+        };
+        let acquire_queue = new_acquire_queue();
+        let fdw_acquire_queue = new_acquire_queue();
         let event_handler = Box::new(EventHandler::new(
             logger_pool.clone(),
             registry.cheap_clone(),
             wait_stats.clone(),
             const_labels.clone(),
             state_tracker,
+            acquire_queue.clone(),
         ));
 
         // Connect to Postgres
         let conn_manager = ConnectionManager::new(postgres_url.clone());
-        let min_idle = ENV_VARS.store.connection_min_idle.filter(|min_idle| {
-            if *min_idle <= pool_size {
-                true
-            } else {
-                warn!(
+        let pool_size = {
+            let max_size = ENV_VARS
+                .store
+                .pool_max_size(shard.as_str(), pool_name, pool_size);
+            if max_size != pool_size {
+                info!(
                     logger_pool,
-                    "Configuration error: min idle {} exceeds pool size {}, ignoring min idle",
-                    min_idle,
-                    pool_size
+                    "Overriding pool size {} with {} from GRAPH_STORE_POOL_MAX_SIZE_OVERRIDES",
+                    pool_size,
+                    max_size
                 );
-                false
             }
-        });
+            max_size
+        };
+        let min_idle = ENV_VARS.store.connection_min_idle_for(pool_size);
+        if let (Some(min_idle), None) = (ENV_VARS.store.connection_min_idle, min_idle) {
+            warn!(
+                logger_pool,
+                "Configuration error: min idle {} exceeds pool size {}, ignoring min idle",
+                min_idle,
+                pool_size
+            );
+        }
         let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
             .error_handler(error_handler.clone())
             .event_handler(event_handler.clone())
             .connection_timeout(ENV_VARS.store.connection_timeout)
             .max_size(pool_size)
             .min_idle(min_idle)
-            .idle_timeout(Some(ENV_VARS.store.connection_idle_timeout));
+            .idle_timeout(Some(ENV_VARS.store.connection_idle_timeout))
+            .max_lifetime(ENV_VARS.store.connection_max_lifetime);
         let pool = builder.build_unchecked(conn_manager);
         let fdw_pool = fdw_pool_size.map(|pool_size| {
             let conn_manager = ConnectionManager::new(postgres_url.clone());
+            let event_handler = Box::new(EventHandler {
+                acquire_queue: fdw_acquire_queue.clone(),
+                ..*event_handler
+            });
             let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
                 .error_handler(error_handler)
                 .event_handler(event_handler)
                 .connection_timeout(ENV_VARS.store.connection_timeout)
                 .max_size(pool_size)
                 .min_idle(Some(1))
-                .idle_timeout(Some(FDW_IDLE_TIMEOUT));
+                .idle_timeout(Some(FDW_IDLE_TIMEOUT))
+                .max_lifetime(ENV_VARS.store.connection_max_lifetime);
             builder.build_unchecked(conn_manager)
         });
+        let ramp = ENV_VARS
+            .store
+            .connection_ramp_rate
+            .map(|rate| Arc::new(ConnectionRamp::new(rate)));
 
         let max_concurrent_queries = pool_size as usize + ENV_VARS.store.extra_query_permits;
         let limiter = Arc::new(Semaphore::new(max_concurrent_queries));
+        let (query_semaphore, status_semaphore) = query_semaphores(
+            pool_size as usize,
+            ENV_VARS.store.extra_query_permits,
+            ENV_VARS.store.health_reserved_connections,
+        );
         info!(logger_store, "Pool successfully connected to Postgres");
 
         let semaphore_wait_gauge = registry
@@ -859,18 +1140,22 @@ impl PoolInner {
                 const_labels,
             )
             .expect("failed to create `query_effort_ms` counter");
-        let query_semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrent_queries));
         PoolInner {
             logger: logger_pool,
             shard,
             postgres_url,
             pool,
             fdw_pool,
+            min_idle: min_idle.unwrap_or(0),
+            ramp,
             limiter,
             wait_stats,
             semaphore_wait_stats: Arc::new(RwLock::new(MovingStats::default())),
             query_semaphore,
+            status_semaphore,
             semaphore_wait_gauge,
+            acquire_queue,
+            fdw_acquire_queue,
         }
     }
 
@@ -962,16 +1247,82 @@ impl PoolInner {
         }
     }
 
+    /// If getting a connection would make the pool open a new connection
+    /// beyond `min_idle`, wait until the connection ramp allows that
+    fn wait_for_ramp(&self) {
+        let Some(ramp) = &self.ramp else {
+            return;
+        };
+        let state = self.pool.state();
+        if state.idle_connections > 0
+            || state.connections < self.min_idle
+            || state.connections >= self.pool.max_size()
+        {
+            return;
+        }
+        let wait = ramp.reserve(Instant::now());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Check out up to `min_idle` idle connections and return them right
+    /// away so that the time they have been idle starts over. Since the
+    /// pool hands out the connection that was returned last, the idle
+    /// timeout only closes idle connections beyond the `min_idle` most
+    /// recently used ones, and never makes the pool drop below `min_idle`.
+    ///
+    /// This never waits for a connection: it only checks out connections
+    /// that are idle, and stops as soon as there are none. Checking out a
+    /// connection validates it with a query though, and this should
+    /// therefore not be called from an async task
+    fn keep_min_idle(&self) {
+        let idle = self.pool.state().idle_connections;
+        let conns: Vec<_> = (0..connections_to_keep(idle, self.min_idle))
+            .map_while(|_| self.pool.try_get())
+            .collect();
+        drop(conns);
+    }
+
+    /// Get a connection from `pool`, waiting in `queue` if there is one,
+    /// and give up after `GRAPH_STORE_CONNECTION_TIMEOUT`
+    fn get_timeout(
+        pool: &Pool<ConnectionManager<PgConnection>>,
+        queue: &Option<Arc<AcquireQueue>>,
+    ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, StoreError> {
+        let timeout = ENV_VARS.store.connection_timeout;
+        match queue {
+            None => pool.get_timeout(timeout).map_err(StoreError::from),
+            Some(queue) => queue
+                .acquire(timeout, pool)
+                .ok_or(StoreError::DatabaseUnavailable),
+        }
+    }
+
     pub fn get(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, StoreError> {
-        self.pool.get().map_err(|_| StoreError::DatabaseUnavailable)
+        self.wait_for_ramp();
+        Self::get_timeout(&self.pool, &self.acquire_queue)
+            .map_err(|_| StoreError::DatabaseUnavailable)
     }
 
     pub fn get_with_timeout_warning(
         &self,
         logger: &Logger,
     ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, StoreError> {
+        self.wait_for_ramp();
         loop {
-            match self.pool.get_timeout(ENV_VARS.store.connection_timeout) {
+            if let Some(clock) = MOCK_CLOCK.as_ref() {
+                // Only time out once the mock clock says so
+                if let Some(conn) = self.pool.try_get() {
+                    return Ok(conn);
+                }
+                clock.wait(ENV_VARS.store.connection_timeout);
+                error!(logger, "Error checking out connection, retrying";
+                   "error" => "timed out waiting for a connection",
+                );
+                continue;
+            }
+            match Self::get_timeout(&self.pool, &self.acquire_queue) {
                 Ok(conn) => return Ok(conn),
                 Err(e) => error!(logger, "Error checking out connection, retrying";
                    "error" => brief_error_msg(&e),
@@ -1005,11 +1356,11 @@ impl PoolInner {
             }
         };
         loop {
-            match pool.get() {
+            match Self::get_timeout(pool, &self.fdw_acquire_queue) {
                 Ok(conn) => return Ok(conn),
                 Err(e) => {
                     if timeout() {
-                        return Err(e.into());
+                        return Err(e);
                     }
                 }
             }
@@ -1022,8 +1373,7 @@ impl PoolInner {
 
     /// Check that we can connect to the database
     pub fn check(&self) -> bool {
-        self.pool
-            .get()
+        Self::get_timeout(&self.pool, &self.acquire_queue)
             .ok()
             .map(|mut conn| sql_query("select 1").execute(&mut conn).is_ok())
             .unwrap_or(false)
@@ -1106,6 +1456,15 @@ impl PoolInner {
         permit.unwrap()
     }
 
+    /// Like `query_permit`, but for status queries, which can use the
+    /// connections reserved with `GRAPH_STORE_HEALTH_RESERVED_CONNECTIONS`
+    pub(crate) async fn status_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        match &self.status_semaphore {
+            Some(semaphore) => semaphore.cheap_clone().acquire_owned().await.unwrap(),
+            None => self.query_permit().await,
+        }
+    }
+
     fn configure_fdw(&self, servers: &[ForeignServer]) -> Result<(), StoreError> {
         info!(&self.logger, "Setting up fdw");
         let mut conn = self.get()?;
@@ -1418,3 +1777,205 @@ impl PoolCoordinator {
             .ok_or_else(|| constraint_violation!("unknown shard {shard}"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_ramp() {
+        // Two connections per second
+        let ramp = ConnectionRamp::new(2.0);
+        let start = Instant::now();
+        let ms = |ms: u64| Duration::from_millis(ms);
+
+        // A burst of connections is spread out at the ramp rate
+        assert_eq!(ms(0), ramp.reserve(start));
+        assert_eq!(ms(500), ramp.reserve(start));
+        assert_eq!(ms(1000), ramp.reserve(start));
+
+        // Slots that were handed out are not reused by later callers
+        assert_eq!(ms(700), ramp.reserve(start + ms(800)));
+
+        // After a quiet period, connections can be opened right away again
+        assert_eq!(ms(0), ramp.reserve(start + ms(5000)));
+        assert_eq!(ms(500), ramp.reserve(start + ms(5000)));
+    }
+
+    #[test]
+    fn idle_timeout_keeps_min_idle() {
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+        // How often r2d2 looks for connections that were idle too long
+        const REAPER_RATE: Duration = Duration::from_secs(30);
+        const MIN_IDLE: u32 = 3;
+
+        // Simulate an idle pool and return the smallest number of idle
+        // connections it had right after the reaper closed connections, and
+        // the number of connections it ends up with. `idle` holds the
+        // times at which idle connections were returned to the pool, with
+        // the connection that was returned last at the end
+        fn simulate(keep_min_idle: bool) -> (u32, u32) {
+            let mut idle: Vec<Duration> = (0..10).map(Duration::from_secs).collect();
+            let mut fewest = u32::MAX;
+            let interval = keep_min_idle_interval(IDLE_TIMEOUT);
+            let mut now = Duration::ZERO;
+            while now < 4 * IDLE_TIMEOUT {
+                now += Duration::from_secs(1);
+                if keep_min_idle && (now.as_secs() % interval.as_secs()) == 0 {
+                    let keep = connections_to_keep(idle.len() as u32, MIN_IDLE) as usize;
+                    let kept = idle.split_off(idle.len() - keep);
+                    idle.extend(kept.iter().map(|_| now));
+                }
+                if (now.as_secs() % REAPER_RATE.as_secs()) == 0 {
+                    idle.retain(|returned| now - *returned < IDLE_TIMEOUT);
+                    fewest = fewest.min(idle.len() as u32);
+                    // r2d2 opens new connections until it has `min_idle`
+                    while (idle.len() as u32) < MIN_IDLE {
+                        idle.push(now);
+                    }
+                }
+            }
+            (fewest, idle.len() as u32)
+        }
+
+        // Connections above `min_idle` are closed, but the idle timeout
+        // never takes the pool below `min_idle`
+        assert_eq!((MIN_IDLE, MIN_IDLE), simulate(true));
+
+        // Without that, the idle timeout closes all connections and the
+        // pool has to open new ones
+        assert_eq!((0, MIN_IDLE), simulate(false));
+    }
+
+    #[test]
+    fn reserved_status_connections() {
+        // Without reserved connections, status queries compete with user
+        // queries
+        let (query, status) = query_semaphores(10, 2, 0);
+        assert_eq!(12, query.available_permits());
+        assert!(status.is_none());
+
+        // Status queries still get a permit when user queries use up all
+        // of theirs
+        let (query, status) = query_semaphores(10, 0, 2);
+        let status = status.unwrap();
+        let _saturated = query.try_acquire_many(8).unwrap();
+        assert!(query.try_acquire().is_err());
+        let _first = status.try_acquire().unwrap();
+        let _second = status.try_acquire().unwrap();
+        assert!(status.try_acquire().is_err());
+
+        // User queries always keep at least one connection
+        let (query, status) = query_semaphores(3, 0, 5);
+        assert_eq!(1, query.available_permits());
+        assert_eq!(2, status.unwrap().available_permits());
+
+        // Extra permits can not make user queries use reserved connections
+        let (query, status) = query_semaphores(10, 5, 2);
+        assert_eq!(8, query.available_permits());
+        assert_eq!(2, status.unwrap().available_permits());
+    }
+
+    #[test]
+    fn fdw_fetch_size_option() {
+        assert_eq!(", fetch_size '10000'", fetch_size_option(10000, None));
+        assert_eq!(", set fetch_size '500'", fetch_size_option(500, Some(true)));
+        assert_eq!(
+            ", add fetch_size '500'",
+            fetch_size_option(500, Some(false))
+        );
+
+        // A fetch size of 0 leaves the option unset
+        assert_eq!("", fetch_size_option(0, None));
+        assert_eq!("", fetch_size_option(0, Some(false)));
+        assert_eq!(", drop fetch_size", fetch_size_option(0, Some(true)));
+    }
+
+    /// A pool that counts its idle and open connections
+    #[derive(Default)]
+    struct MockPool {
+        idle: Mutex<u32>,
+        connections: Mutex<u32>,
+        max_size: u32,
+    }
+
+    impl ConnectionSource for MockPool {
+        type Conn = ();
+
+        fn try_get(&self) -> Option<()> {
+            let mut idle = self.idle.lock().unwrap();
+            (*idle > 0).then(|| *idle -= 1)
+        }
+
+        fn can_grow(&self) -> bool {
+            *self.connections.lock().unwrap() < self.max_size
+        }
+
+        fn get_timeout(&self, _timeout: Duration) -> Option<()> {
+            let mut connections = self.connections.lock().unwrap();
+            (*connections < self.max_size).then(|| *connections += 1)
+        }
+    }
+
+    #[test]
+    fn connection_acquire_policy() {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+
+        // The order in which three waiters get a connection from a pool
+        // that has none available until connections are returned one at
+        // a time
+        fn served(policy: ConnectionAcquirePolicy) -> Vec<usize> {
+            let queue = Arc::new(AcquireQueue::new(policy));
+            let pool = Arc::new(MockPool::default());
+            let order = Arc::new(Mutex::new(Vec::new()));
+
+            // Waiters line up one after the other
+            let waiters: Vec<_> = (1..=3)
+                .map(|waiter| {
+                    let (queue, pool, order) = (queue.clone(), pool.clone(), order.clone());
+                    let handle = std::thread::spawn(move || {
+                        queue.acquire(TIMEOUT, &*pool).unwrap();
+                        order.lock().unwrap().push(waiter);
+                    });
+                    while queue.state.lock().unwrap().waiting.len() < waiter {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    handle
+                })
+                .collect();
+
+            for served in 1..=3 {
+                *pool.idle.lock().unwrap() += 1;
+                queue.available();
+                while order.lock().unwrap().len() < served {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+            for waiter in waiters {
+                waiter.join().unwrap();
+            }
+            Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+        }
+
+        assert_eq!(vec![1, 2, 3], served(ConnectionAcquirePolicy::Fifo));
+        assert_eq!(vec![3, 2, 1], served(ConnectionAcquirePolicy::Lifo));
+
+        // A pool below its maximum size opens new connections for
+        // waiters even though none are idle
+        let queue = AcquireQueue::new(ConnectionAcquirePolicy::Lifo);
+        let pool = MockPool {
+            max_size: 2,
+            ..Default::default()
+        };
+        assert!(queue.acquire(TIMEOUT, &pool).is_some());
+        assert!(queue.acquire(TIMEOUT, &pool).is_some());
+        assert_eq!(2, *pool.connections.lock().unwrap());
+
+        // Once the pool is full, waiting for a connection is bounded by
+        // the timeout
+        let start = Instant::now();
+        assert!(queue.acquire(Duration::from_millis(50), &pool).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(queue.state.lock().unwrap().waiting.is_empty());
+    }
+}
@@ -13,28 +13,35 @@
 //! `graph-node` was restarted while the copy was running.
 use std::{
     convert::TryFrom,
-    ops::DerefMut,
-    sync::Arc,
+    sync::{Arc, Condvar, Mutex},
     time::{Duration, Instant},
 };
 
 use diesel::{
+    connection::SimpleConnection,
     dsl::sql,
     insert_into,
     r2d2::{ConnectionManager, PooledConnection},
-    select, sql_query, update, Connection as _, ExpressionMethods, OptionalExtension, PgConnection,
-    QueryDsl, RunQueryDsl,
+    result::{DatabaseErrorKind, Error as DieselError},
+    select, sql_query,
+    sql_types::{Nullable, Text},
+    update, Connection as _, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl,
+    RunQueryDsl,
 };
 use graph::{
     constraint_violation,
-    prelude::{info, o, warn, BlockNumber, BlockPtr, Logger, StoreError},
+    env::{CopyIndexTiming, CopyTableOrder},
+    prelude::{info, lazy_static, o, warn, BlockNumber, BlockPtr, Logger, StoreError, ENV_VARS},
     schema::EntityType,
+    util::backoff::ExponentialBackoff,
 };
 use itertools::Itertools;
+use rand::{seq::SliceRandom, thread_rng};
 
 use crate::{
     advisory_lock, catalog, deployment,
     dynds::DataSourcesTable,
+    maintenance_log::{self, MaintenanceEvent, Operation},
     primary::{DeploymentId, Site},
     relational::index::IndexList,
     vid_batcher::{VidBatcher, VidRange},
@@ -54,6 +61,11 @@ const ACCEPTABLE_REPLICATION_LAG: Duration = Duration::from_secs(30);
 /// the lag again
 const REPLICATION_SLEEP: Duration = Duration::from_secs(10);
 
+lazy_static! {
+    static ref COPY_LIMITER: Arc<CopyLimiter> =
+        Arc::new(CopyLimiter::new(ENV_VARS.store.max_concurrent_copies));
+}
+
 table! {
     subgraphs.copy_state(dst) {
         // deployment_schemas.id
@@ -108,6 +120,20 @@ pub fn is_source(conn: &mut PgConnection, site: &Site) -> Result<bool, StoreErro
     .map_err(StoreError::from)
 }
 
+/// Return `true` if the site is the destination of a copy operation. The
+/// copy operation might be just queued or in progress already
+pub fn is_destination(conn: &mut PgConnection, site: &Site) -> Result<bool, StoreError> {
+    use active_copies as ac;
+
+    select(diesel::dsl::exists(
+        ac::table
+            .filter(ac::dst.eq(site.id))
+            .filter(ac::cancelled_at.is_null()),
+    ))
+    .get_result::<bool>(conn)
+    .map_err(StoreError::from)
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Status {
     Finished,
@@ -124,6 +150,7 @@ struct CopyState {
 
 impl CopyState {
     fn new(
+        logger: &Logger,
         conn: &mut PgConnection,
         src: Arc<Layout>,
         dst: Arc<Layout>,
@@ -163,21 +190,22 @@ impl CopyState {
                         src.site.id
                     ));
                 }
-                Self::load(conn, src, dst, target_block)
+                Self::load(logger, conn, src, dst, target_block)
             }
-            None => Self::create(conn, src, dst, target_block),
+            None => Self::create(logger, conn, src, dst, target_block),
         }?;
 
         Ok(state)
     }
 
     fn load(
+        logger: &Logger,
         conn: &mut PgConnection,
         src: Arc<Layout>,
         dst: Arc<Layout>,
         target_block: BlockPtr,
     ) -> Result<CopyState, StoreError> {
-        let tables = TableState::load(conn, src.as_ref(), dst.as_ref())?;
+        let tables = TableState::load(logger, conn, src.as_ref(), dst.as_ref())?;
         Ok(CopyState {
             src,
             dst,
@@ -187,6 +215,7 @@ impl CopyState {
     }
 
     fn create(
+        logger: &Logger,
         conn: &mut PgConnection,
         src: Arc<Layout>,
         dst: Arc<Layout>,
@@ -212,6 +241,7 @@ impl CopyState {
                     .ok()
                     .map(|src_table| {
                         TableState::init(
+                            logger,
                             conn,
                             dst.site.clone(),
                             &src,
@@ -223,7 +253,14 @@ impl CopyState {
             })
             .collect::<Result<_, _>>()?;
         tables.sort_by_key(|table| table.dst.object.to_string());
+        sort_tables(
+            &mut tables,
+            ENV_VARS.store.copy_table_order,
+            TableState::remaining,
+        );
 
+        // The tables are inserted in the order in which they are copied so
+        // that a copy that is resumed keeps that order
         let values = tables
             .iter()
             .map(|table| {
@@ -288,6 +325,21 @@ impl CopyState {
     }
 }
 
+/// Sort `tables`, which must be sorted by name, into the order in which
+/// they should be copied. `size` returns the number of rows that need to
+/// be copied for a table. Tables of the same size stay in name order
+fn sort_tables<T, F>(tables: &mut [T], order: CopyTableOrder, size: F)
+where
+    F: Fn(&T) -> i64,
+{
+    match order {
+        CopyTableOrder::Declared => {}
+        CopyTableOrder::SizeAsc => tables.sort_by_key(size),
+        CopyTableOrder::SizeDesc => tables.sort_by_key(|table| std::cmp::Reverse(size(table))),
+        CopyTableOrder::Random => tables.shuffle(&mut thread_rng()),
+    }
+}
+
 pub(crate) fn source(
     conn: &mut PgConnection,
     dst: &Site,
@@ -314,10 +366,20 @@ struct TableState {
     dst_site: Arc<Site>,
     batcher: VidBatcher,
     duration_ms: i64,
+    /// The number of rows copied since this process started copying
+    rows: i64,
+}
+
+/// How far copying a table had gotten at some point in time
+struct TableCheckpoint {
+    position: (i64, i64),
+    duration_ms: i64,
+    rows: i64,
 }
 
 impl TableState {
     fn init(
+        logger: &Logger,
         conn: &mut PgConnection,
         dst_site: Arc<Site>,
         src_layout: &Layout,
@@ -326,13 +388,20 @@ impl TableState {
         target_block: &BlockPtr,
     ) -> Result<Self, StoreError> {
         let vid_range = VidRange::for_copy(conn, &src, target_block)?;
-        let batcher = VidBatcher::load(conn, &src_layout.site.namespace, src.as_ref(), vid_range)?;
+        let batcher = VidBatcher::load(
+            logger,
+            conn,
+            &src_layout.site.namespace,
+            src.as_ref(),
+            vid_range,
+        )?;
         Ok(Self {
             src,
             dst,
             dst_site,
             batcher,
             duration_ms: 0,
+            rows: 0,
         })
     }
 
@@ -340,7 +409,46 @@ impl TableState {
         self.batcher.finished()
     }
 
+    /// Remember how far copying this table has gotten so that a batch
+    /// that fails can be retried with `rewind`
+    fn checkpoint(&self) -> TableCheckpoint {
+        TableCheckpoint {
+            position: self.batcher.position(),
+            duration_ms: self.duration_ms,
+            rows: self.rows,
+        }
+    }
+
+    /// Undo the progress that was made since `checkpoint` was taken. Since
+    /// a failed batch rolls back its transaction, this makes our state
+    /// match what is in the database again
+    fn rewind(&mut self, checkpoint: TableCheckpoint) {
+        self.batcher.rewind(checkpoint.position);
+        self.duration_ms = checkpoint.duration_ms;
+        self.rows = checkpoint.rows;
+    }
+
+    /// Run `op` to copy a batch, and undo the progress it made if it fails
+    /// so that the batch can be retried
+    fn attempt<T, F>(&mut self, op: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, StoreError>,
+    {
+        let checkpoint = self.checkpoint();
+        let res = op(self);
+        if res.is_err() {
+            self.rewind(checkpoint);
+        }
+        res
+    }
+
+    /// An estimate of the number of rows that still need to be copied
+    fn remaining(&self) -> i64 {
+        (self.batcher.target_vid() - self.batcher.next_vid() + 1).max(0)
+    }
+
     fn load(
+        logger: &Logger,
         conn: &mut PgConnection,
         src_layout: &Layout,
         dst_layout: &Layout,
@@ -379,7 +487,7 @@ impl TableState {
                 cts::batch_size,
                 cts::duration_ms,
             ))
-            .order_by(cts::entity_type)
+            .order_by(cts::id)
             .load::<(i32, String, i64, i64, i64, i64)>(conn)?
             .into_iter()
             .map(
@@ -397,6 +505,7 @@ impl TableState {
                     match (src, dst) {
                         (Ok(src), Ok(dst)) => {
                             let batcher = VidBatcher::load(
+                                logger,
                                 conn,
                                 &src_layout.site.namespace,
                                 &src,
@@ -410,6 +519,7 @@ impl TableState {
                                 dst_site: dst_layout.site.clone(),
                                 batcher,
                                 duration_ms,
+                                rows: 0,
                             })
                         }
                         (Err(e), _) => Err(e),
@@ -490,14 +600,31 @@ impl TableState {
 
     fn copy_batch(&mut self, conn: &mut PgConnection) -> Result<Status, StoreError> {
         let (duration, count) = self.batcher.step(|start, end| {
-            let count = rq::CopyEntityBatchQuery::new(self.dst.as_ref(), &self.src, start, end)?
+            let copy = rq::CopyEntityBatchQuery::new(
+                self.dst.as_ref(),
+                &self.src,
+                start,
+                end,
+                ENV_VARS.store.copy_overlap_policy,
+                ENV_VARS.store.copy_row_order,
+            )?;
+            // Versions that are removed from the destination because the
+            // source versions take precedence are not current anymore
+            let removed = match copy.delete_overlaps() {
+                Some(delete) => delete
+                    .get_result::<i64>(conn)
+                    .map_err(StoreError::from_write_error)?,
+                None => 0,
+            };
+            let (current, total) = copy
                 .count_current()
-                .get_result::<i64>(conn)
-                .optional()?;
-            Ok(count.unwrap_or(0) as i32)
+                .get_result::<(i64, i64)>(conn)
+                .map_err(StoreError::from_write_error)?;
+            Ok(((current - removed) as i32, total))
         })?;
 
-        let count = count.unwrap_or(0);
+        let (count, rows) = count.unwrap_or((0, 0));
+        self.rows += rows;
 
         deployment::update_entity_count(conn, &self.dst_site, count)?;
 
@@ -509,6 +636,127 @@ impl TableState {
 
         Ok(Status::Finished)
     }
+
+    /// Copy a batch like `copy_batch`, but with the `fetch_size` option of
+    /// the foreign table `self.src` set to `fetch_size`. The option is put
+    /// back to what it was before the batch in the same transaction so
+    /// that it only affects this batch. Must only be called when
+    /// `self.src` is a foreign table, i.e., when copying across shards
+    fn copy_batch_with_fetch_size(
+        &mut self,
+        conn: &mut PgConnection,
+        fetch_size: usize,
+    ) -> Result<Status, StoreError> {
+        let prev = select(sql::<Nullable<Text>>(&format!(
+            "(select substr(opt, length('fetch_size=') + 1) \
+                from pg_foreign_table ft, unnest(ft.ftoptions) opt \
+               where ft.ftrelid = '{}'::regclass \
+                 and opt like 'fetch_size=%')",
+            self.src.qualified_name
+        )))
+        .get_result::<Option<String>>(conn)?;
+        let action = if prev.is_some() { "set" } else { "add" };
+        self.alter_fetch_size(conn, &format!("{action} fetch_size '{fetch_size}'"))?;
+
+        let status = self.copy_batch(conn)?;
+
+        let reset = match prev {
+            Some(prev) => format!("set fetch_size '{prev}'"),
+            None => "drop fetch_size".to_string(),
+        };
+        self.alter_fetch_size(conn, &reset)?;
+        Ok(status)
+    }
+
+    fn alter_fetch_size(&self, conn: &mut PgConnection, option: &str) -> Result<(), StoreError> {
+        let query = format!(
+            "alter foreign table {} options ({})",
+            self.src.qualified_name, option
+        );
+        conn.batch_execute(&query)?;
+        Ok(())
+    }
+}
+
+/// Return `true` if `e` indicates that a foreign scan failed because it
+/// tried to fetch too many rows at once from the remote server. That is
+/// the case when our connection was closed, or when the database reported
+/// that it ran out of memory or lost protocol synchronization with the
+/// remote server. Diesel does not expose the SQLSTATE of errors, and we
+/// therefore have to look at the primary message of the database error
+/// for the latter
+fn is_fetch_size_error(e: &StoreError) -> bool {
+    const MESSAGES: [&str; 3] = [
+        "out of memory",
+        "lost synchronization with server",
+        "invalid message format",
+    ];
+
+    let StoreError::Unknown(e) = e else {
+        return false;
+    };
+    match e.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::ClosedConnection, _)) => true,
+        Some(DieselError::DatabaseError(DatabaseErrorKind::Unknown, info)) => {
+            let msg = info.message();
+            MESSAGES.iter().any(|m| msg.starts_with(m))
+        }
+        _ => false,
+    }
+}
+
+/// Run `op` with `None`; if that fails because a foreign scan fetched too
+/// many rows at once, run it once more with
+/// `Some(GRAPH_STORE_FDW_FETCH_SIZE_FALLBACK)`, and `op` should get a new
+/// connection and use that fetch size for the retry
+fn with_fetch_size_fallback<T, F>(logger: &Logger, mut op: F) -> Result<T, StoreError>
+where
+    F: FnMut(Option<usize>) -> Result<T, StoreError>,
+{
+    match op(None) {
+        Err(e) if is_fetch_size_error(&e) => {
+            let fetch_size = ENV_VARS.store.fdw_fetch_size_fallback;
+            warn!(logger, "Foreign scan failed, retrying with smaller fetch size";
+                  "fetch_size" => fetch_size,
+                  "error" => e.to_string());
+            op(Some(fetch_size))
+        }
+        res => res,
+    }
+}
+
+/// The connection in `conn`. It is only missing if we could not get a new
+/// connection after the old one failed
+fn live_conn(
+    conn: &mut Option<PooledConnection<ConnectionManager<PgConnection>>>,
+) -> Result<&mut PgConnection, StoreError> {
+    conn.as_deref_mut()
+        .ok_or_else(|| constraint_violation!("the connection for copying was lost"))
+}
+
+/// Run `op` and run it again up to `retries` times if it fails with a
+/// transient error. The `op` must undo any changes it made before it
+/// failed so that it can be run again. It is passed the number of the
+/// attempt, starting at 0, and must get a new connection for every attempt
+/// after the first one
+fn with_copy_retries<T, F>(logger: &Logger, retries: usize, mut op: F) -> Result<T, StoreError>
+where
+    F: FnMut(usize) -> Result<T, StoreError>,
+{
+    let mut backoff = ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(15));
+    let mut attempt = 0;
+    loop {
+        match op(attempt) {
+            Err(StoreError::DatabaseUnavailable) if attempt < retries => {
+                attempt += 1;
+                warn!(logger, "Copying a batch failed with a transient error, retrying the batch";
+                      "attempt" => attempt,
+                      "retries" => retries);
+                backoff.sleep();
+            }
+            res => return res,
+        }
+    }
 }
 
 // A helper for logging progress while data is being copied
@@ -591,17 +839,132 @@ impl<'a> CopyProgress<'a> {
     }
 }
 
+/// The steps of copying a deployment that depend on
+/// `GRAPH_STORE_COPY_INDEX_TIMING`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CopyStep {
+    /// Copy the data of all tables
+    LoadData,
+    /// Create the indexes whose creation was postponed
+    CreateIndexes,
+}
+
+/// The order in which the steps of a copy happen
+fn copy_steps(timing: CopyIndexTiming) -> [CopyStep; 2] {
+    match timing {
+        CopyIndexTiming::After => [CopyStep::LoadData, CopyStep::CreateIndexes],
+        CopyIndexTiming::Before => [CopyStep::CreateIndexes, CopyStep::LoadData],
+    }
+}
+
+/// The statements that make a transaction that reads from the source of a
+/// copy use the snapshot `snapshot` that was exported with
+/// `pg_export_snapshot()`
+fn snapshot_sql(snapshot: &str) -> [String; 2] {
+    [
+        "set transaction isolation level repeatable read".to_string(),
+        format!("set transaction snapshot '{}'", snapshot),
+    ]
+}
+
+/// A snapshot of the database that is exported once per copy so that all
+/// the transactions that read from the source see the same data. The
+/// snapshot can only be imported for as long as the transaction that
+/// exported it is open, and that transaction is rolled back when this is
+/// dropped
+struct CopySnapshot {
+    conn: PooledConnection<ConnectionManager<PgConnection>>,
+    id: String,
+}
+
+impl CopySnapshot {
+    fn export(pool: &ConnectionPool) -> Result<Self, StoreError> {
+        let mut conn = pool.get()?;
+        conn.batch_execute("begin transaction isolation level repeatable read")?;
+        let id = select(sql::<Text>("pg_export_snapshot()")).get_result::<String>(&mut conn)?;
+        Ok(Self { conn, id })
+    }
+}
+
+impl Drop for CopySnapshot {
+    fn drop(&mut self) {
+        // If this fails, the connection is broken, and the transaction
+        // ends with it
+        self.conn.batch_execute("rollback").ok();
+    }
+}
+
+/// Limits how many copies can run at the same time across all shards
+struct CopyLimiter {
+    /// The maximum number of copies that can run at once; 0 means there is
+    /// no limit
+    max_concurrent: usize,
+    running: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl CopyLimiter {
+    fn new(max_concurrent: usize) -> Self {
+        CopyLimiter {
+            max_concurrent,
+            running: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Block until fewer than `max_concurrent` copies are running. While
+    /// blocked, call `waiting` at least once every `LOG_INTERVAL`. The slot
+    /// is released when the returned `CopySlot` is dropped
+    fn acquire(self: &Arc<Self>, mut waiting: impl FnMut()) -> CopySlot {
+        let mut running = self.running.lock().unwrap();
+        if self.max_concurrent > 0 {
+            while *running >= self.max_concurrent {
+                waiting();
+                running = self.changed.wait_timeout(running, LOG_INTERVAL).unwrap().0;
+            }
+        }
+        *running += 1;
+        CopySlot {
+            limiter: self.clone(),
+        }
+    }
+
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// A slot for running one copy, handed out by `CopyLimiter::acquire`
+struct CopySlot {
+    limiter: Arc<CopyLimiter>,
+}
+
+impl Drop for CopySlot {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
 /// A helper for copying subgraphs
 pub struct Connection {
     /// The connection pool for the shard that will contain the destination
     /// of the copy
+    pool: ConnectionPool,
     logger: Logger,
-    conn: PooledConnection<ConnectionManager<PgConnection>>,
+    /// The connection from the fdw pool that we copy with. It is only
+    /// `None` if `reconnect` could not get a new connection
+    conn: Option<PooledConnection<ConnectionManager<PgConnection>>>,
     src: Arc<Layout>,
     dst: Arc<Layout>,
     target_block: BlockPtr,
     src_manifest_idx_and_name: Vec<(i32, String)>,
     dst_manifest_idx_and_name: Vec<(i32, String)>,
+    /// The snapshot that transactions reading from the source use while
+    /// `copy_data_internal` runs with `GRAPH_STORE_COPY_SNAPSHOT`
+    snapshot: Option<CopySnapshot>,
+    /// Held for as long as the copy runs
+    _slot: CopySlot,
 }
 
 impl Connection {
@@ -630,30 +993,110 @@ impl Connection {
             )));
         }
 
-        let mut last_log = Instant::now();
-        let conn = pool.get_fdw(&logger, || {
-            if last_log.elapsed() > LOG_INTERVAL {
-                info!(&logger, "waiting for other copy operations to finish");
-                last_log = Instant::now();
-            }
-            false
-        })?;
+        let slot = COPY_LIMITER.acquire(|| {
+            info!(&logger, "waiting for other copies to finish";
+                  "max_concurrent_copies" => COPY_LIMITER.max_concurrent);
+        });
+
+        let conn = Self::get_fdw(&logger, &pool)?;
         Ok(Self {
+            pool,
             logger,
-            conn,
+            conn: Some(conn),
             src,
             dst,
             target_block,
             src_manifest_idx_and_name,
             dst_manifest_idx_and_name,
+            snapshot: None,
+            _slot: slot,
         })
     }
 
+    /// Get a connection from the fdw pool, waiting for as long as it
+    /// takes for one to become available
+    fn get_fdw(
+        logger: &Logger,
+        pool: &ConnectionPool,
+    ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, StoreError> {
+        let mut last_log = Instant::now();
+        pool.get_fdw(logger, || {
+            if last_log.elapsed() > LOG_INTERVAL {
+                info!(logger, "waiting for other copy operations to finish");
+                last_log = Instant::now();
+            }
+            false
+        })
+    }
+
+    fn conn(&mut self) -> Result<&mut PgConnection, StoreError> {
+        live_conn(&mut self.conn)
+    }
+
+    /// Take the copy lock and set up the session on our connection for
+    /// copying. Must be undone with `release`
+    fn acquire(&mut self) -> Result<(), StoreError> {
+        let conn = live_conn(&mut self.conn)?;
+        advisory_lock::lock_copying(conn, self.dst.site.as_ref())?;
+        let work_mem = ENV_VARS.store.maintenance_work_mem.as_deref();
+        catalog::set_maintenance_work_mem(conn, work_mem)
+    }
+
+    /// Undo what `acquire` did. We always try to unlock, even if resetting
+    /// `maintenance_work_mem` fails, so that the copy lock is not leaked,
+    /// and report the first error
+    fn release(&mut self) -> Result<(), StoreError> {
+        let conn = live_conn(&mut self.conn)?;
+        let work_mem = ENV_VARS.store.maintenance_work_mem.as_deref();
+        let reset = catalog::reset_maintenance_work_mem(conn, work_mem);
+        let unlock = advisory_lock::unlock_copying(conn, self.dst.site.as_ref());
+        reset.and(unlock)
+    }
+
+    /// Replace our connection with a new one from the pool after a batch
+    /// failed with a transient error since the old connection might be
+    /// broken. The old connection goes back to the pool before we wait for
+    /// a new one so that copies that all retry at the same time can not
+    /// starve each other of connections
+    fn reconnect(&mut self) -> Result<(), StoreError> {
+        if self.conn.is_some() {
+            // If the connection is broken, this fails, and Postgres
+            // releases the copy lock once it notices that the session is
+            // gone. `acquire` waits for that to happen
+            if let Err(e) = self.release() {
+                warn!(self.logger, "Failed to release the copy connection";
+                      "error" => e.to_string());
+            }
+            self.conn = None;
+        }
+        self.conn = Some(Self::get_fdw(&self.logger, &self.pool)?);
+        self.acquire()
+    }
+
     fn transaction<T, F>(&mut self, f: F) -> Result<T, StoreError>
     where
         F: FnOnce(&mut PgConnection) -> Result<T, StoreError>,
     {
-        self.conn.transaction(|conn| f(conn))
+        self.conn()?.transaction(|conn| f(conn))
+    }
+
+    /// Like `transaction`, but for transactions that read from the source.
+    /// With `GRAPH_STORE_COPY_SNAPSHOT`, they all import the snapshot that
+    /// was exported at the start of the copy so that every batch sees the
+    /// same state of the source, even while it is being written to
+    fn copy_transaction<T, F>(&mut self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, StoreError>,
+    {
+        let snapshot = self.snapshot.as_ref().map(|snapshot| snapshot.id.clone());
+        self.conn()?.transaction(|conn| {
+            if let Some(snapshot) = snapshot {
+                for sql in snapshot_sql(&snapshot) {
+                    conn.batch_execute(&sql)?;
+                }
+            }
+            f(conn)
+        })
     }
 
     /// Copy private data sources if the source uses a schema version that
@@ -661,7 +1104,7 @@ impl Connection {
     /// transaction.
     fn copy_private_data_sources(&mut self, state: &CopyState) -> Result<(), StoreError> {
         if state.src.site.schema_version.private_data_sources() {
-            let conn = &mut self.conn;
+            let conn = live_conn(&mut self.conn)?;
             conn.transaction(|conn| {
                 DataSourcesTable::new(state.src.site.namespace.clone()).copy_to(
                     conn,
@@ -675,28 +1118,27 @@ impl Connection {
         Ok(())
     }
 
-    pub fn copy_data_internal(&mut self, index_list: IndexList) -> Result<Status, StoreError> {
-        let src = self.src.clone();
-        let dst = self.dst.clone();
-        let target_block = self.target_block.clone();
-        let mut state = self.transaction(|conn| CopyState::new(conn, src, dst, target_block))?;
-
+    /// Copy the data of all tables that have not been copied completely
+    fn load_data(
+        &mut self,
+        state: &mut CopyState,
+        progress: &mut CopyProgress<'_>,
+    ) -> Result<Status, StoreError> {
         let logger = &self.logger.clone();
-        let mut progress = CopyProgress::new(logger, &state);
-        progress.start();
+        let crosses_shards = state.crosses_shards();
 
         for table in state.tables.iter_mut().filter(|table| !table.finished()) {
             while !table.finished() {
                 // It is important that this check happens outside the write
                 // transaction so that we do not hold on to locks acquired
                 // by the check
-                if table.is_cancelled(&mut self.conn)? {
+                if table.is_cancelled(self.conn()?)? {
                     return Ok(Status::Cancelled);
                 }
 
                 // Pause copying if replication is lagging behind to avoid
                 // overloading replicas
-                let mut lag = catalog::replication_lag(&mut self.conn)?;
+                let mut lag = catalog::replication_lag(self.conn()?)?;
                 if lag > MAX_REPLICATION_LAG {
                     loop {
                         info!(&self.logger,
@@ -704,26 +1146,70 @@ impl Connection {
                              REPLICATION_SLEEP.as_secs();
                              "lag_s" => lag.as_secs());
                         std::thread::sleep(REPLICATION_SLEEP);
-                        lag = catalog::replication_lag(&mut self.conn)?;
+                        lag = catalog::replication_lag(self.conn()?)?;
                         if lag <= ACCEPTABLE_REPLICATION_LAG {
                             break;
                         }
                     }
                 }
 
-                let status = self.transaction(|conn| table.copy_batch(conn))?;
+                let status = with_copy_retries(logger, ENV_VARS.store.copy_retries, |attempt| {
+                    if attempt > 0 {
+                        self.reconnect()?;
+                    }
+                    table.attempt(|table| {
+                        if crosses_shards {
+                            with_fetch_size_fallback(logger, |fetch_size| match fetch_size {
+                                None => self.copy_transaction(|conn| table.copy_batch(conn)),
+                                Some(fetch_size) => {
+                                    // The failed foreign scan might have
+                                    // left the connection unusable
+                                    self.reconnect()?;
+                                    self.copy_transaction(|conn| {
+                                        table.copy_batch_with_fetch_size(conn, fetch_size)
+                                    })
+                                }
+                            })
+                        } else {
+                            self.copy_transaction(|conn| table.copy_batch(conn))
+                        }
+                    })
+                })?;
                 if status == Status::Cancelled {
                     return Ok(status);
                 }
                 progress.update(&table.dst.object, &table.batcher);
             }
             progress.table_finished(&table.batcher);
+            let strategy = if crosses_shards {
+                "cross_shard"
+            } else {
+                "same_shard"
+            };
+            maintenance_log::log_event(logger, live_conn(&mut self.conn)?, &table.dst, |bytes| {
+                MaintenanceEvent {
+                    operation: Operation::Copy,
+                    deployment: &self.dst.site.deployment,
+                    table: table.dst.name.as_str(),
+                    rows: table.rows as usize,
+                    bytes,
+                    duration: Duration::from_millis(table.duration_ms as u64),
+                    strategy,
+                }
+            });
         }
+        Ok(Status::Finished)
+    }
 
-        // Create indexes for all the attributes that were postponed at the start of
-        // the copy/graft operations.
+    /// Create indexes for all the attributes that were postponed at the
+    /// start of the copy/graft operations
+    fn create_postponed_indexes(
+        &mut self,
+        state: &CopyState,
+        index_list: &IndexList,
+    ) -> Result<(), StoreError> {
         // First recreate the indexes that existed in the original subgraph.
-        let conn = self.conn.deref_mut();
+        let conn = live_conn(&mut self.conn)?;
         for table in state.tables.iter() {
             let arr = index_list.indexes_for_table(
                 &self.dst.site.namespace,
@@ -758,6 +1244,33 @@ impl Connection {
                 query.execute(conn)?;
             }
         }
+        Ok(())
+    }
+
+    pub fn copy_data_internal(&mut self, index_list: IndexList) -> Result<Status, StoreError> {
+        let src = self.src.clone();
+        let dst = self.dst.clone();
+        let target_block = self.target_block.clone();
+        let logger = &self.logger.clone();
+        if ENV_VARS.store.copy_snapshot {
+            self.snapshot = Some(CopySnapshot::export(&self.pool)?);
+        }
+        let mut state =
+            self.copy_transaction(|conn| CopyState::new(logger, conn, src, dst, target_block))?;
+
+        let mut progress = CopyProgress::new(logger, &state);
+        progress.start();
+
+        for step in copy_steps(ENV_VARS.store.copy_index_timing) {
+            match step {
+                CopyStep::LoadData => {
+                    if self.load_data(&mut state, &mut progress)? == Status::Cancelled {
+                        return Ok(Status::Cancelled);
+                    }
+                }
+                CopyStep::CreateIndexes => self.create_postponed_indexes(&state, &index_list)?,
+            }
+        }
 
         self.copy_private_data_sources(&state)?;
 
@@ -796,12 +1309,271 @@ impl Connection {
             &self.logger,
             "Obtaining copy lock (this might take a long time if another process is still copying)"
         );
-        advisory_lock::lock_copying(&mut self.conn, self.dst.site.as_ref())?;
+        self.acquire()?;
         let res = self.copy_data_internal(index_list);
-        advisory_lock::unlock_copying(&mut self.conn, self.dst.site.as_ref())?;
+        // End the transaction that keeps the snapshot alive, even if the
+        // copy failed or was cancelled
+        self.snapshot = None;
+        self.release()?;
         if matches!(res, Ok(Status::Cancelled)) {
             warn!(&self.logger, "Copying was cancelled and is incomplete");
         }
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use graph::{prelude::anyhow::anyhow, slog};
+
+    use super::*;
+    use crate::relational::test_layout;
+
+    #[test]
+    fn table_order() {
+        // (name, size) for each table, sorted by name
+        const TABLES: [(&str, i64); 4] = [("a", 50), ("b", 300), ("c", 10), ("d", 50)];
+
+        let sorted = |order| {
+            let mut tables = TABLES.to_vec();
+            sort_tables(&mut tables, order, |(_, size)| *size);
+            tables.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["a", "b", "c", "d"], sorted(CopyTableOrder::Declared));
+        // Tables of the same size are copied in name order
+        assert_eq!(vec!["c", "a", "d", "b"], sorted(CopyTableOrder::SizeAsc));
+        assert_eq!(vec!["b", "a", "d", "c"], sorted(CopyTableOrder::SizeDesc));
+
+        let mut random = sorted(CopyTableOrder::Random);
+        random.sort();
+        assert_eq!(vec!["a", "b", "c", "d"], random);
+    }
+
+    #[test]
+    fn copy_index_timing() {
+        use CopyStep::*;
+
+        // By default, indexes are created once all the data is loaded
+        assert_eq!(
+            [LoadData, CreateIndexes],
+            copy_steps(ENV_VARS.store.copy_index_timing)
+        );
+        assert_eq!(
+            [LoadData, CreateIndexes],
+            copy_steps(CopyIndexTiming::After)
+        );
+        assert_eq!(
+            [CreateIndexes, LoadData],
+            copy_steps(CopyIndexTiming::Before)
+        );
+    }
+
+    #[test]
+    fn copy_limiter() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+
+        let limiter = Arc::new(CopyLimiter::new(1));
+        let first = limiter.acquire(|| panic!("the first copy does not wait"));
+
+        // The second copy waits until the first one is done
+        let started = Arc::new(AtomicBool::new(false));
+        let second = thread::spawn({
+            let limiter = limiter.clone();
+            let started = started.clone();
+            move || {
+                let _slot = limiter.acquire(|| {});
+                started.store(true, Ordering::SeqCst);
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!started.load(Ordering::SeqCst));
+
+        drop(first);
+        second.join().unwrap();
+        assert!(started.load(Ordering::SeqCst));
+        assert_eq!(0, *limiter.running.lock().unwrap());
+
+        // Without a limit, copies never wait
+        let limiter = Arc::new(CopyLimiter::new(0));
+        let slots: Vec<_> = (0..10)
+            .map(|_| limiter.acquire(|| panic!("copies do not wait")))
+            .collect();
+        assert_eq!(10, *limiter.running.lock().unwrap());
+        drop(slots);
+    }
+
+    #[test]
+    fn copy_snapshot() {
+        assert_eq!(
+            [
+                "set transaction isolation level repeatable read".to_string(),
+                "set transaction snapshot '00000003-0000001B-1'".to_string()
+            ],
+            snapshot_sql("00000003-0000001B-1")
+        );
+    }
+
+    #[test]
+    fn fetch_size_fallback() {
+        let logger = Logger::root(slog::Discard, o!());
+
+        let db_error = |kind: DatabaseErrorKind, msg: &str| {
+            StoreError::from(DieselError::DatabaseError(kind, Box::new(msg.to_string())))
+        };
+
+        // A simulated out-of-memory error makes us retry with the fallback
+        let mut fetch_sizes = Vec::new();
+        let res = with_fetch_size_fallback(&logger, |fetch_size| {
+            fetch_sizes.push(fetch_size);
+            match fetch_size {
+                None => Err(db_error(DatabaseErrorKind::Unknown, "out of memory")),
+                Some(fetch_size) => Ok(fetch_size),
+            }
+        });
+        assert_eq!(ENV_VARS.store.fdw_fetch_size_fallback, res.unwrap());
+        assert_eq!(
+            vec![None, Some(ENV_VARS.store.fdw_fetch_size_fallback)],
+            fetch_sizes
+        );
+
+        // Other errors are passed through without a retry, even if their
+        // message mentions running out of memory somewhere
+        for e in [
+            db_error(DatabaseErrorKind::Unknown, "relation does not exist"),
+            db_error(
+                DatabaseErrorKind::UniqueViolation,
+                "out of memory is not a unique name",
+            ),
+            StoreError::Unknown(anyhow!("out of memory")),
+        ] {
+            let mut e = Some(e);
+            let mut fetch_sizes = Vec::new();
+            let res: Result<(), _> = with_fetch_size_fallback(&logger, |fetch_size| {
+                fetch_sizes.push(fetch_size);
+                Err(e.take().unwrap())
+            });
+            assert!(res.is_err());
+            assert_eq!(vec![None], fetch_sizes);
+        }
+
+        // A closed connection also makes us retry
+        let mut fetch_sizes = Vec::new();
+        let res = with_fetch_size_fallback(&logger, |fetch_size| {
+            fetch_sizes.push(fetch_size);
+            match fetch_size {
+                None => Err(db_error(DatabaseErrorKind::ClosedConnection, "")),
+                Some(_) => Ok(()),
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(2, fetch_sizes.len());
+
+        // The retry is only attempted once
+        let mut fetch_sizes = Vec::new();
+        let res: Result<(), _> = with_fetch_size_fallback(&logger, |fetch_size| {
+            fetch_sizes.push(fetch_size);
+            Err(db_error(
+                DatabaseErrorKind::Unknown,
+                "lost synchronization with server: got message type \"d\"",
+            ))
+        });
+        assert!(res.is_err());
+        assert_eq!(2, fetch_sizes.len());
+    }
+
+    #[test]
+    fn copy_retries() {
+        let logger = Logger::root(slog::Discard, o!());
+
+        // Copy batches 0..5 where copying batch 2 fails once after it has
+        // already made some progress
+        let copy = |retries: usize| {
+            let mut copied: Vec<usize> = Vec::new();
+            let mut failed = false;
+            for batch in 0..5 {
+                with_copy_retries(&logger, retries, |_| {
+                    let checkpoint = copied.len();
+                    copied.push(batch);
+                    if batch == 2 && !failed {
+                        failed = true;
+                        copied.truncate(checkpoint);
+                        return Err(StoreError::DatabaseUnavailable);
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok::<_, StoreError>(copied)
+        };
+
+        // With retries, the copy resumes with the batch that failed
+        assert_eq!(vec![0, 1, 2, 3, 4], copy(1).unwrap());
+
+        // Without retries, the failure ends the copy
+        assert!(matches!(copy(0), Err(StoreError::DatabaseUnavailable)));
+
+        // Errors that are not transient are never retried
+        let mut attempts = 0;
+        let res: Result<(), _> = with_copy_retries(&logger, 3, |_| {
+            attempts += 1;
+            Err(StoreError::Unknown(anyhow!("relation does not exist")))
+        });
+        assert!(res.is_err());
+        assert_eq!(1, attempts);
+
+        // Every retry is told that it needs a new connection
+        let mut seen = Vec::new();
+        let res: Result<(), _> = with_copy_retries(&logger, 2, |attempt| {
+            seen.push(attempt);
+            Err(StoreError::DatabaseUnavailable)
+        });
+        assert!(res.is_err());
+        assert_eq!(vec![0, 1, 2], seen);
+    }
+
+    #[test]
+    fn copy_retries_rewind_table() {
+        let logger = Logger::root(slog::Discard, o!());
+        let layout = test_layout("subgraph", "sgd0815", "type Thing @entity { id: ID! }");
+        let site = layout.site.clone();
+        let table = layout.tables.values().next().unwrap().clone();
+        let mut state = TableState {
+            src: table.clone(),
+            dst: table,
+            dst_site: site,
+            batcher: VidBatcher::fixed(&logger, VidRange::new(0, 49), 10).unwrap(),
+            duration_ms: 0,
+            rows: 0,
+        };
+
+        // Copy the table in batches where the third batch fails once after
+        // the batcher has already moved past it
+        let mut copied: Vec<(i64, i64)> = Vec::new();
+        let mut failed = false;
+        while !state.finished() {
+            with_copy_retries(&logger, 1, |_| {
+                state.attempt(|table| {
+                    let (_, range) = table.batcher.step(|start, end| Ok((start, end)))?;
+                    let (start, end) = range.unwrap();
+                    table.rows += end - start + 1;
+                    if copied.len() == 2 && !failed {
+                        failed = true;
+                        return Err(StoreError::DatabaseUnavailable);
+                    }
+                    copied.push((start, end));
+                    Ok(())
+                })
+            })
+            .unwrap();
+        }
+
+        // The failed batch was copied again, and neither the batches nor
+        // the row count skip or double count anything
+        assert!(failed);
+        assert_eq!(0, copied[0].0);
+        assert_eq!(49, copied.last().unwrap().1);
+        assert!(copied.windows(2).all(|w| w[1].0 == w[0].1 + 1));
+        assert_eq!(50, state.rows);
+    }
+}
@@ -8,8 +8,8 @@ use graph::blockchain::block_stream::{EntitySourceOperation, FirehoseCursor};
 use graph::blockchain::BlockTime;
 use graph::components::store::write::RowGroup;
 use graph::components::store::{
-    Batch, DeploymentLocator, DerivedEntityQuery, PrunePhase, PruneReporter, PruneRequest,
-    PruningStrategy, QueryPermit, StoredDynamicDataSource, VersionStats,
+    Batch, DeploymentLocator, DerivedEntityQuery, MaintenanceRecord, PrunePhase, PruneReporter,
+    PruneRequest, PruningStrategy, QueryPermit, StoredDynamicDataSource, VersionStats,
 };
 use graph::components::versions::VERSIONS;
 use graph::data::query::Trace;
@@ -19,9 +19,10 @@ use graph::data_source::CausalityRegion;
 use graph::derive::CheapClone;
 use graph::futures03::FutureExt;
 use graph::prelude::{
-    ApiVersion, CancelHandle, CancelToken, CancelableError, EntityOperation, PoolWaitStats,
-    SubgraphDeploymentEntity,
+    ApiVersion, CancelHandle, CancelToken, CancelableError, Counter, EntityOperation,
+    MetricsRegistry, PoolWaitStats, SubgraphDeploymentEntity,
 };
+use graph::prometheus::HistogramVec;
 use graph::semver::Version;
 use graph::tokio::task::JoinHandle;
 use itertools::Itertools;
@@ -53,9 +54,11 @@ use crate::detail::ErrorDetail;
 use crate::dynds::DataSourcesTable;
 use crate::primary::DeploymentId;
 use crate::relational::index::{CreateIndex, IndexList, Method};
-use crate::relational::{Layout, LayoutCache, SqlName, Table};
+use crate::relational::{
+    acquire_prune_slot, Layout, LayoutCache, PrunePlan, PruneThrottle, SqlName, Table,
+};
 use crate::relational_queries::FromEntityData;
-use crate::{advisory_lock, catalog, copy, retry};
+use crate::{advisory_lock, catalog, copy, maintenance_log, retry};
 use crate::{connection_pool::ConnectionPool, detail};
 use crate::{dynds, primary::Site};
 
@@ -113,6 +116,18 @@ pub struct StoreInner {
     pub(crate) layout_cache: LayoutCache,
 
     prune_handles: Mutex<HashMap<DeploymentId, PruneHandle>>,
+
+    /// Skips automatic pruning of deployments that were pruned too
+    /// recently according to `GRAPH_STORE_PRUNE_MIN_INTERVAL`
+    prune_throttle: PruneThrottle,
+
+    /// Counts how often inserting entities had to be split into several
+    /// statements to stay below Postgres' limit on bind variables
+    param_limit_recoveries: Counter,
+
+    /// How long the phases of queries take when
+    /// `GRAPH_STORE_QUERY_PHASE_TIMING` is set
+    query_phase_duration: HistogramVec,
 }
 
 /// Storage of the data for individual deployments. Each `DeploymentStore`
@@ -133,6 +148,7 @@ impl DeploymentStore {
         pool: ConnectionPool,
         read_only_pools: Vec<ConnectionPool>,
         mut pool_weights: Vec<usize>,
+        registry: Arc<MetricsRegistry>,
     ) -> Self {
         // Create a store-specific logger
         let logger = logger.new(o!("component" => "Store"));
@@ -157,6 +173,24 @@ impl DeploymentStore {
         replica_order.shuffle(&mut rng);
         debug!(logger, "Using postgres host order {:?}", replica_order);
 
+        let param_limit_recoveries = registry
+            .global_counter(
+                "store_insert_param_limit_recoveries_total",
+                "Number of entity inserts that were split into several statements \
+                 to stay below the Postgres limit of 65535 bind variables",
+                HashMap::from([("shard".to_string(), pool.shard.to_string())]),
+            )
+            .expect("can register the store_insert_param_limit_recoveries_total counter");
+
+        let query_phase_duration = registry
+            .global_histogram_vec_with_labels(
+                "store_query_phase_duration_secs",
+                "How long building, executing and decoding relational queries takes",
+                HashMap::from([("shard".to_string(), pool.shard.to_string())]),
+                &["phase"],
+            )
+            .expect("can register the store_query_phase_duration_secs histogram");
+
         // Create the store
         let store = StoreInner {
             logger: logger.clone(),
@@ -167,6 +201,9 @@ impl DeploymentStore {
             subgraph_cache: Mutex::new(LruCache::with_capacity(100)),
             layout_cache: LayoutCache::new(ENV_VARS.store.query_stats_refresh_interval),
             prune_handles: Mutex::new(HashMap::new()),
+            prune_throttle: PruneThrottle::new(ENV_VARS.store.prune_min_interval),
+            param_limit_recoveries,
+            query_phase_duration,
         };
 
         DeploymentStore(Arc::new(store))
@@ -267,6 +304,7 @@ impl DeploymentStore {
             if !site.schema_version.private_data_sources() {
                 crate::dynds::shared::drop(conn, &site.deployment)?;
             }
+            maintenance_log::drop_history(conn, &site.deployment)?;
             crate::deployment::drop_metadata(conn, site)
         })
     }
@@ -283,7 +321,12 @@ impl DeploymentStore {
             .logger
             .cheap_clone()
             .unwrap_or_else(|| self.logger.cheap_clone());
-        layout.query(&logger, conn, query)
+        if !ENV_VARS.store.query_phase_timing {
+            return layout.query(&logger, conn, query);
+        }
+        let (values, trace, phases) = layout.query_timed(&logger, conn, query)?;
+        phases.record(&logger, &self.query_phase_duration);
+        Ok((values, trace))
     }
 
     fn check_intf_uniqueness(
@@ -332,7 +375,9 @@ impl DeploymentStore {
             section.end();
 
             let section = stopwatch.start_section("apply_entity_modifications_insert");
-            layout.insert(conn, group, stopwatch)?;
+            if layout.insert(conn, group, stopwatch)? > 1 {
+                self.param_limit_recoveries.inc();
+            }
             section.end();
         }
 
@@ -448,12 +493,8 @@ impl DeploymentStore {
     /// connection for the lookup and should only be called if the caller
     /// does not have a connection currently. If it does, use `layout`
     pub(crate) fn find_layout(&self, site: Arc<Site>) -> Result<Arc<Layout>, StoreError> {
-        if let Some(layout) = self.layout_cache.find(site.as_ref()) {
-            return Ok(layout);
-        }
-
-        let mut conn = self.get_conn()?;
-        self.layout(&mut conn, site)
+        self.layout_cache
+            .find_or_load(&self.logger, site, || self.get_conn())
     }
 
     fn subgraph_info_with_conn(
@@ -610,6 +651,7 @@ impl DeploymentStore {
         delete from subgraphs.subgraph_manifest;
         delete from subgraphs.copy_table_state;
         delete from subgraphs.copy_state;
+        delete from subgraphs.maintenance_history;
         delete from active_copies;
     ";
 
@@ -627,6 +669,15 @@ impl DeploymentStore {
         .await
     }
 
+    /// Keep the idle timeout from closing the minimum number of idle
+    /// connections in the main pool and in all replicas
+    pub(crate) fn keep_min_idle(&self) {
+        self.pool.keep_min_idle();
+        for pool in &self.read_only_pools {
+            pool.keep_min_idle();
+        }
+    }
+
     /// Runs the SQL `ANALYZE` command in a table.
     pub(crate) fn analyze(&self, site: Arc<Site>, entity: Option<&str>) -> Result<(), StoreError> {
         let mut conn = self.get_conn()?;
@@ -824,27 +875,38 @@ impl DeploymentStore {
         site: Arc<Site>,
         req: PruneRequest,
     ) -> Result<Box<dyn PruneReporter>, StoreError> {
-        fn do_prune(
-            store: Arc<DeploymentStore>,
-            mut conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+        fn prune_plan(
+            store: &DeploymentStore,
+            conn: &mut PgConnection,
             site: Arc<Site>,
             cancel: &CancelHandle,
             req: PruneRequest,
-            mut reporter: Box<dyn PruneReporter>,
-        ) -> Result<Box<dyn PruneReporter>, CancelableError<StoreError>> {
-            let layout = store.layout(&mut conn, site.clone())?;
+            reporter: &mut dyn PruneReporter,
+        ) -> Result<Option<PrunePlan>, CancelableError<StoreError>> {
+            let layout = store.layout(conn, site.clone())?;
             cancel.check_cancel()?;
-            let state = deployment::state(&mut conn, site.deployment.clone())?;
+            let state = deployment::state(conn, site.deployment.clone())?;
 
             if state.latest_block.number <= req.history_blocks {
                 // We haven't accumulated enough history yet, nothing to prune
-                return Ok(reporter);
+                return Ok(None);
             }
 
             if state.earliest_block_number > req.earliest_block {
                 // We already have less history than we need (e.g., because
                 // of a manual onetime prune), nothing to prune
-                return Ok(reporter);
+                return Ok(None);
+            }
+
+            if ENV_VARS.store.prune_defer_during_copy && copy::is_destination(conn, &site)? {
+                // Pruning would change the data that the copy is writing
+                // into; pruning runs again once the copy has finished
+                info!(
+                    store.logger,
+                    "Deferring pruning since a copy into this deployment is in progress";
+                    "deployment" => site.deployment.as_str()
+                );
+                return Ok(None);
             }
 
             conn.transaction(|conn| {
@@ -853,22 +915,109 @@ impl DeploymentStore {
 
             cancel.check_cancel()?;
 
-            layout.prune(&store.logger, reporter.as_mut(), &mut conn, &req, cancel)?;
-            Ok(reporter)
+            layout.prune_plan(conn, reporter, &req, cancel)
+        }
+
+        /// Run `f` while holding the lock for pruning `site`, with the
+        /// settings for pruning applied to `conn`. Returns `None` without
+        /// running `f` if another node is pruning `site`.
+        ///
+        /// We lock pruning for this deployment to make sure that if the
+        /// deployment is reassigned to another node, that node won't kick
+        /// off a pruning run while this node might still be pruning
+        fn with_prune_lock<T>(
+            conn: &mut PgConnection,
+            site: &Site,
+            f: impl FnOnce(&mut PgConnection) -> Result<T, CancelableError<StoreError>>,
+        ) -> Result<Option<T>, CancelableError<StoreError>> {
+            if !advisory_lock::try_lock_pruning(conn, site)? {
+                return Ok(None);
+            }
+            let work_mem = ENV_VARS.store.maintenance_work_mem.as_deref();
+            let timeout = ENV_VARS.store.prune_timeout;
+            let res = catalog::set_maintenance_work_mem(conn, work_mem)
+                .and_then(|()| catalog::set_statement_timeout(conn, timeout))
+                .map_err(CancelableError::from)
+                .and_then(|()| f(conn));
+            // Undo the settings and release the lock regardless of whether
+            // pruning or any of these steps failed so that none of them
+            // linger on the pooled connection. Errors from pruning take
+            // precedence over errors from cleaning up
+            let reset_timeout = catalog::reset_statement_timeout(conn, timeout);
+            let reset_work_mem = catalog::reset_maintenance_work_mem(conn, work_mem);
+            let unlock = advisory_lock::unlock_pruning(conn, site);
+            let cleanup = reset_timeout.and(reset_work_mem).and(unlock);
+            res.and_then(|t| cleanup.map(|()| Some(t)).map_err(CancelableError::from))
+        }
+
+        let store = self.clone();
+        let site2 = site.cheap_clone();
+        let (mut reporter, plan) = self
+            .with_conn(move |conn, cancel| {
+                let mut reporter = reporter;
+                let plan = with_prune_lock(conn, &site2, |conn| {
+                    prune_plan(
+                        &store,
+                        conn,
+                        site2.cheap_clone(),
+                        cancel,
+                        req,
+                        reporter.as_mut(),
+                    )
+                })?;
+                Ok((reporter, plan.flatten()))
+            })
+            .await?;
+        let Some(mut plan) = plan else {
+            return Ok(reporter);
+        };
+
+        // Prune one table at a time, and wait for our turn for each table
+        // before taking a connection from the pool so that deployments take
+        // turns pruning their tables and do not tie up connections while
+        // they wait
+        for index in 0..plan.table_count() {
+            let deployment = site.id;
+            let slot = graph::spawn_blocking_allow_panic(move || acquire_prune_slot(deployment))
+                .await
+                .unwrap(); // Propagate panics, there shouldn't be any.
+
+            let store = self.clone();
+            let site = site.cheap_clone();
+            let (next_reporter, next_plan) = self
+                .with_conn(move |conn, cancel| {
+                    let _slot = slot;
+                    let (mut reporter, mut plan) = (reporter, plan);
+                    let layout = store.layout(conn, site.cheap_clone())?;
+                    let pruned = with_prune_lock(conn, &site, |conn| {
+                        layout.prune_table(
+                            &store.logger,
+                            reporter.as_mut(),
+                            conn,
+                            &mut plan,
+                            index,
+                            cancel,
+                        )
+                    })?;
+                    Ok((reporter, pruned.map(|()| plan)))
+                })
+                .await?;
+            reporter = next_reporter;
+            let Some(next_plan) = next_plan else {
+                // Another node started pruning this deployment
+                return Ok(reporter);
+            };
+            plan = next_plan;
         }
 
         let store = self.clone();
         self.with_conn(move |conn, cancel| {
-            // We lock pruning for this deployment to make sure that if the
-            // deployment is reassigned to another node, that node won't
-            // kick off a pruning run while this node might still be pruning
-            if advisory_lock::try_lock_pruning(conn, &site)? {
-                let res = do_prune(store, conn, site.cheap_clone(), cancel, req, reporter);
-                advisory_lock::unlock_pruning(conn, &site)?;
-                res
-            } else {
-                Ok(reporter)
-            }
+            let mut reporter = reporter;
+            let layout = store.layout(conn, site.cheap_clone())?;
+            with_prune_lock(conn, &site, |conn| {
+                layout.finish_prune(conn, reporter.as_mut(), plan, cancel)
+            })?;
+            Ok(reporter)
         })
         .await
     }
@@ -1255,6 +1404,11 @@ impl DeploymentStore {
         }
 
         if !prune_in_progress(&self, &site)? {
+            // This is checked for every block once the deployment needs
+            // pruning, so don't log anything here
+            if !self.prune_throttle.try_start(site.id, Instant::now()) {
+                return Ok(());
+            }
             let req = PruneRequest::new(
                 &site.as_ref().into(),
                 history_blocks,
@@ -1601,6 +1755,8 @@ impl DeploymentStore {
         }
 
         let mut conn = self.get_conn()?;
+        dst.check_schema_drift(logger, &mut conn)?;
+
         if ENV_VARS.postpone_attribute_index_creation {
             // check if all indexes are valid and recreate them if they aren't
             self.load_indexes(site.clone())?
@@ -1793,6 +1949,14 @@ impl DeploymentStore {
         deployment::error_count(&mut conn, id)
     }
 
+    pub(crate) fn maintenance_history(
+        &self,
+        id: &DeploymentHash,
+    ) -> Result<Vec<MaintenanceRecord>, StoreError> {
+        let mut conn = self.get_conn()?;
+        maintenance_log::history(&mut conn, id)
+    }
+
     pub(crate) async fn mirror_primary_tables(&self, logger: &Logger) {
         self.pool.mirror_primary_tables().await.unwrap_or_else(|e| {
             warn!(logger, "Mirroring primary tables failed. We will try again in a few minutes";
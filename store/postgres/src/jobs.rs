@@ -6,11 +6,14 @@ use std::time::{Duration, Instant};
 use async_trait::async_trait;
 use diesel::{prelude::RunQueryDsl, sql_query, sql_types::Double};
 
-use graph::prelude::{error, Logger, MetricsRegistry, StoreError, ENV_VARS};
+use graph::components::store::DeploymentLocator;
+use graph::env::GinIndexMode;
+use graph::prelude::{error, info, Logger, MetricsRegistry, StoreError, ENV_VARS};
 use graph::prometheus::Gauge;
 use graph::util::jobs::{Job, Runner};
 
-use crate::connection_pool::ConnectionPool;
+use crate::connection_pool::{keep_min_idle_interval, ConnectionPool};
+use crate::relational::{gin::ARRAY_QUERIES, index::Method};
 use crate::{unused, Store, SubgraphStore};
 
 pub fn register(
@@ -37,6 +40,13 @@ pub fn register(
         15 * ONE_MINUTE,
     );
 
+    if ENV_VARS.store.idle_timeout_respects_min_idle {
+        runner.register(
+            Arc::new(KeepMinIdle::new(store.subgraph_store())),
+            keep_min_idle_interval(ENV_VARS.store.connection_idle_timeout),
+        );
+    }
+
     // Remove unused deployments every 2 hours
     runner.register(
         Arc::new(UnusedJob::new(store.subgraph_store())),
@@ -47,6 +57,13 @@ pub fn register(
         Arc::new(RefreshMaterializedView::new(store.subgraph_store())),
         6 * ONE_HOUR,
     );
+
+    if ENV_VARS.store.create_gin_indexes == GinIndexMode::Auto {
+        runner.register(
+            Arc::new(CreateGinIndexes::new(store.subgraph_store())),
+            ONE_MINUTE,
+        );
+    }
 }
 
 /// A job that vacuums `subgraphs.subgraph_deployment`. With a large number
@@ -156,6 +173,32 @@ impl Job for MirrorPrimary {
     }
 }
 
+/// A job that keeps the idle timeout of connection pools from closing the
+/// connections that pools keep idle because of `min_idle`
+struct KeepMinIdle {
+    store: Arc<SubgraphStore>,
+}
+
+impl KeepMinIdle {
+    fn new(store: Arc<SubgraphStore>) -> KeepMinIdle {
+        KeepMinIdle { store }
+    }
+}
+
+#[async_trait]
+impl Job for KeepMinIdle {
+    fn name(&self) -> &str {
+        "Keep the minimum number of idle connections open"
+    }
+
+    async fn run(&self, _: &Logger) {
+        let store = self.store.clone();
+        graph::spawn_blocking_allow_panic(move || store.keep_min_idle())
+            .await
+            .ok();
+    }
+}
+
 struct RefreshMaterializedView {
     store: Arc<SubgraphStore>,
 }
@@ -177,6 +220,53 @@ impl Job for RefreshMaterializedView {
     }
 }
 
+/// A job that creates GIN indexes for the array attributes that have been
+/// used in enough array containment queries when
+/// `GRAPH_STORE_CREATE_GIN_INDEXES` is `auto`
+struct CreateGinIndexes {
+    store: Arc<SubgraphStore>,
+}
+
+impl CreateGinIndexes {
+    fn new(store: Arc<SubgraphStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl Job for CreateGinIndexes {
+    fn name(&self) -> &str {
+        "Create GIN indexes for frequently queried array attributes"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        for column in ARRAY_QUERIES.take_pending() {
+            let loc = DeploymentLocator::from(column.site.as_ref());
+            info!(logger, "Creating GIN index for frequently queried array attribute";
+                  "deployment" => column.site.deployment.to_string(),
+                  "entity" => &column.entity,
+                  "column" => &column.column);
+            if let Err(e) = self
+                .store
+                .create_manual_index(
+                    &loc,
+                    &column.entity,
+                    vec![column.column.clone()],
+                    Method::Gin,
+                    None,
+                )
+                .await
+            {
+                error!(logger, "Failed to create GIN index";
+                       "deployment" => column.site.deployment.to_string(),
+                       "entity" => &column.entity,
+                       "column" => &column.column,
+                       "error" => e.to_string());
+            }
+        }
+    }
+}
+
 struct UnusedJob {
     store: Arc<SubgraphStore>,
 }
@@ -194,7 +284,8 @@ impl Job for UnusedJob {
     }
 
     /// Record unused deployments and remove ones that were recorded at
-    /// least `UNUSED_INTERVAL` ago
+    /// least `UNUSED_INTERVAL` ago, and have been unused for at least
+    /// `REMOVE_UNUSED_MIN_AGE`
     async fn run(&self, logger: &Logger) {
         // Work on removing about 5 minutes
         const REMOVAL_DEADLINE: Duration = Duration::from_secs(5 * 60);
@@ -209,7 +300,7 @@ impl Job for UnusedJob {
         let remove = match self
             .store
             .list_unused_deployments(unused::Filter::UnusedLongerThan(
-                ENV_VARS.store.remove_unused_interval,
+                ENV_VARS.store.remove_unused_after(),
             )) {
             Ok(remove) => remove,
             Err(e) => {
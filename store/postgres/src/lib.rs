@@ -17,6 +17,7 @@ mod block_store;
 mod catalog;
 mod chain_head_listener;
 mod chain_store;
+mod clock;
 pub mod connection_pool;
 mod copy;
 mod deployment;
@@ -26,6 +27,7 @@ mod dynds;
 mod fork;
 mod functions;
 mod jobs;
+mod maintenance_log;
 mod notification_listener;
 mod primary;
 pub mod query_store;
@@ -45,13 +47,21 @@ pub mod graphman;
 pub mod layout_for_tests {
     pub use crate::block_range::*;
     pub use crate::block_store::FAKE_NETWORK_SHARED;
-    pub use crate::catalog::set_account_like;
+    pub use crate::catalog::{
+        reset_maintenance_work_mem, reset_statement_timeout, set_account_like,
+        set_maintenance_work_mem, set_statement_timeout,
+    };
+    pub use crate::clock::{MockClock, MOCK_CLOCK};
+    #[cfg(debug_assertions)]
+    pub use crate::maintenance_log::MAINTENANCE_HISTORY_ENABLED;
     pub use crate::primary::{
         make_dummy_site, Connection, Mirror, Namespace, EVENT_TAP, EVENT_TAP_ENABLED,
     };
+    #[cfg(debug_assertions)]
+    pub use crate::relational::rollup::ROLLUP_RESULT_CACHE_TTL;
     pub use crate::relational::*;
     pub mod writable {
-        pub use crate::writable::test_support::allow_steps;
+        pub use crate::writable::test_support::{allow_steps, BATCH_REORG_POLICY};
     }
 }
 
@@ -0,0 +1,239 @@
+//! Structured events for completed maintenance operations. When
+//! `GRAPH_STORE_MAINTENANCE_EVENT_LOG` is set, pruning and copying log one
+//! JSON line for each table they finish so that the durations can be
+//! collected for capacity planning. When `GRAPH_STORE_MAINTENANCE_HISTORY`
+//! is set, they also record each event in `subgraphs.maintenance_history`
+use std::time::Duration;
+
+use diesel::{
+    sql_query,
+    sql_types::{BigInt, Double, Text, Timestamptz},
+    Connection, PgConnection, RunQueryDsl,
+};
+use graph::components::store::MaintenanceRecord;
+use graph::prelude::{
+    chrono::{DateTime, Utc},
+    info,
+    serde_json::{self, json},
+    warn, DeploymentHash, Logger, StoreError, ENV_VARS,
+};
+
+#[cfg(debug_assertions)]
+lazy_static::lazy_static! {
+    /// Tests set this to true so that events are recorded in the
+    /// maintenance history even if `GRAPH_STORE_MAINTENANCE_HISTORY` is not
+    /// set
+    pub static ref MAINTENANCE_HISTORY_ENABLED: std::sync::Mutex<bool> =
+        std::sync::Mutex::new(false);
+}
+
+use crate::{catalog, relational::Table};
+
+/// The kind of maintenance operation
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Operation {
+    Prune,
+    Copy,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Prune => "prune",
+            Operation::Copy => "copy",
+        }
+    }
+}
+
+/// The outcome of a maintenance operation for one table
+pub(crate) struct MaintenanceEvent<'a> {
+    pub operation: Operation,
+    pub deployment: &'a DeploymentHash,
+    pub table: &'a str,
+    /// The number of rows that were copied or deleted
+    pub rows: usize,
+    /// The size of the table after the operation
+    pub bytes: i64,
+    pub duration: Duration,
+    /// How the operation was performed, e.g., `rebuild` or `delete` for
+    /// pruning
+    pub strategy: &'a str,
+}
+
+impl<'a> MaintenanceEvent<'a> {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "event": "maintenance",
+            "operation": self.operation.as_str(),
+            "deployment": self.deployment.as_str(),
+            "table": self.table,
+            "rows": self.rows,
+            "bytes": self.bytes,
+            "duration_ms": self.duration.as_millis() as u64,
+            "strategy": self.strategy,
+        })
+    }
+}
+
+fn history_enabled() -> bool {
+    #[cfg(debug_assertions)]
+    if *MAINTENANCE_HISTORY_ENABLED.lock().unwrap() {
+        return true;
+    }
+    ENV_VARS.store.maintenance_history
+}
+
+/// Log a `MaintenanceEvent` for `table` if the event log is turned on, and
+/// record it in the maintenance history if that is turned on. The event is
+/// constructed by `event` from the size of `table` so that we only look up
+/// the size when we actually need the event
+pub(crate) fn log_event<'a, F>(logger: &Logger, conn: &mut PgConnection, table: &Table, event: F)
+where
+    F: FnOnce(i64) -> MaintenanceEvent<'a>,
+{
+    let log = ENV_VARS.store.maintenance_event_log;
+    let history = history_enabled();
+    if !log && !history {
+        return;
+    }
+    // Failing to record the event must not fail the operation it is
+    // about. If `conn` is in a transaction, this becomes a savepoint so
+    // that an error here does not abort the outer transaction
+    let res = conn.transaction(|conn| {
+        let bytes = catalog::table_size(conn, &table.qualified_name)?;
+        let event = event(bytes);
+        if log {
+            info!(logger, "{}", event.to_json());
+        }
+        if history {
+            record(conn, &event)?;
+        }
+        Ok::<_, StoreError>(())
+    });
+    if let Err(e) = res {
+        warn!(logger, "Failed to record maintenance event";
+              "table" => table.name.as_str(),
+              "error" => e.to_string());
+    }
+}
+
+/// Add `event` to the maintenance history and remove entries that are
+/// older than `GRAPH_STORE_MAINTENANCE_HISTORY_RETENTION`. The operation is
+/// taken to have finished right now
+fn record(conn: &mut PgConnection, event: &MaintenanceEvent) -> Result<(), StoreError> {
+    const QUERY: &str = "insert into subgraphs.maintenance_history(deployment, operation, \
+                           table_name, started_at, finished_at, rows, bytes, strategy) \
+                         values ($1, $2, $3, clock_timestamp() - make_interval(secs => $4), \
+                                 clock_timestamp(), $5, $6, $7)";
+
+    sql_query(QUERY)
+        .bind::<Text, _>(event.deployment.as_str())
+        .bind::<Text, _>(event.operation.as_str())
+        .bind::<Text, _>(event.table)
+        .bind::<Double, _>(event.duration.as_secs_f64())
+        .bind::<BigInt, _>(event.rows as i64)
+        .bind::<BigInt, _>(event.bytes)
+        .bind::<Text, _>(event.strategy)
+        .execute(conn)?;
+
+    if let Some(retention) = ENV_VARS.store.maintenance_history_retention {
+        sql_query(
+            "delete from subgraphs.maintenance_history \
+              where finished_at < clock_timestamp() - make_interval(secs => $1)",
+        )
+        .bind::<Double, _>(retention.as_secs_f64())
+        .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Remove the maintenance history for `deployment`
+pub(crate) fn drop_history(
+    conn: &mut PgConnection,
+    deployment: &DeploymentHash,
+) -> Result<(), StoreError> {
+    sql_query("delete from subgraphs.maintenance_history where deployment = $1")
+        .bind::<Text, _>(deployment.as_str())
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Return the maintenance history for `deployment`, oldest first
+pub(crate) fn history(
+    conn: &mut PgConnection,
+    deployment: &DeploymentHash,
+) -> Result<Vec<MaintenanceRecord>, StoreError> {
+    const QUERY: &str = "select operation, table_name, started_at, finished_at, \
+                                rows, bytes, strategy \
+                           from subgraphs.maintenance_history \
+                          where deployment = $1 \
+                          order by id";
+
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Text)]
+        operation: String,
+        #[diesel(sql_type = Text)]
+        table_name: String,
+        #[diesel(sql_type = Timestamptz)]
+        started_at: DateTime<Utc>,
+        #[diesel(sql_type = Timestamptz)]
+        finished_at: DateTime<Utc>,
+        #[diesel(sql_type = BigInt)]
+        rows: i64,
+        #[diesel(sql_type = BigInt)]
+        bytes: i64,
+        #[diesel(sql_type = Text)]
+        strategy: String,
+    }
+
+    let records = sql_query(QUERY)
+        .bind::<Text, _>(deployment.as_str())
+        .get_results::<Row>(conn)?
+        .into_iter()
+        .map(|row| MaintenanceRecord {
+            operation: row.operation,
+            table: row.table_name,
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+            rows: row.rows,
+            bytes: row.bytes,
+            strategy: row.strategy,
+        })
+        .collect();
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_event() {
+        let deployment = DeploymentHash::new("QmPruned").unwrap();
+        let event = MaintenanceEvent {
+            operation: Operation::Prune,
+            deployment: &deployment,
+            table: "token",
+            rows: 1234,
+            bytes: 8192,
+            duration: Duration::from_millis(2500),
+            strategy: "rebuild",
+        };
+
+        let exp = json!({
+            "event": "maintenance",
+            "operation": "prune",
+            "deployment": "QmPruned",
+            "table": "token",
+            "rows": 1234,
+            "bytes": 8192,
+            "duration_ms": 2500,
+            "strategy": "rebuild",
+        });
+        assert_eq!(exp, event.to_json());
+
+        // The event is logged as a single line
+        assert!(!event.to_json().to_string().contains('\n'));
+    }
+}
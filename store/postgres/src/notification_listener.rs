@@ -1,6 +1,8 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use diesel::pg::PgConnection;
 use diesel::select;
 use diesel::sql_types::Text;
+use graph::env::NotificationCodec;
 use graph::prelude::tokio::sync::mpsc::error::SendTimeoutError;
 use graph::util::backoff::ExponentialBackoff;
 use lazy_static::lazy_static;
@@ -8,11 +10,11 @@ use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres::Notification;
 use postgres::{fallible_iterator::FallibleIterator, Client};
 use postgres_openssl::MakeTlsConnector;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use graph::prelude::serde_json;
 use graph::prelude::*;
@@ -54,6 +56,9 @@ pub struct NotificationListener {
     terminate_worker: Arc<AtomicBool>,
     worker_barrier: Arc<Barrier>,
     started: bool,
+    /// How many notifications were dropped because more than
+    /// `GRAPH_STORE_MAX_PENDING_NOTIFICATIONS` were waiting to be processed
+    dropped: Arc<AtomicUsize>,
 }
 
 impl NotificationListener {
@@ -71,9 +76,25 @@ impl NotificationListener {
         postgres_url: String,
         channel_name: SafeChannelName,
     ) -> (Self, Receiver<JsonNotification>) {
+        // Create a boolean variable for signalling when the worker thread
+        // should be terminated
+        let terminate_worker = Arc::new(AtomicBool::new(false));
+        let worker_barrier = Arc::new(Barrier::new(2));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        // Create a channel for notifications
+        let (sender, receiver) = channel(100);
+
         // Listen to Postgres notifications in a worker thread
-        let (receiver, worker_handle, terminate_worker, worker_barrier) =
-            Self::listen(logger, postgres_url, channel_name);
+        let worker_handle = Self::listen(
+            logger,
+            postgres_url,
+            channel_name,
+            sender,
+            terminate_worker.clone(),
+            worker_barrier.clone(),
+            dropped.clone(),
+        );
 
         (
             NotificationListener {
@@ -81,6 +102,7 @@ impl NotificationListener {
                 terminate_worker,
                 worker_barrier,
                 started: false,
+                dropped,
             },
             receiver,
         )
@@ -95,16 +117,21 @@ impl NotificationListener {
         }
     }
 
+    /// The number of notifications that were dropped because consumers
+    /// could not keep up with them
+    pub fn dropped_notifications(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
     fn listen(
         logger: &Logger,
         postgres_url: String,
         channel_name: SafeChannelName,
-    ) -> (
-        Receiver<JsonNotification>,
-        thread::JoinHandle<()>,
-        Arc<AtomicBool>,
-        Arc<Barrier>,
-    ) {
+        sender: Sender<JsonNotification>,
+        terminate: Arc<AtomicBool>,
+        barrier: Arc<Barrier>,
+        dropped: Arc<AtomicUsize>,
+    ) -> thread::JoinHandle<()> {
         /// Connect to the database at `postgres_url` and do a `LISTEN
         /// {channel_name}`. If that fails, retry with exponential backoff
         /// with a delay between 1s and 32s
@@ -158,17 +185,7 @@ impl NotificationListener {
             ENV_VARS.store.large_notification_cleanup_interval.as_secs(),
         );
 
-        // Create two ends of a boolean variable for signalling when the worker
-        // thread should be terminated
-        let terminate = Arc::new(AtomicBool::new(false));
-        let terminate_worker = terminate.clone();
-        let barrier = Arc::new(Barrier::new(2));
-        let worker_barrier = barrier.clone();
-
-        // Create a channel for notifications
-        let (sender, receiver) = channel(100);
-
-        let worker_handle = graph::spawn_thread("notification_listener", move || {
+        graph::spawn_thread("notification_listener", move || {
             // We exit the process on panic so unwind safety is irrelevant.
             std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
                 let mut connected = true;
@@ -203,6 +220,23 @@ impl NotificationListener {
                     }
                     max_queue_size_seen = queue_size.max(max_queue_size_seen);
 
+                    // If consumers can not keep up, do not let the
+                    // notifications that are waiting for them pile up
+                    // without bounds
+                    let max_pending = ENV_VARS.store.max_pending_notifications;
+                    let count = drop_oldest(
+                        conn.notifications().iter().iterator(),
+                        queue_size,
+                        max_pending,
+                        &dropped,
+                    );
+                    if count > 0 {
+                        warn!(logger, "Dropped notifications because too many were pending";
+                                      "dropped" => count,
+                                      "dropped_total" => dropped.load(Ordering::SeqCst),
+                                      "max_pending" => max_pending);
+                    }
+
                     // Obtain pending notifications from Postgres. We load
                     // them all into memory, since for large notifications
                     // we need to query the database again; avoiding this
@@ -286,10 +320,21 @@ impl NotificationListener {
                 }
             }))
             .unwrap_or_else(|_| std::process::exit(1))
-        });
+        })
+    }
+}
 
-        (receiver, worker_handle, terminate_worker, worker_barrier)
+/// Drop the oldest of the `len` entries in `pending`, which must yield
+/// them oldest first, so that at most `max` remain, and add the number of
+/// dropped entries to `dropped`. With a `max` of 0, nothing is dropped.
+/// Returns how many entries were dropped
+fn drop_oldest<I: Iterator>(pending: I, len: usize, max: usize, dropped: &AtomicUsize) -> usize {
+    if max == 0 || len <= max {
+        return 0;
     }
+    let count = pending.take(len - max).count();
+    dropped.fetch_add(count, Ordering::SeqCst);
+    count
 }
 
 impl Drop for NotificationListener {
@@ -321,6 +366,12 @@ mod public {
 // via NOTIFY in place of the actual payload. Consumers of large
 // notifications are then responsible to fetch the actual payload from
 // the `large_notifications` table.
+//
+// With `GRAPH_STORE_NOTIFICATION_COMPRESS`, payloads are compressed with
+// the codec from `GRAPH_STORE_NOTIFICATION_COMPRESSION_CODEC` and sent as a
+// base64 encoded JSON string so that more of them fit into a NOTIFY
+// payload. The first byte of the compressed data identifies the codec so
+// that listeners can decompress payloads regardless of their own settings.
 #[derive(Debug)]
 pub struct JsonNotification {
     pub payload: serde_json::Value,
@@ -331,12 +382,16 @@ pub struct JsonNotification {
 // its id in the table will be sent via `notify`
 static LARGE_NOTIFICATION_THRESHOLD: usize = 7800;
 
+/// The header bytes that identify the codec of a compressed payload
+const ZSTD_HEADER: u8 = 1;
+const LZ4_HEADER: u8 = 2;
+
 impl JsonNotification {
     pub fn parse(
         notification: &Notification,
         conn: &mut Client,
     ) -> Result<JsonNotification, StoreError> {
-        let value = serde_json::from_str(notification.payload())?;
+        let value = Self::decode(notification.payload())?;
 
         match value {
             serde_json::Value::Number(n) => {
@@ -371,13 +426,63 @@ impl JsonNotification {
                 let payload: String = payload_rows.get(0).unwrap().get(0);
 
                 Ok(JsonNotification {
-                    payload: serde_json::from_str(&payload)?,
+                    payload: Self::decode(&payload)?,
                 })
             }
             serde_json::Value::Object(_) => Ok(JsonNotification { payload: value }),
             _ => Err(anyhow!("JSON notifications must be numbers or objects"))?,
         }
     }
+
+    /// Turn `data` into the text that is sent as the notification,
+    /// compressing it with `codec`
+    fn encode(data: &serde_json::Value, codec: NotificationCodec) -> Result<String, StoreError> {
+        let msg = data.to_string();
+        let compressed = match codec {
+            NotificationCodec::None => return Ok(msg),
+            NotificationCodec::Zstd => {
+                let mut compressed = vec![ZSTD_HEADER];
+                zstd::stream::copy_encode(msg.as_bytes(), &mut compressed, 0)
+                    .map_err(|e| anyhow!("Error compressing notification: {}", e))?;
+                compressed
+            }
+            NotificationCodec::Lz4 => {
+                let mut compressed = vec![LZ4_HEADER];
+                compressed.extend(lz4_flex::compress_prepend_size(msg.as_bytes()));
+                compressed
+            }
+        };
+        Ok(serde_json::Value::String(STANDARD.encode(compressed)).to_string())
+    }
+
+    /// Parse the text of a notification, decompressing it if it was
+    /// compressed by `encode`
+    fn decode(text: &str) -> Result<serde_json::Value, StoreError> {
+        match serde_json::from_str(text)? {
+            serde_json::Value::String(encoded) => {
+                let compressed = STANDARD
+                    .decode(encoded)
+                    .map_err(|e| anyhow!("Invalid compressed notification: {}", e))?;
+                let msg = match compressed.split_first() {
+                    Some((&ZSTD_HEADER, data)) => zstd::decode_all(data)
+                        .map_err(|e| anyhow!("Error decompressing notification: {}", e))?,
+                    Some((&LZ4_HEADER, data)) => lz4_flex::decompress_size_prepended(data)
+                        .map_err(|e| anyhow!("Error decompressing notification: {}", e))?,
+                    Some((header, _)) => {
+                        return Err(anyhow!("Unknown notification codec {}", header).into())
+                    }
+                    None => return Err(anyhow!("Empty compressed notification").into()),
+                };
+                Ok(serde_json::from_slice(&msg)?)
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// Whether `msg` is too big to be sent directly with `NOTIFY`
+    fn is_large(msg: &str) -> bool {
+        msg.len() > LARGE_NOTIFICATION_THRESHOLD
+    }
 }
 
 /// Send notifications via `pg_notify`. All sending of notifications through
@@ -411,6 +516,7 @@ impl NotificationSender {
         data: &serde_json::Value,
     ) -> Result<(), StoreError> {
         use diesel::ExpressionMethods;
+        use diesel::QueryDsl;
         use diesel::RunQueryDsl;
         use public::large_notifications::dsl::*;
 
@@ -418,9 +524,14 @@ impl NotificationSender {
             fn pg_notify(channel: Text, msg: Text)
         }
 
-        let msg = data.to_string();
+        let codec = if ENV_VARS.store.notification_compress {
+            ENV_VARS.store.notification_compression_codec
+        } else {
+            NotificationCodec::None
+        };
+        let msg = JsonNotification::encode(data, codec)?;
 
-        if msg.len() <= LARGE_NOTIFICATION_THRESHOLD {
+        if !JsonNotification::is_large(&msg) {
             select(pg_notify(channel, &msg)).execute(conn)?;
         } else {
             // Write the notification payload to the large_notifications table
@@ -449,6 +560,14 @@ impl NotificationSender {
             // If we can't get the lock, another thread in this process is
             // already checking, and we can just skip checking
             if let Ok(mut last_check) = LAST_CLEANUP_CHECK.try_lock() {
+                // Ids are handed out in increasing order, and deleting
+                // everything that is more than `max_rows` older than the
+                // row we just wrote keeps the table at that many rows
+                // without having to count them
+                let max_rows = ENV_VARS.store.large_notification_max_rows;
+                if let Some(max_id) = max_deleted_id(payload_id, max_rows) {
+                    diesel::delete(large_notifications.filter(id.le(max_id))).execute(conn)?;
+                }
                 if last_check.elapsed() > ENV_VARS.store.large_notification_cleanup_interval {
                     diesel::sql_query(format!(
                         "delete from large_notifications
@@ -466,3 +585,118 @@ impl NotificationSender {
         Ok(())
     }
 }
+
+/// The largest id of the large notifications that need to be deleted so
+/// that at most `max_rows` rows up to `newest_id` remain, or `None` if
+/// nothing needs to be deleted. A `max_rows` of 0 means that the table can
+/// have any number of rows
+fn max_deleted_id(newest_id: i32, max_rows: usize) -> Option<i32> {
+    if max_rows == 0 {
+        return None;
+    }
+    let max_id = i64::from(newest_id) - max_rows as i64;
+    (max_id > 0).then(|| max_id as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(entries: usize) -> serde_json::Value {
+        let changes: Vec<_> = (0..entries)
+            .map(|i| serde_json::json!({ "entity_type": "Token", "id": format!("token-{i}") }))
+            .collect();
+        serde_json::json!({ "changes": changes })
+    }
+
+    #[test]
+    fn max_pending_notifications() {
+        let dropped = AtomicUsize::new(0);
+
+        // Exceeding the limit drops the oldest notifications
+        let mut pending = (0..10).collect::<Vec<_>>().into_iter();
+        assert_eq!(6, drop_oldest(pending.by_ref(), 10, 4, &dropped));
+        assert_eq!(vec![6, 7, 8, 9], pending.collect::<Vec<_>>());
+        assert_eq!(6, dropped.load(Ordering::SeqCst));
+
+        // Staying within the limit, or not having a limit, drops nothing
+        let mut pending = (0..4).collect::<Vec<_>>().into_iter();
+        assert_eq!(0, drop_oldest(pending.by_ref(), 4, 4, &dropped));
+        assert_eq!(0, drop_oldest(pending.by_ref(), 4, 0, &dropped));
+        assert_eq!(vec![0, 1, 2, 3], pending.collect::<Vec<_>>());
+
+        // The counter keeps counting across calls
+        let pending = (0..5).collect::<Vec<_>>().into_iter();
+        assert_eq!(2, drop_oldest(pending, 5, 3, &dropped));
+        assert_eq!(8, dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn large_notification_max_rows() {
+        // Without a cap, nothing is deleted
+        assert_eq!(None, max_deleted_id(1_000_000, 0));
+
+        // Nothing is deleted while the table can not have more rows than
+        // the cap
+        assert_eq!(None, max_deleted_id(100, 100));
+
+        // Otherwise, the oldest rows are deleted so that `max_rows` remain
+        assert_eq!(Some(1), max_deleted_id(101, 100));
+        assert_eq!(Some(900), max_deleted_id(1000, 100));
+        assert_eq!(Some(i32::MAX - 1), max_deleted_id(i32::MAX, 1));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let data = payload(10);
+
+        let msg = JsonNotification::encode(&data, NotificationCodec::Zstd).unwrap();
+        assert!(msg.starts_with('"'));
+        assert_eq!(data, JsonNotification::decode(&msg).unwrap());
+
+        // Uncompressed notifications are still understood
+        let msg = JsonNotification::encode(&data, NotificationCodec::None).unwrap();
+        assert_eq!(data, JsonNotification::decode(&msg).unwrap());
+    }
+
+    #[test]
+    fn notification_compression_codec() {
+        let data = payload(300);
+
+        for (codec, header) in [
+            (NotificationCodec::Zstd, Some(ZSTD_HEADER)),
+            (NotificationCodec::Lz4, Some(LZ4_HEADER)),
+            (NotificationCodec::None, None),
+        ] {
+            let msg = JsonNotification::encode(&data, codec).unwrap();
+            assert_eq!(data, JsonNotification::decode(&msg).unwrap(), "{codec:?}");
+
+            // The codec is recorded in the first byte of the payload
+            let first = serde_json::from_str::<String>(&msg)
+                .ok()
+                .map(|encoded| STANDARD.decode(encoded).unwrap()[0]);
+            assert_eq!(header, first, "{codec:?}");
+        }
+
+        // Payloads with an unknown codec are rejected
+        let msg = serde_json::Value::String(STANDARD.encode([42u8, 0, 0])).to_string();
+        assert!(JsonNotification::decode(&msg).is_err());
+    }
+
+    #[test]
+    fn compressed_size_boundary() {
+        let inline = |msg: &str| !JsonNotification::is_large(msg);
+
+        assert!(inline(&"x".repeat(LARGE_NOTIFICATION_THRESHOLD)));
+        assert!(!inline(&"x".repeat(LARGE_NOTIFICATION_THRESHOLD + 1)));
+
+        // A payload that is too big to be sent inline fits once it is
+        // compressed
+        let data = payload(300);
+        let plain = JsonNotification::encode(&data, NotificationCodec::None).unwrap();
+        let compressed = JsonNotification::encode(&data, NotificationCodec::Zstd).unwrap();
+        assert!(!inline(&plain));
+        assert!(inline(&compressed));
+        assert_eq!(data, JsonNotification::decode(&compressed).unwrap());
+    }
+}
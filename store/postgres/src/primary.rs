@@ -1554,6 +1554,36 @@ impl<'a> Connection<'a> {
             .map_err(|e| constraint_violation!("database has illegal shard name: {}", e))
     }
 
+    /// Return the number of deployments stored in each of the given
+    /// `shards`, including shards that have no deployments in them
+    pub fn deployments_per_shard(
+        &mut self,
+        shards: &[Shard],
+    ) -> Result<Vec<(Shard, i64)>, StoreError> {
+        use deployment_schemas as ds;
+
+        let counts = ds::table
+            .filter(ds::shard.eq_any(shards))
+            .filter(ds::name.ne("subgraphs"))
+            .select((ds::shard, sql::<BigInt>("count(*)")))
+            .group_by(ds::shard)
+            .load::<(String, i64)>(self.conn.as_mut())?;
+
+        let mut counts: Vec<_> = shards
+            .iter()
+            .map(|shard| {
+                let count = counts
+                    .iter()
+                    .find(|(s, _)| s == shard.as_str())
+                    .map(|(_, count)| *count)
+                    .unwrap_or(0);
+                (shard.clone(), count)
+            })
+            .collect();
+        counts.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        Ok(counts)
+    }
+
     #[cfg(debug_assertions)]
     pub fn versions_for_subgraph(
         &mut self,
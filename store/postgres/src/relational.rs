@@ -15,11 +15,14 @@ mod ddl_tests;
 mod query_tests;
 
 pub(crate) mod dsl;
+pub(crate) mod gin;
 pub(crate) mod index;
 mod prune;
-mod rollup;
+pub(crate) mod rollup;
 pub(crate) mod value;
 
+pub(crate) use prune::{acquire_prune_slot, PrunePlan, PruneThrottle};
+
 use diesel::deserialize::FromSql;
 use diesel::pg::Pg;
 use diesel::serialize::{Output, ToSql};
@@ -38,22 +41,23 @@ use graph::data::graphql::TypeExt as _;
 use graph::data::query::Trace;
 use graph::data::value::Word;
 use graph::data_source::CausalityRegion;
-use graph::prelude::{q, EntityQuery, StopwatchMetrics, ENV_VARS};
+use graph::env::SchemaDriftPolicy;
+use graph::prelude::{lazy_static, q, EntityCollection, EntityQuery, StopwatchMetrics, ENV_VARS};
+use graph::prometheus::HistogramVec;
 use graph::schema::{
     EntityKey, EntityType, Field, FulltextConfig, FulltextDefinition, InputSchema,
 };
-use graph::slog::warn;
+use graph::slog::{debug, warn};
 use index::IndexList;
 use inflector::Inflector;
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::{From, TryFrom};
 use std::fmt::{self, Write};
-use std::ops::Range;
+use std::ops::{DerefMut, Range};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::time::{Duration, Instant};
 
 use crate::relational::value::{FromOidRow, OidRow};
@@ -64,27 +68,33 @@ use crate::relational_queries::{
 use crate::{
     primary::{Namespace, Site},
     relational_queries::{
-        ClampRangeQuery, EntityData, EntityDeletion, FilterCollection, FilterQuery, FindManyQuery,
-        FindRangeQuery, InsertQuery, RevertClampQuery, RevertRemoveQuery,
+        ClampRangeQuery, EntityData, EntityDeletion, ExplainQuery, FilterCollection, FilterQuery,
+        FindManyQuery, FindRangeQuery, InsertQuery, QueryMemory, RevertClampQuery,
+        RevertRemoveQuery,
     },
 };
 use graph::components::store::{AttributeNames, DerivedEntityQuery};
+use graph::data::store::scalar::Timestamp;
 use graph::data::store::{Id, IdList, IdType, BYTES_SCALAR};
 use graph::data::subgraph::schema::POI_TABLE;
 use graph::prelude::{
     anyhow, info, BlockNumber, DeploymentHash, Entity, EntityOperation, Logger,
-    QueryExecutionError, StoreError, ValueType,
+    QueryExecutionError, StoreError, ValueType, BLOCK_NUMBER_MAX,
 };
 
-use crate::block_range::{BoundSide, BLOCK_COLUMN, BLOCK_RANGE_COLUMN};
+use crate::block_range::{BoundSide, BLOCK_COLUMN, BLOCK_RANGE_COLUMN, CAUSALITY_REGION_COLUMN};
 pub use crate::catalog::Catalog;
 use crate::connection_pool::ForeignServer;
-use crate::{catalog, deployment};
+use crate::{catalog, deployment, relational_queries};
 
-use self::rollup::Rollup;
+use self::rollup::{Rollup, RollupResultCache};
 
 const DELETE_OPERATION_CHUNK_SIZE: usize = 1_000;
 
+/// How long to wait before retrying to load a schema; the wait grows
+/// linearly with the number of attempts
+const SCHEMA_LOAD_BACKOFF: Duration = Duration::from_millis(100);
+
 /// The size of string prefixes that we index. This is chosen so that we
 /// will index strings that people will do string comparisons like
 /// `=` or `!=` on; if text longer than this is stored in a String attribute
@@ -94,13 +104,6 @@ const DELETE_OPERATION_CHUNK_SIZE: usize = 1_000;
 pub const STRING_PREFIX_SIZE: usize = 256;
 pub const BYTE_ARRAY_PREFIX_SIZE: usize = 64;
 
-lazy_static! {
-    static ref STATEMENT_TIMEOUT: Option<String> = ENV_VARS
-        .graphql
-        .sql_statement_timeout
-        .map(|duration| format!("set local statement_timeout={}", duration.as_millis()));
-}
-
 /// A string we use as a SQL name for a table or column. The important thing
 /// is that SQL names are snake cased. Using this type makes it easier to
 /// spot cases where we use a GraphQL name like 'bigThing' when we should
@@ -238,6 +241,9 @@ pub struct Layout {
 
     /// The rollups for aggregations in this layout
     rollups: Vec<Rollup>,
+
+    /// Results of queries for closed aggregation buckets
+    rollup_cache: Arc<RollupResultCache>,
 }
 
 impl Layout {
@@ -304,6 +310,7 @@ impl Layout {
             history_blocks: i32::MAX,
             input_schema: schema.cheap_clone(),
             rollups,
+            rollup_cache: Arc::new(RollupResultCache::default()),
         })
     }
 
@@ -372,6 +379,7 @@ impl Layout {
             is_account_like: false,
             immutable: false,
             has_causality_region: false,
+            brin_disabled: false,
         }
     }
 
@@ -727,12 +735,16 @@ impl Layout {
         Ok(changes)
     }
 
+    /// Insert the rows in `group` and return how many insert statements
+    /// that took. More than one statement means that the rows had to be
+    /// split into chunks to stay below the maximum number of bind variables
+    /// Postgres allows in one query
     pub fn insert<'a>(
         &'a self,
         conn: &mut PgConnection,
         group: &'a RowGroup,
         stopwatch: &StopwatchMetrics,
-    ) -> Result<(), StoreError> {
+    ) -> Result<usize, StoreError> {
         fn chunk_details(chunk: &WriteChunk) -> (BlockNumber, String) {
             let count = chunk.len();
             let first = chunk.iter().map(|row| row.block).min().unwrap_or(0);
@@ -759,6 +771,7 @@ impl Layout {
         // We insert the entities in chunks to make sure each operation does
         // not exceed the maximum number of bindings allowed in queries
         let chunk_size = InsertQuery::chunk_size(table);
+        let mut statements = 0;
         for chunk in group.write_chunks(chunk_size) {
             // Empty chunks would lead to invalid SQL
             if !chunk.is_empty() {
@@ -768,9 +781,10 @@ impl Layout {
                         let (block, msg) = chunk_details(&chunk);
                         StoreError::write_failure(e, table.object.as_str(), block, msg)
                     })?;
+                statements += 1;
             }
         }
-        Ok(())
+        Ok(statements)
     }
 
     pub fn conflicting_entities(
@@ -792,6 +806,18 @@ impl Layout {
         conn: &mut PgConnection,
         query: EntityQuery,
     ) -> Result<(Vec<T>, Trace), QueryExecutionError> {
+        self.query_timed(logger, conn, query)
+            .map(|(values, trace, _)| (values, trace))
+    }
+
+    /// Like `query`, but also return how long the phases of running the
+    /// query took
+    pub fn query_timed<T: crate::relational_queries::FromEntityData>(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+        query: EntityQuery,
+    ) -> Result<(Vec<T>, Trace, QueryPhases), QueryExecutionError> {
         fn log_query_timing(
             logger: &Logger,
             query: &FilterQuery,
@@ -834,7 +860,10 @@ impl Layout {
         }
 
         let trace = query.trace;
+        let rollup_ttl = rollup::rollup_result_cache_ttl();
+        let closed_bucket = rollup_ttl.and_then(|_| self.closed_bucket_query(&query));
 
+        let build_start = Instant::now();
         let filter_collection =
             FilterCollection::new(self, query.collection, query.filter.as_ref(), query.block)?;
         let query = FilterQuery::new(
@@ -846,56 +875,144 @@ impl Layout {
             query.block,
             query.query_id,
             &self.site,
+            ENV_VARS.store.max_query_rows,
+            ENV_VARS.store.max_order_by_columns,
         )?;
+        if query.skipped_block_range() {
+            debug!(
+                logger,
+                "Not ordering by block range since the order has too many columns";
+                "query" => query.to_string(),
+                "max_order_by_columns" => ENV_VARS.store.max_order_by_columns
+            );
+        }
 
         let query_clone = query.clone();
+        // Turning the query into a string can fail, see below; we then
+        // just do not cache the result
+        let cache_key = closed_bucket.as_ref().and_then(|_| {
+            let mut key = String::new();
+            write!(key, "{}", debug_query(&query_clone))
+                .ok()
+                .map(|()| key)
+        });
+        let cached = cache_key
+            .as_deref()
+            .zip(rollup_ttl)
+            .and_then(|(key, ttl)| self.rollup_cache.get(key, ttl));
 
         let start = Instant::now();
-        let values = conn
-            .transaction(|conn| {
-                if let Some(ref timeout_sql) = *STATEMENT_TIMEOUT {
-                    conn.batch_execute(timeout_sql)?;
-                }
-                query.load::<EntityData>(conn)
-            })
-            .map_err(|e| {
-                use diesel::result::DatabaseErrorKind;
-                use diesel::result::Error::*;
-                // Sometimes `debug_query(..)` can't be turned into a
-                // string, e.g., because `walk_ast` for one of its fragments
-                // returns an error. When that happens, avoid a panic from
-                // simply calling `to_string()` on it, and output a string
-                // representation of the `FilterQuery` instead of the SQL
-                let mut query_text = String::new();
-                match write!(query_text, "{}", debug_query(&query_clone)) {
-                    Ok(()) => (),
-                    Err(_) => {
-                        write!(query_text, "{query_clone}").ok();
+        let build = start - build_start;
+        let (values, closed) = match cached {
+            Some(values) => (values.as_ref().clone(), false),
+            None => conn
+                .transaction(|conn| {
+                    if let Some(timeout) = ENV_VARS.store.query_timeout_for(
+                        self.site.deployment.as_str(),
+                        self.site.namespace.as_str(),
+                        ENV_VARS.graphql.sql_statement_timeout,
+                    ) {
+                        conn.batch_execute(&format!(
+                            "set local statement_timeout={}",
+                            timeout.as_millis()
+                        ))?;
                     }
-                };
-                match e {
-                    DatabaseError(DatabaseErrorKind::Unknown, ref info)
-                        if info.message().starts_with("syntax error in tsquery") =>
-                    {
-                        QueryExecutionError::FulltextQueryInvalidSyntax(info.message().to_string())
+                    let min_cost = ENV_VARS.store.parallel_query_min_cost;
+                    let seqscan_threshold = ENV_VARS.store.block_range_seqscan_threshold;
+                    if min_cost > 0.0 || seqscan_threshold > 0 {
+                        if let Some(plan) = ExplainQuery::new(&query).plan(conn)? {
+                            if min_cost > 0.0 {
+                                if let Some(parallel_sql) =
+                                    parallel_query_sql(plan.total_cost()?, min_cost)
+                                {
+                                    conn.batch_execute(&parallel_sql)?;
+                                }
+                            }
+                            if let Some(seqscan_sql) =
+                                block_range_seqscan_sql(plan.block_range_pages(), seqscan_threshold)
+                            {
+                                conn.batch_execute(seqscan_sql)?;
+                            }
+                        }
                     }
-                    _ => QueryExecutionError::ResolveEntitiesError(format!(
-                        "{e}, query = {query_text}",
-                    )),
-                }
-            })?;
-        let trace = log_query_timing(logger, &query_clone, start.elapsed(), values.len(), trace);
+                    let values = query.load::<EntityData>(conn)?;
+                    // Check that the buckets are closed in the same
+                    // transaction so that it sees the same data as the query
+                    let mut closed = false;
+                    if let (Some(_), Some((tables, bound))) = (&cache_key, &closed_bucket) {
+                        closed = true;
+                        for table in tables {
+                            closed = closed && rollup::buckets_closed(conn, table, *bound)?;
+                        }
+                    }
+                    Ok::<_, diesel::result::Error>((values, closed))
+                })
+                .map_err(|e| {
+                    use diesel::result::DatabaseErrorKind;
+                    use diesel::result::Error::*;
+                    // Sometimes `debug_query(..)` can't be turned into a
+                    // string, e.g., because `walk_ast` for one of its fragments
+                    // returns an error. When that happens, avoid a panic from
+                    // simply calling `to_string()` on it, and output a string
+                    // representation of the `FilterQuery` instead of the SQL
+                    let mut query_text = String::new();
+                    match write!(query_text, "{}", debug_query(&query_clone)) {
+                        Ok(()) => (),
+                        Err(_) => {
+                            write!(query_text, "{query_clone}").ok();
+                        }
+                    };
+                    match e {
+                        DatabaseError(DatabaseErrorKind::Unknown, ref info)
+                            if info.message().starts_with("syntax error in tsquery") =>
+                        {
+                            QueryExecutionError::FulltextQueryInvalidSyntax(
+                                info.message().to_string(),
+                            )
+                        }
+                        _ => QueryExecutionError::ResolveEntitiesError(format!(
+                            "{e}, query = {query_text}",
+                        )),
+                    }
+                })?,
+        };
+        if let (Some(key), Some(ttl), true) = (cache_key, rollup_ttl, closed) {
+            let max_bytes = ENV_VARS.store.rollup_result_cache_max_bytes;
+            self.rollup_cache.set(key, ttl, max_bytes, values.clone());
+        }
+        let sql = start.elapsed();
+        let trace = log_query_timing(logger, &query_clone, sql, values.len(), trace);
+        query_clone.check_max_rows(values.len())?;
+        if let Some(shape) =
+            query_clone.large_result(values.len(), ENV_VARS.store.large_result_warn_rows)
+        {
+            warn!(
+                logger,
+                "Query returned a large result";
+                "query" => shape,
+                "entity_count" => values.len(),
+                "warn_rows" => ENV_VARS.store.large_result_warn_rows
+            );
+        }
 
+        let decode_start = Instant::now();
         let parent_type = filter_collection.parent_type()?.map(ColumnType::from);
-        values
+        let mut memory = QueryMemory::new(ENV_VARS.store.query_memory_limit_bytes);
+        let values = values
             .into_iter()
             .map(|entity_data| {
+                memory.add(entity_data.weight())?;
                 entity_data
                     .deserialize_with_layout(self, parent_type.as_ref())
                     .map_err(|e| e.into())
             })
-            .collect::<Result<Vec<T>, _>>()
-            .map(|values| (values, trace))
+            .collect::<Result<Vec<T>, QueryExecutionError>>()?;
+        let phases = QueryPhases {
+            build,
+            sql,
+            decode: decode_start.elapsed(),
+        };
+        Ok((values, trace, phases))
     }
 
     pub fn update<'a>(
@@ -1068,6 +1185,61 @@ impl Layout {
         Ok(())
     }
 
+    /// Log the tables whose `TYPEA_BATCH_SIZE` is reduced because they have
+    /// too many columns
+    fn log_clamped_typea_batch_sizes(&self, logger: &Logger) {
+        for table in self.tables.values() {
+            let batch_size = table.typea_batch_size();
+            if batch_size < ENV_VARS.store.typea_batch_size {
+                info!(
+                    logger,
+                    "Reducing TYPEA_BATCH_SIZE for `{}` with {} columns to {}",
+                    table.name,
+                    table.columns.len(),
+                    batch_size
+                );
+            }
+        }
+    }
+
+    /// Describe every table and column that this layout needs but that is
+    /// missing from `actual`, which maps table names to the names of their
+    /// columns as they exist in the database
+    fn schema_drift(&self, actual: &HashMap<String, HashSet<String>>) -> Vec<String> {
+        let mut drift = Vec::new();
+        for table in self.tables.values() {
+            let Some(columns) = actual.get(table.name.as_str()) else {
+                drift.push(format!("table `{}` is missing", table.name));
+                continue;
+            };
+            let causality_region = table
+                .has_causality_region
+                .then_some(CAUSALITY_REGION_COLUMN);
+            for column in table.column_names().chain(causality_region) {
+                if !columns.contains(column) {
+                    drift.push(format!("column `{}.{}` is missing", table.name, column));
+                }
+            }
+        }
+        drift.sort();
+        drift
+    }
+
+    /// Compare this layout to the tables in the database and handle any
+    /// differences according to `GRAPH_STORE_SCHEMA_DRIFT_POLICY`
+    pub(crate) fn check_schema_drift(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+    ) -> Result<(), StoreError> {
+        let policy = ENV_VARS.store.schema_drift_policy;
+        if policy == SchemaDriftPolicy::Ignore {
+            return Ok(());
+        }
+        let actual = catalog::table_columns(conn, &self.site.namespace)?;
+        apply_schema_drift_policy(logger, policy, &self.site, &self.schema_drift(&actual))
+    }
+
     pub fn is_cacheable(&self) -> bool {
         // This would be false if we still needed to migrate the Layout, but
         // since there are no migrations in the code right now, it is always
@@ -1075,6 +1247,32 @@ impl Layout {
         true
     }
 
+    /// An estimate of how many bytes of memory this layout uses. It only
+    /// accounts for the tables and their columns, which is what makes up
+    /// most of a layout
+    fn weight(&self) -> usize {
+        let table_weight = |table: &Table| {
+            std::mem::size_of::<Table>()
+                + table.name.as_str().len()
+                + table.qualified_name.as_str().len()
+                + table
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        std::mem::size_of::<Column>()
+                            + column.name.as_str().len()
+                            + column.field.len()
+                    })
+                    .sum::<usize>()
+        };
+        std::mem::size_of::<Layout>()
+            + self
+                .tables
+                .values()
+                .map(|table| table_weight(table))
+                .sum::<usize>()
+    }
+
     /// Update the layout with the latest information from the database; an
     /// update can only change the `is_account_like` flag for tables, the
     /// layout's site, or the `history_blocks`. If no update is needed, just
@@ -1139,6 +1337,27 @@ impl Layout {
         Ok(block_time)
     }
 
+    /// If `query` only reads aggregations and limits their timestamp,
+    /// return the aggregation tables it reads and the latest timestamp it
+    /// can match. Once all buckets up to that timestamp are closed, the
+    /// result of the query can be cached
+    fn closed_bucket_query(&self, query: &EntityQuery) -> Option<(Vec<Arc<Table>>, Timestamp)> {
+        let EntityCollection::All(types) = &query.collection else {
+            return None;
+        };
+        let bound = rollup::timestamp_upper_bound(query.filter.as_ref()?)?;
+        let tables = types
+            .iter()
+            .map(|(entity_type, _)| {
+                self.rollups
+                    .iter()
+                    .find(|rollup| &rollup.agg_table.object == entity_type)
+                    .map(|rollup| rollup.agg_table.cheap_clone())
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((tables, bound))
+    }
+
     /// Find the time of the last rollup for the subgraph. We do this by
     /// looking for the maximum timestamp in any aggregation table and
     /// adding a little bit more than the corresponding interval to it. This
@@ -1626,6 +1845,10 @@ pub struct Table {
     /// Whether this table has an explicit `causality_region` column. If `false`, then the column is
     /// not present and the causality region for all rows is implicitly `0` (equivalent to CasualityRegion::ONCHAIN).
     pub(crate) has_causality_region: bool,
+
+    /// Queries against this table never use the BRIN index on the block
+    /// range since it is listed in `GRAPH_STORE_BRIN_DISABLED_TABLES`
+    pub(crate) brin_disabled: bool,
 }
 
 impl Table {
@@ -1654,6 +1877,11 @@ impl Table {
         let qualified_name = SqlName::qualified_name(&catalog.site.namespace, &table_name);
         let immutable = defn.is_immutable();
         let nsp = catalog.site.namespace.clone();
+        let brin_disabled = ENV_VARS.store.brin_disabled(
+            catalog.site.deployment.as_str(),
+            nsp.as_str(),
+            table_name.as_str(),
+        );
         let table = Table {
             object: defn.cheap_clone(),
             name: table_name,
@@ -1667,6 +1895,7 @@ impl Table {
             position,
             immutable,
             has_causality_region,
+            brin_disabled,
         };
         Ok(table)
     }
@@ -1684,11 +1913,51 @@ impl Table {
             position: self.position,
             immutable: self.immutable,
             has_causality_region: self.has_causality_region,
+            brin_disabled: self.brin_disabled,
         };
 
         Arc::new(other)
     }
 
+    /// Whether a query against this table at `block` should restrict the
+    /// block range so that Postgres can use the BRIN index on it. Queries
+    /// that filter by id only do that with `use_brin_for_all_query_types`
+    pub(crate) fn use_brin(
+        &self,
+        block: BlockNumber,
+        filters_by_id: bool,
+        use_brin_for_all_query_types: bool,
+    ) -> bool {
+        // When block is BLOCK_NUMBER_MAX, the checks would be wrong; we
+        // don't worry about adding the equivalent in that case since we
+        // generally only see BLOCK_NUMBER_MAX here for metadata queries
+        // where block ranges don't matter anyway.
+        //
+        // We also don't need to add these if the query already filters by
+        // id, because the ideal index is the GiST index on id and
+        // block_range.
+        self.is_account_like
+            && !self.brin_disabled
+            && block < BLOCK_NUMBER_MAX
+            && (!filters_by_id || use_brin_for_all_query_types)
+    }
+
+    /// The position of this table's entity type in the schema
+    pub(crate) fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// The `TYPEA_BATCH_SIZE` for queries against this table, reduced if
+    /// the table has so many columns that a batch would exceed
+    /// `GRAPH_STORE_MAX_INSERT_PARAMS`
+    pub(crate) fn typea_batch_size(&self) -> usize {
+        relational_queries::typea_batch_size(
+            self,
+            ENV_VARS.store.typea_batch_size,
+            relational_queries::max_insert_params(),
+        )
+    }
+
     /// Find the column `name` in this table. The name must be in snake case,
     /// i.e., use SQL conventions
     pub fn column(&self, name: &SqlName) -> Option<&Column> {
@@ -1761,6 +2030,132 @@ impl Table {
 struct CacheEntry {
     value: Arc<Layout>,
     expires: Instant,
+    /// When the statistics in `value` were last refreshed
+    refreshed: Instant,
+}
+
+/// Whether a cached layout can be used as is
+#[derive(Debug, PartialEq)]
+enum Freshness {
+    Fresh,
+    /// The layout should be refreshed unless another refresh is running
+    Expired,
+    /// The statistics in the layout are older than the maximum age and
+    /// the layout must be refreshed before it is used
+    TooOld,
+}
+
+impl CacheEntry {
+    fn freshness(&self, now: Instant, max_age: Option<Duration>) -> Freshness {
+        if max_age.is_some_and(|max_age| now.saturating_duration_since(self.refreshed) > max_age) {
+            Freshness::TooOld
+        } else if now > self.expires {
+            Freshness::Expired
+        } else {
+            Freshness::Fresh
+        }
+    }
+}
+
+type CacheEntries = Mutex<HashMap<DeploymentHash, CacheEntry>>;
+
+lazy_static! {
+    /// The budget that all `LayoutCache`s share
+    static ref SCHEMA_CACHE_BUDGET: Arc<SchemaCacheBudget> = Arc::new(SchemaCacheBudget::new(
+        ENV_VARS.store.schema_cache_global_max_bytes
+    ));
+}
+
+#[derive(Default)]
+struct BudgetState {
+    /// The id for the next cache that registers
+    next_cache: usize,
+    /// The entries of each registered cache by cache id
+    caches: HashMap<usize, Weak<CacheEntries>>,
+    /// The weight of each cached layout and when it was last used by cache
+    /// id and deployment
+    layouts: HashMap<(usize, DeploymentHash), (usize, Instant)>,
+}
+
+/// Keep the combined weight of the layouts in all `LayoutCache`s that
+/// share this budget below `max_bytes` by evicting the least recently used
+/// layouts, regardless of which cache they are in
+struct SchemaCacheBudget {
+    /// The maximum combined weight; 0 means there is no limit
+    max_bytes: usize,
+    state: Mutex<BudgetState>,
+}
+
+impl SchemaCacheBudget {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::new(BudgetState::default()),
+        }
+    }
+
+    /// Register the entries of a cache and return the id under which the
+    /// cache should record its layouts
+    fn register(&self, entries: &Arc<CacheEntries>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_cache;
+        state.next_cache += 1;
+        state.caches.insert(id, Arc::downgrade(entries));
+        id
+    }
+
+    /// Record that the cache `cache` holds a layout for `deployment` that
+    /// weighs `weight` bytes, and evict the least recently used layouts
+    /// from all caches until the combined weight fits the budget again.
+    /// The layout that is being recorded is never evicted.
+    ///
+    /// Callers must not hold the lock on the entries of any cache
+    fn record(&self, cache: usize, deployment: &DeploymentHash, weight: usize, now: Instant) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let key = (cache, deployment.clone());
+        state.layouts.insert(key.clone(), (weight, now));
+        let mut total: usize = state.layouts.values().map(|(weight, _)| weight).sum();
+        while total > self.max_bytes {
+            let victim = state
+                .layouts
+                .iter()
+                .filter(|(victim, _)| *victim != &key)
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(victim, _)| victim.clone());
+            let Some(victim) = victim else {
+                break;
+            };
+            if let Some((weight, _)) = state.layouts.remove(&victim) {
+                total -= weight;
+            }
+            if let Some(entries) = state.caches.get(&victim.0).and_then(Weak::upgrade) {
+                entries.lock().unwrap().remove(&victim.1);
+            }
+        }
+    }
+
+    /// Note that the layout for `deployment` in cache `cache` was used
+    fn touch(&self, cache: usize, deployment: &DeploymentHash, now: Instant) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, last_used)) = state.layouts.get_mut(&(cache, deployment.clone())) {
+            *last_used = now;
+        }
+    }
+
+    /// Stop accounting for the layout for `deployment` in cache `cache`
+    fn forget(&self, cache: usize, deployment: &DeploymentHash) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.layouts.remove(&(cache, deployment.clone()));
+    }
 }
 
 /// Cache layouts for some time and refresh them when they expire.
@@ -1768,21 +2163,324 @@ struct CacheEntry {
 /// blocking while a refresh happens, favoring using an expired layout over
 /// a refreshed one.
 pub struct LayoutCache {
-    entries: Mutex<HashMap<DeploymentHash, CacheEntry>>,
+    entries: Arc<CacheEntries>,
     ttl: Duration,
     /// Use this so that we only refresh one layout at any given time to
     /// avoid refreshing the same layout multiple times
     refresh: Mutex<()>,
     last_sweep: Mutex<Instant>,
+    /// Deployments whose layouts are never removed by `sweep`
+    pinned: HashSet<String>,
+    loads: SchemaLoads,
+    /// How old the statistics of a cached layout may get before `get`
+    /// waits for a refresh
+    max_age: Option<Duration>,
+    /// The node-wide budget for the weight of cached layouts, and the id
+    /// of this cache in it
+    budget: Arc<SchemaCacheBudget>,
+    id: usize,
+}
+
+/// Limits how many layouts are loaded at the same time, and makes sure
+/// that concurrent requests for the layout of the same deployment share
+/// one load. Without that, the queries that come in right after a restart
+/// can cause a stampede of layout loads. Only used by requests that do not
+/// hold a connection yet since they may have to wait
+struct SchemaLoads {
+    /// The maximum number of layouts that can be loaded at once; 0 means
+    /// there is no limit
+    max_concurrent: usize,
+    /// How many failed loads of another request a request waits for
+    /// before it loads the layout on its own
+    retries: usize,
+    state: Mutex<SchemaLoadState>,
+    changed: Condvar,
+}
+
+struct SchemaLoadState {
+    /// How many layouts are currently being loaded
+    running: usize,
+    /// The loads that are in progress, by deployment
+    loads: HashMap<DeploymentHash, Arc<SharedLoad>>,
+}
+
+/// A load that other requests for the same layout wait for
+#[derive(Default)]
+struct SharedLoad {
+    /// `None` while the load is running, and `Some(None)` if it failed
+    result: Mutex<Option<Option<Arc<Layout>>>>,
+    done: Condvar,
+}
+
+impl SharedLoad {
+    /// Block until the load is done and return the layout it loaded
+    fn wait(&self) -> Option<Arc<Layout>> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            if let Some(layout) = result.as_ref() {
+                return layout.clone();
+            }
+            result = self.done.wait(result).unwrap();
+        }
+    }
+}
+
+impl SchemaLoads {
+    fn new(max_concurrent: usize, retries: usize) -> Self {
+        SchemaLoads {
+            max_concurrent,
+            retries,
+            state: Mutex::new(SchemaLoadState {
+                running: 0,
+                loads: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Load the layout for `deployment` with `load`. If the layout is
+    /// already being loaded, wait for that load and use its layout instead.
+    /// If that load fails, one of the waiting requests takes over and
+    /// loads the layout for the others. After `retries` failed loads, a
+    /// request stops waiting and calls `load` itself
+    fn load<F>(&self, deployment: &DeploymentHash, load: F) -> Result<Arc<Layout>, StoreError>
+    where
+        F: FnOnce() -> Result<Arc<Layout>, StoreError>,
+    {
+        let mut failed = 0;
+        let mut state = self.state.lock().unwrap();
+        while let Some(shared) = state.loads.get(deployment).cloned() {
+            drop(state);
+            if let Some(layout) = shared.wait() {
+                return Ok(layout);
+            }
+            failed += 1;
+            if failed > self.retries {
+                let _permit = self.acquire();
+                return load();
+            }
+            state = self.state.lock().unwrap();
+        }
+
+        let shared = Arc::new(SharedLoad::default());
+        state.loads.insert(deployment.clone(), shared.clone());
+        drop(state);
+
+        // Make sure waiting requests are woken up even if `load` panics
+        let mut finish = FinishLoad {
+            loads: self,
+            deployment,
+            shared,
+            layout: None,
+        };
+        let res = {
+            let _permit = self.acquire();
+            load()
+        };
+        finish.layout = res.as_ref().ok().cloned();
+        res
+    }
+
+    /// Block until fewer than `max_concurrent` layouts are being loaded.
+    /// The slot is released when the returned permit is dropped
+    fn acquire(&self) -> SchemaLoadPermit<'_> {
+        let mut state = self.state.lock().unwrap();
+        if self.max_concurrent > 0 {
+            while state.running >= self.max_concurrent {
+                state = self.changed.wait(state).unwrap();
+            }
+        }
+        state.running += 1;
+        SchemaLoadPermit { loads: self }
+    }
+}
+
+struct SchemaLoadPermit<'a> {
+    loads: &'a SchemaLoads,
+}
+
+impl Drop for SchemaLoadPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.loads.state.lock().unwrap();
+        state.running -= 1;
+        self.loads.changed.notify_all();
+    }
+}
+
+/// Hands the result of a shared load to the requests waiting for it when
+/// it is dropped
+struct FinishLoad<'a> {
+    loads: &'a SchemaLoads,
+    deployment: &'a DeploymentHash,
+    shared: Arc<SharedLoad>,
+    layout: Option<Arc<Layout>>,
+}
+
+impl Drop for FinishLoad<'_> {
+    fn drop(&mut self) {
+        self.loads
+            .state
+            .lock()
+            .unwrap()
+            .loads
+            .remove(self.deployment);
+        *self.shared.result.lock().unwrap() = Some(self.layout.take());
+        self.shared.done.notify_all();
+    }
+}
+
+/// How long the phases of running a query took
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryPhases {
+    /// Turning the `EntityQuery` into SQL
+    pub build: Duration,
+    /// Running the SQL in the database and loading the rows
+    pub sql: Duration,
+    /// Turning the rows into entities
+    pub decode: Duration,
+}
+
+impl QueryPhases {
+    fn iter(&self) -> impl Iterator<Item = (&'static str, Duration)> {
+        [
+            ("build", self.build),
+            ("sql", self.sql),
+            ("decode", self.decode),
+        ]
+        .into_iter()
+    }
+
+    /// Log the phases and add them to `metrics`
+    pub fn record(&self, logger: &Logger, metrics: &HistogramVec) {
+        for (phase, duration) in self.iter() {
+            metrics
+                .with_label_values(&[phase])
+                .observe(duration.as_secs_f64());
+        }
+        info!(
+            logger,
+            "Query timing (phases)";
+            "build_ms" => self.build.as_millis(),
+            "sql_ms" => self.sql.as_millis(),
+            "decode_ms" => self.decode.as_millis()
+        );
+    }
+}
+
+/// The number of parallel workers per gather node that we allow for
+/// queries that are expensive enough to benefit from them
+const PARALLEL_WORKERS_PER_GATHER: u32 = 4;
+
+/// Return the statement that lets a query with estimated `cost` use
+/// parallel workers for the rest of the transaction if the cost is above
+/// `min_cost`. Cheaper queries keep the configured number of workers since
+/// starting workers has too much overhead for them
+fn parallel_query_sql(cost: f64, min_cost: f64) -> Option<String> {
+    (cost > min_cost).then(|| {
+        format!("set local max_parallel_workers_per_gather = {PARALLEL_WORKERS_PER_GATHER}")
+    })
+}
+
+/// Return the statement that makes the planner avoid index scans for the
+/// rest of the transaction if a query would read more than `threshold`
+/// pages through `block_range` indexes since reading that much of a table
+/// is cheaper with a sequential scan. A `threshold` of 0 turns this off
+fn block_range_seqscan_sql(pages: f64, threshold: usize) -> Option<&'static str> {
+    (threshold > 0 && pages > threshold as f64)
+        .then_some("set local enable_indexscan = off; set local enable_bitmapscan = off")
+}
+
+/// Log or reject the differences in `drift` between the layout for `site`
+/// and the database, depending on `policy`
+fn apply_schema_drift_policy(
+    logger: &Logger,
+    policy: SchemaDriftPolicy,
+    site: &Site,
+    drift: &[String],
+) -> Result<(), StoreError> {
+    if drift.is_empty() {
+        return Ok(());
+    }
+    match policy {
+        SchemaDriftPolicy::Ignore => Ok(()),
+        SchemaDriftPolicy::Warn => {
+            warn!(
+                logger,
+                "The tables in the database do not match the subgraph schema";
+                "deployment" => &site.deployment,
+                "namespace" => site.namespace.as_str(),
+                "drift" => drift.join(", ")
+            );
+            Ok(())
+        }
+        SchemaDriftPolicy::Refuse => Err(constraint_violation!(
+            "refusing to use deployment {} since the tables in {} do not match its schema: {}",
+            site.deployment,
+            site.namespace,
+            drift.join(", ")
+        )),
+    }
+}
+
+/// Call `load` until it succeeds, but at most `retries + 1` times, and
+/// wait a little between attempts. Only errors that indicate that the
+/// database is temporarily unavailable are retried; any other error, and
+/// the error from the last attempt, is returned right away
+fn load_with_retries<T, F>(
+    logger: &Logger,
+    site: &Site,
+    retries: usize,
+    backoff: Duration,
+    mut load: F,
+) -> Result<T, StoreError>
+where
+    F: FnMut() -> Result<T, StoreError>,
+{
+    let mut attempt = 0;
+    loop {
+        match load() {
+            Ok(value) => return Ok(value),
+            Err(e @ StoreError::DatabaseUnavailable) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    logger,
+                    "failed to load schema, retrying";
+                    "deployment" => &site.deployment,
+                    "attempt" => attempt,
+                    "error" => e.to_string()
+                );
+                std::thread::sleep(backoff * attempt as u32);
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 impl LayoutCache {
     pub fn new(ttl: Duration) -> Self {
+        Self::with_pinned(ttl, ENV_VARS.store.schema_cache_pinned.clone())
+    }
+
+    fn with_pinned(ttl: Duration, pinned: HashSet<String>) -> Self {
+        Self::with_budget(ttl, pinned, SCHEMA_CACHE_BUDGET.cheap_clone())
+    }
+
+    fn with_budget(ttl: Duration, pinned: HashSet<String>, budget: Arc<SchemaCacheBudget>) -> Self {
+        let entries = Arc::new(Mutex::new(HashMap::new()));
+        let id = budget.register(&entries);
         Self {
-            entries: Mutex::new(HashMap::new()),
+            entries,
             ttl,
             refresh: Mutex::new(()),
             last_sweep: Mutex::new(Instant::now()),
+            pinned,
+            loads: SchemaLoads::new(
+                ENV_VARS.store.max_concurrent_schema_loads,
+                ENV_VARS.store.schema_load_retries,
+            ),
+            max_age: ENV_VARS.store.query_stats_max_age,
+            budget,
+            id,
         }
     }
 
@@ -1798,27 +2496,42 @@ impl LayoutCache {
     fn cache(&self, layout: Arc<Layout>) {
         if self.ttl > Duration::ZERO && layout.is_cacheable() {
             let deployment = layout.site.deployment.clone();
+            let now = Instant::now();
+            let weight = layout.weight();
             let entry = CacheEntry {
-                expires: Instant::now() + self.ttl,
+                expires: now + self.ttl,
+                refreshed: now,
                 value: layout,
             };
-            self.entries.lock().unwrap().insert(deployment, entry);
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(deployment.clone(), entry);
+            if !self.pinned.contains(deployment.as_str()) {
+                self.budget.record(self.id, &deployment, weight, now);
+            }
         }
     }
 
     /// Return the corresponding layout if we have one in cache already, and
     /// ignore expiration information
     pub(crate) fn find(&self, site: &Site) -> Option<Arc<Layout>> {
-        self.entries
+        let layout = self
+            .entries
             .lock()
             .unwrap()
             .get(&site.deployment)
-            .map(|CacheEntry { value, expires: _ }| value.clone())
+            .map(|entry| entry.value.clone());
+        if layout.is_some() {
+            self.budget.touch(self.id, &site.deployment, Instant::now());
+        }
+        layout
     }
 
     /// Get the layout for `site`. If it's not in cache, load it. If it is
     /// expired, try to refresh it if there isn't another refresh happening
-    /// already
+    /// already. If its statistics are older than the maximum age, wait for
+    /// other refreshes to finish and refresh it
     pub fn get(
         &self,
         logger: &Logger,
@@ -1831,33 +2544,88 @@ impl LayoutCache {
             lock.get(&site.deployment).cloned()
         };
         let layout = match entry {
-            Some(CacheEntry { value, expires }) => {
-                if now <= expires {
-                    // Entry is not expired; use it
-                    value
-                } else {
+            Some(entry) => match entry.freshness(now, self.max_age) {
+                // Entry is not expired; use it
+                Freshness::Fresh => {
+                    self.budget.touch(self.id, &site.deployment, now);
+                    entry.value
+                }
+                Freshness::Expired => {
                     // Only do a cache refresh once; we don't want to have
                     // multiple threads refreshing the same layout
                     // simultaneously. It's easiest to refresh at most one
                     // layout globally
                     let refresh = self.refresh.try_lock();
                     if refresh.is_err() {
-                        value
+                        entry.value
                     } else {
-                        self.refresh(logger, conn, site, value)
+                        self.refresh(logger, conn, site, entry.value)
                     }
                 }
-            }
-            None => {
-                let layout = Self::load(conn, site)?;
-                self.cache(layout.cheap_clone());
-                layout
-            }
+                Freshness::TooOld => {
+                    let _refresh = self.refresh.lock().unwrap();
+                    // Another thread might have refreshed the layout while
+                    // we were waiting
+                    match self.find(&site) {
+                        Some(value) if !Arc::ptr_eq(&value, &entry.value) => value,
+                        _ => self.refresh(logger, conn, site, entry.value),
+                    }
+                }
+            },
+            // The caller already holds a connection, and waiting for a
+            // load by another request would tie it up; just load the
+            // layout with it
+            None => self.load_and_cache(logger, conn, site)?,
         };
         self.sweep(now);
         Ok(layout)
     }
 
+    /// Get the layout for `site` when the caller does not hold a
+    /// connection. If the layout is not cached, concurrent requests share
+    /// one load through `SchemaLoads`. Only the request that does the load
+    /// checks out a connection with `get_conn`, and only once it may load,
+    /// so that requests that wait do not tie up connections. Like `find`,
+    /// this ignores expiration information
+    pub(crate) fn find_or_load<C, F>(
+        &self,
+        logger: &Logger,
+        site: Arc<Site>,
+        get_conn: F,
+    ) -> Result<Arc<Layout>, StoreError>
+    where
+        C: DerefMut<Target = PgConnection>,
+        F: FnOnce() -> Result<C, StoreError>,
+    {
+        if let Some(layout) = self.find(&site) {
+            return Ok(layout);
+        }
+        let layout = self.loads.load(&site.deployment, || {
+            let mut conn = get_conn()?;
+            self.load_and_cache(logger, &mut conn, site.cheap_clone())
+        })?;
+        self.sweep(Instant::now());
+        Ok(layout)
+    }
+
+    fn load_and_cache(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+        site: Arc<Site>,
+    ) -> Result<Arc<Layout>, StoreError> {
+        let layout = load_with_retries(
+            logger,
+            &site,
+            ENV_VARS.store.schema_load_retries,
+            SCHEMA_LOAD_BACKOFF,
+            || Self::load(conn, site.cheap_clone()),
+        )?;
+        layout.log_clamped_typea_batch_sizes(logger);
+        self.cache(layout.cheap_clone());
+        Ok(layout)
+    }
+
     fn refresh(
         &self,
         logger: &Logger,
@@ -1886,29 +2654,427 @@ impl LayoutCache {
     }
 
     pub(crate) fn remove(&self, site: &Site) -> Option<Arc<Layout>> {
-        self.entries
+        let layout = self
+            .entries
             .lock()
             .unwrap()
             .remove(&site.deployment)
-            .map(|CacheEntry { value, expires: _ }| value)
+            .map(|entry| entry.value);
+        self.budget.forget(self.id, &site.deployment);
+        layout
     }
 
     // Only needed for tests
     #[cfg(debug_assertions)]
     pub(crate) fn clear(&self) {
-        self.entries.lock().unwrap().clear()
+        let deployments: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(d, _)| d)
+            .collect();
+        for deployment in deployments {
+            self.budget.forget(self.id, &deployment);
+        }
     }
 
     /// Periodically sweep the cache to remove expired entries; an entry is
-    /// expired if it was last updated more than 2*self.ttl ago
+    /// expired if it was last updated more than 2*self.ttl ago. Entries for
+    /// pinned deployments are never removed
     fn sweep(&self, now: Instant) {
         if now - *self.last_sweep.lock().unwrap() < ENV_VARS.store.schema_cache_ttl {
             return;
         }
-        let mut entries = self.entries.lock().unwrap();
-        // We allow entries to stick around for 2*ttl; if an entry was used
-        // in that time, it will get refreshed and have its expiry updated
-        entries.retain(|_, entry| entry.expires + self.ttl > now);
+        let mut removed = Vec::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            // We allow entries to stick around for 2*ttl; if an entry was
+            // used in that time, it will get refreshed and have its expiry
+            // updated
+            entries.retain(|deployment, entry| {
+                let keep =
+                    entry.expires + self.ttl > now || self.pinned.contains(deployment.as_str());
+                if !keep {
+                    removed.push(deployment.clone());
+                }
+                keep
+            });
+        }
+        for deployment in removed {
+            self.budget.forget(self.id, &deployment);
+        }
         *self.last_sweep.lock().unwrap() = now;
     }
 }
+
+/// A site for the deployment `deployment` in `namespace` for unit tests
+#[cfg(test)]
+pub(crate) fn test_site(deployment: &str, namespace: &str) -> Arc<Site> {
+    let deployment = DeploymentHash::new(deployment).unwrap();
+    let namespace = Namespace::new(namespace.to_string()).unwrap();
+    Arc::new(crate::primary::make_dummy_site(
+        deployment,
+        namespace,
+        "anet".to_string(),
+    ))
+}
+
+/// A layout with the schema `gql` for the deployment `deployment` in
+/// `namespace` for unit tests
+#[cfg(test)]
+pub(crate) fn test_layout(deployment: &str, namespace: &str, gql: &str) -> Layout {
+    let site = test_site(deployment, namespace);
+    let schema =
+        InputSchema::parse_latest(gql, site.deployment.clone()).expect("Test schema invalid");
+    let catalog =
+        Catalog::for_tests(site.clone(), BTreeSet::new()).expect("Can not create catalog");
+    Layout::new(site, &schema, catalog).expect("Failed to construct Layout")
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::prelude::{o, DeploymentHash};
+    use graph::slog;
+
+    use super::*;
+
+    fn layout(deployment: &str, namespace: &str) -> Arc<Layout> {
+        const GQL: &str = "type Thing @entity { id: ID!, name: String! }";
+
+        Arc::new(test_layout(deployment, namespace, GQL))
+    }
+
+    #[test]
+    fn schema_load_retries() {
+        let logger = Logger::root(slog::Discard, o!());
+        let layout = layout("QmRetry", "sgd3");
+
+        // A load that fails once and then succeeds uses the loaded schema
+        let mut calls = 0;
+        let loaded = load_with_retries(&logger, &layout.site, 2, Duration::ZERO, || {
+            calls += 1;
+            if calls == 1 {
+                Err(StoreError::DatabaseUnavailable)
+            } else {
+                Ok(layout.cheap_clone())
+            }
+        })
+        .unwrap();
+        assert_eq!(2, calls);
+        assert!(Arc::ptr_eq(&layout, &loaded));
+
+        // We give up after the configured number of retries
+        let mut calls = 0;
+        let res: Result<Arc<Layout>, _> =
+            load_with_retries(&logger, &layout.site, 2, Duration::ZERO, || {
+                calls += 1;
+                Err(StoreError::DatabaseUnavailable)
+            });
+        assert!(res.is_err());
+        assert_eq!(3, calls);
+
+        // Errors that are not transient are not retried
+        let mut calls = 0;
+        let res: Result<Arc<Layout>, _> =
+            load_with_retries(&logger, &layout.site, 2, Duration::ZERO, || {
+                calls += 1;
+                Err(StoreError::Unknown(anyhow!("relation does not exist")))
+            });
+        assert!(res.is_err());
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn pinned_layouts_survive_sweep() {
+        let ttl = Duration::from_secs(1);
+        let cache = LayoutCache::with_pinned(ttl, HashSet::from(["QmPinned".to_string()]));
+
+        let pinned = layout("QmPinned", "sgd1");
+        let unpinned = layout("QmUnpinned", "sgd2");
+        cache.cache(pinned.cheap_clone());
+        cache.cache(unpinned.cheap_clone());
+
+        // Sweep at a time when both entries have long expired
+        let later = Instant::now() + ENV_VARS.store.schema_cache_ttl + 3 * ttl;
+        cache.sweep(later);
+
+        assert!(cache.find(&pinned.site).is_some());
+        assert!(cache.find(&unpinned.site).is_none());
+    }
+
+    #[test]
+    fn schema_cache_global_max_bytes() {
+        let ttl = Duration::from_secs(300);
+        let one = layout("QmBudgetOne", "sgd7");
+        let two = layout("QmBudgetTwo", "sgd8");
+        let three = layout("QmBudgetThree", "sgd9");
+
+        // Enough room for two layouts, but not for three
+        let budget = Arc::new(SchemaCacheBudget::new(
+            one.weight() + two.weight() + three.weight() / 2,
+        ));
+        let first = LayoutCache::with_budget(ttl, HashSet::new(), budget.cheap_clone());
+        let second = LayoutCache::with_budget(ttl, HashSet::new(), budget.cheap_clone());
+
+        first.cache(one.cheap_clone());
+        std::thread::sleep(Duration::from_millis(2));
+        second.cache(two.cheap_clone());
+        std::thread::sleep(Duration::from_millis(2));
+        // Using `two` leaves `one` as the least recently used layout
+        assert!(second.find(&two.site).is_some());
+        std::thread::sleep(Duration::from_millis(2));
+
+        // Caching `three` in the second cache evicts `one` from the first
+        // cache
+        second.cache(three.cheap_clone());
+        assert!(first.find(&one.site).is_none());
+        assert!(second.find(&two.site).is_some());
+        assert!(second.find(&three.site).is_some());
+
+        // Removing a layout frees up its share of the budget
+        second.remove(&two.site);
+        first.cache(one.cheap_clone());
+        assert!(first.find(&one.site).is_some());
+        assert!(second.find(&three.site).is_some());
+    }
+
+    /// Block until `count` requests wait for the running load of
+    /// `deployment`. Besides the waiters, only `loads` and the request
+    /// doing the load hold on to it
+    fn wait_for_waiters(loads: &SchemaLoads, deployment: &DeploymentHash, count: usize) {
+        loop {
+            let waiting = loads
+                .state
+                .lock()
+                .unwrap()
+                .loads
+                .get(deployment)
+                .map(|shared| Arc::strong_count(shared) - 2);
+            if waiting == Some(count) {
+                return;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn concurrent_schema_loads() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Barrier;
+        use std::thread;
+
+        const REQUESTS: usize = 4;
+
+        let layout = layout("QmLoads", "sgd5");
+        let loads = Arc::new(SchemaLoads::new(1, 0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let start = Arc::new(Barrier::new(REQUESTS));
+
+        let requests: Vec<_> = (0..REQUESTS)
+            .map(|_| {
+                let layout = layout.clone();
+                let loads = loads.clone();
+                let calls = calls.clone();
+                let start = start.clone();
+                thread::spawn(move || {
+                    start.wait();
+                    loads
+                        .load(&layout.site.deployment, || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            // Wait for the other requests to find this load
+                            wait_for_waiters(&loads, &layout.site.deployment, REQUESTS - 1);
+                            Ok(layout.clone())
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+
+        for request in requests {
+            assert!(Arc::ptr_eq(&layout, &request.join().unwrap()));
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert!(loads.state.lock().unwrap().loads.is_empty());
+        assert_eq!(0, loads.state.lock().unwrap().running);
+    }
+
+    #[test]
+    fn schema_load_leader_fails() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::mpsc;
+        use std::thread;
+
+        let layout = layout("QmLeader", "sgd6");
+        let deployment = layout.site.deployment.clone();
+        let loads = Arc::new(SchemaLoads::new(0, 1));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        // The first request becomes the leader and fails once the waiter
+        // is waiting for it
+        let (started, leader_started) = mpsc::channel();
+        let (fail, leader_fails) = mpsc::channel::<()>();
+        let leader = thread::spawn({
+            let loads = loads.clone();
+            let calls = calls.clone();
+            let deployment = deployment.clone();
+            move || {
+                loads.load(&deployment, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    started.send(()).unwrap();
+                    leader_fails.recv().unwrap();
+                    Err(StoreError::DatabaseUnavailable)
+                })
+            }
+        });
+        leader_started.recv().unwrap();
+
+        let waiter = thread::spawn({
+            let loads = loads.clone();
+            let calls = calls.clone();
+            let layout = layout.clone();
+            move || {
+                loads.load(&layout.site.deployment, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(layout.clone())
+                })
+            }
+        });
+        wait_for_waiters(&loads, &deployment, 1);
+        fail.send(()).unwrap();
+
+        assert!(leader.join().unwrap().is_err());
+        // The waiter took over as the new leader and loaded the layout
+        let loaded = waiter.join().unwrap().unwrap();
+        assert!(Arc::ptr_eq(&layout, &loaded));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        assert!(loads.state.lock().unwrap().loads.is_empty());
+    }
+
+    /// A drain that remembers the messages that were logged to it
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for Recorder {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn schema_drift_policy() {
+        let layout = layout("QmDrift", "sgd4");
+        let columns = |names: &[&str]| {
+            HashMap::from([(
+                "thing".to_string(),
+                names.iter().map(|name| name.to_string()).collect(),
+            )])
+        };
+
+        assert!(layout
+            .schema_drift(&columns(&["vid", "id", "name", "block_range"]))
+            .is_empty());
+        assert_eq!(
+            vec!["table `thing` is missing".to_string()],
+            layout.schema_drift(&HashMap::new())
+        );
+
+        // Someone dropped the `name` column by hand
+        let drift = layout.schema_drift(&columns(&["vid", "id", "block_range"]));
+        assert_eq!(vec!["column `thing.name` is missing".to_string()], drift);
+
+        let recorder = Recorder::default();
+        let logger = Logger::root(recorder.clone(), o!());
+        let apply = |policy| apply_schema_drift_policy(&logger, policy, &layout.site, &drift);
+
+        assert!(apply(SchemaDriftPolicy::Refuse).is_err());
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        assert!(apply(SchemaDriftPolicy::Ignore).is_ok());
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        assert!(apply(SchemaDriftPolicy::Warn).is_ok());
+        assert_eq!(1, recorder.0.lock().unwrap().len());
+
+        // Without drift, nothing is logged or rejected
+        assert!(
+            apply_schema_drift_policy(&logger, SchemaDriftPolicy::Refuse, &layout.site, &[])
+                .is_ok()
+        );
+        assert_eq!(1, recorder.0.lock().unwrap().len());
+    }
+
+    #[test]
+    fn parallel_query() {
+        const SET_WORKERS: &str = "set local max_parallel_workers_per_gather = 4";
+
+        assert_eq!(None, parallel_query_sql(100.0, 5000.0));
+        assert_eq!(None, parallel_query_sql(5000.0, 5000.0));
+        assert_eq!(
+            Some(SET_WORKERS.to_string()),
+            parallel_query_sql(5000.5, 5000.0)
+        );
+        assert_eq!(
+            Some(SET_WORKERS.to_string()),
+            parallel_query_sql(1e9, 5000.0)
+        );
+    }
+
+    #[test]
+    fn block_range_seqscan() {
+        const NO_INDEX_SCANS: &str =
+            "set local enable_indexscan = off; set local enable_bitmapscan = off";
+
+        assert_eq!(None, block_range_seqscan_sql(100.0, 1000));
+        assert_eq!(None, block_range_seqscan_sql(1000.0, 1000));
+        assert_eq!(Some(NO_INDEX_SCANS), block_range_seqscan_sql(1001.0, 1000));
+        // A threshold of 0 turns the hint off
+        assert_eq!(None, block_range_seqscan_sql(1e9, 0));
+    }
+
+    #[test]
+    fn brin_disabled() {
+        let layout = layout("QmBrin", "sgd5");
+        let mut table = layout.table(&SqlName::from("Thing")).unwrap().clone();
+        table.is_account_like = true;
+
+        // Without filtering by id, BRIN is always used; with it, only when
+        // the global setting asks for it
+        assert!(table.use_brin(100, false, false));
+        assert!(!table.use_brin(100, true, false));
+        assert!(table.use_brin(100, true, true));
+
+        // A listed table never uses BRIN
+        table.brin_disabled = true;
+        assert!(!table.use_brin(100, false, false));
+        assert!(!table.use_brin(100, true, true));
+    }
+
+    #[test]
+    fn query_stats_max_age() {
+        let start = Instant::now();
+        let entry = CacheEntry {
+            value: layout("QmMaxAge", "sgd4"),
+            expires: start + Duration::from_secs(300),
+            refreshed: start,
+        };
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let max_age = Some(Duration::from_secs(600));
+
+        assert_eq!(Freshness::Fresh, entry.freshness(at(100), max_age));
+        assert_eq!(Freshness::Expired, entry.freshness(at(400), max_age));
+        // Stats older than the maximum age force a refresh
+        assert_eq!(Freshness::TooOld, entry.freshness(at(700), max_age));
+        // Without a maximum age, stats are only ever expired
+        assert_eq!(Freshness::Expired, entry.freshness(at(7000), None));
+
+        // A maximum age below the TTL forces refreshes before the entry
+        // expires
+        let max_age = Some(Duration::from_secs(60));
+        assert_eq!(Freshness::TooOld, entry.freshness(at(100), max_age));
+    }
+}
@@ -4,6 +4,8 @@ use std::{
 };
 
 use graph::{
+    data::subgraph::schema::POI_TABLE,
+    env::{GinIndexMode, LargeFieldStorage, PoiIndexMethod},
     prelude::{BLOCK_NUMBER_MAX, ENV_VARS},
     schema::InputSchema,
 };
@@ -300,21 +302,49 @@ impl Table {
     }
 
     fn create_attribute_indexes(&self, out: &mut String) -> fmt::Result {
+        self.create_attribute_indexes_inner(
+            out,
+            ENV_VARS.store.poi_index_method,
+            ENV_VARS.store.gin_fastupdate,
+        )
+    }
+
+    // `pub` for tests.
+    pub(crate) fn create_attribute_indexes_inner(
+        &self,
+        out: &mut String,
+        poi_index_method: PoiIndexMethod,
+        gin_fastupdate: Option<bool>,
+    ) -> fmt::Result {
         let columns = self.columns_to_index();
 
         for (column_index, column) in columns.enumerate() {
-            let (method, index_expr) =
+            let (mut method, index_expr) =
                 Self::calculate_attr_index_method_and_expression(self.immutable, column);
 
-            // If `create_gin_indexes` is set to false, we don't create
+            // The POI table is only ever looked up by `id` with an `=`, which
+            // a hash index can support at least as well as a BTree
+            if self.name.as_str() == POI_TABLE && column.is_primary_key() {
+                method = poi_index_method.as_str().to_string();
+            }
+
+            // Unless `create_gin_indexes` is set to `true`, we don't create
             // indexes on array attributes. Experience has shown that these
             // indexes are very expensive to update and can have a very bad
             // impact on the write performance of the database, but are
-            // hardly ever used or needed by queries.
-            if !column.is_list() || ENV_VARS.store.create_gin_indexes {
+            // hardly ever used or needed by queries. With `auto`, they are
+            // created later for the attributes that queries actually use
+            if !column.is_list() || ENV_VARS.store.create_gin_indexes == GinIndexMode::Always {
+                let with = match gin_fastupdate {
+                    Some(fastupdate) if method == "gin" => {
+                        let fastupdate = if fastupdate { "on" } else { "off" };
+                        format!(" with (fastupdate={fastupdate})")
+                    }
+                    _ => String::new(),
+                };
                 write!(
                     out,
-                    "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {qname} using {method}({index_expr});\n",
+                    "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {qname} using {method}({index_expr}){with};\n",
                     table_index = self.position,
                     table_name = self.name,
                     column_name = column.name,
@@ -404,6 +434,8 @@ impl Table {
         out: &mut String,
     ) -> fmt::Result {
         self.create_table(out)?;
+        self.autovacuum_ddl(out)?;
+        self.storage_ddl(out)?;
         self.create_time_travel_indexes(catalog, out)?;
         if index_def.is_some() && ENV_VARS.postpone_attribute_index_creation {
             let arr = index_def
@@ -426,22 +458,103 @@ impl Table {
         self.create_aggregate_indexes(schema, out)
     }
 
+    fn autovacuum_ddl(&self, out: &mut String) -> fmt::Result {
+        self.autovacuum_ddl_inner(
+            out,
+            ENV_VARS.store.table_autovacuum_scale_factor,
+            ENV_VARS.store.table_autovacuum_threshold,
+        )
+    }
+
+    // `pub` for tests.
+    pub(crate) fn autovacuum_ddl_inner(
+        &self,
+        out: &mut String,
+        scale_factor: Option<f64>,
+        threshold: Option<u32>,
+    ) -> fmt::Result {
+        let params: Vec<_> = scale_factor
+            .map(|scale_factor| format!("autovacuum_vacuum_scale_factor = {scale_factor}"))
+            .into_iter()
+            .chain(threshold.map(|threshold| format!("autovacuum_vacuum_threshold = {threshold}")))
+            .collect();
+        if params.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "
+    alter table {qname} set ({params});",
+            qname = self.qualified_name,
+            params = params.join(", ")
+        )
+    }
+
+    fn storage_ddl(&self, out: &mut String) -> fmt::Result {
+        self.storage_ddl_inner(out, ENV_VARS.store.large_field_storage)
+    }
+
+    // `pub` for tests.
+    pub(crate) fn storage_ddl_inner(
+        &self,
+        out: &mut String,
+        storage: LargeFieldStorage,
+    ) -> fmt::Result {
+        let Some(storage) = storage.as_sql() else {
+            return Ok(());
+        };
+        let columns: Vec<_> = self
+            .columns
+            .iter()
+            .filter(|column| !column.is_primary_key())
+            .filter(|column| matches!(column.column_type, ColumnType::String | ColumnType::Bytes))
+            .map(|column| {
+                format!(
+                    "alter column {} set storage {storage}",
+                    column.name.quoted()
+                )
+            })
+            .collect();
+        if columns.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "
+    alter table {qname} {columns};",
+            qname = self.qualified_name,
+            columns = columns.join(", ")
+        )
+    }
+
     pub fn exclusion_ddl(&self, out: &mut String) -> fmt::Result {
         // Tables with causality regions need to use exclusion constraints for correctness,
         // to catch violations of write isolation.
         let as_constraint = self.has_causality_region || CREATE_EXCLUSION_CONSTRAINT;
 
-        self.exclusion_ddl_inner(out, as_constraint)
+        self.exclusion_ddl_inner(
+            out,
+            as_constraint,
+            ENV_VARS.store.block_range_index_fillfactor,
+        )
     }
 
     // `pub` for tests.
-    pub(crate) fn exclusion_ddl_inner(&self, out: &mut String, as_constraint: bool) -> fmt::Result {
+    pub(crate) fn exclusion_ddl_inner(
+        &self,
+        out: &mut String,
+        as_constraint: bool,
+        fillfactor: Option<u8>,
+    ) -> fmt::Result {
+        let with = fillfactor
+            .map(|fillfactor| format!(" with (fillfactor={fillfactor})"))
+            .unwrap_or_default();
         if as_constraint {
             writeln!(
                 out,
                 "
     alter table {qname}
-        add constraint {bare_name}_{id}_{block_range}_excl exclude using gist ({id} with =, {block_range} with &&);",
+        add constraint {bare_name}_{id}_{block_range}_excl exclude using gist ({id} with =, {block_range} with &&){with};",
                 qname = self.qualified_name,
                 bare_name = self.name,
                 id = self.primary_key().name,
@@ -452,7 +565,7 @@ impl Table {
                 out,
                 "
         create index {bare_name}_{id}_{block_range}_excl on {qname}
-         using gist ({id}, {block_range});
+         using gist ({id}, {block_range}){with};
                ",
                 qname = self.qualified_name,
                 bare_name = self.name,
@@ -258,7 +258,7 @@ fn exlusion_ddl() {
     // When `as_constraint` is false, just create an index
     let mut out = String::new();
     table
-        .exclusion_ddl_inner(&mut out, false)
+        .exclusion_ddl_inner(&mut out, false, None)
         .expect("can write exclusion DDL");
     check_eqv(
         r#"create index thing_id_block_range_excl on "sgd0815"."thing" using gist (id, block_range);"#,
@@ -268,12 +268,99 @@ fn exlusion_ddl() {
     // When `as_constraint` is true, add an exclusion constraint
     let mut out = String::new();
     table
-        .exclusion_ddl_inner(&mut out, true)
+        .exclusion_ddl_inner(&mut out, true, None)
         .expect("can write exclusion DDL");
     check_eqv(
         r#"alter table "sgd0815"."thing" add constraint thing_id_block_range_excl exclude using gist (id with =, block_range with &&);"#,
         out.trim(),
     );
+
+    // With a fillfactor, both the index and the constraint use it
+    let mut out = String::new();
+    table
+        .exclusion_ddl_inner(&mut out, false, Some(70))
+        .expect("can write exclusion DDL");
+    check_eqv(
+        r#"create index thing_id_block_range_excl on "sgd0815"."thing" using gist (id, block_range) with (fillfactor=70);"#,
+        out.trim(),
+    );
+
+    let mut out = String::new();
+    table
+        .exclusion_ddl_inner(&mut out, true, Some(70))
+        .expect("can write exclusion DDL");
+    check_eqv(
+        r#"alter table "sgd0815"."thing" add constraint thing_id_block_range_excl exclude using gist (id with =, block_range with &&) with (fillfactor=70);"#,
+        out.trim(),
+    );
+}
+
+#[test]
+fn autovacuum_ddl() {
+    let layout = test_layout(THING_GQL);
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Thing").unwrap())
+        .unwrap();
+
+    // Without any settings, the Postgres defaults are used
+    let mut out = String::new();
+    table
+        .autovacuum_ddl_inner(&mut out, None, None)
+        .expect("can write autovacuum DDL");
+    assert_eq!("", out);
+
+    let mut out = String::new();
+    table
+        .autovacuum_ddl_inner(&mut out, Some(0.01), None)
+        .expect("can write autovacuum DDL");
+    check_eqv(
+        r#"alter table "sgd0815"."thing" set (autovacuum_vacuum_scale_factor = 0.01);"#,
+        out.trim(),
+    );
+
+    let mut out = String::new();
+    table
+        .autovacuum_ddl_inner(&mut out, Some(0.01), Some(5000))
+        .expect("can write autovacuum DDL");
+    check_eqv(
+        r#"alter table "sgd0815"."thing" set (autovacuum_vacuum_scale_factor = 0.01, autovacuum_vacuum_threshold = 5000);"#,
+        out.trim(),
+    );
+}
+
+#[test]
+fn storage_ddl() {
+    use graph::env::LargeFieldStorage;
+
+    let layout = test_layout(THING_GQL);
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Scalar").unwrap())
+        .unwrap();
+
+    // With the default setting, storage is left alone
+    let mut out = String::new();
+    table
+        .storage_ddl_inner(&mut out, LargeFieldStorage::Default)
+        .expect("can write storage DDL");
+    assert_eq!("", out);
+
+    let mut out = String::new();
+    table
+        .storage_ddl_inner(&mut out, LargeFieldStorage::External)
+        .expect("can write storage DDL");
+    check_eqv(
+        r#"alter table "sgd0815"."scalar" alter column "string" set storage external, alter column "bytes" set storage external;"#,
+        out.trim(),
+    );
+
+    let mut out = String::new();
+    table
+        .storage_ddl_inner(&mut out, LargeFieldStorage::Extended)
+        .expect("can write storage DDL");
+    check_eqv(
+        r#"alter table "sgd0815"."scalar" alter column "string" set storage extended, alter column "bytes" set storage extended;"#,
+        out.trim(),
+    );
 }
 
 #[test]
@@ -443,6 +530,62 @@ fn postponed_indexes_with_block_column() {
     assert_eq!(0, arr.len());
 }
 
+#[test]
+fn poi_index_method() {
+    use graph::env::PoiIndexMethod;
+
+    let layout = test_layout(THING_GQL);
+    let table = Layout::make_poi_table(&layout.input_schema, &layout.catalog, false, 3);
+
+    let ddl = |method| {
+        let mut out = String::new();
+        table
+            .create_attribute_indexes_inner(&mut out, method, None)
+            .unwrap();
+        out
+    };
+
+    let sql = ddl(PoiIndexMethod::Btree);
+    assert!(sql.contains(r#"on "sgd0815"."poi2$" using btree("id")"#));
+    assert!(sql.contains(r#"on "sgd0815"."poi2$" using btree("digest")"#));
+
+    // Only the index on `id` changes
+    let sql = ddl(PoiIndexMethod::Hash);
+    assert!(sql.contains(r#"on "sgd0815"."poi2$" using hash("id")"#));
+    assert!(sql.contains(r#"on "sgd0815"."poi2$" using btree("digest")"#));
+}
+
+#[test]
+fn gin_fastupdate() {
+    use graph::env::PoiIndexMethod;
+
+    let layout = test_layout(FULLTEXT_GQL);
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Animal").unwrap())
+        .unwrap();
+
+    let ddl = |fastupdate| {
+        let mut out = String::new();
+        table
+            .create_attribute_indexes_inner(&mut out, PoiIndexMethod::Btree, fastupdate)
+            .unwrap();
+        out
+    };
+
+    // Without a setting, the Postgres default is used
+    let sql = ddl(None);
+    assert!(sql.contains(r#"on "sgd0815"."animal" using gin("search");"#));
+    assert!(!sql.contains("fastupdate"));
+
+    let sql = ddl(Some(true));
+    assert!(sql.contains(r#"on "sgd0815"."animal" using gin("search") with (fastupdate=on);"#));
+
+    let sql = ddl(Some(false));
+    assert!(sql.contains(r#"on "sgd0815"."animal" using gin("search") with (fastupdate=off);"#));
+    // Only GIN indexes are affected
+    assert!(sql.contains(r#"on "sgd0815"."animal" using btree("id");"#));
+}
+
 const THING_GQL: &str = r#"
         type Thing @entity {
             id: ID!
@@ -582,19 +582,11 @@ impl<'a> QueryFragment<Pg> for AtBlock<'a> {
             out.push_sql(" @> ");
             out.push_bind_param::<Integer, _>(&self.block)?;
 
-            let should_use_brin =
-                !self.filters_by_id || ENV_VARS.store.use_brin_for_all_query_types;
-            if self.column.table.meta.is_account_like
-                && self.block < BLOCK_NUMBER_MAX
-                && should_use_brin
-            {
-                // When block is BLOCK_NUMBER_MAX, these checks would be wrong; we
-                // don't worry about adding the equivalent in that case since
-                // we generally only see BLOCK_NUMBER_MAX here for metadata
-                // queries where block ranges don't matter anyway.
-                //
-                // We also don't need to add these if the query already filters by ID,
-                // because the ideal index is the GiST index on id and block_range.
+            if self.column.table.meta.use_brin(
+                self.block,
+                self.filters_by_id,
+                ENV_VARS.store.use_brin_for_all_query_types,
+            ) {
                 out.push_sql(" and coalesce(upper(");
                 self.column.walk_ast(out.reborrow())?;
                 out.push_sql("), 2147483647) > ");
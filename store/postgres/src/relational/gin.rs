@@ -0,0 +1,127 @@
+//! Track how often array attributes are used in array containment queries
+//! so that GIN indexes can be created for the attributes that queries use
+//! a lot when `GRAPH_STORE_CREATE_GIN_INDEXES` is `auto`. Queries only
+//! record their use of an attribute; the indexes are created by a
+//! background job that picks up the attributes that crossed the threshold
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use graph::{
+    components::store::DeploymentId,
+    env::GinIndexMode,
+    prelude::{lazy_static, ENV_VARS},
+};
+
+use crate::primary::Site;
+
+lazy_static! {
+    pub(crate) static ref ARRAY_QUERIES: ArrayQueryTracker = ArrayQueryTracker::new(
+        ENV_VARS.store.create_gin_indexes == GinIndexMode::Auto,
+        ENV_VARS.store.gin_auto_threshold,
+    );
+}
+
+/// An array attribute that should get a GIN index
+#[derive(Clone, Debug)]
+pub(crate) struct ArrayColumn {
+    pub site: Arc<Site>,
+    /// The name of the entity type
+    pub entity: String,
+    /// The SQL name of the column
+    pub column: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    counts: HashMap<(DeploymentId, String, String), u64>,
+    pending: Vec<ArrayColumn>,
+}
+
+pub(crate) struct ArrayQueryTracker {
+    enabled: bool,
+    threshold: u64,
+    inner: Mutex<Inner>,
+}
+
+impl ArrayQueryTracker {
+    fn new(enabled: bool, threshold: u64) -> Self {
+        Self {
+            enabled,
+            threshold: threshold.max(1),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Record that a query used `column` of `entity` in an array
+    /// containment filter. Once that has happened `threshold` times, the
+    /// column is queued for index creation
+    pub(crate) fn record(&self, site: &Arc<Site>, entity: &str, column: &str) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner
+            .counts
+            .entry((site.id, entity.to_string(), column.to_string()))
+            .or_default();
+        *count += 1;
+        if *count == self.threshold {
+            inner.pending.push(ArrayColumn {
+                site: site.clone(),
+                entity: entity.to_string(),
+                column: column.to_string(),
+            });
+        }
+    }
+
+    /// Return the columns that need a GIN index and that have not been
+    /// returned by a previous call
+    pub(crate) fn take_pending(&self) -> Vec<ArrayColumn> {
+        std::mem::take(&mut self.inner.lock().unwrap().pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relational::test_site;
+
+    use super::*;
+
+    fn site() -> Arc<Site> {
+        test_site("gin", "sgd0815")
+    }
+
+    #[test]
+    fn auto_creation_after_threshold() {
+        let site = site();
+        let tracker = ArrayQueryTracker::new(true, 3);
+
+        tracker.record(&site, "Token", "holders");
+        tracker.record(&site, "Token", "holders");
+        tracker.record(&site, "Token", "tags");
+        assert!(tracker.take_pending().is_empty());
+
+        tracker.record(&site, "Token", "holders");
+        let pending = tracker.take_pending();
+        assert_eq!(1, pending.len());
+        assert_eq!(site.id, pending[0].site.id);
+        assert_eq!("Token", pending[0].entity);
+        assert_eq!("holders", pending[0].column);
+
+        // A column is only queued once
+        tracker.record(&site, "Token", "holders");
+        assert!(tracker.take_pending().is_empty());
+    }
+
+    #[test]
+    fn no_auto_creation_when_disabled() {
+        let site = site();
+        let tracker = ArrayQueryTracker::new(false, 1);
+
+        tracker.record(&site, "Token", "holders");
+        assert!(tracker.take_pending().is_empty());
+    }
+}
@@ -1,16 +1,24 @@
-use std::{collections::HashMap, fmt::Write, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
 use diesel::{
     connection::SimpleConnection,
     sql_query,
-    sql_types::{BigInt, Integer},
+    sql_types::{BigInt, Bool, Integer},
     Connection, PgConnection, RunQueryDsl,
 };
 use graph::{
-    components::store::{PrunePhase, PruneReporter, PruneRequest, PruningStrategy, VersionStats},
+    components::store::{
+        DeploymentId, PrunePhase, PruneReporter, PruneRequest, PruningStrategy, VersionStats,
+    },
+    env::PruneTableOrder,
     prelude::{
-        BlockNumber, CancelHandle, CancelToken, CancelableError, CheapClone, StoreError,
-        BLOCK_NUMBER_MAX,
+        lazy_static, BlockNumber, CancelHandle, CancelToken, CancelableError, CheapClone,
+        StoreError, BLOCK_NUMBER_MAX, ENV_VARS,
     },
     schema::InputSchema,
     slog::{warn, Logger},
@@ -19,8 +27,9 @@ use itertools::Itertools;
 
 use crate::{
     catalog, deployment,
+    maintenance_log::{self, MaintenanceEvent, Operation},
     relational::{Table, VID_COLUMN},
-    vid_batcher::{VidBatcher, VidRange},
+    vid_batcher::{AdaptiveBatchSize, VidBatcher, VidRange},
 };
 
 use super::{
@@ -28,6 +37,194 @@ use super::{
     Catalog, Layout, Namespace,
 };
 
+lazy_static! {
+    static ref PRUNE_SCHEDULER: Arc<PruneScheduler> =
+        Arc::new(PruneScheduler::new(ENV_VARS.store.max_concurrent_prunes));
+    static ref SOFT_DELETES: SoftDeletes =
+        SoftDeletes::new(ENV_VARS.store.prune_soft_delete_window);
+}
+
+/// Keeps automatic pruning of the same deployment from running more often
+/// than once every `min_interval`
+pub(crate) struct PruneThrottle {
+    min_interval: Duration,
+    last_prune: Mutex<HashMap<DeploymentId, Instant>>,
+}
+
+impl PruneThrottle {
+    pub(crate) fn new(min_interval: Duration) -> Self {
+        PruneThrottle {
+            min_interval,
+            last_prune: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return `true` if `deployment` may be pruned at `now`, and remember
+    /// that it was pruned then. Return `false` if the last prune was less
+    /// than `min_interval` ago
+    pub(crate) fn try_start(&self, deployment: DeploymentId, now: Instant) -> bool {
+        if self.min_interval.is_zero() {
+            return true;
+        }
+
+        let mut last_prune = self.last_prune.lock().unwrap();
+        match last_prune.get(&deployment) {
+            Some(last) if now.saturating_duration_since(*last) < self.min_interval => false,
+            _ => {
+                last_prune.insert(deployment, now);
+                true
+            }
+        }
+    }
+}
+
+/// Keeps rows that pruning removes around for a while after they have been
+/// hidden from queries. Pruning hides rows by moving the `earliest_block`
+/// of the deployment, and queries for blocks before that fail. We remember
+/// when each `earliest_block` was requested and only physically remove rows
+/// before the latest `earliest_block` that was requested at least `window`
+/// ago. Since this bookkeeping is only kept in memory, a restart means
+/// that hidden rows stay around until a later pruning run
+struct SoftDeletes {
+    window: Duration,
+    requested: Mutex<HashMap<DeploymentId, Vec<(Instant, BlockNumber)>>>,
+}
+
+impl SoftDeletes {
+    fn new(window: Duration) -> Self {
+        SoftDeletes {
+            window,
+            requested: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that pruning `deployment` to `earliest_block` was requested
+    /// at `now` and return the block before which rows can be physically
+    /// removed, or `None` if no rows can be removed yet
+    fn removable_before(
+        &self,
+        deployment: DeploymentId,
+        earliest_block: BlockNumber,
+        now: Instant,
+    ) -> Option<BlockNumber> {
+        if self.window.is_zero() {
+            return Some(earliest_block);
+        }
+
+        let mut requested = self.requested.lock().unwrap();
+        let requests = requested.entry(deployment).or_default();
+        requests.push((now, earliest_block));
+        let expired = |at: &Instant| now.saturating_duration_since(*at) >= self.window;
+        let block = requests
+            .iter()
+            .filter(|(at, _)| expired(at))
+            .map(|(_, block)| *block)
+            .max();
+        requests.retain(|(at, _)| !expired(at));
+        block
+    }
+}
+
+/// Limits how many tables can be pruned at the same time across all
+/// deployments. Deployments wait for a slot in a queue and get slots in
+/// the order in which they asked for them. Since a deployment prunes one
+/// table at a time and has to get back in line for each table, deployments
+/// take turns pruning their tables, and a deployment with many tables can
+/// not starve other deployments. Waiting happens before a connection for
+/// pruning the table is checked out so that deployments that wait do not
+/// tie up connections
+struct PruneScheduler {
+    /// The maximum number of tables that can be pruned at once; 0 means
+    /// there is no limit
+    max_concurrent: usize,
+    state: Mutex<SchedulerState>,
+    changed: Condvar,
+}
+
+struct SchedulerState {
+    /// How many tables are currently being pruned
+    running: usize,
+    /// The deployments that are waiting for a slot, in the order in which
+    /// they will get one
+    waiting: VecDeque<DeploymentId>,
+}
+
+impl PruneScheduler {
+    fn new(max_concurrent: usize) -> Self {
+        PruneScheduler {
+            max_concurrent,
+            state: Mutex::new(SchedulerState {
+                running: 0,
+                waiting: VecDeque::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Block until it is `deployment`'s turn to prune a table. The slot is
+    /// released when the returned `PruneSlot` is dropped
+    fn acquire(self: &Arc<Self>, deployment: DeploymentId) -> PruneSlot {
+        let mut state = self.state.lock().unwrap();
+        if self.max_concurrent > 0 {
+            state.waiting.push_back(deployment);
+            while state.running >= self.max_concurrent || state.waiting.front() != Some(&deployment)
+            {
+                state = self.changed.wait(state).unwrap();
+            }
+            state.waiting.pop_front();
+            // The next deployment in line might be able to run, too
+            self.changed.notify_all();
+        }
+        state.running += 1;
+        PruneSlot {
+            scheduler: self.cheap_clone(),
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// A slot for pruning one table, handed out by `PruneScheduler::acquire`
+pub(crate) struct PruneSlot {
+    scheduler: Arc<PruneScheduler>,
+}
+
+impl Drop for PruneSlot {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// Block until it is `deployment`'s turn to prune a table. The slot must be
+/// held while the table is pruned, and must be acquired before checking
+/// out the connection for pruning the table since waiting for it can take
+/// a long time
+pub(crate) fn acquire_prune_slot(deployment: DeploymentId) -> PruneSlot {
+    PRUNE_SCHEDULER.acquire(deployment)
+}
+
+/// The tables that one run of pruning a deployment goes through and how
+/// each of them is pruned, made by `Layout::prune_plan`. Since each table
+/// is pruned on its own, pruning different tables can use different
+/// connections
+pub(crate) struct PrunePlan {
+    req: PruneRequest,
+    tables: Vec<(Arc<Table>, PruningStrategy)>,
+    /// Whether the namespace for rebuilt tables still needs to be created
+    recreate_dst_nsp: bool,
+}
+
+impl PrunePlan {
+    /// The number of tables that need to be pruned
+    pub(crate) fn table_count(&self) -> usize {
+        self.tables.len()
+    }
+}
+
 /// Utility to copy relevant data out of a source table and into a new
 /// destination table and replace the source table with the destination
 /// table
@@ -88,17 +285,20 @@ impl TablePair {
     /// concurrently to this copy
     fn copy_final_entities(
         &self,
+        logger: &Logger,
         conn: &mut PgConnection,
         reporter: &mut dyn PruneReporter,
         earliest_block: BlockNumber,
         final_block: BlockNumber,
+        deterministic: bool,
         cancel: &CancelHandle,
-    ) -> Result<(), CancelableError<StoreError>> {
+    ) -> Result<usize, CancelableError<StoreError>> {
         let column_list = self.column_list();
+        let mut total = 0;
 
         // Determine the last vid that we need to copy
         let range = VidRange::for_prune(conn, &self.src, earliest_block, final_block)?;
-        let mut batcher = VidBatcher::load(conn, &self.src_nsp, &self.src, range)?;
+        let mut batcher = batcher(logger, conn, &self.src_nsp, &self.src, range, deterministic)?;
 
         while !batcher.finished() {
             let (_, rows) = batcher.step(|start, end| {
@@ -134,6 +334,7 @@ impl TablePair {
             })?;
             cancel.check_cancel()?;
 
+            total += rows.unwrap_or(0);
             reporter.prune_batch(
                 self.src.name.as_str(),
                 rows.unwrap_or(0),
@@ -141,7 +342,7 @@ impl TablePair {
                 batcher.finished(),
             );
         }
-        Ok(())
+        Ok(total)
     }
 
     /// Copy all entity versions visible after `final_block` in batches,
@@ -149,15 +350,18 @@ impl TablePair {
     /// other write activity to the source table is blocked while we copy
     fn copy_nonfinal_entities(
         &self,
+        logger: &Logger,
         conn: &mut PgConnection,
         reporter: &mut dyn PruneReporter,
         final_block: BlockNumber,
-    ) -> Result<(), StoreError> {
+        deterministic: bool,
+    ) -> Result<usize, StoreError> {
         let column_list = self.column_list();
+        let mut total = 0;
 
         // Determine the last vid that we need to copy
         let range = VidRange::for_prune(conn, &self.src, final_block + 1, BLOCK_NUMBER_MAX)?;
-        let mut batcher = VidBatcher::load(conn, &self.src.nsp, &self.src, range)?;
+        let mut batcher = batcher(logger, conn, &self.src.nsp, &self.src, range, deterministic)?;
 
         while !batcher.finished() {
             let (_, rows) = batcher.step(|start, end| {
@@ -187,6 +391,7 @@ impl TablePair {
                 })
             })?;
 
+            total += rows.unwrap_or(0);
             reporter.prune_batch(
                 self.src.name.as_str(),
                 rows.unwrap_or(0),
@@ -194,7 +399,7 @@ impl TablePair {
                 batcher.finished(),
             );
         }
-        Ok(())
+        Ok(total)
     }
 
     /// Replace the `src` table with the `dst` table
@@ -290,6 +495,71 @@ impl Layout {
         self.analyze_tables(conn, reporter, tables, cancel)
     }
 
+    /// Return statistics for the tables in this `Layout` after analyzing
+    /// the tables whose statistics are older than `max_age`
+    fn stale_version_stats(
+        &self,
+        conn: &mut PgConnection,
+        reporter: &mut dyn PruneReporter,
+        max_age: Duration,
+        cancel: &CancelHandle,
+    ) -> Result<Vec<VersionStats>, CancelableError<StoreError>> {
+        let stale = catalog::stale_stats(conn, &self.site.namespace, max_age)?;
+        let tables: Vec<_> = self
+            .tables
+            .values()
+            .filter(|table| stale.contains(&table.name))
+            .collect();
+
+        self.analyze_tables(conn, reporter, tables, cancel)
+    }
+
+    /// If pruning `table` to `earliest_block` would remove all of its
+    /// versions, remove them with `truncate` and return how many versions
+    /// there were. Otherwise, leave the table alone and return `None`
+    fn truncate_if_full(
+        &self,
+        conn: &mut PgConnection,
+        table: &Table,
+        earliest_block: BlockNumber,
+    ) -> Result<Option<usize>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Keep {
+            #[diesel(sql_type = Bool)]
+            keep: bool,
+        }
+
+        #[derive(QueryableByName)]
+        struct Versions {
+            #[diesel(sql_type = BigInt)]
+            count: i64,
+        }
+
+        let qname = &table.qualified_name;
+        // Block writes so that no new versions can appear between checking
+        // the table and truncating it
+        deployment::with_lock(conn, &self.site, |conn| {
+            let keep = sql_query(format!(
+                "select exists(select 1 from {qname} \
+                                where coalesce(upper(block_range), 2147483647) > $1) as keep"
+            ))
+            .bind::<Integer, _>(earliest_block)
+            .get_result::<Keep>(conn)?
+            .keep;
+            if keep {
+                return Ok(None);
+            }
+
+            let versions = sql_query(format!("select count(*) as count from {qname}"))
+                .get_result::<Versions>(conn)?
+                .count;
+            conn.batch_execute(&format!(
+                "/* controller=prune,phase=truncate */ truncate table {qname}"
+            ))?;
+            Ok(Some(versions as usize))
+        })
+    }
+
     /// Return all tables and the strategy to prune them withir stats whose ratio of distinct entities
     /// to versions is less than `prune_ratio`
     fn prunable_tables(
@@ -307,16 +577,26 @@ impl Layout {
                     .find(|stats| stats.tablename == table.name.as_str())
                     .map(|stats| (table, stats))
             })
-            .filter_map(|(table, stats)| req.strategy(stats).map(|strat| (table, strat)))
+            .filter_map(|(table, stats)| {
+                req.strategy(stats)
+                    .map(|strat| (table, strat, stats.versions))
+            })
             .collect::<Vec<_>>();
-        prunable_tables.sort_by(|(a, _), (b, _)| a.name.as_str().cmp(b.name.as_str()));
+        sort_tables(&mut prunable_tables, req.table_order, |(table, _, size)| {
+            (table.position(), *size)
+        });
         prunable_tables
+            .into_iter()
+            .map(|(table, strat, _)| (table, strat))
+            .collect()
     }
 
-    /// Remove all data from the underlying deployment that is not needed to
-    /// respond to queries before block `earliest_block`. The `req` is used
-    /// to determine which strategy should be used for pruning, rebuild or
-    /// delete.
+    /// Plan removing all data from the underlying deployment that is not
+    /// needed to respond to queries before block `earliest_block`. The
+    /// `req` is used to determine which strategy should be used for pruning
+    /// each table, rebuild or delete. The tables of the plan are then
+    /// pruned one at a time with `prune_table`, and pruning is wrapped up
+    /// with `finish_prune`. Returns `None` if there is nothing to prune.
     ///
     /// Blocks before `req.final_block` are considered final and it is
     /// assumed that they will not be modified in any way while pruning is
@@ -351,117 +631,207 @@ impl Layout {
     /// also block queries to the deployment, often for extended periods of
     /// time. The rebuild strategy never blocks reads, it only ever blocks
     /// writes.
-    pub fn prune(
+    pub fn prune_plan(
         &self,
-        logger: &Logger,
-        reporter: &mut dyn PruneReporter,
         conn: &mut PgConnection,
+        reporter: &mut dyn PruneReporter,
         req: &PruneRequest,
         cancel: &CancelHandle,
-    ) -> Result<(), CancelableError<StoreError>> {
+    ) -> Result<Option<PrunePlan>, CancelableError<StoreError>> {
         reporter.start(req);
 
-        let stats = self.version_stats(conn, reporter, true, cancel)?;
+        // With a soft delete window, the rows that `req` hides might have
+        // to stay around for now, and we only remove rows that have been
+        // hidden for long enough
+        let Some(earliest_block) =
+            SOFT_DELETES.removable_before(self.site.id, req.earliest_block, Instant::now())
+        else {
+            reporter.finish();
+            return Ok(None);
+        };
+        let req = PruneRequest {
+            earliest_block,
+            ..*req
+        };
 
-        let prunable_tables: Vec<_> = self.prunable_tables(&stats, req).into_iter().collect();
+        let stats = match req.stats_max_age {
+            Some(max_age) => self.stale_version_stats(conn, reporter, max_age, cancel)?,
+            None => self.version_stats(conn, reporter, true, cancel)?,
+        };
 
-        // create a shadow namespace where we will put the copies of our
-        // tables, but only create it in the database if we really need it
+        let tables = self
+            .prunable_tables(&stats, &req)
+            .into_iter()
+            .map(|(table, strat)| (table.cheap_clone(), strat))
+            .collect();
+
+        // The shadow namespace where we will put the copies of our tables
+        // is only created in the database if we really need it
+        Ok(Some(PrunePlan {
+            req,
+            tables,
+            recreate_dst_nsp: true,
+        }))
+    }
+
+    /// Prune the table at `index` in `plan`. Note that the subgraph writer
+    /// can write in between pruning tables, and in between the execution
+    /// of the `with_lock` block below, and might therefore work with tables
+    /// where some are pruned and some are not pruned yet. That does not
+    /// affect correctness since we make no assumption about where the
+    /// subgraph head is. If the subgraph advances while tables are pruned,
+    /// we might have an unnecessarily pessimistic but still safe value for
+    /// `final_block`. We do assume that `final_block` is far enough from
+    /// the subgraph head that it stays final even if a revert happens
+    /// while tables are pruned, but that is the definition of 'final'
+    pub fn prune_table(
+        &self,
+        logger: &Logger,
+        reporter: &mut dyn PruneReporter,
+        conn: &mut PgConnection,
+        plan: &mut PrunePlan,
+        index: usize,
+        cancel: &CancelHandle,
+    ) -> Result<(), CancelableError<StoreError>> {
+        let req = plan.req;
+        let (table, strat) = plan.tables[index].clone();
+        let table = &table;
         let dst_nsp = Namespace::prune(self.site.id);
-        let mut recreate_dst_nsp = true;
-
-        // Go table by table; note that the subgraph writer can write in
-        // between the execution of the `with_lock` block below, and might
-        // therefore work with tables where some are pruned and some are not
-        // pruned yet. That does not affect correctness since we make no
-        // assumption about where the subgraph head is. If the subgraph
-        // advances during this loop, we might have an unnecessarily
-        // pessimistic but still safe value for `final_block`. We do assume
-        // that `final_block` is far enough from the subgraph head that it
-        // stays final even if a revert happens during this loop, but that
-        // is the definition of 'final'
-        for (table, strat) in &prunable_tables {
-            reporter.start_table(table.name.as_str());
-            match strat {
-                PruningStrategy::Rebuild => {
-                    if recreate_dst_nsp {
-                        catalog::recreate_schema(conn, dst_nsp.as_str())?;
-                        recreate_dst_nsp = false;
-                    }
-                    let pair = TablePair::create(
-                        conn,
-                        table.cheap_clone(),
-                        self.site.namespace.clone(),
-                        dst_nsp.clone(),
-                        &self.input_schema,
-                        &self.catalog,
-                    )?;
-                    // Copy final entities. This can happen in parallel to indexing as
-                    // that part of the table will not change
-                    pair.copy_final_entities(
-                        conn,
-                        reporter,
-                        req.earliest_block,
-                        req.final_block,
-                        cancel,
-                    )?;
-                    // Copy nonfinal entities, and replace the original `src` table with
-                    // the smaller `dst` table
-                    // see also: deployment-lock-for-update
-                    reporter.start_switch();
+
+        let start = Instant::now();
+        reporter.start_table(table.name.as_str());
+        let truncated = if req.truncate_when_full {
+            self.truncate_if_full(conn, table, req.earliest_block)?
+        } else {
+            None
+        };
+        let rows = match (truncated, strat) {
+            (Some(rows), _) => {
+                reporter.prune_batch(table.name.as_str(), rows, PrunePhase::Truncate, true);
+                rows
+            }
+            (None, PruningStrategy::Rebuild) => {
+                if plan.recreate_dst_nsp {
+                    catalog::recreate_schema(conn, dst_nsp.as_str())?;
+                    plan.recreate_dst_nsp = false;
+                }
+                let pair = TablePair::create(
+                    conn,
+                    table.cheap_clone(),
+                    self.site.namespace.clone(),
+                    dst_nsp.clone(),
+                    &self.input_schema,
+                    &self.catalog,
+                )?;
+                // Copy final entities. This can happen in parallel to indexing as
+                // that part of the table will not change
+                let final_rows = pair.copy_final_entities(
+                    logger,
+                    conn,
+                    reporter,
+                    req.earliest_block,
+                    req.final_block,
+                    req.deterministic,
+                    cancel,
+                )?;
+                // Copy nonfinal entities, and replace the original `src` table with
+                // the smaller `dst` table
+                // see also: deployment-lock-for-update
+                reporter.start_switch();
+                let nonfinal_rows =
                     deployment::with_lock(conn, &self.site, |conn| -> Result<_, StoreError> {
-                        pair.copy_nonfinal_entities(conn, reporter, req.final_block)?;
+                        let rows = pair.copy_nonfinal_entities(
+                            logger,
+                            conn,
+                            reporter,
+                            req.final_block,
+                            req.deterministic,
+                        )?;
                         cancel.check_cancel().map_err(CancelableError::from)?;
 
                         conn.transaction(|conn| pair.switch(logger, conn))?;
                         cancel.check_cancel().map_err(CancelableError::from)?;
 
-                        Ok(())
+                        Ok(rows)
                     })?;
-                    reporter.finish_switch();
-                }
-                PruningStrategy::Delete => {
-                    // Delete all entity versions whose range was closed
-                    // before `req.earliest_block`
-                    let range = VidRange::for_prune(conn, &table, 0, req.earliest_block)?;
-                    let mut batcher = VidBatcher::load(conn, &self.site.namespace, &table, range)?;
-
-                    while !batcher.finished() {
-                        let (_, rows) = batcher.step(|start, end| {sql_query(format!(
-                            "/* controller=prune,phase=delete,start_vid={start},batch_size={batch_size} */ \
-                             delete from {qname} \
-                                          where coalesce(upper(block_range), 2147483647) <= $1 \
-                                            and vid >= $2 and vid <= $3",
-                            qname = table.qualified_name,
-                            batch_size = end - start + 1
+                reporter.finish_switch();
+                final_rows + nonfinal_rows
+            }
+            (None, PruningStrategy::Delete) => {
+                // Delete all entity versions whose range was closed
+                // before `req.earliest_block`
+                let range = VidRange::for_prune(conn, &table, 0, req.earliest_block)?;
+                let mut batcher = batcher(
+                    logger,
+                    conn,
+                    &self.site.namespace,
+                    table,
+                    range,
+                    req.deterministic,
+                )?;
+                let mut deleted = 0;
+
+                while !batcher.finished() {
+                    let (_, rows) = batcher.step(|start, end| {
+                        sql_query(delete_sql(
+                            table.qualified_name.as_str(),
+                            table.primary_key().name.quoted().as_str(),
+                            start,
+                            end,
+                            req.deterministic,
                         ))
                         .bind::<Integer, _>(req.earliest_block)
                         .bind::<BigInt, _>(start)
                         .bind::<BigInt, _>(end)
-                        .execute(conn).map_err(StoreError::from)})?;
-
-                        reporter.prune_batch(
-                            table.name.as_str(),
-                            rows.unwrap_or(0),
-                            PrunePhase::Delete,
-                            batcher.finished(),
-                        );
-                    }
+                        .execute(conn)
+                        .map_err(StoreError::from)
+                    })?;
+
+                    deleted += rows.unwrap_or(0);
+                    reporter.prune_batch(
+                        table.name.as_str(),
+                        rows.unwrap_or(0),
+                        PrunePhase::Delete,
+                        batcher.finished(),
+                    );
                 }
+                deleted
             }
-            reporter.finish_table(table.name.as_str());
-        }
+        };
+        reporter.finish_table(table.name.as_str());
+        let strategy = strat.to_string().to_lowercase();
+        maintenance_log::log_event(logger, conn, table, |bytes| MaintenanceEvent {
+            operation: Operation::Prune,
+            deployment: &self.site.deployment,
+            table: table.name.as_str(),
+            rows,
+            bytes,
+            duration: start.elapsed(),
+            strategy: &strategy,
+        });
+        Ok(())
+    }
+
+    /// Wrap up pruning after all tables in `plan` have been pruned
+    pub fn finish_prune(
+        &self,
+        conn: &mut PgConnection,
+        reporter: &mut dyn PruneReporter,
+        plan: PrunePlan,
+        cancel: &CancelHandle,
+    ) -> Result<(), CancelableError<StoreError>> {
         // Get rid of the temporary prune schema if we actually created it
-        if !recreate_dst_nsp {
+        if !plan.recreate_dst_nsp {
+            let dst_nsp = Namespace::prune(self.site.id);
             catalog::drop_schema(conn, dst_nsp.as_str())?;
         }
 
-        for (table, _) in &prunable_tables {
-            catalog::set_last_pruned_block(conn, &self.site, &table.name, req.earliest_block)?;
+        for (table, _) in &plan.tables {
+            catalog::set_last_pruned_block(conn, &self.site, &table.name, plan.req.earliest_block)?;
         }
 
         // Analyze the new tables
-        let tables = prunable_tables.iter().map(|(table, _)| *table).collect();
+        let tables = plan.tables.iter().map(|(table, _)| table).collect();
         self.analyze_tables(conn, reporter, tables, cancel)?;
 
         reporter.finish();
@@ -469,3 +839,252 @@ impl Layout {
         Ok(())
     }
 }
+
+/// Create the batcher for going through the `range` of `vid`s of `table`.
+/// With `deterministic`, batches only depend on `range`, so that pruning
+/// the same data always runs the same statements
+fn batcher(
+    logger: &Logger,
+    conn: &mut PgConnection,
+    nsp: &Namespace,
+    table: &Table,
+    range: VidRange,
+    deterministic: bool,
+) -> Result<VidBatcher, StoreError> {
+    if deterministic {
+        let size = AdaptiveBatchSize::new(table).size;
+        VidBatcher::fixed(logger, range, size)
+    } else {
+        VidBatcher::load(logger, conn, nsp, table, range)
+    }
+}
+
+/// The statement that deletes the entity versions in `qname` whose block
+/// range was closed before `$1` for `$2 <= vid <= $3`. With
+/// `deterministic`, the rows are locked and deleted ordered by `id` so
+/// that repeated runs on the same data behave the same
+fn delete_sql(qname: &str, id: &str, start: i64, end: i64, deterministic: bool) -> String {
+    let batch_size = end - start + 1;
+    let comment =
+        format!("/* controller=prune,phase=delete,start_vid={start},batch_size={batch_size} */");
+    if deterministic {
+        format!(
+            "{comment} \
+             delete from {qname} \
+              where vid in (select vid from {qname} \
+                             where coalesce(upper(block_range), 2147483647) <= $1 \
+                               and vid >= $2 and vid <= $3 \
+                             order by {id}, vid \
+                               for update)"
+        )
+    } else {
+        format!(
+            "{comment} \
+             delete from {qname} \
+              where coalesce(upper(block_range), 2147483647) <= $1 \
+                and vid >= $2 and vid <= $3"
+        )
+    }
+}
+
+/// Sort `tables` into the order in which they should be pruned. `key`
+/// returns the position of a table in the schema and the number of rows
+/// in it
+fn sort_tables<T, F>(tables: &mut [T], order: PruneTableOrder, key: F)
+where
+    F: Fn(&T) -> (u32, i64),
+{
+    match order {
+        PruneTableOrder::SizeDesc => tables.sort_by_key(|table| {
+            let (position, size) = key(table);
+            (std::cmp::Reverse(size), position)
+        }),
+        PruneTableOrder::SizeAsc => tables.sort_by_key(|table| {
+            let (position, size) = key(table);
+            (size, position)
+        }),
+        PruneTableOrder::Declared => tables.sort_by_key(|table| key(table).0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn wait_until_waiting(scheduler: &PruneScheduler, count: usize) {
+        while scheduler.state.lock().unwrap().waiting.len() < count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn prune_scheduler_interleaves_tables() {
+        const A: DeploymentId = DeploymentId(1);
+        const B: DeploymentId = DeploymentId(2);
+
+        let scheduler = Arc::new(PruneScheduler::new(1));
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // Prune `tables` one at a time, getting a slot for each table
+        let prune = |deployment: DeploymentId, tables: &'static [&'static str]| {
+            let scheduler = scheduler.cheap_clone();
+            let log = log.cheap_clone();
+            thread::spawn(move || {
+                for table in tables {
+                    let _slot = scheduler.acquire(deployment);
+                    log.lock().unwrap().push(*table);
+                    thread::sleep(Duration::from_millis(20));
+                }
+            })
+        };
+
+        // Deployment A starts pruning its first table, and both
+        // deployments queue up for their next table
+        let slot = scheduler.acquire(A);
+        log.lock().unwrap().push("a1");
+        let b = prune(B, &["b1", "b2", "b3"]);
+        wait_until_waiting(&scheduler, 1);
+        let a = prune(A, &["a2", "a3", "a4"]);
+        wait_until_waiting(&scheduler, 2);
+        drop(slot);
+
+        a.join().unwrap();
+        b.join().unwrap();
+
+        // The deployments take turns, table by table, rather than one
+        // deployment pruning all its tables first
+        let log = log.lock().unwrap().clone();
+        assert_eq!(vec!["a1", "b1", "a2", "b2", "a3", "b3", "a4"], log);
+    }
+
+    #[test]
+    fn prune_min_interval() {
+        const D: DeploymentId = DeploymentId(1);
+        const MINUTE: Duration = Duration::from_secs(60);
+
+        let throttle = PruneThrottle::new(30 * MINUTE);
+        let start = Instant::now();
+
+        assert!(throttle.try_start(D, start));
+        // A second prune within the interval is skipped, and does not
+        // restart the interval
+        assert!(!throttle.try_start(D, start + 10 * MINUTE));
+        assert!(!throttle.try_start(D, start + 29 * MINUTE));
+        // Other deployments are not affected
+        assert!(throttle.try_start(DeploymentId(2), start + 10 * MINUTE));
+        // Once the interval has passed, pruning runs again
+        assert!(throttle.try_start(D, start + 30 * MINUTE));
+        assert!(!throttle.try_start(D, start + 31 * MINUTE));
+
+        // Without an interval, pruning is never skipped
+        let throttle = PruneThrottle::new(Duration::ZERO);
+        assert!(throttle.try_start(D, start));
+        assert!(throttle.try_start(D, start));
+    }
+
+    #[test]
+    fn soft_delete_window() {
+        const D: DeploymentId = DeploymentId(1);
+        const MINUTE: Duration = Duration::from_secs(60);
+
+        // Without a window, rows are removed right away
+        let soft_deletes = SoftDeletes::new(Duration::ZERO);
+        assert_eq!(
+            Some(100),
+            soft_deletes.removable_before(D, 100, Instant::now())
+        );
+
+        let soft_deletes = SoftDeletes::new(10 * MINUTE);
+        let start = Instant::now();
+
+        // Within the window, rows before block 100 are hidden but not
+        // removed
+        assert_eq!(None, soft_deletes.removable_before(D, 100, start));
+        assert_eq!(
+            None,
+            soft_deletes.removable_before(D, 150, start + 5 * MINUTE)
+        );
+
+        // Once the window has passed for the first request, rows before
+        // block 100 can be removed, but not the ones before block 150
+        assert_eq!(
+            Some(100),
+            soft_deletes.removable_before(D, 200, start + 10 * MINUTE)
+        );
+        assert_eq!(
+            Some(200),
+            soft_deletes.removable_before(D, 250, start + 30 * MINUTE)
+        );
+
+        // Other deployments are tracked separately
+        assert_eq!(
+            None,
+            soft_deletes.removable_before(DeploymentId(2), 100, start + 30 * MINUTE)
+        );
+    }
+
+    #[test]
+    fn prune_scheduler_unlimited() {
+        let scheduler = Arc::new(PruneScheduler::new(0));
+        let slots: Vec<_> = (0..10)
+            .map(|id| scheduler.acquire(DeploymentId(id)))
+            .collect();
+        assert_eq!(10, scheduler.state.lock().unwrap().running);
+        drop(slots);
+        assert_eq!(0, scheduler.state.lock().unwrap().running);
+    }
+
+    #[test]
+    fn table_order() {
+        // (position, size) for each table
+        const TABLES: [(u32, i64); 4] = [(2, 300), (0, 50), (3, 50), (1, 10)];
+
+        let sorted = |order| {
+            let mut tables = TABLES.to_vec();
+            sort_tables(&mut tables, order, |table| *table);
+            tables.into_iter().map(|(pos, _)| pos).collect::<Vec<_>>()
+        };
+
+        // Tables of the same size are pruned in declared order
+        assert_eq!(vec![2, 0, 3, 1], sorted(PruneTableOrder::SizeDesc));
+        assert_eq!(vec![1, 0, 3, 2], sorted(PruneTableOrder::SizeAsc));
+        assert_eq!(vec![0, 1, 2, 3], sorted(PruneTableOrder::Declared));
+    }
+
+    #[test]
+    fn prune_deterministic() {
+        let logger = Logger::root(graph::slog::Discard, graph::prelude::o!());
+
+        // The delete statements for going through a range of vids, where
+        // each batch takes `pause` longer than the previous one
+        let statements = |pause: Duration| {
+            let mut batcher = VidBatcher::fixed(&logger, VidRange::new(1, 1000), 100).unwrap();
+            let mut statements = Vec::new();
+            let mut sleep = Duration::ZERO;
+            while !batcher.finished() {
+                batcher
+                    .step(|start, end| {
+                        thread::sleep(sleep);
+                        statements.push(delete_sql(r#"sgd1."thing""#, r#""id""#, start, end, true));
+                        Ok(())
+                    })
+                    .unwrap();
+                sleep += pause;
+            }
+            statements
+        };
+
+        // Batches do not depend on how long they take, and rows are
+        // deleted in order of their id
+        let first = statements(Duration::ZERO);
+        let second = statements(Duration::from_millis(1));
+        assert_eq!(first, second);
+        assert!(first.len() > 1);
+        assert!(first
+            .iter()
+            .all(|sql| sql.contains(r#"order by "id", vid"#)));
+        assert!(!delete_sql(r#"sgd1."thing""#, r#""id""#, 1, 100, false).contains("order by"));
+    }
+}
@@ -1,18 +1,22 @@
-use std::{collections::BTreeSet, sync::Arc};
-
 use diesel::{debug_query, pg::Pg};
 use graph::{
-    prelude::{r, serde_json as json, DeploymentHash, EntityFilter},
-    schema::InputSchema,
+    components::store::AttributeNames,
+    env::{CopyOverlapPolicy, CopyRowOrder},
+    prelude::{
+        r, serde_json as json, EntityCollection, EntityFilter, EntityOrder, EntityRange,
+        QueryExecutionError, ValueType, BLOCK_NUMBER_MAX,
+    },
 };
 
 use crate::{
-    layout_for_tests::{make_dummy_site, Namespace},
-    relational::{Catalog, ColumnType, Layout},
+    relational::{self, ColumnType, Layout},
     relational_queries::FromColumnValue,
 };
 
-use crate::relational_queries::Filter;
+use crate::relational_queries::{
+    typea_batch_size, CopyEntityBatchQuery, EntityData, Filter, FilterCollection, FilterQuery,
+    QueryMemory, QueryPlan,
+};
 
 #[test]
 fn gql_value_from_bytes() {
@@ -29,13 +33,7 @@ fn gql_value_from_bytes() {
 }
 
 fn test_layout(gql: &str) -> Layout {
-    let subgraph = DeploymentHash::new("subgraph").unwrap();
-    let schema = InputSchema::parse_latest(gql, subgraph.clone()).expect("Test schema invalid");
-    let namespace = Namespace::new("sgd0815".to_owned()).unwrap();
-    let site = Arc::new(make_dummy_site(subgraph, namespace, "anet".to_string()));
-    let catalog =
-        Catalog::for_tests(site.clone(), BTreeSet::new()).expect("Can not create catalog");
-    Layout::new(site, &schema, catalog).expect("Failed to construct Layout")
+    relational::test_layout("subgraph", "sgd0815", gql)
 }
 
 #[track_caller]
@@ -86,3 +84,405 @@ fn prefix() {
     let filter = EntityFilter::In("address".to_string(), vec!["0xbeef".into()]);
     filter_contains(filter, r#"substring(c."address", 1, 64) in ($1)"#);
 }
+
+#[test]
+fn copy_overlap_policy() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: Bytes!,
+        name: String
+    }";
+    // The source and destination have the same structure, which is all
+    // the copy queries care about
+    let layout = test_layout(SCHEMA);
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Thing").unwrap())
+        .unwrap();
+    let copy = |policy| {
+        CopyEntityBatchQuery::new(table, table, 1, 100, policy, CopyRowOrder::Physical).unwrap()
+    };
+    const OVERLAP: &str = "d.id = s.id and d.block_range && s.block_range";
+
+    // With `fail`, we copy everything and let the exclusion constraint on
+    // the destination reject a version that overlaps with an existing one
+    let query = copy(CopyOverlapPolicy::Fail);
+    assert!(query.delete_overlaps().is_none());
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    assert!(
+        !sql.contains(OVERLAP),
+        "unexpected overlap check in /{}/",
+        sql
+    );
+
+    // With `skip`, source versions that overlap are not copied
+    let query = copy(CopyOverlapPolicy::Skip);
+    assert!(query.delete_overlaps().is_none());
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    let exp = format!(
+        "and not exists (select 1 from \"sgd0815\".\"thing\" d where {})",
+        OVERLAP
+    );
+    assert!(
+        sql.contains(&exp),
+        "Expected query /{}/ to contain /{}/",
+        sql,
+        exp
+    );
+
+    // With `prefer_source`, overlapping versions in the destination are
+    // removed before everything is copied
+    let query = copy(CopyOverlapPolicy::PreferSource);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    assert!(
+        !sql.contains(OVERLAP),
+        "unexpected overlap check in /{}/",
+        sql
+    );
+    let delete = query.delete_overlaps().unwrap();
+    let sql = debug_query::<Pg, _>(&delete).to_string();
+    let exp = format!(
+        "delete from \"sgd0815\".\"thing\" d using \"sgd0815\".\"thing\" s\n \
+         where s.vid >= $1 and s.vid <= $2 and {}",
+        OVERLAP
+    );
+    assert!(
+        sql.contains(&exp),
+        "Expected query /{}/ to contain /{}/",
+        sql,
+        exp
+    );
+}
+
+#[test]
+fn copy_row_order() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: Bytes!,
+        name: String
+    }";
+    let layout = test_layout(SCHEMA);
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Thing").unwrap())
+        .unwrap();
+    let sql = |order| {
+        let query = CopyEntityBatchQuery::new(table, table, 1, 100, CopyOverlapPolicy::Fail, order)
+            .unwrap();
+        debug_query::<Pg, _>(&query).to_string()
+    };
+
+    // In physical order, rows are copied in whatever order Postgres reads
+    // them
+    let physical = sql(CopyRowOrder::Physical);
+    assert!(
+        !physical.contains("order by"),
+        "unexpected order in /{}/",
+        physical
+    );
+
+    // Ordering by id makes the layout of the destination predictable
+    let by_id = sql(CopyRowOrder::ById);
+    assert!(
+        by_id.contains(" and vid <= $2\n order by id, vid\n returning "),
+        "missing order by id in /{}/",
+        by_id
+    );
+}
+
+#[test]
+fn max_query_rows() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: Bytes!,
+        name: String
+    }";
+    let layout = test_layout(SCHEMA);
+    let thing = layout.input_schema.entity_type("Thing").unwrap();
+    let collection = FilterCollection::new(
+        &layout,
+        EntityCollection::All(vec![(thing, AttributeNames::All)]),
+        None,
+        BLOCK_NUMBER_MAX,
+    )
+    .unwrap();
+    let query = |max_rows| {
+        FilterQuery::new(
+            &collection,
+            &layout,
+            None,
+            EntityOrder::Default,
+            EntityRange::first(1000),
+            BLOCK_NUMBER_MAX,
+            None,
+            &layout.site,
+            max_rows,
+            0,
+        )
+        .unwrap()
+    };
+
+    let sql = |query: &FilterQuery| {
+        let sql = debug_query::<Pg, _>(query).to_string();
+        let (sql, _binds) = sql.split_once(" -- binds").unwrap();
+        sql.to_string()
+    };
+
+    // Without a limit, the query is unchanged
+    let unlimited = sql(&query(0));
+    assert!(
+        unlimited.ends_with(") c"),
+        "unexpected row limit in /{}/",
+        unlimited
+    );
+    assert!(query(0).check_max_rows(1_000_000).is_ok());
+
+    // With a limit, we fetch one row more than allowed to detect when the
+    // limit is exceeded. The limit goes on the outermost statement rather
+    // than on a subquery around it, which would not keep the rows in order
+    let query = query(10);
+    assert_eq!(format!("{unlimited}\n limit 11"), sql(&query));
+
+    assert!(query.check_max_rows(10).is_ok());
+    let err = query.check_max_rows(11).unwrap_err();
+    assert_eq!(
+        "the query returns more than the allowed limit of 10 rows from the store. \
+         Use `first` and filters to query fewer entities",
+        err.to_string()
+    );
+}
+
+#[test]
+fn large_result_warning() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: Bytes!,
+        name: String
+    }";
+    let layout = test_layout(SCHEMA);
+    let thing = layout.input_schema.entity_type("Thing").unwrap();
+    let filter = EntityFilter::Equal("name".to_string(), "secret".into());
+    let collection = FilterCollection::new(
+        &layout,
+        EntityCollection::All(vec![(thing, AttributeNames::All)]),
+        Some(&filter),
+        BLOCK_NUMBER_MAX,
+    )
+    .unwrap();
+    let query = FilterQuery::new(
+        &collection,
+        &layout,
+        Some(&filter),
+        EntityOrder::Default,
+        EntityRange::first(1000),
+        BLOCK_NUMBER_MAX,
+        None,
+        &layout.site,
+        100,
+        0,
+    )
+    .unwrap();
+
+    // Without a threshold, no result is too large
+    assert_eq!(None, query.large_result(100, 0));
+    assert_eq!(None, query.large_result(10, 10));
+
+    // A result above the threshold but below the hard cap triggers the
+    // warning, and the warning does not mention the filter values
+    assert!(query.check_max_rows(11).is_ok());
+    let shape = query.large_result(11, 10).unwrap();
+    assert!(
+        shape.starts_with("from sgd0815.thing order id"),
+        "unexpected shape /{}/",
+        shape
+    );
+    assert!(
+        shape.contains(" first 1000 at "),
+        "unexpected shape /{}/",
+        shape
+    );
+    assert!(!shape.contains("secret"), "shape /{}/ contains data", shape);
+}
+
+#[test]
+fn max_order_by_columns() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: Bytes!,
+        name: String
+    }";
+    let layout = test_layout(SCHEMA);
+    let thing = layout.input_schema.entity_type("Thing").unwrap();
+    let collection = FilterCollection::new(
+        &layout,
+        EntityCollection::All(vec![(thing, AttributeNames::All)]),
+        None,
+        BLOCK_NUMBER_MAX,
+    )
+    .unwrap();
+    let filter_query = |order, max_columns| {
+        FilterQuery::new(
+            &collection,
+            &layout,
+            None,
+            order,
+            EntityRange::first(100),
+            BLOCK_NUMBER_MAX,
+            None,
+            &layout.site,
+            0,
+            max_columns,
+        )
+        .unwrap()
+    };
+    let order_by = |query: &FilterQuery| {
+        let sql = debug_query::<Pg, _>(query).to_string();
+        let start = sql.find("order by").expect("query has an order by clause");
+        let end = sql[start..].find("\n").map_or(sql.len(), |end| start + end);
+        sql[start..end].to_string()
+    };
+
+    // Without a cap, or while the cap is not exceeded, ordering by id also
+    // orders by the block range
+    for max_columns in [0, 2] {
+        let query = filter_query(EntityOrder::Default, max_columns);
+        assert!(!query.skipped_block_range());
+        assert_eq!("order by \"id\", block_range", order_by(&query));
+    }
+
+    // Ordering by an attribute already uses two columns and never adds the
+    // block range
+    let query = filter_query(
+        EntityOrder::Descending("name".to_string(), ValueType::String),
+        1,
+    );
+    assert!(!query.skipped_block_range());
+    assert!(!order_by(&query).contains("block_range"));
+
+    // A cap that the block range would exceed suppresses it
+    let query = filter_query(EntityOrder::Default, 1);
+    assert!(query.skipped_block_range());
+    assert_eq!("order by \"id\"", order_by(&query));
+}
+
+#[test]
+fn typea_batch_size_for_wide_tables() {
+    let fields = (0..50)
+        .map(|i| format!("field{i}: Int"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let schema = format!(
+        "
+    type Narrow @entity {{
+        id: Bytes!,
+        name: String
+    }}
+    type Wide @entity {{
+        id: Bytes!,
+        {fields}
+    }}"
+    );
+    let layout = test_layout(&schema);
+    let table = |name: &str| {
+        let entity_type = layout.input_schema.entity_type(name).unwrap();
+        layout.table_for_entity(&entity_type).unwrap().clone()
+    };
+
+    // The narrow table uses the configured batch size, the wide table
+    // gets a batch size that keeps it below the parameter limit
+    assert_eq!(150, typea_batch_size(&table("Narrow"), 150, 1000));
+    let wide = typea_batch_size(&table("Wide"), 150, 1000);
+    assert!(wide < 150);
+    assert!(wide * table("Wide").columns.len() <= 1000);
+
+    // The batch size never drops to 0
+    assert_eq!(1, typea_batch_size(&table("Wide"), 150, 10));
+}
+
+#[test]
+fn query_memory_limit() {
+    // A synthetic result of 100 rows with about 1kB each
+    let rows: Vec<_> = (0..100)
+        .map(|i| {
+            EntityData::new(
+                "Thing".to_string(),
+                json::json!({ "id": format!("{i}"), "name": "x".repeat(1024) }),
+            )
+        })
+        .collect();
+    let total: usize = rows.iter().map(|row| row.weight()).sum();
+    assert!(total > 100 * 1024);
+
+    let decode = |limit: usize| {
+        let mut memory = QueryMemory::new(limit);
+        rows.iter()
+            .map(|row| memory.add(row.weight()))
+            .collect::<Result<Vec<_>, _>>()
+    };
+
+    // Without a limit, or with a limit that the result fits in, decoding
+    // succeeds
+    assert!(decode(0).is_ok());
+    assert!(decode(total).is_ok());
+
+    // A result that is larger than the limit aborts decoding
+    let err = decode(10 * 1024).unwrap_err();
+    assert!(matches!(
+        err,
+        QueryExecutionError::QueryMemoryExceeded(10240)
+    ));
+    assert_eq!(
+        "decoding the query result from the store needs more than the allowed \
+         limit of 10240 bytes. Use `first` and filters to query fewer entities",
+        err.to_string()
+    );
+}
+
+#[test]
+fn block_range_pages() {
+    fn pages(plan: json::Value) -> f64 {
+        QueryPlan::from_json(&plan.to_string())
+            .unwrap()
+            .block_range_pages()
+    }
+
+    // 81920 rows of 100 bytes through the brin index are 1000 pages
+    let bitmap = json::json!([{ "Plan": {
+        "Node Type": "Limit",
+        "Total Cost": 5000.0,
+        "Plans": [{
+            "Node Type": "Bitmap Heap Scan",
+            "Plan Rows": 81920,
+            "Plan Width": 100,
+            "Plans": [{
+                "Node Type": "Bitmap Index Scan",
+                "Index Name": "brin_thing",
+                "Plan Rows": 81920,
+                "Plan Width": 0
+            }]
+        }]
+    }}]);
+    assert_eq!(1000.0, pages(bitmap));
+
+    let index = json::json!([{ "Plan": {
+        "Node Type": "Index Scan",
+        "Index Name": "thing_block_range_excl",
+        "Plan Rows": 100,
+        "Plan Width": 100
+    }}]);
+    assert_eq!(2.0, pages(index));
+
+    // Scans through other indexes and sequential scans do not count
+    let other = json::json!([{ "Plan": {
+        "Node Type": "Append",
+        "Plans": [{
+            "Node Type": "Index Scan",
+            "Index Name": "attr_0_1_thing_name",
+            "Plan Rows": 81920,
+            "Plan Width": 100
+        }, {
+            "Node Type": "Seq Scan",
+            "Plan Rows": 81920,
+            "Plan Width": 100
+        }]
+    }}]);
+    assert_eq!(0.0, pages(other));
+}
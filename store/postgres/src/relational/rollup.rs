@@ -53,18 +53,21 @@
 //!                    group by id, timestamp, <dimensions>)
 //!   select id, timestamp, <dimensions>, <aggregates> from combined
 //! ```
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use diesel::{sql_query, PgConnection, RunQueryDsl as _};
+use diesel::{sql_query, PgConnection, QueryResult, RunQueryDsl as _};
 
 use diesel::sql_types::{Integer, Nullable, Timestamptz};
 use graph::blockchain::BlockTime;
-use graph::components::store::{BlockNumber, StoreError};
+use graph::components::store::{BlockNumber, EntityFilter, StoreError};
 use graph::constraint_violation;
-use graph::data::store::IdType;
+use graph::data::store::scalar::Timestamp;
+use graph::data::store::{IdType, Value};
+use graph::prelude::ENV_VARS;
 use graph::schema::{
     Aggregate, AggregateFn, Aggregation, AggregationInterval, ExprVisitor, VisitExpr,
 };
@@ -73,9 +76,28 @@ use graph::sqlparser::parser::ParserError;
 use itertools::Itertools;
 
 use crate::relational::Table;
+use crate::relational_queries::EntityData;
 
 use super::{Column, SqlName};
 
+#[cfg(debug_assertions)]
+lazy_static::lazy_static! {
+    /// Tests set this to cache the results of rollup queries even if
+    /// `GRAPH_STORE_ROLLUP_RESULT_CACHE_TTL` is not set
+    pub static ref ROLLUP_RESULT_CACHE_TTL: std::sync::Mutex<Option<Duration>> =
+        std::sync::Mutex::new(None);
+}
+
+/// How long the results of queries for closed buckets are cached, or
+/// `None` if they are not cached
+pub(crate) fn rollup_result_cache_ttl() -> Option<Duration> {
+    #[cfg(debug_assertions)]
+    if let Some(ttl) = *ROLLUP_RESULT_CACHE_TTL.lock().unwrap() {
+        return Some(ttl);
+    }
+    ENV_VARS.store.rollup_result_cache_ttl
+}
+
 /// Rewrite `expr` by replacing field names with column names and return the
 /// rewritten SQL expression and the columns used in the expression
 fn rewrite<'a>(table: &'a Table, expr: &str) -> Result<(String, Vec<&'a str>), StoreError> {
@@ -227,8 +249,7 @@ impl<'a> Agg<'a> {
 #[derive(Debug, Clone)]
 pub(crate) struct Rollup {
     pub(crate) interval: AggregationInterval,
-    #[allow(dead_code)]
-    agg_table: Arc<Table>,
+    pub(crate) agg_table: Arc<Table>,
     insert_sql: String,
     /// A query that determines the last time a rollup was done. The query
     /// finds the latest timestamp in the aggregation table and adds the
@@ -310,6 +331,109 @@ impl Rollup {
     }
 }
 
+/// The results of queries for aggregations that only cover closed
+/// buckets, keyed by the SQL of the query. Rollups only write a bucket once
+/// it has closed, and the bucket does not change after that unless the
+/// block that wrote it is reverted, which the TTL for entries bounds
+#[derive(Debug, Default)]
+pub(crate) struct RollupResultCache {
+    entries: RwLock<HashMap<String, CachedResult>>,
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    cached: Instant,
+    /// An estimate of how many bytes the key and the values use
+    weight: usize,
+    values: Arc<Vec<EntityData>>,
+}
+
+impl RollupResultCache {
+    /// Return the result for `key` if it was cached less than `ttl` ago
+    pub(crate) fn get(&self, key: &str, ttl: Duration) -> Option<Arc<Vec<EntityData>>> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(key)?;
+        (entry.cached.elapsed() < ttl).then(|| entry.values.clone())
+    }
+
+    /// Cache `values` for `key`, and remove any entries that are older
+    /// than `ttl`. If the cache would then use more than `max_bytes`,
+    /// remove the oldest entries until it does not. Results that are
+    /// bigger than `max_bytes` by themselves are not cached
+    pub(crate) fn set(
+        &self,
+        key: String,
+        ttl: Duration,
+        max_bytes: usize,
+        values: Vec<EntityData>,
+    ) {
+        let weight = key.len() + values.iter().map(EntityData::weight).sum::<usize>();
+        if weight > max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|_, entry| entry.cached.elapsed() < ttl);
+        let mut total: usize = entries.values().map(|entry| entry.weight).sum();
+        while total + weight > max_bytes {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.cached)
+                .map(|(key, _)| key.clone());
+            let Some(entry) = oldest.and_then(|oldest| entries.remove(&oldest)) else {
+                break;
+            };
+            total -= entry.weight;
+        }
+        let entry = CachedResult {
+            cached: Instant::now(),
+            weight,
+            values: Arc::new(values),
+        };
+        entries.insert(key, entry);
+    }
+}
+
+/// The latest `timestamp` of an aggregation that `filter` matches, or
+/// `None` if `filter` does not limit the timestamp
+pub(crate) fn timestamp_upper_bound(filter: &EntityFilter) -> Option<Timestamp> {
+    use EntityFilter::*;
+
+    match filter {
+        Equal(attr, Value::Timestamp(ts))
+        | LessThan(attr, Value::Timestamp(ts))
+        | LessOrEqual(attr, Value::Timestamp(ts))
+            if attr == "timestamp" =>
+        {
+            Some(*ts)
+        }
+        And(filters) => filters.iter().filter_map(timestamp_upper_bound).min(),
+        _ => None,
+    }
+}
+
+/// Whether all buckets in `agg_table` that start at or before `bound` are
+/// closed. Since rollups write buckets in order, that is the case if the
+/// table already has a bucket that starts at or after `bound`
+pub(crate) fn buckets_closed(
+    conn: &mut PgConnection,
+    agg_table: &Table,
+    bound: Timestamp,
+) -> QueryResult<bool> {
+    #[derive(QueryableByName)]
+    struct LastBucket {
+        #[diesel(sql_type = Nullable<Timestamptz>)]
+        last_bucket: Option<Timestamp>,
+    }
+
+    let query = format!(
+        "select max(timestamp) as last_bucket from {}",
+        agg_table.qualified_name
+    );
+    let last_bucket = sql_query(query).get_result::<LastBucket>(conn)?.last_bucket;
+    Ok(last_bucket.map(|last| bound <= last).unwrap_or(false))
+}
+
 struct RollupSql<'a> {
     interval: AggregationInterval,
     src_table: &'a SqlName,
@@ -548,14 +672,18 @@ fn write_dims(dimensions: &[&Column], w: &mut dyn fmt::Write) -> fmt::Result {
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeSet, sync::Arc};
+    use std::{collections::BTreeSet, sync::Arc, time::Duration};
 
     use graph::{data::subgraph::DeploymentHash, schema::InputSchema};
     use itertools::Itertools as _;
 
     use crate::{
         layout_for_tests::{make_dummy_site, Namespace},
-        relational::{rollup::Rollup, Catalog, Layout},
+        relational::{
+            rollup::{Rollup, RollupResultCache},
+            Catalog, Layout,
+        },
+        relational_queries::EntityData,
     };
 
     // Check that the two strings are the same after replacing runs of
@@ -742,4 +870,36 @@ mod tests {
         let count_only = rollup_for(&layout, "count_only_day");
         check_eqv(COUNT_ONLY_SQL, &count_only.insert_sql);
     }
+
+    #[test]
+    fn rollup_result_cache_max_bytes() {
+        const TTL: Duration = Duration::from_secs(600);
+
+        let values = |n: usize| {
+            let row = EntityData::new("Stats".to_string(), serde_json::json!({ "id": "1" }));
+            vec![row; n]
+        };
+        let weight = |key: &str, n: usize| {
+            key.len() + values(n).iter().map(EntityData::weight).sum::<usize>()
+        };
+        let max_bytes = weight("one", 2) + weight("two", 2) + weight("three", 1);
+
+        let cache = RollupResultCache::default();
+        cache.set("one".to_string(), TTL, max_bytes, values(2));
+        std::thread::sleep(Duration::from_millis(2));
+        cache.set("two".to_string(), TTL, max_bytes, values(2));
+        assert!(cache.get("one", TTL).is_some());
+
+        // Making room for `three` evicts the oldest entry
+        std::thread::sleep(Duration::from_millis(2));
+        cache.set("three".to_string(), TTL, max_bytes, values(2));
+        assert!(cache.get("one", TTL).is_none());
+        assert!(cache.get("two", TTL).is_some());
+        assert!(cache.get("three", TTL).is_some());
+
+        // A result that is too big by itself is not cached
+        cache.set("four".to_string(), TTL, max_bytes, values(100));
+        assert!(cache.get("four", TTL).is_none());
+        assert!(cache.get("three", TTL).is_some());
+    }
 }
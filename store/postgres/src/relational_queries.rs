@@ -5,7 +5,7 @@
 ///!
 ///! Code in this module works very hard to minimize the number of allocations
 ///! that it performs
-use diesel::pg::Pg;
+use diesel::pg::{Pg, PgConnection};
 use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
 use diesel::query_dsl::RunQueryDsl;
 use diesel::result::{Error as DieselError, QueryResult};
@@ -18,10 +18,11 @@ use graph::data::store::{Id, IdType, NULL};
 use graph::data::store::{IdList, IdRef, QueryObject};
 use graph::data::value::{Object, Word};
 use graph::data_source::CausalityRegion;
+use graph::env::{CopyOverlapPolicy, CopyRowOrder};
 use graph::prelude::{
-    anyhow, r, serde_json, BlockNumber, ChildMultiplicity, Entity, EntityCollection, EntityFilter,
-    EntityLink, EntityOrder, EntityOrderByChild, EntityOrderByChildInfo, EntityRange, EntityWindow,
-    ParentLink, QueryExecutionError, StoreError, Value, ENV_VARS,
+    anyhow, r, serde_json, BlockNumber, CacheWeight, ChildMultiplicity, Entity, EntityCollection,
+    EntityFilter, EntityLink, EntityOrder, EntityOrderByChild, EntityOrderByChildInfo, EntityRange,
+    EntityWindow, ParentLink, QueryExecutionError, StoreError, Value, ENV_VARS,
 };
 use graph::schema::{EntityType, FulltextAlgorithm, FulltextConfig, InputSchema};
 use graph::{components::store::AttributeNames, data::store::scalar};
@@ -34,9 +35,11 @@ use std::iter::FromIterator;
 use std::ops::Range;
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::Arc;
 
 use crate::block_range::{BoundSide, EntityBlockRange};
 use crate::relational::dsl::AtBlock;
+use crate::relational::gin::ARRAY_QUERIES;
 use crate::relational::{
     dsl, Column, ColumnType, Layout, SqlName, Table, BYTE_ARRAY_PREFIX_SIZE, PRIMARY_KEY_COLUMN,
     STRING_PREFIX_SIZE, VID_COLUMN,
@@ -461,6 +464,11 @@ impl EntityData {
         schema.entity_type(&self.entity).unwrap()
     }
 
+    /// An estimate of how many bytes this row uses in memory
+    pub fn weight(&self) -> usize {
+        self.entity.weight() + self.data.weight()
+    }
+
     /// Map the `EntityData` using the schema information in `Layout`
     pub fn deserialize_with_layout<T: FromEntityData>(
         self,
@@ -540,6 +548,32 @@ impl EntityData {
     }
 }
 
+/// Keeps track of how many bytes decoding the result of a query has used
+/// so far, and fails once that exceeds `limit`. A `limit` of 0 means that
+/// there is no limit. This is only applied to rows that have already been
+/// fetched from the database, and therefore only bounds the memory that
+/// their decoded copy uses
+pub struct QueryMemory {
+    used: usize,
+    limit: usize,
+}
+
+impl QueryMemory {
+    pub fn new(limit: usize) -> Self {
+        QueryMemory { used: 0, limit }
+    }
+
+    /// Account for `bytes` more bytes and return an error if that puts us
+    /// over the limit
+    pub fn add(&mut self, bytes: usize) -> Result<(), QueryExecutionError> {
+        self.used += bytes;
+        if self.limit > 0 && self.used > self.limit {
+            return Err(QueryExecutionError::QueryMemoryExceeded(self.limit));
+        }
+        Ok(())
+    }
+}
+
 #[derive(QueryableByName, Clone, Debug, Default)]
 pub struct EntityDataExt {
     #[diesel(sql_type = Text)]
@@ -1398,12 +1432,16 @@ impl<'a> Filter<'a> {
         }
 
         fn contains<'s>(
+            site: &Arc<Site>,
             table: dsl::Table<'s>,
             attr: &String,
             op: ContainsOp,
             value: &'s Value,
         ) -> Result<Filter<'s>, StoreError> {
             let column = table.column_for_field(attr)?;
+            if column.is_list() {
+                ARRAY_QUERIES.record(site, table.meta.object.as_str(), column.name());
+            }
             let pattern = QueryValue::new(value, column.column_type())?;
             let pattern = match &pattern.value {
                 SqlValue::String(s) => {
@@ -1479,10 +1517,12 @@ impl<'a> Filter<'a> {
                 let values = QueryValue::many(values, &column.column_type())?;
                 Ok(F::NotIn(column, values))
             }
-            Contains(attr, value) => contains(table, attr, K::Like, value),
-            ContainsNoCase(attr, value) => contains(table, attr, K::ILike, value),
-            NotContains(attr, value) => contains(table, attr, K::NotLike, value),
-            NotContainsNoCase(attr, value) => contains(table, attr, K::NotILike, value),
+            Contains(attr, value) => contains(&layout.site, table, attr, K::Like, value),
+            ContainsNoCase(attr, value) => contains(&layout.site, table, attr, K::ILike, value),
+            NotContains(attr, value) => contains(&layout.site, table, attr, K::NotLike, value),
+            NotContainsNoCase(attr, value) => {
+                contains(&layout.site, table, attr, K::NotILike, value)
+            }
 
             StartsWith(attr, value) => starts_or_ends_with(table, attr, value, " like ", true),
             StartsWithNoCase(attr, value) => {
@@ -2326,6 +2366,11 @@ impl<'a> InsertQuery<'a> {
     /// query, and depends on what columns `table` has and how they get put
     /// into the query
     pub fn chunk_size(table: &Table) -> usize {
+        max_insert_params() / Self::params_per_row(table)
+    }
+
+    /// The number of bind variables that each row of `table` uses
+    fn params_per_row(table: &Table) -> usize {
         // We always have one column for the block number/range
         let mut count = 1 + ENV_VARS.store.insert_extra_cols;
         if table.has_causality_region {
@@ -2346,10 +2391,29 @@ impl<'a> InsertQuery<'a> {
                 count += 1
             }
         }
-        POSTGRES_MAX_PARAMETERS / count
+        count
     }
 }
 
+/// The maximum number of bind variables that we use in one query, set by
+/// `GRAPH_STORE_MAX_INSERT_PARAMS` but never more than Postgres allows
+pub(crate) fn max_insert_params() -> usize {
+    ENV_VARS
+        .store
+        .max_insert_params
+        .clamp(1, POSTGRES_MAX_PARAMETERS)
+}
+
+/// Return the number of parent ids up to which type A queries against
+/// `table` use the parent ids as an additional filter. That is the
+/// configured `TYPEA_BATCH_SIZE` unless one batch worth of rows of `table`
+/// would need more than `max_params` bind variables, in which case the
+/// batch size is reduced accordingly
+pub(crate) fn typea_batch_size(table: &Table, configured: usize, max_params: usize) -> usize {
+    let max = (max_params / InsertQuery::params_per_row(table)).max(1);
+    configured.min(max)
+}
+
 impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
     fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
         let out = &mut out;
@@ -2706,8 +2770,9 @@ impl<'a> FilterWindow<'a> {
         //     limit {parent_ids.len} + 1
         //
         // The redundant `&&` clause is only added when we have fewer than
-        // TYPEA_BATCH_SIZE children and helps Postgres to narrow down the
-        // rows it needs to pick from `children` to join with `p(id)`
+        // TYPEA_BATCH_SIZE children, reduced for wide tables, and helps
+        // Postgres to narrow down the rows it needs to pick from `children`
+        // to join with `p(id)`
         out.push_sql("\n/* child_type_a */ from unnest(");
         self.ids.push_bind_param(out)?;
         out.push_sql(") as p(id), ");
@@ -2718,7 +2783,7 @@ impl<'a> FilterWindow<'a> {
         out.push_sql(" and ");
         column.walk_ast(out.reborrow())?;
         out.push_sql(" @> array[p.id]");
-        if self.ids.len() < ENV_VARS.store.typea_batch_size {
+        if self.ids.len() < self.table.meta.typea_batch_size() {
             out.push_sql(" and ");
             column.walk_ast(out.reborrow())?;
             out.push_sql(" && ");
@@ -3373,12 +3438,65 @@ impl std::fmt::Display for SortDirection {
 }
 
 impl<'a> SortKey<'a> {
+    /// Create the sort key for `order`. When `order_by_block_range` adds
+    /// the block range to the `order by` clause, but that makes the clause
+    /// have more than `max_columns` columns, the block range is left out
+    /// again; the second return value is `true` in that case. A
+    /// `max_columns` of 0 means there is no limit
     fn new(
         order: EntityOrder,
         collection: &'a FilterCollection,
         filter: Option<&'a EntityFilter>,
         layout: &'a Layout,
         block: BlockNumber,
+        max_columns: usize,
+    ) -> Result<(Self, bool), QueryExecutionError> {
+        let mut key = Self::for_order(order, collection, filter, layout, block)?;
+        let skipped =
+            max_columns > 0 && key.order_by_columns() > max_columns && key.drop_block_column();
+        Ok((key, skipped))
+    }
+
+    /// The number of columns in the `order by` clause for this key
+    fn order_by_columns(&self) -> usize {
+        let block_column = |use_block_column: &UseBlockColumn| match use_block_column {
+            UseBlockColumn::Yes => 1,
+            UseBlockColumn::No => 0,
+        };
+        match self {
+            SortKey::None => 0,
+            SortKey::Id(_, br_column) => 1 + br_column.iter().count(),
+            SortKey::Key { .. } => 2,
+            SortKey::ChildKey(ChildKey::Single(_)) | SortKey::ChildKey(ChildKey::Many(_, _)) => 2,
+            SortKey::ChildKey(ChildKey::ManyId(_, _, use_block_column))
+            | SortKey::ChildKey(ChildKey::Id(_, _, use_block_column)) => {
+                1 + block_column(use_block_column)
+            }
+        }
+    }
+
+    /// Remove the block range from the `order by` clause for this key.
+    /// Return `true` if the clause contained the block range
+    fn drop_block_column(&mut self) -> bool {
+        match self {
+            SortKey::Id(_, br_column) => br_column.take().is_some(),
+            SortKey::ChildKey(ChildKey::ManyId(_, _, use_block_column))
+            | SortKey::ChildKey(ChildKey::Id(_, _, use_block_column)) => {
+                std::mem::replace(use_block_column, UseBlockColumn::No) == UseBlockColumn::Yes
+            }
+            SortKey::None
+            | SortKey::Key { .. }
+            | SortKey::ChildKey(ChildKey::Single(_))
+            | SortKey::ChildKey(ChildKey::Many(_, _)) => false,
+        }
+    }
+
+    fn for_order(
+        order: EntityOrder,
+        collection: &'a FilterCollection,
+        filter: Option<&'a EntityFilter>,
+        layout: &'a Layout,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         fn sort_key_from_value<'a>(
             column: dsl::Column<'a>,
@@ -4201,6 +4319,11 @@ pub struct FilterQuery<'a> {
     block: BlockNumber,
     query_id: Option<String>,
     site: &'a Site,
+    /// The maximum number of rows the query may return; 0 means no limit
+    max_rows: usize,
+    /// Whether the block range was left out of the `order by` clause
+    /// because of `GRAPH_STORE_MAX_ORDER_BY_COLUMNS`
+    skipped_block_range: bool,
 }
 
 /// String representation that is useful for debugging when `walk_ast` fails
@@ -4228,8 +4351,17 @@ impl<'a> FilterQuery<'a> {
         block: BlockNumber,
         query_id: Option<String>,
         site: &'a Site,
+        max_rows: usize,
+        max_order_by_columns: usize,
     ) -> Result<Self, QueryExecutionError> {
-        let sort_key = SortKey::new(order, collection, filter, layout, block)?;
+        let (sort_key, skipped_block_range) = SortKey::new(
+            order,
+            collection,
+            filter,
+            layout,
+            block,
+            max_order_by_columns,
+        )?;
         let range = FilterRange(range);
         let limit = ParentLimit { sort_key, range };
 
@@ -4239,9 +4371,50 @@ impl<'a> FilterQuery<'a> {
             block,
             query_id,
             site,
+            max_rows,
+            skipped_block_range,
         })
     }
 
+    /// Whether the block range was left out of the `order by` clause
+    /// because it would have had too many columns
+    pub fn skipped_block_range(&self) -> bool {
+        self.skipped_block_range
+    }
+
+    /// Check that a query that returned `count` rows stayed within the
+    /// limit on the number of rows
+    pub fn check_max_rows(&self, count: usize) -> Result<(), QueryExecutionError> {
+        if self.max_rows > 0 && count > self.max_rows {
+            return Err(QueryExecutionError::TooManyRows(self.max_rows));
+        }
+        Ok(())
+    }
+
+    /// If a query that returned `count` rows returned more than
+    /// `warn_rows`, return a description of the query that is suitable for
+    /// logging. It only mentions the tables the query reads and how it
+    /// orders and limits rows, but none of the values that it filters by.
+    /// A `warn_rows` of 0 means that no result is too large
+    pub fn large_result(&self, count: usize, warn_rows: usize) -> Option<String> {
+        if warn_rows == 0 || count <= warn_rows {
+            return None;
+        }
+        let tables: Vec<_> = match self.collection {
+            FilterCollection::All(tables) => tables.iter().map(|wh| wh.table).collect(),
+            FilterCollection::SingleWindow(w) => vec![w.table],
+            FilterCollection::MultiWindow(ws, _) => ws.iter().map(|w| w.table).collect(),
+        };
+        let tables = tables
+            .iter()
+            .map(|table| table.meta.qualified_name.as_str().replace('"', ""))
+            .join(", ");
+        Some(format!(
+            "from {} order {} {} at {}",
+            tables, self.limit.sort_key, self.limit.range, self.block
+        ))
+    }
+
     /// Generate
     ///     from schema.table c
     ///    where block_range @> $block
@@ -4524,16 +4697,28 @@ impl<'a> QueryFragment<Pg> for FilterQuery<'a> {
                     let wh = entities
                         .first()
                         .expect("a query always uses at least one table");
-                    self.query_no_window_one_entity(wh, &mut out)
+                    self.query_no_window_one_entity(wh, &mut out)?
                 } else {
-                    self.query_no_window(entities, &mut out)
+                    self.query_no_window(entities, &mut out)?
                 }
             }
-            FilterCollection::SingleWindow(window) => self.query_window_one_entity(window, out),
+            FilterCollection::SingleWindow(window) => {
+                self.query_window_one_entity(window, out.reborrow())?
+            }
             FilterCollection::MultiWindow(windows, parent_ids) => {
-                self.query_window(windows, parent_ids, &mut out)
+                self.query_window(windows, parent_ids, &mut out)?
             }
         }
+        // To enforce the limit on the number of rows, we fetch one more row
+        // than allowed so that `check_max_rows` can tell that the limit
+        // was exceeded. The limit goes directly on the outermost statement
+        // rather than on a subquery wrapped around it so that it applies
+        // after that statement's `order by`, and the rows keep their order
+        if self.max_rows > 0 {
+            out.push_sql("\n limit ");
+            out.push_sql(&(self.max_rows + 1).to_string());
+        }
+        Ok(())
     }
 }
 
@@ -4549,6 +4734,121 @@ impl<'a> Query for FilterQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FilterQuery<'a> {}
 
+/// Ask Postgres for the plan it would use for a `FilterQuery` so that we
+/// can find out how expensive it thinks the query is
+#[derive(Debug)]
+pub struct ExplainQuery<'a> {
+    query: &'a FilterQuery<'a>,
+}
+
+impl<'a> ExplainQuery<'a> {
+    pub fn new(query: &'a FilterQuery<'a>) -> Self {
+        Self { query }
+    }
+
+    /// Return the plan that the planner chooses for the query, or `None` if
+    /// the query does not touch any tables
+    pub fn plan(self, conn: &mut PgConnection) -> QueryResult<Option<QueryPlan>> {
+        if self.query.collection.is_empty() {
+            return Ok(None);
+        }
+        let plan: String = self.get_result(conn)?;
+        QueryPlan::from_json(&plan).map(Some)
+    }
+}
+
+/// A query plan in the format produced by `explain (format json)`
+#[derive(Debug)]
+pub struct QueryPlan(serde_json::Value);
+
+impl QueryPlan {
+    /// The size of a Postgres page in bytes
+    const PAGE_SIZE: f64 = 8192.0;
+
+    pub fn from_json(plan: &str) -> QueryResult<Self> {
+        let plan: serde_json::Value =
+            serde_json::from_str(plan).map_err(|e| DieselError::DeserializationError(e.into()))?;
+        Ok(QueryPlan(plan))
+    }
+
+    fn root(&self) -> &serde_json::Value {
+        &self.0[0]["Plan"]
+    }
+
+    /// Return the total cost that the planner estimates for the query
+    pub fn total_cost(&self) -> QueryResult<f64> {
+        self.root()["Total Cost"].as_f64().ok_or_else(|| {
+            DieselError::DeserializationError(format!("query plan has no cost: {}", self.0).into())
+        })
+    }
+
+    /// Estimate how many table pages the query reads through one of the
+    /// indexes on `block_range`. The estimate is based on the number and
+    /// width of the rows the planner expects these scans to return
+    pub fn block_range_pages(&self) -> f64 {
+        fn is_block_range_index(node: &serde_json::Value) -> bool {
+            node["Index Name"]
+                .as_str()
+                .map(|name| name.starts_with("brin_") || name.contains("_block_range_"))
+                .unwrap_or(false)
+        }
+
+        fn children(node: &serde_json::Value) -> impl Iterator<Item = &serde_json::Value> {
+            node["Plans"].as_array().into_iter().flatten()
+        }
+
+        // Whether `node` is an index scan that uses a `block_range` index,
+        // or a bitmap scan that is built from such an index
+        fn uses_block_range_index(node: &serde_json::Value) -> bool {
+            match node["Node Type"].as_str() {
+                Some("Index Scan") | Some("Index Only Scan") | Some("Bitmap Index Scan") => {
+                    is_block_range_index(node)
+                }
+                Some("Bitmap Heap Scan") | Some("BitmapAnd") | Some("BitmapOr") => {
+                    children(node).any(uses_block_range_index)
+                }
+                _ => false,
+            }
+        }
+
+        fn pages(node: &serde_json::Value) -> f64 {
+            let is_table_scan = matches!(
+                node["Node Type"].as_str(),
+                Some("Index Scan") | Some("Index Only Scan") | Some("Bitmap Heap Scan")
+            );
+            if is_table_scan && uses_block_range_index(node) {
+                let rows = node["Plan Rows"].as_f64().unwrap_or(0.0);
+                let width = node["Plan Width"].as_f64().unwrap_or(0.0);
+                (rows * width / QueryPlan::PAGE_SIZE).ceil()
+            } else {
+                children(node).map(pages).sum()
+            }
+        }
+
+        pages(self.root())
+    }
+}
+
+impl<'a> QueryFragment<Pg> for ExplainQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("explain (format json) ");
+        self.query.walk_ast(out)
+    }
+}
+
+impl<'a> QueryId for ExplainQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> Query for ExplainQuery<'a> {
+    type SqlType = Text;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for ExplainQuery<'a> {}
+
 /// Reduce the upper bound of the current entry's block range to `block` as
 /// long as that does not result in an empty block range
 #[derive(Debug)]
@@ -4763,6 +5063,8 @@ pub struct CopyEntityBatchQuery<'a> {
     columns: Vec<&'a Column>,
     first_vid: i64,
     last_vid: i64,
+    overlap_policy: CopyOverlapPolicy,
+    row_order: CopyRowOrder,
 }
 
 impl<'a> CopyEntityBatchQuery<'a> {
@@ -4771,6 +5073,8 @@ impl<'a> CopyEntityBatchQuery<'a> {
         src: &'a Table,
         first_vid: i64,
         last_vid: i64,
+        overlap_policy: CopyOverlapPolicy,
+        row_order: CopyRowOrder,
     ) -> Result<Self, StoreError> {
         let mut columns = Vec::new();
         for dcol in &dst.columns {
@@ -4797,12 +5101,52 @@ impl<'a> CopyEntityBatchQuery<'a> {
             columns,
             first_vid,
             last_vid,
+            overlap_policy,
+            row_order,
         })
     }
 
     pub fn count_current(self) -> CountCurrentVersionsQuery<'a> {
         CountCurrentVersionsQuery::new(self)
     }
+
+    /// If the overlap policy is `prefer_source`, return a query that
+    /// removes the versions in `dst` that overlap with the versions this
+    /// query will copy. That query must be run before this one
+    pub fn delete_overlaps(&self) -> Option<DeleteOverlapsQuery<'a>> {
+        match self.overlap_policy {
+            CopyOverlapPolicy::Fail | CopyOverlapPolicy::Skip => None,
+            CopyOverlapPolicy::PreferSource => Some(DeleteOverlapsQuery {
+                src: self.src,
+                dst: self.dst,
+                first_vid: self.first_vid,
+                last_vid: self.last_vid,
+            }),
+        }
+    }
+}
+
+/// Write a condition that is true if the version `d` in `dst` overlaps with
+/// the version `s` in `src`, i.e., if copying `s` into `dst` would violate
+/// the exclusion constraint on `dst`
+fn write_copy_overlap(src: &Table, dst: &Table, out: &mut AstPass<Pg>) {
+    out.push_sql("d.");
+    out.push_sql(PRIMARY_KEY_COLUMN);
+    out.push_sql(" = s.");
+    out.push_sql(PRIMARY_KEY_COLUMN);
+    if !dst.immutable {
+        out.push_sql(" and d.");
+        out.push_sql(BLOCK_RANGE_COLUMN);
+        out.push_sql(" && ");
+        if src.immutable {
+            out.push_sql("int4range(s.");
+            out.push_sql(BLOCK_COLUMN);
+            out.push_sql(", null)");
+        } else {
+            out.push_sql("s.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+        }
+    }
 }
 
 impl<'a> QueryFragment<Pg> for CopyEntityBatchQuery<'a> {
@@ -4815,6 +5159,7 @@ impl<'a> QueryFragment<Pg> for CopyEntityBatchQuery<'a> {
         //   insert into {dst}({columns})
         //   select {columns} from {src}
         //    where vid >= {first_vid} and vid <= {last_vid}
+        //   [order by id, vid]
         //   returning {upper_inf(block_range)|true}
         out.push_sql("insert into ");
         out.push_sql(self.dst.qualified_name.as_str());
@@ -4907,10 +5252,25 @@ impl<'a> QueryFragment<Pg> for CopyEntityBatchQuery<'a> {
 
         out.push_sql(" from ");
         out.push_sql(self.src.qualified_name.as_str());
-        out.push_sql(" where vid >= ");
+        out.push_sql(" s where vid >= ");
         out.push_bind_param::<BigInt, _>(&self.first_vid)?;
         out.push_sql(" and vid <= ");
         out.push_bind_param::<BigInt, _>(&self.last_vid)?;
+        if self.overlap_policy == CopyOverlapPolicy::Skip {
+            out.push_sql("\n and not exists (select 1 from ");
+            out.push_sql(self.dst.qualified_name.as_str());
+            out.push_sql(" d where ");
+            write_copy_overlap(self.src, self.dst, &mut out);
+            out.push_sql(")");
+        }
+        match self.row_order {
+            CopyRowOrder::Physical => (),
+            CopyRowOrder::ById => {
+                out.push_sql("\n order by ");
+                out.push_sql(PRIMARY_KEY_COLUMN);
+                out.push_sql(", vid");
+            }
+        }
         out.push_sql("\n returning ");
         if self.dst.immutable {
             out.push_sql("true");
@@ -4929,6 +5289,8 @@ impl<'a> QueryId for CopyEntityBatchQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for CopyEntityBatchQuery<'a> {}
 
+/// Run a `CopyEntityBatchQuery` and return the number of current versions
+/// and the total number of versions it copied
 #[derive(Debug, Clone)]
 pub struct CountCurrentVersionsQuery<'a> {
     copy: CopyEntityBatchQuery<'a>,
@@ -4943,10 +5305,10 @@ impl<'a> QueryFragment<Pg> for CountCurrentVersionsQuery<'a> {
     fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
         // Generate a query
         // with copy_cte as ( {copy} )
-        // select count(*) from copy_cte where {block_range_current}
+        // select count(*) filter (where current), count(*) from copy_cte
         out.push_sql("with copy_cte(current) as (");
         self.copy.walk_ast(out.reborrow())?;
-        out.push_sql(")\nselect count(*) from copy_cte where current");
+        out.push_sql(")\nselect count(*) filter (where current), count(*) from copy_cte");
         Ok(())
     }
 }
@@ -4958,11 +5320,68 @@ impl<'a> QueryId for CountCurrentVersionsQuery<'a> {
 }
 
 impl<'a> Query for CountCurrentVersionsQuery<'a> {
-    type SqlType = BigInt;
+    type SqlType = (BigInt, BigInt);
 }
 
 impl<'a, Conn> RunQueryDsl<Conn> for CountCurrentVersionsQuery<'a> {}
 
+/// Remove the versions in `dst` that overlap with the versions in `src`
+/// with a vid between `first_vid` and `last_vid` and return how many of
+/// them were current. See `CopyEntityBatchQuery::delete_overlaps`
+#[derive(Debug, Clone)]
+pub struct DeleteOverlapsQuery<'a> {
+    src: &'a Table,
+    dst: &'a Table,
+    first_vid: i64,
+    last_vid: i64,
+}
+
+impl<'a> QueryFragment<Pg> for DeleteOverlapsQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   with deleted(current) as (
+        //     delete from {dst} d using {src} s
+        //      where s.vid >= {first_vid} and s.vid <= {last_vid}
+        //        and {overlap}
+        //     returning {upper_inf(d.block_range)|true})
+        //   select count(*) from deleted where current
+        out.push_sql("with deleted(current) as (\ndelete from ");
+        out.push_sql(self.dst.qualified_name.as_str());
+        out.push_sql(" d using ");
+        out.push_sql(self.src.qualified_name.as_str());
+        out.push_sql(" s\n where s.vid >= ");
+        out.push_bind_param::<BigInt, _>(&self.first_vid)?;
+        out.push_sql(" and s.vid <= ");
+        out.push_bind_param::<BigInt, _>(&self.last_vid)?;
+        out.push_sql(" and ");
+        write_copy_overlap(self.src, self.dst, &mut out);
+        out.push_sql("\n returning ");
+        if self.dst.immutable {
+            out.push_sql("true");
+        } else {
+            out.push_sql("upper_inf(d.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(")");
+        }
+        out.push_sql(")\nselect count(*) from deleted where current");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for DeleteOverlapsQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> Query for DeleteOverlapsQuery<'a> {
+    type SqlType = BigInt;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for DeleteOverlapsQuery<'a> {}
+
 /// Helper struct for returning the id's touched by the RevertRemove and
 /// RevertExtend queries
 #[derive(QueryableByName, PartialEq, Eq, Hash)]
@@ -5,8 +5,8 @@ use graph::{
     components::{
         server::index_node::VersionInfo,
         store::{
-            BlockPtrForNumber, BlockStore as BlockStoreTrait, QueryPermit, QueryStoreManager,
-            StatusStore, Store as StoreTrait,
+            BlockPtrForNumber, BlockStore as BlockStoreTrait, MaintenanceRecord, QueryPermit,
+            QueryStoreManager, StatusStore, Store as StoreTrait,
         },
     },
     constraint_violation,
@@ -140,6 +140,21 @@ impl StatusStore for Store {
             .subgraphs_for_deployment_hash(deployment_hash)
     }
 
+    fn deployments_per_pool(&self) -> Result<Vec<(String, i64)>, StoreError> {
+        let counts = self.subgraph_store.deployments_per_pool()?;
+        Ok(counts
+            .into_iter()
+            .map(|(shard, count)| (shard.to_string(), count))
+            .collect())
+    }
+
+    fn maintenance_history(
+        &self,
+        id: &DeploymentHash,
+    ) -> Result<Vec<MaintenanceRecord>, StoreError> {
+        self.subgraph_store.maintenance_history(id)
+    }
+
     async fn get_proof_of_indexing(
         &self,
         subgraph_id: &DeploymentHash,
@@ -7,9 +7,15 @@ use diesel::{
 use std::fmt;
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::{atomic::AtomicU8, Arc, Mutex},
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use std::{
+    iter::FromIterator,
+    time::{Duration, Instant},
 };
-use std::{iter::FromIterator, time::Duration};
 
 use graph::futures03::future::join_all;
 use graph::{
@@ -18,19 +24,20 @@ use graph::{
         server::index_node::VersionInfo,
         store::{
             self, BlockPtrForNumber, BlockStore, DeploymentLocator, EnsLookup as EnsLookupTrait,
-            PruneReporter, PruneRequest, SubgraphFork,
+            MaintenanceRecord, PruneReporter, PruneRequest, SubgraphFork,
         },
     },
     constraint_violation,
     data::query::QueryTarget,
     data::subgraph::{schema::DeploymentCreate, status, DeploymentFeatures},
     prelude::{
-        anyhow, lazy_static, o, web3::types::Address, ApiVersion, BlockNumber, BlockPtr,
-        ChainStore, DeploymentHash, EntityOperation, Logger, MetricsRegistry, NodeId,
+        anyhow, info, lazy_static, o, warn, web3::types::Address, ApiVersion, BlockNumber,
+        BlockPtr, ChainStore, DeploymentHash, EntityOperation, Logger, MetricsRegistry, NodeId,
         PartialBlockPtr, StoreError, SubgraphDeploymentEntity, SubgraphName,
-        SubgraphStore as SubgraphStoreTrait, SubgraphVersionSwitchingMode,
+        SubgraphStore as SubgraphStoreTrait, SubgraphVersionSwitchingMode, ENV_VARS,
     },
     prelude::{CancelableError, StoreEvent},
+    prometheus::GaugeVec,
     schema::{ApiSchema, InputSchema},
     url::Url,
     util::timed_cache::TimedCache,
@@ -322,6 +329,26 @@ impl std::ops::Deref for SubgraphStore {
     }
 }
 
+/// Call `f` for all `items`, running it for up to `batch_size` items at the
+/// same time on separate threads. The next batch is only started once all
+/// items of the current batch are done
+fn for_each_in_batches<T, F>(items: Vec<T>, batch_size: usize, f: F)
+where
+    T: Send,
+    F: Fn(T) + Sync,
+{
+    let f = &f;
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let batch: Vec<_> = items.by_ref().take(batch_size.max(1)).collect();
+        std::thread::scope(|scope| {
+            for item in batch {
+                scope.spawn(move || f(item));
+            }
+        });
+    }
+}
+
 pub struct SubgraphStoreInner {
     mirror: PrimaryMirror,
     stores: HashMap<Shard, Arc<DeploymentStore>>,
@@ -336,6 +363,10 @@ pub struct SubgraphStoreInner {
     sender: Arc<NotificationSender>,
     writables: Mutex<HashMap<DeploymentId, Arc<WritableStore>>>,
     registry: Arc<MetricsRegistry>,
+    /// The number of deployments stored in each pool, labeled by the name
+    /// of the pool's shard
+    deployments_per_pool: Box<GaugeVec>,
+    logger: Logger,
 }
 
 impl SubgraphStoreInner {
@@ -379,11 +410,19 @@ impl SubgraphStoreInner {
                         main_pool,
                         read_only_pools,
                         weights,
+                        registry.cheap_clone(),
                     )),
                 )
             },
         ));
         let sites = TimedCache::new(SITES_CACHE_TTL);
+        let deployments_per_pool = registry
+            .new_gauge_vec(
+                "store_deployments_per_pool",
+                "Number of deployments stored in each database pool",
+                vec!["pool".to_string()],
+            )
+            .expect("Can't register the gauge");
         SubgraphStoreInner {
             mirror,
             stores,
@@ -392,12 +431,54 @@ impl SubgraphStoreInner {
             sender,
             writables: Mutex::new(HashMap::new()),
             registry,
+            deployments_per_pool,
+            logger: logger.clone(),
+        }
+    }
+
+    /// Return the number of deployments stored in each pool, sorted by
+    /// the name of the pool, and update the `store_deployments_per_pool`
+    /// gauge with them
+    pub fn deployments_per_pool(&self) -> Result<Vec<(Shard, i64)>, StoreError> {
+        let shards: Vec<_> = self.stores.keys().cloned().collect();
+        let counts = self.primary_conn()?.deployments_per_shard(&shards)?;
+        for (shard, count) in &counts {
+            self.deployments_per_pool
+                .with_label_values(&[shard.as_str()])
+                .set(*count as f64);
         }
+        Ok(counts)
+    }
+
+    /// Update the `store_deployments_per_pool` gauge after a deployment
+    /// was added or removed. Since that change has already been committed,
+    /// failing to count deployments is only logged
+    fn refresh_deployments_per_pool(&self) {
+        if let Err(e) = self.deployments_per_pool() {
+            warn!(self.logger, "Failed to update the number of deployments per pool";
+                  "error" => e.to_string());
+        }
+    }
+
+    pub fn maintenance_history(
+        &self,
+        id: &DeploymentHash,
+    ) -> Result<Vec<MaintenanceRecord>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.maintenance_history(id)
     }
 
     // Only needed for tests
     #[cfg(debug_assertions)]
-    pub(crate) fn clear_caches(&self) {
+    pub fn deployments_per_pool_gauge(&self, shard: &Shard) -> f64 {
+        self.deployments_per_pool
+            .with_label_values(&[shard.as_str()])
+            .get()
+    }
+
+    // Only needed for tests
+    #[cfg(debug_assertions)]
+    pub fn clear_caches(&self) {
         for store in self.stores.values() {
             store.layout_cache.clear();
         }
@@ -411,6 +492,14 @@ impl SubgraphStoreInner {
             .map(|site| site.shard.clone())
     }
 
+    // Only needed for tests
+    #[cfg(debug_assertions)]
+    pub fn layout_is_cached(&self, deployment: &DeploymentLocator) -> Result<bool, StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        let store = self.for_site(&site)?;
+        Ok(store.layout_cache.find(&site).is_some())
+    }
+
     fn cache_active(&self, site: &Arc<Site>) {
         if site.active {
             self.sites.set(site.deployment.clone(), site.clone());
@@ -664,6 +753,7 @@ impl SubgraphStoreInner {
             pconn.send_store_event(&self.sender, &event)?;
             Ok(())
         })?;
+        self.refresh_deployments_per_pool();
         Ok(site.as_ref().into())
     }
 
@@ -743,6 +833,7 @@ impl SubgraphStoreInner {
             pconn.send_store_event(&self.sender, &event)?;
             Ok(())
         })?;
+        self.refresh_deployments_per_pool();
         Ok(dst.as_ref().into())
     }
 
@@ -920,6 +1011,7 @@ impl SubgraphStoreInner {
             store.drop_deployment(&site)?;
 
             self.primary_conn()?.drop_site(site.as_ref())?;
+            self.refresh_deployments_per_pool();
         } else {
             self.primary_conn()?
                 .unused_deployment_is_used(site.as_ref())?;
@@ -1037,6 +1129,14 @@ impl SubgraphStoreInner {
         join_all(self.stores.values().map(|store| store.vacuum())).await
     }
 
+    /// Keep the idle timeout from closing the minimum number of idle
+    /// connections in each pool; see `ConnectionPool::keep_min_idle`
+    pub(crate) fn keep_min_idle(&self) {
+        for store in self.stores.values() {
+            store.keep_min_idle();
+        }
+    }
+
     pub fn rewind(&self, id: DeploymentHash, block_ptr_to: BlockPtr) -> Result<(), StoreError> {
         let (store, site) = self.store(&id)?;
         store.rewind(site, block_ptr_to)
@@ -1138,6 +1238,35 @@ impl SubgraphStoreInner {
         .await;
     }
 
+    /// Load the layouts of all deployments, and with them the statistics
+    /// that queries use, into the cache so that the first queries after
+    /// startup do not have to wait for them. Deployments whose layout can
+    /// not be loaded are skipped. Up to `GRAPH_STORE_SCHEMA_WARMUP_BATCH`
+    /// layouts are loaded at the same time
+    pub fn load_query_stats(&self, logger: &Logger) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let sites = self.primary_conn()?.sites()?;
+        let loaded = AtomicUsize::new(0);
+        for_each_in_batches(sites, ENV_VARS.store.schema_warmup_batch, |site| {
+            let site = Arc::new(site);
+            match self
+                .for_site(&site)
+                .and_then(|store| store.find_layout(site.cheap_clone()))
+            {
+                Ok(_) => {
+                    loaded.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(e) => warn!(logger, "Failed to load query statistics";
+                                "deployment" => site.deployment.as_str(),
+                                "error" => e.to_string()),
+            }
+        });
+        info!(logger, "Loaded query statistics";
+              "deployments" => loaded.into_inner(),
+              "time_ms" => start.elapsed().as_millis());
+        Ok(())
+    }
+
     pub async fn refresh_materialized_views(&self, logger: &Logger) {
         join_all(
             self.stores
@@ -1619,3 +1748,37 @@ impl SubgraphStoreTrait for SubgraphStore {
         Ok(info.instrument)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_warmup_batches() {
+        use std::sync::Barrier;
+
+        const BATCH_SIZE: usize = 3;
+
+        let items: Vec<usize> = (0..10).collect();
+        // Every item waits until all items of its batch are running so
+        // that each batch reaches `BATCH_SIZE` concurrent items
+        let barriers: Vec<_> = items
+            .chunks(BATCH_SIZE)
+            .map(|batch| Barrier::new(batch.len()))
+            .collect();
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        for_each_in_batches(items, BATCH_SIZE, |i: usize| {
+            // All items of earlier batches have been loaded
+            assert!(done.load(Ordering::SeqCst) >= i / BATCH_SIZE * BATCH_SIZE);
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            barriers[i / BATCH_SIZE].wait();
+            running.fetch_sub(1, Ordering::SeqCst);
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(10, done.into_inner());
+        assert_eq!(BATCH_SIZE, max_running.into_inner());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
 use diesel::{
@@ -7,7 +8,7 @@ use diesel::{
 };
 use graph::{
     env::ENV_VARS,
-    prelude::{BlockNumber, BlockPtr, StoreError},
+    prelude::{o, warn, BlockNumber, BlockPtr, Logger, StoreError},
     util::ogive::Ogive,
 };
 
@@ -41,9 +42,19 @@ impl AdaptiveBatchSize {
             INITIAL_BATCH_SIZE
         };
 
+        Self::with_target(
+            size,
+            ENV_VARS.store.batch_target_duration,
+            ENV_VARS.store.batch_min_duration,
+        )
+    }
+
+    /// Batches aim to take `target`, but never less than `min_duration`,
+    /// since for very short batches the overhead of each batch dominates
+    fn with_target(size: i64, target: Duration, min_duration: Duration) -> Self {
         Self {
             size,
-            target: ENV_VARS.store.batch_target_duration,
+            target: target.max(min_duration),
         }
     }
 
@@ -51,6 +62,10 @@ impl AdaptiveBatchSize {
     // get close to TARGET_DURATION for the time it takes to copy one
     // batch, but don't step up batch_size by more than 2x at once
     pub fn adapt(&mut self, duration: Duration) -> i64 {
+        if !ENV_VARS.store.adaptive_batching {
+            return self.size;
+        }
+
         // Avoid division by zero
         let duration = duration.as_millis().max(1);
         let new_batch_size = self.size as f64 * self.target.as_millis() as f64 / duration as f64;
@@ -59,6 +74,49 @@ impl AdaptiveBatchSize {
     }
 }
 
+/// The number of recent batches whose durations `BatchDurations` looks at
+const DURATION_WINDOW: usize = 10;
+
+/// The durations of the most recent batches. Batch durations that vary a
+/// lot even though batch sizes adapt to how long batches take indicate
+/// that the database is unstable
+#[derive(Default)]
+struct BatchDurations {
+    recent: VecDeque<Duration>,
+}
+
+impl BatchDurations {
+    /// Record the `duration` of a batch. If the variance of the durations
+    /// of the last `DURATION_WINDOW` batches exceeds `threshold` seconds
+    /// squared, return these durations. They are then forgotten so that
+    /// the next alert is only raised once a whole new window of batches
+    /// varies that much. A `threshold` of 0 turns alerts off
+    fn record(&mut self, duration: Duration, threshold: f64) -> Option<Vec<Duration>> {
+        if threshold <= 0.0 {
+            return None;
+        }
+        if self.recent.len() == DURATION_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+        if self.recent.len() < DURATION_WINDOW || self.variance() <= threshold {
+            return None;
+        }
+        Some(self.recent.drain(..).collect())
+    }
+
+    /// The variance of the recent durations in seconds squared
+    fn variance(&self) -> f64 {
+        let n = self.recent.len() as f64;
+        let mean = self.recent.iter().map(Duration::as_secs_f64).sum::<f64>() / n;
+        self.recent
+            .iter()
+            .map(|duration| (duration.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / n
+    }
+}
+
 /// A timer that works like `std::time::Instant` in non-test code, but
 /// returns a fake elapsed value in tests
 struct Timer {
@@ -101,13 +159,18 @@ impl Timer {
 /// takes uneven distributions of `vid` values into account by using the
 /// histogram from `pg_stats` for the table through which we are iterating.
 pub(crate) struct VidBatcher {
+    logger: Logger,
     batch_size: AdaptiveBatchSize,
+    durations: BatchDurations,
     start: i64,
     end: i64,
     max_vid: i64,
 
     ogive: Option<Ogive>,
 
+    /// When `true`, the batch size is never adapted
+    fixed: bool,
+
     step_timer: Timer,
 }
 
@@ -119,6 +182,7 @@ impl VidBatcher {
     /// all vids `vid_range.0 <= vid <= vid_range.1`; for an empty table,
     /// the `vid_range` must be set to `(-1, 0)`
     pub fn load(
+        logger: &Logger,
         conn: &mut PgConnection,
         nsp: &Namespace,
         table: &Table,
@@ -126,10 +190,31 @@ impl VidBatcher {
     ) -> Result<Self, StoreError> {
         let bounds = catalog::histogram_bounds(conn, nsp, &table.name, VID_COLUMN)?;
         let batch_size = AdaptiveBatchSize::new(table);
-        Self::new(bounds, vid_range, batch_size)
+        let logger = logger.new(o!("table" => table.name.to_string()));
+        Self::new(logger, bounds, vid_range, batch_size)
+    }
+
+    /// Initialize a batcher whose batches only depend on `vid_range` and
+    /// `batch_size`, not on table statistics or on how long batches take.
+    /// Going through the same range therefore always results in the same
+    /// batches
+    pub fn fixed(
+        logger: &Logger,
+        vid_range: VidRange,
+        batch_size: i64,
+    ) -> Result<Self, StoreError> {
+        let batch_size = AdaptiveBatchSize::with_target(
+            batch_size,
+            ENV_VARS.store.batch_target_duration,
+            ENV_VARS.store.batch_min_duration,
+        );
+        let mut batcher = Self::new(logger.clone(), vec![], vid_range, batch_size)?;
+        batcher.fixed = true;
+        Ok(batcher)
     }
 
     fn new(
+        logger: Logger,
         bounds: Vec<i64>,
         range: VidRange,
         batch_size: AdaptiveBatchSize,
@@ -167,11 +252,14 @@ impl VidBatcher {
         };
 
         Ok(Self {
+            logger,
             batch_size,
+            durations: BatchDurations::default(),
             start,
             end,
             max_vid: range.max,
             ogive,
+            fixed: false,
             step_timer: Timer::new(),
         })
     }
@@ -190,6 +278,17 @@ impl VidBatcher {
         self.max_vid
     }
 
+    /// The range of `vid`s that the next batch will cover
+    pub(crate) fn position(&self) -> (i64, i64) {
+        (self.start, self.end)
+    }
+
+    /// Go back to a `position` from earlier so that the batches from there
+    /// on are processed again
+    pub(crate) fn rewind(&mut self, position: (i64, i64)) {
+        (self.start, self.end) = position;
+    }
+
     pub fn batch_size(&self) -> usize {
         self.batch_size.size as usize
     }
@@ -225,7 +324,21 @@ impl VidBatcher {
                 let res = f(self.start, self.end)?;
                 let duration = self.step_timer.elapsed();
 
-                let batch_size = self.batch_size.adapt(duration);
+                let batch_size = if self.fixed {
+                    self.batch_size.size
+                } else {
+                    self.batch_size.adapt(duration)
+                };
+                if ENV_VARS.store.adaptive_batching && !self.fixed {
+                    let threshold = ENV_VARS.store.batch_duration_variance_alert;
+                    if let Some(durations) = self.durations.record(duration, threshold) {
+                        let durations_ms: Vec<_> =
+                            durations.iter().map(|d| d.as_millis()).collect();
+                        warn!(self.logger, "Batch durations vary a lot; the database might be unstable";
+                              "durations_ms" => format!("{:?}", durations_ms),
+                              "batch_size" => batch_size);
+                    }
+                }
                 self.start = self.end + 1;
                 self.end = ogive.next_point(self.start, batch_size as usize)?;
 
@@ -324,6 +437,25 @@ mod tests {
     const S100: Duration = Duration::from_secs(100);
     const S200: Duration = Duration::from_secs(200);
 
+    fn logger() -> Logger {
+        Logger::root(graph::slog::Discard, o!())
+    }
+
+    #[test]
+    fn min_duration() {
+        // A target above the minimum is used as is
+        let mut batch_size = AdaptiveBatchSize::with_target(1000, S100, S010);
+        assert_eq!(S100, batch_size.target);
+        assert_eq!(500, batch_size.adapt(S200));
+
+        // A target below the minimum is raised to the minimum, and batches
+        // are sized to take that long
+        let mut batch_size = AdaptiveBatchSize::with_target(1000, S001, S010);
+        assert_eq!(S010, batch_size.target);
+        assert_eq!(1000, batch_size.adapt(S010));
+        assert_eq!(200, batch_size.adapt(S050));
+    }
+
     struct Batcher {
         vid: VidBatcher,
     }
@@ -333,7 +465,7 @@ mod tests {
             let batch_size = AdaptiveBatchSize { size, target: S100 };
             let vid_range = VidRange::new(bounds[0], *bounds.last().unwrap());
             Self {
-                vid: VidBatcher::new(bounds, vid_range, batch_size).unwrap(),
+                vid: VidBatcher::new(logger(), bounds, vid_range, batch_size).unwrap(),
             }
         }
 
@@ -411,6 +543,25 @@ mod tests {
         batcher.at(50, 49, 80);
     }
 
+    #[test]
+    fn rewind() {
+        let bounds = vec![10, 20, 30, 40, 49];
+        let mut batcher = Batcher::new(bounds, 5);
+
+        batcher.step(10, 15, S001);
+        let position = batcher.vid.position();
+        assert_eq!((16, 26), position);
+        batcher.step(16, 26, S001);
+
+        // After rewinding, the batch that was already processed is
+        // processed again, with the batch size it adapted to in the
+        // meantime
+        batcher.vid.rewind(position);
+        batcher.at(16, 26, 20);
+        batcher.step(16, 26, S001);
+        batcher.at(27, 49, 40);
+    }
+
     #[test]
     fn non_uniform() {
         // A distribution that is flat in the beginning and then steeper and
@@ -453,9 +604,50 @@ mod tests {
             target: S100,
         };
 
-        let vid_batcher = VidBatcher::new(bounds, vid_range, batch_size).unwrap();
+        let vid_batcher = VidBatcher::new(logger(), bounds, vid_range, batch_size).unwrap();
         let ogive = vid_batcher.ogive.as_ref().unwrap();
         assert_eq!(1, ogive.start());
         assert_eq!(100_000, ogive.end());
     }
+
+    #[test]
+    fn batch_duration_variance_alert() {
+        const MS: Duration = Duration::from_millis(1);
+
+        // Steady durations never raise an alert
+        let mut durations = BatchDurations::default();
+        for _ in 0..3 * DURATION_WINDOW {
+            assert_eq!(None, durations.record(S100, 1.0));
+        }
+
+        // Durations alternating between 100s and 102s have a variance of
+        // 1s^2, which does not exceed the threshold
+        let mut durations = BatchDurations::default();
+        for i in 0..3 * DURATION_WINDOW {
+            let duration = S100 + 2000 * MS * (i % 2) as u32;
+            assert_eq!(None, durations.record(duration, 1.0));
+        }
+
+        // Durations alternating between 100s and 104s have a variance of
+        // 4s^2, which raises an alert once the window is full
+        let mut durations = BatchDurations::default();
+        let varied: Vec<_> = (0..DURATION_WINDOW)
+            .map(|i| S100 + 4000 * MS * (i % 2) as u32)
+            .collect();
+        for duration in &varied[..DURATION_WINDOW - 1] {
+            assert_eq!(None, durations.record(*duration, 1.0));
+        }
+        assert_eq!(
+            Some(varied.clone()),
+            durations.record(varied[DURATION_WINDOW - 1], 1.0)
+        );
+        // The alert starts over with a new window
+        assert_eq!(None, durations.record(S100, 1.0));
+
+        // The check is off with a threshold of 0
+        let mut durations = BatchDurations::default();
+        for duration in &varied {
+            assert_eq!(None, durations.record(*duration, 0.0));
+        }
+    }
 }
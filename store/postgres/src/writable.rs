@@ -1,8 +1,9 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
 use std::ops::{Deref, Range};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Mutex, RwLock, TryLockError as RwLockError};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{collections::BTreeMap, sync::Arc};
 
 use async_trait::async_trait;
@@ -13,9 +14,10 @@ use graph::constraint_violation;
 use graph::data::store::IdList;
 use graph::data::subgraph::schema;
 use graph::data_source::CausalityRegion;
+use graph::env::BatchReorgPolicy;
 use graph::prelude::{
-    BlockNumber, CacheWeight, Entity, MetricsRegistry, SubgraphDeploymentEntity,
-    SubgraphStore as _, BLOCK_NUMBER_MAX,
+    lazy_static, BlockNumber, CacheWeight, Counter, Entity, MetricsRegistry,
+    SubgraphDeploymentEntity, SubgraphStore as _, BLOCK_NUMBER_MAX,
 };
 use graph::schema::{EntityKey, EntityType, InputSchema};
 use graph::slog::{debug, info, warn};
@@ -160,6 +162,9 @@ struct SyncStore {
     input_schema: InputSchema,
     manifest_idx_and_name: Arc<Vec<(u32, String)>>,
     last_rollup: LastRollupTracker,
+    /// The number of bytes written by flushing batches, as measured by
+    /// their weight
+    bytes_written: Counter,
 }
 
 impl SyncStore {
@@ -169,6 +174,7 @@ impl SyncStore {
         site: Arc<Site>,
         manifest_idx_and_name: Arc<Vec<(u32, String)>>,
         block: Option<BlockNumber>,
+        registry: &MetricsRegistry,
     ) -> Result<Self, StoreError> {
         let store = WritableSubgraphStore(subgraph_store.clone());
         let writable = subgraph_store.for_site(site.as_ref())?.clone();
@@ -179,6 +185,13 @@ impl SyncStore {
             input_schema.has_aggregations(),
             block,
         )?;
+        let bytes_written = *registry
+            .new_counter_with_labels(
+                "store_bytes_written_total",
+                "The number of bytes written to the store by flushing write batches",
+                write_metric_labels(&site, ENV_VARS.store.metrics_network_label),
+            )
+            .expect("failed to create `store_bytes_written_total` counter");
 
         Ok(Self {
             logger,
@@ -188,10 +201,27 @@ impl SyncStore {
             input_schema,
             manifest_idx_and_name,
             last_rollup,
+            bytes_written,
         })
     }
 }
 
+/// The labels for the write metrics of `site`. The network is only
+/// included when `network_label` is set
+fn write_metric_labels(site: &Site, network_label: bool) -> HashMap<String, String> {
+    let mut labels = HashMap::from([("deployment".to_string(), site.deployment.to_string())]);
+    if network_label {
+        labels.insert("network".to_string(), site.network.clone());
+    }
+    labels
+}
+
+/// Count the weight of a batch that has been written against
+/// `store_bytes_written_total`
+fn record_bytes_written(bytes_written: &Counter, batch: &Batch) {
+    bytes_written.inc_by(batch.weight() as f64);
+}
+
 // Methods that mirror `WritableStoreTrait`
 impl SyncStore {
     async fn block_ptr(&self) -> Result<Option<BlockPtr>, StoreError> {
@@ -303,7 +333,9 @@ impl SyncStore {
             let last_block_time = batch.block_times.last().unwrap().1;
             self.last_rollup.set(Some(last_block_time))?;
             Ok(())
-        })
+        })?;
+        record_bytes_written(&self.bytes_written, batch);
+        Ok(())
     }
 
     fn get_many(
@@ -717,7 +749,7 @@ impl Request {
     fn should_process(&self) -> bool {
         match self {
             Request::Write { queued, batch, .. } => {
-                batch.read().unwrap().weight() >= ENV_VARS.store.write_batch_size
+                batch_ready(batch.read().unwrap().weight())
                     || queued.elapsed() >= ENV_VARS.store.write_batch_duration
             }
             Request::RevertTo { .. } | Request::Stop => true,
@@ -730,6 +762,97 @@ impl Request {
             Request::RevertTo { .. } | Request::Stop => false,
         }
     }
+
+    /// Return `true` if this is a write that has not been processed yet
+    /// and that only contains changes for blocks after `block`
+    fn pending_after(&self, block: BlockNumber) -> bool {
+        match self {
+            Request::Write {
+                batch, processed, ..
+            } => !processed.load(Ordering::SeqCst) && batch.read().unwrap().first_block > block,
+            Request::RevertTo { .. } | Request::Stop => false,
+        }
+    }
+
+    /// The block that the deployment will be at once this request has
+    /// been processed
+    fn block_number(&self) -> Option<BlockNumber> {
+        match self {
+            Request::Write { batch, .. } => Some(batch.read().unwrap().block_ptr.number),
+            Request::RevertTo { block_ptr, .. } => Some(block_ptr.number),
+            Request::Stop => None,
+        }
+    }
+}
+
+/// The policy for pending writes when a revert comes in
+fn reorg_policy() -> BatchReorgPolicy {
+    #[cfg(debug_assertions)]
+    if let Some(policy) = *test_support::BATCH_REORG_POLICY.lock().unwrap() {
+        return policy;
+    }
+    ENV_VARS.store.batch_reorg_policy
+}
+
+/// Remove the entries at the back of `queue` for which `pending` returns
+/// `true` if `policy` says that pending writes should be discarded when a
+/// revert comes in. Returns the number of entries that were removed
+fn discard_pending<T, F>(queue: &BoundedQueue<T>, policy: BatchReorgPolicy, pending: F) -> usize
+where
+    T: Clone,
+    F: Fn(&T) -> bool,
+{
+    match policy {
+        BatchReorgPolicy::FlushThenRevert => 0,
+        BatchReorgPolicy::DiscardPending => {
+            let mut discarded = 0;
+            while queue.pop_newest_if(&pending).is_some() {
+                discarded += 1;
+            }
+            discarded
+        }
+    }
+}
+
+/// Return `true` if a write batch with the given `weight` should be
+/// written without waiting for more blocks to be added to it. Since
+/// batches only ever grow by whole blocks, this is checked whenever a
+/// block has been completed
+fn batch_ready(weight: usize) -> bool {
+    batch_full(
+        weight,
+        ENV_VARS.store.write_batch_size,
+        ENV_VARS.store.write_batch_min_fill_ratio,
+    )
+}
+
+/// Return `true` if a batch of `weight` has reached `size`, or, if
+/// `min_fill_ratio` is positive, that fraction of `size`
+fn batch_full(weight: usize, size: usize, min_fill_ratio: f64) -> bool {
+    weight >= size || (min_fill_ratio > 0.0 && weight as f64 >= min_fill_ratio * size as f64)
+}
+
+/// Wait for `blocked` to finish. While it has not, call `log` every
+/// `interval` with how long we have been waiting. If `interval` is zero,
+/// `log` is never called
+async fn log_backpressure<F: Future>(
+    blocked: F,
+    interval: Duration,
+    mut log: impl FnMut(Duration),
+) -> F::Output {
+    if interval.is_zero() {
+        return blocked.await;
+    }
+
+    let start = graph::tokio::time::Instant::now();
+    let mut ticks = graph::tokio::time::interval_at(start + interval, interval);
+    graph::tokio::pin!(blocked);
+    loop {
+        select! {
+            output = &mut blocked => return output,
+            _ = ticks.tick() => log(start.elapsed()),
+        }
+    }
 }
 
 /// A queue that asynchronously writes requests queued with `push` to the
@@ -763,6 +886,151 @@ struct Queue {
     /// Notify the background writer as soon as we are told to stop
     /// batching or there is a batch that is big enough to proceed.
     batch_ready_notify: Arc<Notify>,
+
+    /// How fast blocks are queued; batching is bypassed when that is
+    /// slower than `write_batch_min_block_rate`
+    block_rate: Mutex<BlockRate>,
+
+    /// Set by `WRITE_BUDGET` when the batches of all deployments are too
+    /// big and the batch of this queue should be written right away
+    force_write: Arc<AtomicBool>,
+
+    /// How long the queue has been close to full
+    saturation: Mutex<QueueSaturation>,
+}
+
+lazy_static! {
+    /// The budget for the batches of all deployments
+    static ref WRITE_BUDGET: WriteBudget =
+        WriteBudget::new(ENV_VARS.store.write_batch_global_max_bytes);
+}
+
+/// A queue whose batch is waiting to be written, as seen by `WriteBudget`
+struct PendingBatch {
+    weight: usize,
+    force_write: Arc<AtomicBool>,
+    batch_ready_notify: Arc<Notify>,
+}
+
+/// Keeps track of the combined weight of the batches that queues are
+/// waiting to fill up. When that exceeds `max_bytes`, the queue with the
+/// biggest batch is told to write it right away
+struct WriteBudget {
+    /// The maximum combined weight; 0 means there is no limit
+    max_bytes: usize,
+    pending: Mutex<HashMap<DeploymentId, PendingBatch>>,
+}
+
+impl WriteBudget {
+    fn new(max_bytes: usize) -> Self {
+        WriteBudget {
+            max_bytes,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that the queue for `deployment` is waiting to fill up
+    /// `batch`. Returns the deployment whose batch has to be written right
+    /// away because all batches together are too big
+    fn record(&self, deployment: DeploymentId, batch: PendingBatch) -> Option<DeploymentId> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert(deployment, batch);
+        let total: usize = pending.values().map(|batch| batch.weight).sum();
+        if total <= self.max_bytes {
+            return None;
+        }
+        let largest = pending
+            .iter()
+            .max_by_key(|(_, batch)| batch.weight)
+            .map(|(id, _)| *id)?;
+        let batch = pending.remove(&largest)?;
+        batch.force_write.store(true, Ordering::SeqCst);
+        batch.batch_ready_notify.notify_one();
+        Some(largest)
+    }
+
+    /// Stop counting the batch of `deployment` since it is being written
+    fn release(&self, deployment: DeploymentId) {
+        if self.max_bytes > 0 {
+            self.pending.lock().unwrap().remove(&deployment);
+        }
+    }
+}
+
+/// The number of recent blocks over which `BlockRate` averages
+const BLOCK_RATE_WINDOW: usize = 10;
+
+/// Keeps track of how many blocks per second are queued for writing,
+/// averaged over the last `BLOCK_RATE_WINDOW` blocks
+#[derive(Default)]
+struct BlockRate {
+    queued: VecDeque<Instant>,
+}
+
+impl BlockRate {
+    fn record(&mut self, now: Instant) {
+        if self.queued.len() == BLOCK_RATE_WINDOW {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(now);
+    }
+
+    /// The number of blocks per second, or `None` if we have not seen
+    /// enough blocks yet to tell
+    fn per_sec(&self) -> Option<f64> {
+        if self.queued.len() < 2 {
+            return None;
+        }
+        let (first, last) = (self.queued.front()?, self.queued.back()?);
+        Some((self.queued.len() - 1) as f64 / last.duration_since(*first).as_secs_f64())
+    }
+
+    /// Return `true` if blocks are queued more slowly than `min_rate`
+    /// blocks per second. With a `min_rate` of 0, that is never the case
+    fn below(&self, min_rate: f64) -> bool {
+        min_rate > 0.0 && self.per_sec().is_some_and(|rate| rate < min_rate)
+    }
+}
+
+/// How long the write queue has to stay saturated before we warn about it
+const QUEUE_SATURATION_PERIOD: Duration = Duration::from_secs(60);
+
+/// Keeps track of how long the write queue has been at least
+/// `write_queue_saturation_warn_ratio` full
+#[derive(Default)]
+struct QueueSaturation {
+    /// When the queue became saturated, or when we last warned about it
+    since: Option<Instant>,
+}
+
+impl QueueSaturation {
+    /// Record that the queue had `depth` entries out of `capacity` at
+    /// `now`. Returns how long the queue has been saturated if that has
+    /// lasted at least `QUEUE_SATURATION_PERIOD` since it became saturated
+    /// or since the last time this returned something. With a `ratio` of
+    /// 0, the queue is never considered saturated
+    fn record(
+        &mut self,
+        depth: usize,
+        capacity: usize,
+        ratio: f64,
+        now: Instant,
+    ) -> Option<Duration> {
+        if ratio == 0.0 || capacity == 0 || (depth as f64) < ratio * capacity as f64 {
+            self.since = None;
+            return None;
+        }
+        let since = *self.since.get_or_insert(now);
+        let saturated = now.saturating_duration_since(since);
+        if saturated < QUEUE_SATURATION_PERIOD {
+            return None;
+        }
+        self.since = Some(now);
+        Some(saturated)
+    }
 }
 
 /// Support for controlling the background writer (pause/resume) only for
@@ -778,6 +1046,7 @@ pub(crate) mod test_support {
 
     use graph::{
         components::store::{DeploymentId, DeploymentLocator},
+        env::BatchReorgPolicy,
         prelude::lazy_static,
         util::bounded_queue::BoundedQueue,
     };
@@ -785,6 +1054,9 @@ pub(crate) mod test_support {
     lazy_static! {
         static ref STEPS: Mutex<HashMap<DeploymentId, Arc<BoundedQueue<()>>>> =
             Mutex::new(HashMap::new());
+        /// Tests set this to use a different policy than
+        /// `GRAPH_STORE_BATCH_REORG_POLICY` for pending writes on revert
+        pub static ref BATCH_REORG_POLICY: Mutex<Option<BatchReorgPolicy>> = Mutex::new(None);
     }
 
     pub(super) async fn take_step(deployment: &DeploymentLocator) {
@@ -862,7 +1134,11 @@ impl Queue {
                         // When this is true, push_write would never add to
                         // `req`, and we therefore execute the request as
                         // waiting for more changes to it would be pointless
-                        if !queue.batch_writes() || queue.queue.len() > 1 || req.should_process() {
+                        if !queue.batch_writes()
+                            || queue.queue.len() > 1
+                            || req.should_process()
+                            || queue.force_write.swap(false, Ordering::SeqCst)
+                        {
                             break;
                         }
 
@@ -893,6 +1169,7 @@ impl Queue {
                     // it here
                     queue.queue.peek_with(|req| req.start_process()).await
                 };
+                WRITE_BUDGET.release(queue.store.site.id);
                 let res = {
                     let _section = queue.stopwatch.start_section("queue_execute");
                     graph::spawn_blocking_allow_panic(move || req.execute()).await
@@ -950,6 +1227,9 @@ impl Queue {
             stopwatch,
             batch_writes: AtomicBool::new(true),
             batch_ready_notify: batch_ready_notify.clone(),
+            block_rate: Mutex::new(BlockRate::default()),
+            force_write: Arc::new(AtomicBool::new(false)),
+            saturation: Mutex::new(QueueSaturation::default()),
         };
         let queue = Arc::new(queue);
 
@@ -971,7 +1251,26 @@ impl Queue {
         if !req.is_write() {
             self.stop_batching();
         }
-        self.queue.push(Arc::new(req)).await;
+        let push = self.queue.push(Arc::new(req));
+        let interval = ENV_VARS.store.write_backpressure_log_interval;
+        log_backpressure(push, interval, |blocked| {
+            warn!(self.store.logger, "Writes are blocked because the write queue is full";
+                  "blocked_ms" => blocked.as_millis(),
+                  "queue_size" => self.queue.capacity());
+        })
+        .await;
+        let saturated = self.saturation.lock().unwrap().record(
+            self.queue.len(),
+            self.queue.capacity(),
+            ENV_VARS.store.write_queue_saturation_warn_ratio,
+            Instant::now(),
+        );
+        if let Some(saturated) = saturated {
+            warn!(self.store.logger, "The write queue has been close to full for a while; the database can not keep up with writes";
+                  "saturated_secs" => saturated.as_secs(),
+                  "queue_depth" => self.queue.len(),
+                  "queue_size" => self.queue.capacity());
+        }
         Ok(())
     }
 
@@ -998,9 +1297,12 @@ impl Queue {
     /// a 'full' write batch, i.e., one that is either big enough or old
     /// enough
     async fn push_write(&self, batch: Batch) -> Result<(), StoreError> {
+        self.block_rate.lock().unwrap().record(Instant::now());
+        let batching = self.batch_writes();
+
         let batch = if ENV_VARS.store.write_batch_size == 0
             || ENV_VARS.store.write_batch_duration.is_zero()
-            || !self.batch_writes()
+            || !batching
         {
             Some(batch)
         } else {
@@ -1039,8 +1341,10 @@ impl Queue {
                                 Ok(mut existing) => {
                                     if existing.weight() < ENV_VARS.store.write_batch_size {
                                         let res = existing.append(batch).map(|()| None);
-                                        if existing.weight() >= ENV_VARS.store.write_batch_size {
+                                        if batch_ready(existing.weight()) {
                                             self.batch_ready_notify.notify_one();
+                                        } else {
+                                            self.record_pending(existing.weight());
                                         }
                                         res
                                     } else {
@@ -1068,12 +1372,21 @@ impl Queue {
         };
 
         if let Some(batch) = batch {
+            let weight = batch.weight();
+            let ready = batch_ready(weight);
             let req = Request::write(
                 self.store.cheap_clone(),
                 self.stopwatch.cheap_clone(),
                 batch,
             );
             self.push(req).await?;
+            // When blocks come in too slowly for batching, the writer
+            // might be waiting for an earlier batch to fill up
+            if ready || !batching {
+                self.batch_ready_notify.notify_one();
+            } else {
+                self.record_pending(weight);
+            }
         }
         Ok(())
     }
@@ -1297,6 +1610,50 @@ impl Queue {
 
     fn batch_writes(&self) -> bool {
         self.batch_writes.load(Ordering::SeqCst)
+            && !self
+                .block_rate
+                .lock()
+                .unwrap()
+                .below(ENV_VARS.store.write_batch_min_block_rate)
+    }
+
+    /// Tell `WRITE_BUDGET` that we are waiting for a batch of `weight` to
+    /// fill up
+    fn record_pending(&self, weight: usize) {
+        let batch = PendingBatch {
+            weight,
+            force_write: self.force_write.cheap_clone(),
+            batch_ready_notify: self.batch_ready_notify.cheap_clone(),
+        };
+        WRITE_BUDGET.record(self.store.site.id, batch);
+    }
+
+    /// Drop the pending writes that a revert to `block_ptr` would undo
+    /// anyway if `GRAPH_STORE_BATCH_REORG_POLICY` says so. Return `false`
+    /// if that leaves no changes after `block_ptr` that need to be reverted
+    async fn discard_pending(&self, block_ptr: &BlockPtr) -> Result<bool, StoreError> {
+        let discarded = discard_pending(&self.queue, reorg_policy(), |req| {
+            req.pending_after(block_ptr.number)
+        });
+        if discarded == 0 {
+            return Ok(true);
+        }
+        WRITE_BUDGET.release(self.store.site.id);
+        info!(self.store.logger, "Discarded pending write batches because of a revert";
+              "batches" => discarded,
+              "block_number" => block_ptr.number);
+
+        // Once the remaining requests are processed, the deployment will be
+        // at the block of the newest one, or, if there are none, where the
+        // database already is
+        let head = match self
+            .queue
+            .map_newest(|req| req.and_then(|req| req.block_number()))
+        {
+            Some(head) => Some(head),
+            None => self.store.block_ptr().await?.map(|ptr| ptr.number),
+        };
+        Ok(head.is_some_and(|head| head > block_ptr.number))
     }
 
     fn stop_batching(&self) {
@@ -1310,6 +1667,20 @@ impl Queue {
     }
 }
 
+/// The size of the write queue for `deployment`; deployments listed in
+/// `sync_deployments` get no queue so that they write synchronously
+fn writer_capacity(
+    deployment: &DeploymentHash,
+    queue_size: usize,
+    sync_deployments: &HashSet<String>,
+) -> usize {
+    if sync_deployments.contains(deployment.as_str()) {
+        0
+    } else {
+        queue_size
+    }
+}
+
 /// A shim to allow bypassing any pipelined store handling if need be
 enum Writer {
     Sync(Arc<SyncStore>),
@@ -1372,6 +1743,9 @@ impl Writer {
             Writer::Sync(store) => store.revert_block_operations(block_ptr_to, &firehose_cursor),
             Writer::Async { queue, .. } => {
                 self.check_queue_running()?;
+                if !queue.discard_pending(&block_ptr_to).await? {
+                    return Ok(());
+                }
                 let req = Request::revert(queue.store.cheap_clone(), block_ptr_to, firehose_cursor);
                 queue.push(req).await
             }
@@ -1489,17 +1863,18 @@ impl WritableStore {
                 site,
                 manifest_idx_and_name,
                 block_ptr.as_ref().map(|ptr| ptr.number),
+                &registry,
             )
             .await?,
         );
         let block_ptr = Mutex::new(block_ptr);
         let block_cursor = Mutex::new(store.block_cursor().await?);
-        let writer = Writer::new(
-            logger,
-            store.clone(),
+        let capacity = writer_capacity(
+            &store.site.deployment,
             ENV_VARS.store.write_queue_size,
-            registry,
+            &ENV_VARS.store.write_batch_disabled_deployments,
         );
+        let writer = Writer::new(logger, store.clone(), capacity, registry);
 
         let is_deployment_synced = store.is_deployment_synced().await?;
 
@@ -1783,3 +2158,265 @@ impl WritableStoreTrait for WritableStore {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_batch_disabled_deployments() {
+        let listed = DeploymentHash::new("QmListed").unwrap();
+        let other = DeploymentHash::new("QmOther").unwrap();
+        let sync_deployments = HashSet::from(["QmListed".to_string()]);
+
+        // A listed deployment writes synchronously while others get a queue
+        // in which writes are batched
+        assert_eq!(0, writer_capacity(&listed, 5, &sync_deployments));
+        assert_eq!(5, writer_capacity(&other, 5, &sync_deployments));
+        assert_eq!(5, writer_capacity(&listed, 5, &HashSet::new()));
+    }
+
+    #[test]
+    fn write_metric_network_label() {
+        let site = primary::make_dummy_site(
+            DeploymentHash::new("QmLabels").unwrap(),
+            primary::Namespace::new("sgd1".to_string()).unwrap(),
+            "mainnet".to_string(),
+        );
+
+        let labels = write_metric_labels(&site, false);
+        assert_eq!(
+            Some("QmLabels"),
+            labels.get("deployment").map(String::as_str)
+        );
+        assert!(!labels.contains_key("network"));
+
+        let labels = write_metric_labels(&site, true);
+        assert_eq!(
+            Some("QmLabels"),
+            labels.get("deployment").map(String::as_str)
+        );
+        assert_eq!(Some("mainnet"), labels.get("network").map(String::as_str));
+    }
+
+    #[test]
+    fn batch_reorg_policy() {
+        // Pending writes, oldest first, as the first block of each batch
+        // and whether the writer has started processing it
+        let queue = || {
+            let queue = BoundedQueue::with_capacity(5);
+            for entry in [(1, true), (3, false), (5, false), (8, false)] {
+                graph::futures03::executor::block_on(queue.push(entry));
+            }
+            queue
+        };
+        let pending_after = |block| {
+            move |&(first_block, processed): &(BlockNumber, bool)| !processed && first_block > block
+        };
+        let first_blocks = |queue: &BoundedQueue<(BlockNumber, bool)>| {
+            queue.fold(Vec::new(), |mut blocks, (first_block, _)| {
+                blocks.push(*first_block);
+                blocks
+            })
+        };
+
+        // With `flush_then_revert`, all pending writes get written before
+        // the revert
+        let flush = queue();
+        assert_eq!(
+            0,
+            discard_pending(&flush, BatchReorgPolicy::FlushThenRevert, pending_after(2))
+        );
+        assert_eq!(vec![8, 5, 3, 1], first_blocks(&flush));
+
+        // With `discard_pending`, writes that only touch blocks after the
+        // revert are dropped
+        let discard = queue();
+        assert_eq!(
+            2,
+            discard_pending(&discard, BatchReorgPolicy::DiscardPending, pending_after(4))
+        );
+        assert_eq!(vec![3, 1], first_blocks(&discard));
+
+        // A write that is already being processed is never dropped
+        let discard = queue();
+        assert_eq!(
+            3,
+            discard_pending(&discard, BatchReorgPolicy::DiscardPending, pending_after(0))
+        );
+        assert_eq!(vec![1], first_blocks(&discard));
+    }
+
+    #[test]
+    fn bytes_written_per_flush() {
+        let registry = MetricsRegistry::mock();
+        let bytes_written = registry
+            .new_deployment_counter("store_bytes_written_total", "bytes written", "QmBytes")
+            .unwrap();
+        let block_ptr = BlockPtr::from((vec![1u8; 32], 1 as BlockNumber));
+        let batch = Batch::new(
+            block_ptr.clone(),
+            BlockTime::for_test(&block_ptr),
+            FirehoseCursor::None,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+        )
+        .unwrap();
+        let weight = batch.weight() as f64;
+        assert!(weight > 0.0);
+
+        record_bytes_written(&bytes_written, &batch);
+        assert_eq!(weight, bytes_written.get());
+        record_bytes_written(&bytes_written, &batch);
+        assert_eq!(2.0 * weight, bytes_written.get());
+    }
+
+    #[test]
+    fn backpressure_log_cadence() {
+        const INTERVAL: Duration = Duration::from_secs(10);
+
+        let runtime = graph::tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // A write that is blocked for 3.5 intervals is logged three
+            // times
+            let mut logged = Vec::new();
+            let blocked = graph::tokio::time::sleep(INTERVAL * 7 / 2);
+            log_backpressure(blocked, INTERVAL, |blocked| logged.push(blocked)).await;
+            assert_eq!(vec![INTERVAL, INTERVAL * 2, INTERVAL * 3], logged);
+
+            // Nothing is logged when logging is turned off
+            let mut logged = Vec::new();
+            let blocked = graph::tokio::time::sleep(INTERVAL * 7 / 2);
+            log_backpressure(blocked, Duration::ZERO, |blocked| logged.push(blocked)).await;
+            assert!(logged.is_empty());
+        });
+    }
+
+    #[test]
+    fn write_batch_min_fill_ratio() {
+        // Without a fill ratio, only a full batch is written early
+        assert!(!batch_full(799, 1000, 0.0));
+        assert!(batch_full(1000, 1000, 0.0));
+
+        // A batch that is at least as full as the ratio is written as soon
+        // as a block is added to it
+        assert!(!batch_full(799, 1000, 0.8));
+        assert!(batch_full(800, 1000, 0.8));
+        assert!(batch_full(1000, 1000, 0.8));
+    }
+
+    #[test]
+    fn write_batch_min_block_rate() {
+        fn rate(interval: Duration, blocks: usize) -> BlockRate {
+            let start = Instant::now();
+            let mut rate = BlockRate::default();
+            for i in 0..blocks {
+                rate.record(start + interval * i as u32);
+            }
+            rate
+        }
+
+        // Close to the chain head, with a block every 12s, batching is
+        // bypassed
+        let slow = rate(Duration::from_secs(12), 5);
+        assert!(slow.below(0.5));
+        // ... but not if the setting is off
+        assert!(!slow.below(0.0));
+
+        // When blocks come in quickly, they are batched
+        let fast = rate(Duration::from_millis(10), 5);
+        assert!(!fast.below(0.5));
+
+        // One block is not enough to tell how fast blocks come in
+        assert!(!rate(Duration::from_secs(12), 1).below(0.5));
+
+        // Only the most recent blocks count
+        let mut rate = rate(Duration::from_secs(12), BLOCK_RATE_WINDOW);
+        let last = *rate.queued.back().unwrap();
+        for i in 1..=BLOCK_RATE_WINDOW {
+            rate.record(last + Duration::from_millis(10) * i as u32);
+        }
+        assert!(!rate.below(0.5));
+    }
+
+    #[test]
+    fn write_queue_saturation_warn_ratio() {
+        let start = Instant::now();
+        let secs = |secs: u64| start + Duration::from_secs(secs);
+
+        // A queue that stays full for long enough causes a warning, and
+        // then again once it has stayed full for another period
+        let mut sat = QueueSaturation::default();
+        assert_eq!(None, sat.record(5, 5, 0.8, secs(0)));
+        assert_eq!(None, sat.record(4, 5, 0.8, secs(30)));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            sat.record(5, 5, 0.8, secs(60))
+        );
+        assert_eq!(None, sat.record(5, 5, 0.8, secs(90)));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            sat.record(5, 5, 0.8, secs(120))
+        );
+
+        // Brief spikes do not cause a warning
+        let mut sat = QueueSaturation::default();
+        for i in 0..10 {
+            assert_eq!(None, sat.record(5, 5, 0.8, secs(i * 40)));
+            assert_eq!(None, sat.record(1, 5, 0.8, secs(i * 40 + 30)));
+        }
+
+        // With a ratio of 0, we never warn
+        let mut sat = QueueSaturation::default();
+        assert_eq!(None, sat.record(5, 5, 0.0, secs(0)));
+        assert_eq!(None, sat.record(5, 5, 0.0, secs(120)));
+    }
+
+    #[test]
+    fn write_batch_global_max_bytes() {
+        let id = |id: i32| DeploymentId::from(graph::components::store::DeploymentId::new(id));
+        let pending = |weight: usize| {
+            let force_write = Arc::new(AtomicBool::new(false));
+            let batch = PendingBatch {
+                weight,
+                force_write: force_write.cheap_clone(),
+                batch_ready_notify: Arc::new(Notify::new()),
+            };
+            (batch, force_write)
+        };
+
+        let budget = WriteBudget::new(100);
+        let (batch1, forced1) = pending(40);
+        let (batch2, forced2) = pending(50);
+        assert_eq!(None, budget.record(id(1), batch1));
+        assert_eq!(None, budget.record(id(2), batch2));
+
+        // Growing the batch of deployment 1 exceeds the budget, and the
+        // batch of deployment 1 is now the largest one
+        let (batch1, forced1b) = pending(70);
+        assert_eq!(Some(id(1)), budget.record(id(1), batch1));
+        assert!(forced1b.load(Ordering::SeqCst));
+        assert!(!forced1.load(Ordering::SeqCst));
+        assert!(!forced2.load(Ordering::SeqCst));
+
+        // Once a batch is being written, it no longer counts
+        let (batch3, _) = pending(45);
+        assert_eq!(None, budget.record(id(3), batch3));
+        budget.release(id(2));
+        let (batch3, _) = pending(95);
+        assert_eq!(None, budget.record(id(3), batch3));
+
+        // Without a budget, nothing is forced
+        let budget = WriteBudget::new(0);
+        let (batch, forced) = pending(1_000_000);
+        assert_eq!(None, budget.record(id(1), batch));
+        assert!(!forced.load(Ordering::SeqCst));
+    }
+}
@@ -30,7 +30,7 @@ use graph::{
         lazy_static, q, r, serde_json, BlockPtr, DeploymentHash, Entity, EntityOperation,
         GraphQlRunner as _, NodeId, Query, QueryError, QueryExecutionError, QueryResult,
         QueryVariables, SubgraphManifest, SubgraphName, SubgraphStore,
-        SubgraphVersionSwitchingMode,
+        SubgraphVersionSwitchingMode, ENV_VARS,
     },
 };
 use graph_graphql::prelude::*;
@@ -2532,6 +2532,22 @@ fn can_query_meta() {
     });
 }
 
+#[test]
+fn can_query_meta_query_flags() {
+    const QUERY: &str =
+        "query { _meta { orderByBlockRange useBrinForAllQueryTypes typedChildrenSetSize } }";
+    run_query(QUERY, |result, _| {
+        let exp = object! {
+            _meta: object! {
+                orderByBlockRange: ENV_VARS.store.order_by_block_range,
+                useBrinForAllQueryTypes: ENV_VARS.store.use_brin_for_all_query_types,
+                typedChildrenSetSize: ENV_VARS.store.typed_children_set_size as i32,
+            },
+        };
+        assert_eq!(extract_data!(result), Some(exp));
+    });
+}
+
 #[test]
 fn non_fatal_errors() {
     use serde_json::json;
@@ -1,13 +1,17 @@
 use std::fmt::Write;
+use std::time::Duration;
 use std::{future::Future, sync::Arc};
 
+use diesel::connection::SimpleConnection;
+
 use graph::{
     blockchain::{block_stream::FirehoseCursor, BlockPtr, BlockTime},
     components::{
         metrics::stopwatch::StopwatchMetrics,
         store::{
             AttributeNames, BlockNumber, DeploymentLocator, EntityCache, EntityCollection,
-            EntityOperation, EntityQuery, ReadStore, StoreError, SubgraphStore as _, WritableStore,
+            EntityFilter, EntityOperation, EntityQuery, ReadStore, StoreError, SubgraphStore as _,
+            WritableStore,
         },
     },
     data::{
@@ -21,8 +25,11 @@ use graph::{
     prelude::lazy_static,
     schema::InputSchema,
 };
+use graph_store_postgres::layout_for_tests::ROLLUP_RESULT_CACHE_TTL;
 use graph_store_postgres::{Store as DieselStore, SubgraphStore};
-use test_store::{create_test_subgraph, run_test_sequentially, BLOCKS, LOGGER, METRICS_REGISTRY};
+use test_store::{
+    create_test_subgraph, run_test_sequentially, BLOCKS, LOGGER, METRICS_REGISTRY, PRIMARY_POOL,
+};
 
 const SCHEMA: &str = r#"
 type Data @entity(timeseries: true) {
@@ -302,3 +309,58 @@ fn simple() {
         }
     })
 }
+
+#[test]
+fn rollup_result_cache() {
+    run_test(|env| async move {
+        let query_stats = |filter: EntityFilter| {
+            let entity_type = env
+                .writable
+                .input_schema()
+                .entity_type("Stats_hour")
+                .unwrap();
+            let query = EntityQuery::new(
+                env.deployment.hash.clone(),
+                BlockNumber::MAX,
+                EntityCollection::All(vec![(entity_type, AttributeNames::All)]),
+            )
+            .filter(filter);
+            env.store.subgraph_store().find(query).unwrap()
+        };
+        // The first bucket is closed since there is a later one
+        let first_bucket = || {
+            query_stats(EntityFilter::LessOrEqual(
+                "timestamp".to_string(),
+                BlockTime::since_epoch(0, 0).into(),
+            ))
+        };
+        // The bucket after the last one is still open
+        let open_bucket = || {
+            query_stats(EntityFilter::Equal(
+                "timestamp".to_string(),
+                BlockTime::since_epoch(7200, 0).into(),
+            ))
+        };
+
+        *ROLLUP_RESULT_CACHE_TTL.lock().unwrap() = Some(Duration::from_secs(600));
+        let exp = stats_hour(&env.writable.input_schema())[2].clone();
+        assert_eq!(exp, first_bucket());
+        assert!(open_bucket().is_empty());
+
+        // Change the rollups behind the store's back; the query for the
+        // closed bucket is answered from the cache, but the query for the
+        // open bucket is not cached
+        let mut conn = PRIMARY_POOL.get().unwrap();
+        conn.batch_execute(&format!(
+            "update sgd{id}.stats_hour set sum = 0;
+             update sgd{id}.stats_hour set timestamp = timestamp + interval '1 hour'
+              where timestamp = to_timestamp(3600)",
+            id = env.deployment.id
+        ))
+        .unwrap();
+        assert_eq!(exp, first_bucket());
+        assert_eq!(2, open_bucket().len());
+
+        *ROLLUP_RESULT_CACHE_TTL.lock().unwrap() = None;
+    })
+}
@@ -417,6 +417,56 @@ fn ancestor_block_skipped() {
     });
 }
 
+#[test]
+fn recent_blocks_prefetch() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_TWO,
+        &*BLOCK_THREE,
+        &*BLOCK_FOUR,
+        &*BLOCK_FIVE,
+    ];
+
+    run_test_async(chain, |store, _, _| async move {
+        let cached = |store: &DieselChainStore| -> Vec<BlockNumber> {
+            store
+                .recent_blocks()
+                .into_iter()
+                .map(|(ptr, _)| ptr.number)
+                .collect()
+        };
+
+        // Without prefetching, a miss only caches the block itself
+        store.clear_recent_blocks();
+        store
+            .cheap_clone()
+            .blocks_with_prefetch(vec![BLOCK_FOUR.block_hash()], 0)
+            .await
+            .unwrap();
+        assert_eq!(vec![4], cached(&store));
+
+        // A miss loads up to `prefetch_depth` ancestors into the cache
+        store.clear_recent_blocks();
+        let blocks = store
+            .cheap_clone()
+            .blocks_with_prefetch(vec![BLOCK_FOUR.block_hash()], 2)
+            .await
+            .unwrap();
+        assert_eq!(1, blocks.len());
+        assert_eq!(vec![2, 3, 4], cached(&store));
+
+        // Prefetching stops at the genesis block
+        store.clear_recent_blocks();
+        store
+            .cheap_clone()
+            .blocks_with_prefetch(vec![BLOCK_TWO.block_hash()], 5)
+            .await
+            .unwrap();
+        assert_eq!(vec![0, 1, 2], cached(&store));
+    })
+}
+
 #[test]
 fn eth_call_cache() {
     let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
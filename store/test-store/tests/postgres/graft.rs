@@ -6,8 +6,8 @@ use std::{marker::PhantomData, str::FromStr};
 use test_store::*;
 
 use graph::components::store::{
-    DeploymentLocator, EntityOrder, EntityQuery, PruneReporter, PruneRequest, PruningStrategy,
-    VersionStats,
+    DeploymentLocator, EntityOrder, EntityQuery, PrunePhase, PruneReporter, PruneRequest,
+    PruningStrategy, VersionStats,
 };
 use graph::data::store::{scalar, Id};
 use graph::data::subgraph::schema::*;
@@ -648,6 +648,8 @@ fn prune() {
                 tablename: USER.to_ascii_lowercase(),
                 ratio: 3.0 / 5.0,
                 last_pruned_block: None,
+                rebuild_threshold: None,
+                delete_threshold: None,
             };
             assert_eq!(
                 Some(strategy),
@@ -671,3 +673,199 @@ fn prune() {
         })
     }
 }
+
+#[test]
+fn prune_deferred_during_copy() {
+    struct Progress;
+    impl PruneReporter for Progress {}
+
+    run_test(|store, src| async move {
+        let earliest_block = |store: &DieselSubgraphStore| {
+            store.status_for_id(src.id).chains[0].earliest_block_number
+        };
+
+        // Forward block ptr to block 6 so that there is history to prune
+        transact_and_wait(&store, &src, BLOCKS[6].clone(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(0, earliest_block(&store));
+
+        // While data is being copied into the deployment, pruning it does
+        // nothing
+        let mut primary = primary_connection();
+        let site = primary.locate_site(src.clone())?.unwrap();
+        primary.record_active_copy(&site, &site)?;
+        let req = PruneRequest::new(&src, 3, 1, 0, 6)?;
+        store.prune(Box::new(Progress), &src, req).await?;
+        assert_eq!(0, earliest_block(&store));
+
+        // Once the copy has finished, pruning goes ahead
+        primary.copy_finished(&site)?;
+        let req = PruneRequest::new(&src, 3, 1, 0, 6)?;
+        store.prune(Box::new(Progress), &src, req).await?;
+        assert_eq!(3, earliest_block(&store));
+        Ok(())
+    })
+}
+
+#[test]
+fn prune_analyzes_stale_stats() {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Remember which tables were analyzed before the first decision
+    /// about how to prune them
+    struct Progress {
+        analyzed: Arc<Mutex<Option<Vec<String>>>>,
+    }
+
+    impl PruneReporter for Progress {
+        fn finish_analyze(&mut self, _stats: &[VersionStats], analyzed: &[&str]) {
+            let mut seen = self.analyzed.lock().unwrap();
+            if seen.is_none() {
+                *seen = Some(analyzed.iter().map(|table| table.to_string()).collect());
+            }
+        }
+    }
+
+    run_test(|store, src| async move {
+        let user = USER.to_ascii_lowercase();
+        let prune = |stats_max_age: Option<Duration>| {
+            let store = store.clone();
+            let src = src.clone();
+            async move {
+                let analyzed = Arc::new(Mutex::new(None));
+                let progress = Progress {
+                    analyzed: analyzed.clone(),
+                };
+                let mut req = PruneRequest::new(&src, 3, 1, 0, 6)?;
+                req.stats_max_age = stats_max_age;
+                store.prune(Box::new(progress), &src, req).await?;
+                let analyzed = analyzed.lock().unwrap().take().unwrap_or_default();
+                Ok::<_, StoreError>(analyzed)
+            }
+        };
+
+        transact_and_wait(&store, &src, BLOCKS[6].clone(), vec![])
+            .await
+            .unwrap();
+
+        // Without a maximum age, all tables are analyzed
+        assert!(prune(None).await?.contains(&user));
+
+        // Give the statistics collector time to record the analyze
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // Statistics that are younger than the maximum age are used as is
+        assert!(!prune(Some(Duration::from_secs(3600)))
+            .await?
+            .contains(&user));
+
+        // Statistics that are older than the maximum age trigger an
+        // analyze before deciding how to prune
+        assert!(prune(Some(Duration::from_secs(1))).await?.contains(&user));
+        Ok(())
+    })
+}
+
+#[test]
+fn prune_truncates_when_full() {
+    use std::sync::Mutex;
+
+    /// Remember whether any table was truncated
+    struct Progress {
+        truncated: Arc<Mutex<bool>>,
+    }
+
+    impl PruneReporter for Progress {
+        fn prune_batch(&mut self, _table: &str, _rows: usize, phase: PrunePhase, _finished: bool) {
+            if matches!(phase, PrunePhase::Truncate) {
+                *self.truncated.lock().unwrap() = true;
+            }
+        }
+    }
+
+    for truncate_when_full in [false, true] {
+        run_test(move |store, src| async move {
+            let user_type = TEST_SUBGRAPH_SCHEMA.entity_type("User").unwrap();
+
+            // Remove all users at block 3 so that pruning to block 3
+            // removes all versions of users
+            let ops = ["1", "2", "3"]
+                .into_iter()
+                .map(|id| EntityOperation::Remove {
+                    key: user_type.parse_key(id).unwrap(),
+                })
+                .collect();
+            transact_and_wait(&store, &src, BLOCKS[3].clone(), ops)
+                .await
+                .unwrap();
+            transact_and_wait(&store, &src, BLOCKS[6].clone(), vec![])
+                .await
+                .unwrap();
+
+            let truncated = Arc::new(Mutex::new(false));
+            let progress = Progress {
+                truncated: truncated.clone(),
+            };
+            let mut req = PruneRequest::new(&src, 3, 1, 0, 6)?;
+            // Make sure the users table gets pruned
+            req.rebuild_threshold = 0.0;
+            req.delete_threshold = 0.0;
+            req.truncate_when_full = truncate_when_full;
+            store.prune(Box::new(progress), &src, req).await?;
+            assert_eq!(truncate_when_full, *truncated.lock().unwrap());
+
+            // Either way, no users are left at any block
+            for block in 0..=6 {
+                let query = EntityQuery::new(
+                    src.hash.clone(),
+                    block,
+                    EntityCollection::All(vec![(user_type.clone(), AttributeNames::All)]),
+                );
+                assert!(store.find(query).unwrap().is_empty());
+            }
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn prune_records_maintenance_history() {
+    use graph_store_postgres::layout_for_tests::MAINTENANCE_HISTORY_ENABLED;
+
+    struct Progress;
+    impl PruneReporter for Progress {}
+
+    run_test(|store, src| async move {
+        let pruned_users = |store: &DieselSubgraphStore| {
+            store
+                .maintenance_history(&src.hash)
+                .unwrap()
+                .into_iter()
+                .filter(|record| record.operation == "prune" && record.table == "user")
+                .collect::<Vec<_>>()
+        };
+
+        // Earlier runs of this test might have left history for the
+        // same deployment behind
+        let before = pruned_users(&store).len();
+
+        *MAINTENANCE_HISTORY_ENABLED.lock().unwrap() = true;
+        let mut req = PruneRequest::new(&src, 3, 1, 0, 6)?;
+        // Make sure the users table gets pruned
+        req.rebuild_threshold = 0.0;
+        req.delete_threshold = 0.0;
+        let res = store.prune(Box::new(Progress), &src, req).await;
+        *MAINTENANCE_HISTORY_ENABLED.lock().unwrap() = false;
+        res?;
+
+        let records = pruned_users(&store);
+        assert_eq!(before + 1, records.len());
+        let record = records.last().unwrap();
+        assert!(record.started_at <= record.finished_at);
+        assert!(record.bytes > 0);
+        assert!(!record.strategy.is_empty());
+        Ok(())
+    })
+}
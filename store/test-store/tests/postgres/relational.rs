@@ -13,6 +13,10 @@ use graph::schema::{EntityKey, EntityType, InputSchema};
 use graph_store_postgres::layout_for_tests::set_account_like;
 use graph_store_postgres::layout_for_tests::LayoutCache;
 use graph_store_postgres::layout_for_tests::SqlName;
+use graph_store_postgres::layout_for_tests::{
+    reset_maintenance_work_mem, reset_statement_timeout, set_maintenance_work_mem,
+    set_statement_timeout,
+};
 use hex_literal::hex;
 use lazy_static::lazy_static;
 use std::collections::BTreeSet;
@@ -821,6 +825,30 @@ fn count_scalar_entities(conn: &mut PgConnection, layout: &Layout) -> usize {
         .len()
 }
 
+#[test]
+fn query_phase_timing() {
+    run_test(|conn, layout| {
+        insert_entity(conn, layout, &*SCALAR_TYPE, vec![SCALAR_ENTITY.clone()]);
+
+        let collection = EntityCollection::All(vec![(SCALAR_TYPE.to_owned(), AttributeNames::All)]);
+        let query = EntityQuery::new(layout.site.deployment.clone(), BLOCK_NUMBER_MAX, collection);
+        let (entities, _, phases) = layout
+            .query_timed::<Entity>(&LOGGER, conn, query)
+            .expect("Query failed");
+        assert_eq!(1, entities.len());
+        assert!(phases.sql > Duration::ZERO);
+
+        let registry = MetricsRegistry::mock();
+        let metrics = registry
+            .global_histogram_vec("query_phase_duration_secs", "query phases", &["phase"])
+            .unwrap();
+        phases.record(&LOGGER, &metrics);
+        for phase in ["build", "sql", "decode"] {
+            assert_eq!(1, metrics.with_label_values(&[phase]).get_sample_count());
+        }
+    });
+}
+
 #[test]
 fn delete() {
     run_test(|conn, layout| {
@@ -885,6 +913,116 @@ fn insert_many_and_delete_many() {
     });
 }
 
+#[test]
+fn insert_in_chunks() {
+    run_test(|conn, layout| {
+        // Each dog needs at least 3 bind variables (id, name, and block
+        // range), and inserting 25_000 of them in one statement would
+        // exceed the 65535 bind variables that Postgres allows
+        let dogs: Vec<_> = (0..25_000)
+            .map(|i| {
+                let dog = entity! { layout.input_schema =>
+                    id: format!("dog{i}"),
+                    name: format!("Dog {i}"),
+                    vid: i as i64,
+                };
+                (DOG_TYPE.key(dog.id()), dog)
+            })
+            .collect();
+
+        let group = row_group_insert(&*DOG_TYPE, 0, dogs);
+        let statements = layout
+            .insert(conn, &group, &MOCK_STOPWATCH)
+            .expect("Failed to insert dogs");
+        assert!(statements > 1, "expected chunked insert, got {statements}");
+
+        // A small insert needs only one statement
+        let dog = entity! { layout.input_schema =>
+            id: "pluto",
+            name: "Pluto",
+            vid: 25_000i64,
+        };
+        let group = row_group_insert(&*DOG_TYPE, 0, vec![(DOG_TYPE.key(dog.id()), dog)]);
+        let statements = layout
+            .insert(conn, &group, &MOCK_STOPWATCH)
+            .expect("Failed to insert pluto");
+        assert_eq!(1, statements);
+    });
+}
+
+#[test]
+fn maintenance_work_mem() {
+    fn work_mem(conn: &mut PgConnection) -> (String, String) {
+        use diesel::dsl::sql;
+        use diesel::sql_types::Text;
+        use diesel::{select, RunQueryDsl};
+
+        let work_mem = select(sql::<Text>("current_setting('work_mem')"))
+            .get_result(conn)
+            .unwrap();
+        let maintenance_work_mem = select(sql::<Text>("current_setting('maintenance_work_mem')"))
+            .get_result(conn)
+            .unwrap();
+        (work_mem, maintenance_work_mem)
+    }
+
+    run_test_with_conn(|conn| {
+        let mut query_conn = PRIMARY_POOL.get().unwrap();
+        let default = work_mem(conn);
+        assert_ne!("77MB", default.0);
+
+        // Without a setting, nothing changes
+        set_maintenance_work_mem(conn, None).unwrap();
+        assert_eq!(default, work_mem(conn));
+
+        // The setting only applies to the maintenance connection
+        set_maintenance_work_mem(conn, Some("77MB")).unwrap();
+        assert_eq!(("77MB".to_string(), "77MB".to_string()), work_mem(conn));
+        assert_eq!(default, work_mem(&mut query_conn));
+
+        // Once maintenance is done, the connection can be used for queries
+        reset_maintenance_work_mem(conn, Some("77MB")).unwrap();
+        assert_eq!(default, work_mem(conn));
+    })
+}
+
+#[test]
+fn prune_timeout() {
+    fn statement_timeout(conn: &mut PgConnection) -> String {
+        use diesel::dsl::sql;
+        use diesel::sql_types::Text;
+        use diesel::{select, RunQueryDsl};
+
+        select(sql::<Text>("current_setting('statement_timeout')"))
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn hang(conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+        conn.batch_execute("select pg_sleep(2)")
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    run_test_with_conn(|conn| {
+        let mut query_conn = PRIMARY_POOL.get().unwrap();
+        let default = statement_timeout(conn);
+
+        // The timeout only applies to the prune connection
+        set_statement_timeout(conn, Some(TIMEOUT)).unwrap();
+        assert_eq!("100ms", statement_timeout(conn));
+        assert_eq!(default, statement_timeout(&mut query_conn));
+
+        // A statement that hangs is aborted
+        let err = hang(conn).unwrap_err();
+        assert!(err.to_string().contains("statement timeout"));
+
+        // Once pruning is done, statements can run as long as they need
+        reset_statement_timeout(conn, Some(TIMEOUT)).unwrap();
+        assert_eq!(default, statement_timeout(conn));
+    })
+}
+
 #[tokio::test]
 async fn layout_cache() {
     // We need to use `block_on` to call the `create_test_subgraph` function which must be called
@@ -10,6 +10,7 @@ use graph::{
         schema::{DeploymentCreate, SubgraphError},
         DeploymentFeatures,
     },
+    prelude::chrono,
     prelude::AssignmentChange,
     prelude::BlockPtr,
     prelude::QueryStoreManager,
@@ -18,12 +19,12 @@ use graph::{
     prelude::SubgraphName,
     prelude::SubgraphVersionSwitchingMode,
     prelude::UnfailOutcome,
-    prelude::{CheapClone, DeploymentHash, NodeId, SubgraphStore as _},
+    prelude::{CheapClone, DeploymentHash, NodeId, SubgraphStore as _, ENV_VARS},
     schema::InputSchema,
     semver::Version,
 };
 use graph_store_postgres::layout_for_tests::Connection as Primary;
-use graph_store_postgres::SubgraphStore;
+use graph_store_postgres::{unused, SubgraphStore, PRIMARY_SHARD};
 use std::{collections::HashSet, marker::PhantomData, sync::Arc};
 use test_store::*;
 
@@ -1194,3 +1195,119 @@ fn fail_unfail_non_deterministic_error_noop() {
         test_store::remove_subgraphs();
     })
 }
+
+#[test]
+fn remove_unused_min_age() {
+    const NAME: &str = "removeUnusedMinAge";
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+
+        let id = DeploymentHash::new(NAME).unwrap();
+        let deployment = create_test_subgraph(&id, SUBGRAPH_GQL).await;
+        let store = store.subgraph_store();
+
+        // Make the deployment unused by removing the subgraph that uses it
+        // and unassigning it
+        store
+            .remove_subgraph(SubgraphName::new(NAME).unwrap())
+            .unwrap();
+        let mut primary = primary_connection();
+        let site = primary.locate_site(deployment.clone()).unwrap().unwrap();
+        primary.unassign_subgraph(&site).unwrap();
+
+        let recorded = store.record_unused_deployments().unwrap();
+        assert_eq!(1, recorded.len());
+
+        let removable = |min_age: chrono::Duration| {
+            store
+                .list_unused_deployments(unused::Filter::UnusedLongerThan(min_age))
+                .unwrap()
+        };
+
+        let mut env = ENV_VARS.store.clone();
+        env.remove_unused_interval = chrono::Duration::zero();
+
+        // The deployment was just unassigned and is spared
+        env.remove_unused_min_age = chrono::Duration::minutes(60);
+        assert!(removable(env.remove_unused_after()).is_empty());
+
+        // Without a minimum age, the deployment is old enough to be removed
+        env.remove_unused_min_age = chrono::Duration::zero();
+        let unused = removable(env.remove_unused_after());
+        assert_eq!(1, unused.len());
+        assert_eq!(NAME, unused[0].deployment);
+        store.remove_deployment(unused[0].id).unwrap();
+        assert!(removable(env.remove_unused_after()).is_empty());
+    })
+}
+
+#[test]
+fn load_query_stats() {
+    const NAME: &str = "loadQueryStats";
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+
+        let id = DeploymentHash::new(NAME).unwrap();
+        let deployment = create_test_subgraph(&id, SUBGRAPH_GQL).await;
+        let store = store.subgraph_store();
+
+        // After a restart, nothing is cached
+        store.clear_caches();
+        assert!(!store.layout_is_cached(&deployment).unwrap());
+
+        // Eager loading at startup caches the layout of every deployment
+        store.load_query_stats(&LOGGER).unwrap();
+        assert!(store.layout_is_cached(&deployment).unwrap());
+    })
+}
+
+#[test]
+fn deployments_per_pool() {
+    const NAME1: &str = "deploymentsPerPool1";
+    const NAME2: &str = "deploymentsPerPool2";
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+
+        let primary_count = |store: &Store| {
+            let counts = store.deployments_per_pool().unwrap();
+            counts
+                .into_iter()
+                .find(|(pool, _)| pool == PRIMARY_SHARD.as_str())
+                .map(|(_, count)| count)
+                .unwrap()
+        };
+        let gauge = |store: &SubgraphStore| store.deployments_per_pool_gauge(&PRIMARY_SHARD);
+
+        assert_eq!(0, primary_count(&store));
+        assert_eq!(0.0, gauge(&store.subgraph_store()));
+
+        // Creating deployments updates the gauge
+        let id1 = DeploymentHash::new(NAME1).unwrap();
+        let deployment = create_test_subgraph(&id1, SUBGRAPH_GQL).await;
+        assert_eq!(1.0, gauge(&store.subgraph_store()));
+        let id2 = DeploymentHash::new(NAME2).unwrap();
+        create_test_subgraph(&id2, SUBGRAPH_GQL).await;
+        assert_eq!(2.0, gauge(&store.subgraph_store()));
+        assert_eq!(2, primary_count(&store));
+
+        // Removing a deployment updates the gauge
+        let subgraph_store = store.subgraph_store();
+        subgraph_store
+            .remove_subgraph(SubgraphName::new(NAME1).unwrap())
+            .unwrap();
+        let mut primary = primary_connection();
+        let site = primary.locate_site(deployment.clone()).unwrap().unwrap();
+        primary.unassign_subgraph(&site).unwrap();
+        subgraph_store.record_unused_deployments().unwrap();
+        let unused = subgraph_store
+            .list_unused_deployments(unused::Filter::All)
+            .unwrap();
+        assert_eq!(1, unused.len());
+        subgraph_store.remove_deployment(unused[0].id).unwrap();
+        assert_eq!(1.0, gauge(&subgraph_store));
+        assert_eq!(1, primary_count(&store));
+    })
+}
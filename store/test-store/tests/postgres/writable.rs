@@ -2,6 +2,7 @@ use graph::blockchain::block_stream::{EntitySourceOperation, FirehoseCursor};
 use graph::data::subgraph::schema::DeploymentCreate;
 use graph::data::value::Word;
 use graph::data_source::CausalityRegion;
+use graph::env::BatchReorgPolicy;
 use graph::schema::{EntityKey, EntityType, InputSchema};
 use lazy_static::lazy_static;
 use std::collections::{BTreeMap, BTreeSet};
@@ -314,6 +315,33 @@ fn get_derived_nobatch() {
     get_with_pending(false, count_get_derived);
 }
 
+#[test]
+fn revert_discarded_writes() {
+    run_test(|store, writable, _, deployment| async move {
+        let subgraph_store = store.subgraph_store();
+        *writable::BATCH_REORG_POLICY.lock().unwrap() = Some(BatchReorgPolicy::DiscardPending);
+
+        for count in 1..3 {
+            insert_count(&subgraph_store, &deployment, count, count, false).await;
+        }
+        pause_writer(&deployment).await;
+
+        // Reverting block 3 while it is still pending drops its write, and
+        // leaves nothing to revert in the database
+        insert_count(&subgraph_store, &deployment, 3, 3, false).await;
+        writable
+            .revert_block_operations(block_pointer(2), FirehoseCursor::None)
+            .await
+            .unwrap();
+        writable::allow_steps(&deployment, 10).await;
+        writable.flush().await.unwrap();
+        *writable::BATCH_REORG_POLICY.lock().unwrap() = None;
+
+        assert_eq!(Some(block_pointer(2)), writable.block_ptr());
+        assert_eq!(2, count_get(writable.as_ref()));
+    })
+}
+
 #[test]
 fn restart() {
     run_test(|store, writable, _, deployment| async move {